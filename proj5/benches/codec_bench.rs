@@ -0,0 +1,65 @@
+use criterion::{criterion_group, criterion_main, Criterion, Fun};
+use proj5::kvs::{Codec, LogEntry};
+
+const CODECS: [Codec; 2] = [Codec::Bson, Codec::Binary];
+
+fn entries() -> Vec<LogEntry> {
+    (0..1000)
+        .map(|i| match i % 10 {
+            0 => LogEntry::Remove {
+                key: format!("key{}", i).into_bytes(),
+            },
+            _ => LogEntry::Set {
+                key: format!("key{}", i).into_bytes(),
+                val: format!("{:0>100}", i).into_bytes(),
+            },
+        })
+        .collect()
+}
+
+fn encode_bench(c: &mut Criterion) {
+    let funs = CODECS
+        .iter()
+        .map(|codec| {
+            let codec = *codec;
+            Fun::new(codec.name(), move |b, entries: &Vec<LogEntry>| {
+                let mut buf = Vec::new();
+                b.iter(|| {
+                    for entry in entries {
+                        buf.clear();
+                        codec.encode(entry, &mut buf).unwrap();
+                    }
+                })
+            })
+        })
+        .collect();
+    c.bench_functions("encode_bench", funs, entries());
+}
+
+fn decode_bench(c: &mut Criterion) {
+    let funs = CODECS
+        .iter()
+        .map(|codec| {
+            let codec = *codec;
+            Fun::new(codec.name(), move |b, entries: &Vec<LogEntry>| {
+                let encoded: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|entry| {
+                        let mut buf = Vec::new();
+                        codec.encode(entry, &mut buf).unwrap();
+                        buf
+                    })
+                    .collect();
+                b.iter(|| {
+                    for buf in &encoded {
+                        codec.decode(buf, 0).unwrap();
+                    }
+                })
+            })
+        })
+        .collect();
+    c.bench_functions("decode_bench", funs, entries());
+}
+
+criterion_group!(benches, encode_bench, decode_bench);
+criterion_main!(benches);
@@ -0,0 +1,400 @@
+use clap::{load_yaml, App, AppSettings, ArgMatches};
+
+use proj5::kvs::backup;
+use proj5::kvs::meta;
+use proj5::kvs::inspect::{self, SegmentCheck};
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::transfer::{self, PairFormat};
+use proj5::kvs::{
+    ClientAuth, ClientTls, Codec, Encoding, KvError, KvStore, KvsAddr, KvsAdmin, KvsClient, Result,
+    SEGMENT_FORMAT_VERSION, SLOTS,
+};
+use slog::{info, o, Drain, Logger};
+use std::env;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+use tokio::runtime::Builder;
+
+fn main() {
+    let log = init_log();
+    let yaml = load_yaml!("kvs-admin.yml");
+
+    let app = App::from(yaml).setting(AppSettings::ArgRequiredElseHelp);
+
+    let matches = app.get_matches();
+
+    if matches.is_present("version") {
+        println!(env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    if let Err(err) = runtime.block_on(run(&log, &matches)) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+async fn run(log: &Logger, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("repair", args)) if args.is_present("dir") => {
+            let dir = parse_dir(log, args)?;
+            let repaired = inspect::repair(&dir)?;
+            if repaired.is_empty() {
+                println!("no corrupt segment");
+            }
+            for check in repaired {
+                let corruption = check.corruption.unwrap();
+                println!(
+                    "{}: truncated at {}, {} bytes dropped",
+                    String::from(check.file_id),
+                    corruption.offset,
+                    check.bytes - corruption.offset as u64
+                );
+            }
+        }
+        Some(("repair", args)) => {
+            if !args.is_present("from") {
+                return Err(KvError::InvalidArgument {
+                    arg: "repair".to_string(),
+                    val: "--from and --to, or --dir, are required".to_string(),
+                });
+            }
+            let opts = parse_connect(args)?;
+            let from = parse_addr(log, args, "from")?;
+            let to = parse_addr(log, args, "to")?;
+            let mut source = connect(log, from, &opts).await?;
+            let mut target = connect(log, to, &opts).await?;
+
+            let report = source.repair(&mut target).await?;
+            println!("ranges_compared: {}", report.ranges_compared);
+            println!("ranges_repaired: {}", report.ranges_repaired);
+            println!("pairs_read: {}", report.pairs_read);
+            println!("keys_set: {}", report.keys_set);
+            println!("keys_removed: {}", report.keys_removed);
+        }
+        Some(("reshard", args)) => {
+            let opts = parse_connect(args)?;
+            let from = parse_addr(log, args, "from")?;
+            let to = parse_addr(log, args, "to")?;
+            let slots = parse_slots(args)?;
+            let mut source = connect(log, from, &opts).await?;
+            let mut target = connect(log, to, &opts).await?;
+
+            let keys = source.move_slots(&mut target, slots).await?;
+            println!("keys_moved: {}", keys);
+        }
+        Some(("backup", args)) => {
+            let opts = parse_connect(args)?;
+            let addr = parse_addr(log, args, "addr")?;
+            let dest = args.value_of("dest").unwrap();
+            let mut client = connect(log, addr, &opts).await?;
+
+            for (name, val) in client.backup(dest).await? {
+                println!("{}: {}", name, val);
+            }
+        }
+        Some(("restore", args)) => {
+            let backup_dir = Path::new(args.value_of("backup").unwrap());
+            let dir = Path::new(args.value_of("dir").unwrap());
+            let meta = backup::validate(backup_dir)?;
+            let data_dir = meta::open(dir, Some(&meta.engine))?.data_dir(dir);
+            info!(log, "restoring to: {}", data_dir.display());
+
+            let meta = backup::restore(backup_dir, &data_dir)?;
+            println!("engine: {}", meta.engine);
+            println!("keys: {}", meta.keys);
+            println!("files: {}", meta.files.len());
+        }
+        Some(("export", args)) => {
+            let (engine, data_dir) = parse_engine_dir(log, args)?;
+            let format: PairFormat = parse_format(args)?;
+            let encoding = parse_encoding(args)?;
+            let prefix = args.value_of("prefix").unwrap_or_default();
+            let mut out: Box<dyn Write> = match args.value_of("output") {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(stdout())),
+            };
+
+            let count = transfer::export(&engine, &data_dir, prefix, format, encoding, &mut out)?;
+            info!(log, "exported {} pairs", count);
+        }
+        Some(("import", args)) => {
+            let engine = args.value_of("engine").unwrap();
+            let dir = Path::new(args.value_of("dir").unwrap());
+            let data_dir = meta::open(dir, Some(engine))?.data_dir(dir);
+            let format: PairFormat = parse_format(args)?;
+            let encoding = parse_encoding(args)?;
+            let mut input: Box<dyn BufRead> = match args.value_of("input") {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(BufReader::new(stdin())),
+            };
+            info!(log, "importing to: {}", data_dir.display());
+
+            let count = transfer::import(engine, &data_dir, format, encoding, &mut input)?;
+            println!("pairs_imported: {}", count);
+        }
+        Some(("segments", args)) => {
+            let dir = parse_dir(log, args)?;
+            for segment in inspect::segments(&dir)? {
+                println!("{} {}", String::from(segment.file_id), segment.bytes);
+            }
+        }
+        Some(("dump", args)) => {
+            let dir = parse_dir(log, args)?;
+            let mut out = BufWriter::new(stdout());
+            let checks = inspect::dump(&dir, &mut out)?;
+            drop(out);
+            check_corruption(&checks)?;
+        }
+        Some(("verify", args)) => {
+            let dir = parse_dir(log, args)?;
+            let checks = inspect::verify(&dir)?;
+            for check in &checks {
+                let file = String::from(check.file_id);
+                match &check.corruption {
+                    Some(corruption) => println!(
+                        "{}: {} frames, corrupt at {}: {}",
+                        file, check.frames, corruption.offset, corruption.msg
+                    ),
+                    None => println!(
+                        "{}: format {}, {} codec, {} frames, {} bytes, ok",
+                        file, check.format_version, check.codec, check.frames, check.bytes
+                    ),
+                }
+            }
+            check_corruption(&checks)?;
+        }
+        Some(("migrate", args)) => {
+            let dir = parse_dir(log, args)?;
+            let migrated = inspect::migrate(&dir)?;
+            for check in &migrated {
+                println!(
+                    "{}: format {} {} to {} {}, {} frames",
+                    String::from(check.file_id),
+                    check.format_version,
+                    check.codec,
+                    SEGMENT_FORMAT_VERSION,
+                    Codec::DEFAULT,
+                    check.frames
+                );
+            }
+            println!("segments_migrated: {}", migrated.len());
+        }
+        Some(("compact", args)) => {
+            let dir = parse_dir(log, args)?;
+            let store = KvStore::<NaiveThreadPool>::open(dir.as_path(), 1)?;
+            store.compact().await?;
+            let stats = store.stats().await?;
+            println!("keys: {}", stats.keys);
+            println!("disk_bytes: {}", stats.disk_bytes);
+        }
+        Some(("stats", args)) => {
+            let dir = parse_dir(log, args)?;
+            let stats = inspect::stats(&dir)?;
+            println!("keys: {}", stats.keys);
+            println!("compression_ratio: {:.2}", stats.compression_ratio());
+            for segment in &stats.segments {
+                println!(
+                    "{}: live_keys: {} dead_keys: {} live_bytes: {} dead_bytes: {}",
+                    String::from(segment.file_id),
+                    segment.live_keys,
+                    segment.dead_keys,
+                    segment.live_bytes,
+                    segment.dead_bytes
+                );
+            }
+            for (bound, count) in stats.key_sizes.buckets() {
+                println!("key_size <= {}: {}", bound, count);
+            }
+            for (bound, count) in stats.value_sizes.buckets() {
+                println!("value_size <= {}: {}", bound, count);
+            }
+        }
+        _ => {
+            unreachable!();
+        }
+    }
+    Ok(())
+}
+
+/// How to connect, shared by every server a subcommand talks to.
+struct ConnectOpts {
+    tls: Option<ClientTls>,
+    auth: Option<ClientAuth>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+async fn connect(log: &Logger, addr: KvsAddr, opts: &ConnectOpts) -> Result<KvsClient> {
+    let mut builder = KvsClient::builder(addr).log(log);
+    if let Some(tls) = &opts.tls {
+        builder = builder.tls(tls.clone());
+    }
+    if let Some(auth) = &opts.auth {
+        builder = builder.auth(auth.clone());
+    }
+    builder
+        .connect_timeout(opts.connect_timeout)
+        .request_timeout(opts.request_timeout)
+        .connect()
+        .await
+}
+
+fn parse_connect(matches: &ArgMatches) -> Result<ConnectOpts> {
+    Ok(ConnectOpts {
+        tls: parse_tls(matches)?,
+        auth: parse_auth(matches)?,
+        connect_timeout: parse_millis(matches, "connect-timeout")?,
+        request_timeout: parse_millis(matches, "request-timeout")?,
+    })
+}
+
+fn parse_millis(matches: &ArgMatches, arg: &str) -> Result<Option<Duration>> {
+    match matches.value_of(arg) {
+        Some(val) => match val.parse() {
+            Ok(millis) => Ok(Some(Duration::from_millis(millis))),
+            Err(_) => Err(KvError::InvalidArgument {
+                arg: arg.to_string(),
+                val: val.to_string(),
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_slots(matches: &ArgMatches) -> Result<RangeInclusive<u16>> {
+    let val = matches.value_of("slots").unwrap();
+    let (first, last) = val.split_once('-').unwrap_or((val, val));
+    match (first.parse(), last.parse()) {
+        (Ok(first), Ok(last)) if first <= last && last < SLOTS => Ok(first..=last),
+        _ => Err(KvError::InvalidArgument {
+            arg: "slots".to_string(),
+            val: val.to_string(),
+        }),
+    }
+}
+
+fn parse_tls(matches: &ArgMatches) -> Result<Option<ClientTls>> {
+    let ca = match matches.value_of("tls-ca") {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let identity = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+        _ => None,
+    };
+    Ok(Some(ClientTls::load(Path::new(ca), identity)?))
+}
+
+fn parse_auth(matches: &ArgMatches) -> Result<Option<ClientAuth>> {
+    if let Some(user) = matches.value_of("user") {
+        let password = match matches.value_of("password") {
+            Some(password) => password.to_string(),
+            None => env::var("KVS_PASSWORD").map_err(|_| KvError::InvalidArgument {
+                arg: "password".to_string(),
+                val: "--password or KVS_PASSWORD is required with --user".to_string(),
+            })?,
+        };
+        return Ok(Some(ClientAuth::Password {
+            user: user.to_string(),
+            password,
+        }));
+    }
+
+    Ok(match matches.value_of("token") {
+        Some(token) => Some(ClientAuth::Token(token.to_string())),
+        None => env::var("KVS_TOKEN").ok().map(ClientAuth::Token),
+    })
+}
+
+/// The kvs data directory, the `kvs_data` directory inside the argument
+/// if it has one.
+fn parse_dir(log: &Logger, matches: &ArgMatches) -> Result<PathBuf> {
+    let dir = PathBuf::from(matches.value_of("dir").unwrap());
+    let dir = match dir.join("kvs_data") {
+        kvs_data if kvs_data.is_dir() => kvs_data,
+        _ => dir,
+    };
+    if !dir.is_dir() {
+        return Err(KvError::InvalidArgument {
+            arg: "dir".to_string(),
+            val: dir.display().to_string(),
+        });
+    }
+
+    info!(log, "dir: {}", dir.display());
+
+    Ok(dir)
+}
+
+fn parse_format(matches: &ArgMatches) -> Result<PairFormat> {
+    matches.value_of("format").unwrap_or("jsonl").parse()
+}
+
+fn parse_encoding(matches: &ArgMatches) -> Result<Encoding> {
+    matches.value_of("encoding").unwrap_or("text").parse()
+}
+
+/// Engine and data directory in the server directory, the one recorded in
+/// its metadata, of `--engine`, or the only one there is.
+fn parse_engine_dir(log: &Logger, matches: &ArgMatches) -> Result<(String, PathBuf)> {
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    if meta::load(dir)?.is_some() {
+        let meta = meta::open(dir, matches.value_of("engine"))?;
+        let data_dir = meta.data_dir(dir);
+        info!(log, "{} data dir: {}", meta.engine, data_dir.display());
+        return Ok((meta.engine, data_dir));
+    }
+    let engines: Vec<&str> = match matches.value_of("engine") {
+        Some(engine) => vec![engine],
+        None => vec!["kvs", "sled"],
+    };
+    let mut found = engines
+        .into_iter()
+        .map(|engine| (engine.to_string(), dir.join(format!("{}_data", engine))))
+        .filter(|(_, data_dir)| data_dir.is_dir());
+    match (found.next(), found.next()) {
+        (Some((engine, data_dir)), None) => {
+            info!(log, "{} data dir: {}", engine, data_dir.display());
+            Ok((engine, data_dir))
+        }
+        _ => Err(KvError::InvalidArgument {
+            arg: "dir".to_string(),
+            val: format!("no single engine data directory in {}", dir.display()),
+        }),
+    }
+}
+
+/// Fails on the first corrupt segment, after the report is printed.
+fn check_corruption(checks: &[SegmentCheck]) -> Result<()> {
+    let corrupt = checks
+        .iter()
+        .find_map(|check| Some((check.file_id, check.corruption.as_ref()?)));
+    match corrupt {
+        Some((file_id, corruption)) => Err(KvError::CorruptFrame {
+            pos: corruption.offset,
+            msg: format!("{}: {}", String::from(file_id), corruption.msg),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn parse_addr(log: &Logger, matches: &ArgMatches, arg: &str) -> Result<KvsAddr> {
+    let addr_str = matches.value_of(arg).unwrap();
+
+    info!(log, "{}: {}", arg, addr_str);
+
+    addr_str.parse()
+}
+
+fn init_log() -> Logger {
+    let decorator = slog_term::PlainDecorator::new(std::io::stderr());
+    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    slog::Logger::root(drain, o!())
+}
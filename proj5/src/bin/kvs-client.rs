@@ -1,14 +1,16 @@
 use clap::{load_yaml, App, AppSettings, ArgMatches};
 
-use proj5::kvs::{KvError, KvStore, KvsClient};
+use proj5::kvs::{
+    BlockingKvsClient, ClientAuth, ClientTls, Encoding, KvError, KvStore, KvsAddr, Result,
+};
 use slog::{info, o, Drain, Logger};
 use std::borrow::Borrow;
 use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::net::SocketAddr;
+use std::path::Path;
 use std::process::exit;
-use tokio::runtime::Builder;
+use std::time::Duration;
 
 fn main() {
     let log = init_log();
@@ -23,68 +25,181 @@ fn main() {
         return;
     }
 
-    let runtime = Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    if let Err(err) = run(&log, &matches) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
 
+fn run(log: &Logger, matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         Some(("get", args)) => {
-            let addr = parse_addr(&log, &args);
-            let key = args.value_of("key").unwrap();
-
-            runtime.block_on(async move {
-                let result = KvsClient::connect(&log, addr)
-                    .await
-                    .unwrap()
-                    .get(key.to_string())
-                    .await
-                    .unwrap()
-                    .unwrap_or("Key not found".to_string());
-                println!("{}", result);
-            });
+            let mut client = connect(log, parse_connect(log, args)?)?;
+            let encoding = parse_encoding(args);
+            let key = encoding.decode(args.value_of("key").unwrap())?;
+
+            match client.get_bytes(key)? {
+                Some(val) => println!("{}", encoding.encode(&val)?),
+                None => println!("Key not found"),
+            }
         }
         Some(("set", args)) => {
-            let addr = parse_addr(&log, &args);
-            let key = args.value_of("key").unwrap();
-            let value = args.value_of("value").unwrap();
-
-            runtime.block_on(async move {
-                KvsClient::connect(&log, addr)
-                    .await
-                    .unwrap()
-                    .set(key.to_string(), value.to_string())
-                    .await
-                    .unwrap();
-            });
+            let mut client = connect(log, parse_connect(log, args)?)?;
+            let encoding = parse_encoding(args);
+            let key = encoding.decode(args.value_of("key").unwrap())?;
+            let value = encoding.decode(args.value_of("value").unwrap())?;
+
+            client.set_bytes(key, value)?;
         }
         Some(("rm", args)) => {
-            let addr = parse_addr(&log, &args);
-            let key = args.value_of("key").unwrap();
-
-            runtime.block_on(async move {
-                let mut client = KvsClient::connect(&log, addr).await.unwrap();
-                match client.remove(key.to_string()).await {
-                    Ok(_) => exit(0),
-                    Err(err) => {
-                        eprintln!("{:?}", err);
-                        exit(1);
-                    }
-                };
-            });
+            let mut client = connect(log, parse_connect(log, args)?)?;
+            let key = parse_encoding(args).decode(args.value_of("key").unwrap())?;
+
+            client.remove_bytes(key)?;
+        }
+        Some(("admin", admin_args)) => {
+            let (cmd, args) = admin_args.subcommand().unwrap();
+            run_admin(log, parse_connect(log, args)?, cmd, args)?;
         }
         _ => {
             unreachable!();
         }
     }
+    Ok(())
+}
+
+fn run_admin(log: &Logger, opts: ConnectOpts, cmd: &str, args: &ArgMatches) -> Result<()> {
+    let mut client = connect(log, opts)?;
+    match cmd {
+        "ping" => {
+            client.ping()?;
+            println!("PONG");
+        }
+        "info" => print_fields(client.info()?),
+        "stats" => print_fields(client.stats()?),
+        "compact" => client.compact()?,
+        "flush" => client.flush()?,
+        "add-member" => client.add_member(parse_member(args)?)?,
+        "remove-member" => client.remove_member(parse_member(args)?)?,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn parse_member(args: &ArgMatches) -> Result<KvsAddr> {
+    args.value_of("member").unwrap().parse()
+}
+
+/// How keys and values are given and printed, `--hex`, `--base64` or text.
+fn parse_encoding(matches: &ArgMatches) -> Encoding {
+    if matches.is_present("hex") {
+        Encoding::Hex
+    } else if matches.is_present("base64") {
+        Encoding::Base64
+    } else {
+        Encoding::Text
+    }
+}
+
+fn print_fields(fields: Vec<(String, String)>) {
+    for (name, val) in fields {
+        println!("{}: {}", name, val);
+    }
+}
+
+/// Where and how to connect, shared by every subcommand.
+struct ConnectOpts {
+    addr: KvsAddr,
+    tls: Option<ClientTls>,
+    auth: Option<ClientAuth>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+fn connect(log: &Logger, opts: ConnectOpts) -> Result<BlockingKvsClient> {
+    let mut builder = BlockingKvsClient::builder(opts.addr).log(log);
+    if let Some(tls) = opts.tls {
+        builder = builder.tls(tls);
+    }
+    if let Some(auth) = opts.auth {
+        builder = builder.auth(auth);
+    }
+    if let Some(timeout) = opts.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = opts.request_timeout {
+        builder = builder.request_timeout(timeout);
+    }
+    builder.connect_blocking()
+}
+
+fn parse_connect(log: &Logger, matches: &ArgMatches) -> Result<ConnectOpts> {
+    Ok(ConnectOpts {
+        addr: parse_addr(log, matches)?,
+        tls: parse_tls(matches)?,
+        auth: parse_auth(matches)?,
+        connect_timeout: parse_millis(matches, "connect-timeout")?,
+        request_timeout: parse_millis(matches, "request-timeout")?,
+    })
+}
+
+fn parse_millis(matches: &ArgMatches, arg: &str) -> Result<Option<Duration>> {
+    match matches.value_of(arg) {
+        Some(val) => match val.parse() {
+            Ok(millis) => Ok(Some(Duration::from_millis(millis))),
+            Err(_) => Err(KvError::InvalidArgument {
+                arg: arg.to_string(),
+                val: val.to_string(),
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_tls(matches: &ArgMatches) -> Result<Option<ClientTls>> {
+    let ca = match matches.value_of("tls-ca") {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let identity = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+        _ => None,
+    };
+
+    let mut tls = ClientTls::load(Path::new(ca), identity)?;
+    if let Some(name) = matches.value_of("tls-server-name") {
+        tls = tls.server_name(name);
+    }
+    Ok(Some(tls))
+}
+
+fn parse_auth(matches: &ArgMatches) -> Result<Option<ClientAuth>> {
+    if let Some(user) = matches.value_of("user") {
+        let password = match matches.value_of("password") {
+            Some(password) => password.to_string(),
+            None => env::var("KVS_PASSWORD").map_err(|_| KvError::InvalidArgument {
+                arg: "password".to_string(),
+                val: "--password or KVS_PASSWORD is required with --user".to_string(),
+            })?,
+        };
+        return Ok(Some(ClientAuth::Password {
+            user: user.to_string(),
+            password,
+        }));
+    }
+
+    Ok(match matches.value_of("token") {
+        Some(token) => Some(ClientAuth::Token(token.to_string())),
+        None => env::var("KVS_TOKEN").ok().map(ClientAuth::Token),
+    })
 }
 
-fn parse_addr(log: &Logger, matches: &ArgMatches) -> SocketAddr {
+fn parse_addr(log: &Logger, matches: &ArgMatches) -> Result<KvsAddr> {
     let addr_str = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
 
     info!(log, "addr: {}", addr_str);
 
-    addr_str.parse().expect("parse addr failed")
+    addr_str.parse()
 }
 
 fn init_log() -> Logger {
@@ -2,11 +2,18 @@ use clap::{load_yaml, App, ArgMatches};
 use proj5::kvs::thread_pool::{
     NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
 };
-use proj5::kvs::{KvError, KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use proj5::kvs::meta;
+use proj5::kvs::{
+    ClientAuth, ClientTls, Credentials, KvError, KvStore, KvsAddr, KvsClient, KvsClientBuilder,
+    ClusterConfig, KvsEngine, KvsServer, RateLimit, Result, ServerLimits, ServerTls,
+    SledKvsEngine, SlotConfig, StoreOptions,
+};
 use sled::Db;
 use slog::{info, o, Drain, Logger};
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{env, format};
 use tokio::runtime::{Builder, Runtime};
@@ -26,82 +33,397 @@ fn main() {
     info!(log, "version: {}", env!("CARGO_PKG_VERSION"));
 
     let dir = env::current_dir().unwrap();
-    let addr = parse_addr(&log, &matches);
+    let addrs = parse_addrs(&log, &matches);
     let engine_name = parse_engine(&log, &matches);
+    let limits = parse_limits(&log, &matches);
+    let store_options = parse_store_options(&log, &matches);
+    let metrics_addr = parse_metrics_addr(&log, &matches);
+    let credentials = parse_credentials(&log, &matches);
+    let tls = parse_tls(&log, &matches);
+    let replication_backlog = parse_replication_backlog(&log, &matches);
+    let replica_of = parse_replica_of(&log, &matches, dir.as_path());
+    let cluster = parse_cluster(&log, &matches, &addrs, dir.as_path());
+    let tso = parse_tso(&log, &matches, dir.as_path());
+    let slots = parse_slots(&log, &matches, &addrs, dir.as_path());
+
+    let opts = ServerOpts {
+        addrs,
+        limits,
+        metrics_addr,
+        credentials,
+        tls,
+        replication_backlog,
+        replica_of,
+        cluster,
+        tso,
+        slots,
+    };
+
+    let engine = engine_name.as_deref();
+    if let Err(e) = start_server(&log, engine, dir.as_path(), store_options, opts) {
+        eprintln!("failed to start server: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Listener and connection options shared by both engines.
+struct ServerOpts {
+    addrs: Vec<KvsAddr>,
+    limits: ServerLimits,
+    metrics_addr: Option<SocketAddr>,
+    credentials: Option<Credentials>,
+    tls: Option<ServerTls>,
+    replication_backlog: Option<usize>,
+    /// The primary and the file the replica saves its position in.
+    replica_of: Option<(KvsClientBuilder, PathBuf)>,
+    cluster: Option<ClusterConfig>,
+    /// The file the timestamp oracle saves its limit in.
+    tso: Option<PathBuf>,
+    /// The initial slot states and the file they are saved in.
+    slots: Option<(SlotConfig, PathBuf)>,
+}
+
+fn parse_addrs(log: &Logger, matches: &ArgMatches) -> Vec<KvsAddr> {
+    let addrs: Vec<&str> = match matches.values_of("addr") {
+        Some(values) => values.collect(),
+        None => vec!["127.0.0.1:4000"],
+    };
+
+    addrs
+        .into_iter()
+        .map(|addr_str| {
+            info!(log, "addr: {}", addr_str);
+            addr_str.parse().expect("parse addr failed")
+        })
+        .collect()
+}
+
+fn parse_metrics_addr(log: &Logger, matches: &ArgMatches) -> Option<SocketAddr> {
+    let addr_str = matches.value_of("metrics-addr")?;
+
+    info!(log, "metrics addr: {}", addr_str);
+
+    Some(addr_str.parse().expect("parse metrics addr failed"))
+}
+
+fn parse_credentials(log: &Logger, matches: &ArgMatches) -> Option<Credentials> {
+    let path = matches.value_of("credentials")?;
+
+    info!(log, "credentials: {}", path);
+
+    Some(Credentials::load(Path::new(path)).expect("load credentials failed"))
+}
+
+fn parse_tls(log: &Logger, matches: &ArgMatches) -> Option<ServerTls> {
+    let cert = matches.value_of("tls-cert")?;
+    let key = matches.value_of("tls-key").unwrap();
+    let client_ca = matches.value_of("tls-client-ca");
+
+    info!(log, "tls cert: {}", cert);
+    if let Some(ca) = client_ca {
+        info!(log, "tls client ca: {}", ca);
+    }
 
+    let tls = ServerTls::load(Path::new(cert), Path::new(key), client_ca.map(Path::new));
+    Some(tls.expect("load tls config failed"))
+}
+
+fn parse_replication_backlog(log: &Logger, matches: &ArgMatches) -> Option<usize> {
+    let backlog = matches.value_of("replication-backlog")?;
+
+    info!(log, "replication backlog: {}", backlog);
 
-    start_server(&log, &engine_name, dir.as_path(), addr)
-        .expect("failed to start server");
+    Some(backlog.parse().expect("parse replication-backlog failed"))
 }
 
-fn parse_addr(log: &Logger, matches: &ArgMatches) -> SocketAddr {
-    let addr_str = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+fn parse_replica_of(
+    log: &Logger,
+    matches: &ArgMatches,
+    root_path: &Path,
+) -> Option<(KvsClientBuilder, PathBuf)> {
+    let addr = matches.value_of("replica-of")?;
+    info!(log, "replica of: {}", addr);
 
-    info!(log, "addr: {}", addr_str);
+    let addr: KvsAddr = addr.parse().expect("parse replica-of failed");
+    let mut primary = KvsClient::builder(addr).log(log);
+    if let Some(ca) = matches.value_of("replica-tls-ca") {
+        info!(log, "replica tls ca: {}", ca);
+        let tls = ClientTls::load(Path::new(ca), None).expect("load replica tls config failed");
+        primary = primary.tls(tls);
+    }
+    let token = match matches.value_of("replica-token") {
+        Some(token) => Some(token.to_string()),
+        None => env::var("KVS_REPLICA_TOKEN").ok(),
+    };
+    if let Some(token) = token {
+        primary = primary.auth(ClientAuth::Token(token));
+    }
 
-    addr_str.parse().expect("parse addr failed")
+    Some((primary, root_path.join("replica_state.json")))
 }
 
-fn parse_engine(log: &Logger, matches: &ArgMatches) -> String {
-    let engine = matches.value_of("engine").unwrap_or("kvs");
+fn parse_cluster(
+    log: &Logger,
+    matches: &ArgMatches,
+    addrs: &[KvsAddr],
+    root_path: &Path,
+) -> Option<ClusterConfig> {
+    let members: Vec<KvsAddr> = match matches.values_of("cluster") {
+        Some(values) => values
+            .map(|member| member.parse().expect("parse cluster member failed"))
+            .collect(),
+        None if matches.is_present("cluster-join") => Vec::new(),
+        None => return None,
+    };
+    let id = match matches.value_of("cluster-id") {
+        Some(id) => id.parse().expect("parse cluster-id failed"),
+        None => addrs[0].clone(),
+    };
+    info!(log, "cluster id: {}", id);
+    for member in &members {
+        info!(log, "cluster member: {}", member);
+    }
+
+    let mut peer_client = KvsClient::builder(id.clone()).log(log);
+    if let Some(ca) = matches.value_of("cluster-tls-ca") {
+        info!(log, "cluster tls ca: {}", ca);
+        let tls = ClientTls::load(Path::new(ca), None).expect("load cluster tls config failed");
+        peer_client = peer_client.tls(tls);
+    }
+    let token = match matches.value_of("cluster-token") {
+        Some(token) => Some(token.to_string()),
+        None => env::var("KVS_CLUSTER_TOKEN").ok(),
+    };
+    if let Some(token) = token {
+        peer_client = peer_client.auth(ClientAuth::Token(token));
+    }
+
+    let dir = root_path.join("raft_data");
+    let config = if members.is_empty() {
+        ClusterConfig::join(id, dir)
+    } else {
+        ClusterConfig::new(id, members, dir)
+    };
+    Some(config.peer_client(peer_client))
+}
+
+fn parse_tso(log: &Logger, matches: &ArgMatches, root_path: &Path) -> Option<PathBuf> {
+    if !matches.is_present("tso") {
+        return None;
+    }
+    let path = root_path.join("tso_state.json");
+    info!(log, "timestamp oracle: {}", path.display());
+    Some(path)
+}
+
+fn parse_slots(
+    log: &Logger,
+    matches: &ArgMatches,
+    addrs: &[KvsAddr],
+    root_path: &Path,
+) -> Option<(SlotConfig, PathBuf)> {
+    let owned = matches.value_of("slots")?;
+    info!(log, "slots: {}", owned);
+
+    let mut config = SlotConfig::new(parse_slot_ranges(owned));
+    if let Some(nodes) = matches.values_of("slot-node") {
+        for node in nodes {
+            info!(log, "slot node: {}", node);
+            let (ranges, addr) = node.split_once('=').expect("parse slot-node failed");
+            let addr: KvsAddr = addr.parse().expect("parse slot-node failed");
+            for range in parse_slot_ranges(ranges) {
+                config = config.node(range, addr.clone());
+            }
+        }
+    }
+
+    let mut peer_client = KvsClient::builder(addrs[0].clone()).log(log);
+    if let Some(ca) = matches.value_of("slot-tls-ca") {
+        info!(log, "slot tls ca: {}", ca);
+        let tls = ClientTls::load(Path::new(ca), None).expect("load slot tls config failed");
+        peer_client = peer_client.tls(tls);
+    }
+    let token = match matches.value_of("slot-token") {
+        Some(token) => Some(token.to_string()),
+        None => env::var("KVS_SLOT_TOKEN").ok(),
+    };
+    if let Some(token) = token {
+        peer_client = peer_client.auth(ClientAuth::Token(token));
+    }
+
+    Some((config.peer_client(peer_client), root_path.join("slots.json")))
+}
+
+/// Parses `0-8191,10000` into slot ranges.
+fn parse_slot_ranges(ranges: &str) -> Vec<RangeInclusive<u16>> {
+    ranges
+        .split(',')
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let first = first.trim().parse().expect("parse slot range failed");
+            let last = last.trim().parse().expect("parse slot range failed");
+            first..=last
+        })
+        .collect()
+}
+
+fn parse_limits(log: &Logger, matches: &ArgMatches) -> ServerLimits {
+    let mut limits = ServerLimits::default();
+
+    if let Some(max) = matches.value_of("max-connections") {
+        limits.max_connections = max.parse().expect("parse max-connections failed");
+    }
+    if let Some(max) = matches.value_of("max-pending") {
+        limits.max_pending_requests = max.parse().expect("parse max-pending failed");
+    }
+    if let Some(rate) = matches.value_of("rate-limit") {
+        let requests_per_sec: u32 = rate.parse().expect("parse rate-limit failed");
+        let burst = match matches.value_of("rate-burst") {
+            Some(burst) => burst.parse().expect("parse rate-burst failed"),
+            None => requests_per_sec,
+        };
+        limits.rate_limit = Some(RateLimit {
+            requests_per_sec,
+            burst,
+        });
+    }
+
+    info!(log, "limits: {:?}", limits);
+    limits
+}
+
+/// Options of the segments a kvs engine writes, ignored by sled.
+fn parse_store_options(log: &Logger, matches: &ArgMatches) -> StoreOptions {
+    let mut options = StoreOptions::default();
+
+    if let Some(compression) = matches.value_of("compression") {
+        options.compression = compression.parse().expect("parse compression failed");
+    }
+    if let Some(threshold) = matches.value_of("compress-threshold") {
+        options.compress_threshold = threshold.parse().expect("parse compress-threshold failed");
+    }
+    options.block_compression = matches.is_present("block-compression");
+
+    info!(log, "store options: {:?}", options);
+    options
+}
+
+/// The engine of `--engine`, `None` for the one of the directory.
+fn parse_engine(log: &Logger, matches: &ArgMatches) -> Option<String> {
+    let engine = matches.value_of("engine")?;
     info!(log, "engine: {}", engine);
-    engine.to_string()
+    Some(engine.to_string())
 }
 
-fn start_server(root_log: &Logger, engine: &str, root_path: &Path, addr: SocketAddr) -> Result<()> {
+fn start_server(
+    root_log: &Logger,
+    engine: Option<&str>,
+    root_path: &Path,
+    store_options: StoreOptions,
+    opts: ServerOpts,
+) -> Result<()> {
     let log = root_log.new(o!());
-    match engine {
+    let meta = meta::open_with(root_path, engine, |engine| match engine {
+        "kvs" => store_options.to_map(),
+        _ => BTreeMap::new(),
+    })?;
+    info!(
+        root_log,
+        "engine data: {}, format version {}", meta.engine, meta.format_version
+    );
+    let data_dir = meta.data_dir(root_path);
+    match meta.engine.as_str() {
         "kvs" => {
-            let kvs = build_kvs(root_log, root_path)?;
-            start_with(kvs, addr, log);
+            let kvs = build_kvs(root_log, &data_dir, store_options)?;
+            start_with(kvs, opts, log);
+        }
+        _ => {
+            let sled = build_sled(&data_dir)?;
+            start_with(sled, opts, log);
         }
-        "sled" => {
-            let sled = build_sled(root_path)?;
-            start_with(sled, addr, log);
-
-        },
-        _ => panic!("undefined engine: {}", engine),
     };
     Ok(())
 }
 
-fn start_with<E: KvsEngine>(engine: E, addr: SocketAddr, log: Logger) {
+fn start_with<E: KvsEngine>(engine: E, opts: ServerOpts, log: Logger) {
+    let ServerOpts {
+        addrs,
+        limits,
+        metrics_addr,
+        credentials,
+        tls,
+        replication_backlog,
+        replica_of,
+        cluster,
+        tso,
+        slots,
+    } = opts;
+
     let runtime = Builder::new_multi_thread()
         .enable_all()
         .worker_threads(num_cpus::get())
         .build()
         .unwrap();
 
-    let server = KvsServer::new(engine, log);
+    let mut server = KvsServer::with_limits(engine, log, limits);
+    if let Some(credentials) = credentials {
+        server = server.with_credentials(credentials);
+    }
+    if let Some(tls) = tls {
+        server = server.with_tls(tls);
+    }
+    if let Some(backlog) = replication_backlog {
+        server = server.with_replication(backlog);
+    }
+    let replica = replica_of.is_some();
+    if let Some((primary, state_path)) = replica_of {
+        server = server.with_replica_of(primary, state_path);
+    }
+    if let Some(path) = tso {
+        server = server.with_tso(path).expect("open timestamp oracle failed");
+    }
+    if let Some((config, path)) = slots {
+        server = server.with_slots(config, path).expect("open slot states failed");
+    }
+    let clustered = cluster.is_some();
+    if let Some(cluster) = cluster {
+        server = server.with_cluster(cluster).expect("open cluster state failed");
+    }
 
     runtime.block_on(async move {
-        server.listen(addr).await;
+        let mut handles = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            handles.push(server.bind(addr).await.expect("bind failed"));
+        }
+        if let Some(addr) = metrics_addr {
+            handles.push(server.bind_metrics(addr).await.expect("bind metrics failed"));
+        }
+        if replica {
+            handles.push(server.replicate().await.expect("start replication failed"));
+        }
+        if clustered {
+            handles.push(server.start_cluster().await.expect("start cluster failed"));
+        }
+        for handle in handles {
+            handle.join().await.expect("server failed");
+        }
     });
 }
 
-fn build_sled(file_path: &Path) -> Result<SledKvsEngine<RayonThreadPool>> {
-    if check_engine_data(file_path, "kvs") {
-        panic!("kvs engine data dir detected");
-    }
-
-    let sled_path = file_path.join("sled_data");
-
-    std::fs::create_dir_all(sled_path.as_path())?;
-    sled::open(sled_path.as_path())
-        .map_err(|err| KvError::Sled(err))
-        .and_then(|db| SledKvsEngine::new(db, num_cpus::get() as u32))
+fn build_sled(sled_path: &Path) -> Result<SledKvsEngine<RayonThreadPool>> {
+    std::fs::create_dir_all(sled_path)?;
+    SledKvsEngine::open(sled_path, num_cpus::get() as u32)
 }
 
-fn build_kvs(log: &Logger, file_path: &Path) -> Result<KvStore<RayonThreadPool>> {
-    if check_engine_data(file_path, "sled") {
-        panic!("sled engine data dir detected");
-    }
-
-    let kvs_path = file_path.join("kvs_data");
-
-    std::fs::create_dir_all(kvs_path.as_path())?;
+fn build_kvs(
+    log: &Logger,
+    kvs_path: &Path,
+    options: StoreOptions,
+) -> Result<KvStore<RayonThreadPool>> {
+    std::fs::create_dir_all(kvs_path)?;
     info!(log, "kvs path: {}", kvs_path.display().to_string());
-    KvStore::open(kvs_path.as_path(), num_cpus::get() as u32)
+    KvStore::open_with(kvs_path, num_cpus::get() as u32, options)
 }
 
 fn init_log() -> Logger {
@@ -110,10 +432,3 @@ fn init_log() -> Logger {
     let drain = slog_async::Async::new(drain).build().fuse();
     slog::Logger::root(drain, o!())
 }
-
-fn check_engine_data(file_path: &Path, engine: &str) -> bool {
-    file_path
-        .join(engine.to_owned() + "_data")
-        .as_path()
-        .exists()
-}
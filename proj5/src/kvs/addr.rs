@@ -0,0 +1,78 @@
+use crate::kvs::{KvError, Result};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a server: either `IP:PORT` or `unix:PATH`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KvsAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl KvsAddr {
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            KvsAddr::Tcp(addr) => Some(*addr),
+            KvsAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl FromStr for KvsAddr {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<KvsAddr> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(KvError::ParseAddr {
+                    addr: s.to_string(),
+                });
+            }
+            return Ok(KvsAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse::<SocketAddr>()
+            .map(KvsAddr::Tcp)
+            .map_err(|_| KvError::ParseAddr {
+                addr: s.to_string(),
+            })
+    }
+}
+
+impl Display for KvsAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvsAddr::Tcp(addr) => write!(f, "{}", addr),
+            KvsAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for KvsAddr {
+    fn from(addr: SocketAddr) -> Self {
+        KvsAddr::Tcp(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::KvsAddr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        let tcp: KvsAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(tcp, KvsAddr::Tcp("127.0.0.1:4000".parse().unwrap()));
+        assert_eq!(tcp.to_string(), "127.0.0.1:4000");
+
+        let unix: KvsAddr = "unix:/run/kvs.sock".parse().unwrap();
+        assert_eq!(unix, KvsAddr::Unix(PathBuf::from("/run/kvs.sock")));
+        assert_eq!(unix.to_string(), "unix:/run/kvs.sock");
+
+        assert!("unix:".parse::<KvsAddr>().is_err());
+        assert!("invalid-addr".parse::<KvsAddr>().is_err());
+    }
+}
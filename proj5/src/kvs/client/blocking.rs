@@ -0,0 +1,464 @@
+use crate::kvs::client::response::{
+    auth_command, interrupted, is_transport, not_connected, parse_fields_response,
+    parse_get_response, parse_ping_response, parse_void_response, should_retry, text_value,
+};
+use crate::kvs::client::{ClientAuth, ClientOptions, KvsClientBuilder, Target};
+use crate::kvs::net::{read, write, Command, CommandResult};
+use crate::kvs::{KvError, KvsAddr, Result};
+use slog::{debug, trace, Logger};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::{ClientConnection, StreamOwned};
+
+/// A client blocking the calling thread, for programs without an async
+/// runtime.
+///
+/// Speaks the same protocol as [`KvsClient`](crate::kvs::KvsClient) and
+/// behaves the same way: timeouts, reconnects, retries of idempotent
+/// requests and authentication of new connections are configured with
+/// [`KvsClientBuilder`] and finished with
+/// [`KvsClientBuilder::connect_blocking`].
+///
+/// ```no_run
+/// # fn run() -> proj5::kvs::Result<()> {
+/// use proj5::kvs::{BlockingKvsClient, KvsAddr};
+///
+/// let addr: KvsAddr = "127.0.0.1:4000".parse()?;
+/// let mut client = BlockingKvsClient::builder(addr).connect_blocking()?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlockingKvsClient {
+    log: Logger,
+    conn: Option<Connection>,
+    target: Target,
+    auth: Option<ClientAuth>,
+    options: ClientOptions,
+}
+
+struct Connection {
+    stream: Stream,
+    last_used: Instant,
+}
+
+enum Stream {
+    Plain(Socket),
+    Tls(Box<StreamOwned<ClientConnection, Socket>>),
+}
+
+/// A socket failing reads and writes with `TimedOut` once its deadline
+/// passes, so a deadline bounds a whole request rather than every read.
+struct Socket {
+    inner: SocketKind,
+    deadline: Option<Instant>,
+}
+
+enum SocketKind {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl BlockingKvsClient {
+    pub fn builder(addr: impl Into<KvsAddr>) -> KvsClientBuilder {
+        KvsClientBuilder::new(addr.into())
+    }
+
+    /// Connects with the default timeouts and retry policy.
+    pub fn connect(log: &Logger, addr: impl Into<KvsAddr>) -> Result<BlockingKvsClient> {
+        BlockingKvsClient::builder(addr).log(log).connect_blocking()
+    }
+
+    pub(crate) fn with_target(
+        log: Logger,
+        target: Target,
+        auth: Option<ClientAuth>,
+        options: ClientOptions,
+    ) -> BlockingKvsClient {
+        BlockingKvsClient {
+            log,
+            conn: None,
+            target,
+            auth,
+            options,
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let result = self.call(Command::Get { key })?;
+        parse_get_response(result)
+    }
+
+    /// Not retried once sent, see
+    /// [`KvsClient::remove_bytes`](crate::kvs::KvsClient::remove_bytes).
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Remove { key })?;
+        parse_void_response(result)
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Set { key, val })?;
+        parse_void_response(result)
+    }
+
+    /// Fails with [`KvError::Ut8Conversion`] on a value that is not UTF-8.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let val = self.get_bytes(key.clone().into_bytes())?;
+        text_value(key, val)
+    }
+
+    /// Not retried once sent, see
+    /// [`KvsClient::remove_bytes`](crate::kvs::KvsClient::remove_bytes).
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    /// Authenticates the connection and every reconnect, see
+    /// [`KvsClient::authenticate`](crate::kvs::KvsClient::authenticate).
+    pub fn authenticate(&mut self, auth: &ClientAuth) -> Result<()> {
+        let result = self.call(auth_command(auth))?;
+        parse_void_response(result)?;
+        self.auth = Some(auth.clone());
+        Ok(())
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        let result = self.call(Command::Ping)?;
+        parse_ping_response(result)
+    }
+
+    pub fn info(&mut self) -> Result<Vec<(String, String)>> {
+        let result = self.call(Command::Info)?;
+        parse_fields_response(result)
+    }
+
+    pub fn stats(&mut self) -> Result<Vec<(String, String)>> {
+        let result = self.call(Command::Stats)?;
+        parse_fields_response(result)
+    }
+
+    pub fn compact(&mut self) -> Result<()> {
+        let result = self.call(Command::Compact)?;
+        parse_void_response(result)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        let result = self.call(Command::Flush)?;
+        parse_void_response(result)
+    }
+
+    /// See [`KvsClient::backup`](crate::kvs::KvsClient::backup).
+    pub fn backup(&mut self, dest: impl AsRef<Path>) -> Result<Vec<(String, String)>> {
+        let dest = dest.as_ref().display().to_string();
+        let result = self.call(Command::Backup { dest })?;
+        parse_fields_response(result)
+    }
+
+    /// See [`KvsClient::add_member`](crate::kvs::KvsClient::add_member).
+    pub fn add_member(&mut self, member: impl Into<KvsAddr>) -> Result<()> {
+        let member = member.into().to_string();
+        let result = self.call(Command::AddMember { member })?;
+        parse_void_response(result)
+    }
+
+    pub fn remove_member(&mut self, member: impl Into<KvsAddr>) -> Result<()> {
+        let member = member.into().to_string();
+        let result = self.call(Command::RemoveMember { member })?;
+        parse_void_response(result)
+    }
+
+    /// Same retry loop as the async client.
+    fn call(&mut self, cmd: Command) -> Result<CommandResult> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.ensure_connected() {
+                Ok(()) => match self.send(&cmd) {
+                    Ok(result) => return Ok(result),
+                    Err(e) => {
+                        self.conn = None;
+                        if is_transport(&e) && !cmd.is_idempotent() {
+                            return Err(interrupted(&cmd, e));
+                        }
+                        e
+                    }
+                },
+                Err(e) => e,
+            };
+
+            if !should_retry(&err, true, attempt, &self.options.retry) {
+                return Err(err);
+            }
+
+            let backoff = self.options.retry.backoff(attempt);
+            debug!(
+                self.log,
+                "retrying {} in {:?}: {}",
+                cmd.name(),
+                backoff,
+                err
+            );
+            sleep(backoff);
+            attempt += 1;
+        }
+    }
+
+    fn send(&mut self, cmd: &Command) -> Result<CommandResult> {
+        let conn = self.conn.as_mut().ok_or_else(not_connected)?;
+        conn.request(&self.log, cmd, self.options.request_timeout)
+    }
+
+    /// Opens the first connection, retrying transport errors.
+    pub(crate) fn establish(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.ensure_connected() {
+                Err(e) if should_retry(&e, true, attempt, &self.options.retry) => {
+                    let backoff = self.options.retry.backoff(attempt);
+                    debug!(self.log, "retrying connect in {:?}: {}", backoff, e);
+                    sleep(backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn ensure_connected(&mut self) -> Result<()> {
+        if let Some(conn) = self.conn.as_ref() {
+            let idle = match self.options.idle_timeout {
+                Some(limit) => conn.last_used.elapsed() > limit,
+                None => false,
+            };
+            if idle || conn.stream.is_closed() {
+                debug!(self.log, "dropping connection, idle: {}", idle);
+                self.conn = None;
+            }
+        }
+
+        if self.conn.is_none() {
+            let conn = self.open().map_err(|e| timed_out(e, "connect"))?;
+            self.conn = Some(conn);
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let deadline = self
+            .options
+            .connect_timeout
+            .map(|limit| Instant::now() + limit);
+        let stream = open_stream(&self.target, deadline)?;
+        let mut conn = Connection {
+            stream,
+            last_used: Instant::now(),
+        };
+        if let Some(auth) = self.auth.as_ref() {
+            let result = conn.exchange(&self.log, &auth_command(auth), deadline)?;
+            parse_void_response(result)?;
+        }
+        Ok(conn)
+    }
+}
+
+impl Connection {
+    fn request(
+        &mut self,
+        log: &Logger,
+        cmd: &Command,
+        timeout: Option<Duration>,
+    ) -> Result<CommandResult> {
+        let deadline = timeout.map(|limit| Instant::now() + limit);
+        self.exchange(log, cmd, deadline)
+            .map_err(|e| timed_out(e, cmd.name()))
+    }
+
+    fn exchange(
+        &mut self,
+        log: &Logger,
+        cmd: &Command,
+        deadline: Option<Instant>,
+    ) -> Result<CommandResult> {
+        trace!(log, "command: {}", cmd);
+        self.stream.socket_mut().deadline = deadline;
+        write(&mut self.stream, cmd)?;
+        let result: CommandResult = read(&mut self.stream)?;
+        trace!(log, "response: {}", &result);
+        self.last_used = Instant::now();
+        Ok(result)
+    }
+}
+
+impl Stream {
+    fn socket(&self) -> &Socket {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    fn socket_mut(&mut self) -> &mut Socket {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => stream.get_mut(),
+        }
+    }
+
+    /// Whether the server closed the connection, see the async client.
+    /// Over TLS the server may send session tickets after the handshake,
+    /// so only EOF and errors count.
+    fn is_closed(&self) -> bool {
+        match self.socket().peek() {
+            Ok(0) => true,
+            Ok(_) => matches!(self, Stream::Plain(_)),
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Socket {
+    /// Sets the socket timeout to what is left until the deadline.
+    fn arm(&self) -> io::Result<()> {
+        let remaining = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(io::ErrorKind::TimedOut.into()),
+            },
+            None => None,
+        };
+        match &self.inner {
+            SocketKind::Tcp(stream) => {
+                stream.set_read_timeout(remaining)?;
+                stream.set_write_timeout(remaining)
+            }
+            SocketKind::Unix(stream) => {
+                stream.set_read_timeout(remaining)?;
+                stream.set_write_timeout(remaining)
+            }
+        }
+    }
+
+    /// Peeks at the next byte without blocking and without consuming it,
+    /// std has no non-blocking peek for Unix sockets.
+    fn peek(&self) -> io::Result<usize> {
+        let fd = match &self.inner {
+            SocketKind::Tcp(stream) => stream.as_raw_fd(),
+            SocketKind::Unix(stream) => stream.as_raw_fd(),
+        };
+        let mut buf = [0u8; 1];
+        let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+        // Safety: the buffer outlives the call and its length is passed.
+        let res = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, 1, flags) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        match &mut self.inner {
+            SocketKind::Tcp(stream) => stream.read(buf),
+            SocketKind::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        match &mut self.inner {
+            SocketKind::Tcp(stream) => stream.write(buf),
+            SocketKind::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            SocketKind::Tcp(stream) => stream.flush(),
+            SocketKind::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+fn open_stream(target: &Target, deadline: Option<Instant>) -> Result<Stream> {
+    let (inner, default_name) = match &target.addr {
+        KvsAddr::Tcp(addr) => {
+            let stream = match deadline {
+                Some(deadline) => {
+                    // Zero is rejected by connect_timeout.
+                    let limit = deadline.saturating_duration_since(Instant::now());
+                    let limit = limit.max(Duration::from_millis(1));
+                    TcpStream::connect_timeout(addr, limit)?
+                }
+                None => TcpStream::connect(addr)?,
+            };
+            stream.set_nodelay(true)?;
+            (SocketKind::Tcp(stream), addr.ip().to_string())
+        }
+        KvsAddr::Unix(path) => (
+            SocketKind::Unix(UnixStream::connect(path)?),
+            "localhost".to_string(),
+        ),
+    };
+
+    let socket = Socket { inner, deadline };
+    match target.tls.as_ref() {
+        Some(tls) => {
+            let stream = tls.connect_blocking(&default_name, socket)?;
+            Ok(Stream::Tls(Box::new(stream)))
+        }
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+/// Reports expired socket timeouts the way the async client reports
+/// expired deadlines.
+fn timed_out(e: KvError, op: &str) -> KvError {
+    match e {
+        KvError::Io(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            KvError::Timeout { op: op.to_string() }
+        }
+        e => e,
+    }
+}
@@ -0,0 +1,216 @@
+use crate::kvs::client::{
+    BlockingKvsClient, ClientAuth, ClientOptions, KvsClient, PooledKvsClient, Target,
+};
+use crate::kvs::{ClientTls, KvsAddr, Result};
+use slog::{o, Discard, Logger};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How transport failures of idempotent requests are retried.
+///
+/// The n-th retry waits a random duration up to
+/// `min(max_backoff, initial_backoff * 2^n)` ("full jitter"), so clients
+/// reconnecting after a server restart do not all arrive at once.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Fails on the first transport error.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.max_backoff);
+        let cap = exp.min(self.max_backoff);
+        cap.mul_f64(random_fraction())
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            idle_timeout: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// A value in `[0, 1)`, random enough for spreading out retries.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Configures how a [`KvsClient`], a [`PooledKvsClient`] or a
+/// [`BlockingKvsClient`] connects and recovers from failures.
+///
+/// ```no_run
+/// # async fn run() -> proj5::kvs::Result<()> {
+/// use proj5::kvs::{KvsAddr, KvsClient};
+/// use std::time::Duration;
+///
+/// let addr: KvsAddr = "127.0.0.1:4000".parse()?;
+/// let mut client = KvsClient::builder(addr)
+///     .request_timeout(Duration::from_secs(1))
+///     .connect()
+///     .await?;
+/// client.set("key".to_owned(), "value".to_owned()).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientBuilder {
+    log: Logger,
+    target: Target,
+    auth: Option<ClientAuth>,
+    options: ClientOptions,
+    health_check_interval: Option<Duration>,
+}
+
+impl KvsClientBuilder {
+    pub(crate) fn new(addr: KvsAddr) -> KvsClientBuilder {
+        KvsClientBuilder {
+            log: Logger::root(Discard, o!()),
+            target: Target { addr, tls: None },
+            auth: None,
+            options: ClientOptions::default(),
+            health_check_interval: None,
+        }
+    }
+
+    /// Same settings for another server.
+    pub(crate) fn addr(mut self, addr: KvsAddr) -> Self {
+        self.target.addr = addr;
+        self
+    }
+
+    pub fn log(mut self, log: &Logger) -> Self {
+        self.log = log.new(o!());
+        self
+    }
+
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.target.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates every new connection, including reconnects.
+    pub fn auth(mut self, auth: ClientAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Bounds establishing a connection: connect, TLS handshake and
+    /// authentication. `None` waits forever.
+    pub fn connect_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.options.connect_timeout = timeout.into();
+        self
+    }
+
+    /// Bounds a single request, from sending the command to reading the
+    /// result. The connection is dropped when it expires.
+    pub fn request_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.options.request_timeout = timeout.into();
+        self
+    }
+
+    /// Connections unused for longer are closed and replaced on the next
+    /// request, before a firewall or the server drops them silently.
+    pub fn idle_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.options.idle_timeout = timeout.into();
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Pings idle pool connections at this interval and replaces the ones
+    /// that fail. Only used by [`KvsClientBuilder::connect_pool`].
+    pub fn health_check_interval(mut self, interval: impl Into<Option<Duration>>) -> Self {
+        self.health_check_interval = interval.into();
+        self
+    }
+
+    /// Connects eagerly, so an unreachable server is reported here rather
+    /// than on the first request.
+    pub async fn connect(self) -> Result<KvsClient> {
+        let mut client = KvsClient::with_target(self.log, self.target, self.auth, self.options);
+        client.establish().await?;
+        Ok(client)
+    }
+
+    /// Like [`KvsClientBuilder::connect`], for a client that blocks the
+    /// calling thread instead of needing an async runtime.
+    pub fn connect_blocking(self) -> Result<BlockingKvsClient> {
+        let mut client =
+            BlockingKvsClient::with_target(self.log, self.target, self.auth, self.options);
+        client.establish()?;
+        Ok(client)
+    }
+
+    /// Opens a pool of `size` connections shared by clones of the returned
+    /// client.
+    pub async fn connect_pool(self, size: usize) -> Result<PooledKvsClient> {
+        PooledKvsClient::connect(self, size).await
+    }
+
+    pub(crate) fn health_check(&self) -> Option<Duration> {
+        self.health_check_interval
+    }
+
+    pub(crate) fn logger(&self) -> &Logger {
+        &self.log
+    }
+
+    pub(crate) fn target_addr(&self) -> &KvsAddr {
+        &self.target.addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_capped() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for attempt in 0..40 {
+            let cap = Duration::from_millis(100 * (1 << attempt.min(4)));
+            assert!(retry.backoff(attempt) <= cap.min(Duration::from_secs(1)));
+        }
+    }
+}
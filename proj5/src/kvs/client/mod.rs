@@ -1,65 +1,498 @@
-use slog::{o, trace, Logger};
-use std::net::{SocketAddr};
+use slog::{debug, o, trace, Logger};
 
-use crate::kvs::net::{read, write, Command, CommandResult, write_async, read_async};
-use crate::kvs::{KvError, Result};
-use serde::de::DeserializeOwned;
-use tokio::net::TcpStream;
+use crate::kvs::net::{read_async, write_async, Command, CommandResult};
+use crate::kvs::server::merkle::{KeyRange, RangeDigest};
+use crate::kvs::server::raft::{RaftRequest, RaftResponse};
+use crate::kvs::server::slots::SlotState;
+use crate::kvs::server::txn::{TxnRequest, TxnResponse};
+use crate::kvs::{ClientTls, KvError, KvsAddr, KvsStream, Result};
+use futures::FutureExt;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::{sleep, timeout};
+
+mod blocking;
+mod builder;
+mod pool;
+mod repair;
+mod response;
+mod ring;
+mod sharded;
+mod txn;
+
+pub use blocking::BlockingKvsClient;
+pub use builder::{KvsClientBuilder, RetryPolicy};
+pub use pool::PooledKvsClient;
+pub use repair::RepairReport;
+pub use sharded::{ShardedKvsClient, ShardedKvsClientBuilder};
+pub use txn::Transaction;
+
+pub(crate) use response::result_error;
+
+use response::{
+    auth_command, interrupted, is_transport, not_connected, parse_count_response,
+    parse_digests_response, parse_fields_response, parse_get_response, parse_pairs_response,
+    parse_ping_response, parse_raft_response, parse_timestamp_response, parse_txn_response,
+    parse_void_response, should_retry, text_value,
+};
+
+/// Credentials sent by [`KvsClient::authenticate`] at connection start.
+#[derive(Clone)]
+pub enum ClientAuth {
+    Password { user: String, password: String },
+    Token(String),
+}
 
 pub struct KvsClient {
     log: Logger,
-    stream: TcpStream,
+    conn: Option<Connection>,
+    target: Option<Target>,
+    auth: Option<ClientAuth>,
+    options: ClientOptions,
+}
+
+/// Where reconnects go, unknown for clients built from a stream.
+#[derive(Clone)]
+pub(crate) struct Target {
+    pub(crate) addr: KvsAddr,
+    pub(crate) tls: Option<ClientTls>,
+}
+
+#[derive(Clone)]
+pub(crate) struct ClientOptions {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) retry: RetryPolicy,
+}
+
+struct Connection {
+    stream: Box<dyn KvsStream>,
+    last_used: Instant,
+    /// Set while a request is on the wire. A request future dropped half
+    /// way leaves it set, and the connection out of sync with the server.
+    in_flight: bool,
 }
 
 impl KvsClient {
-    pub async fn connect(log: &Logger, addr: SocketAddr) -> Result<KvsClient> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(KvsClient {
-            log: log.new(o!()),
-            stream,
-        })
+    pub fn builder(addr: impl Into<KvsAddr>) -> KvsClientBuilder {
+        KvsClientBuilder::new(addr.into())
     }
 
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.write_cmd(Command::Get { key }).await?;
+    /// Connects with the default timeouts and retry policy.
+    pub async fn connect(log: &Logger, addr: impl Into<KvsAddr>) -> Result<KvsClient> {
+        KvsClient::builder(addr).log(log).connect().await
+    }
 
-        let result = self.read_result().await?;
+    /// Connects and runs the TLS handshake before any command is sent.
+    pub async fn connect_tls(
+        log: &Logger,
+        addr: impl Into<KvsAddr>,
+        tls: &ClientTls,
+    ) -> Result<KvsClient> {
+        KvsClient::builder(addr)
+            .log(log)
+            .tls(tls.clone())
+            .connect()
+            .await
+    }
 
-        match result {
-            CommandResult::Ok => Ok(Option::None),
-            CommandResult::OkVal(val) => Ok(Option::Some(val)),
-            CommandResult::Err(err) => Err(KvError::Server { msg: err }),
+    /// Runs the protocol over an already established stream. The client
+    /// cannot reconnect once the stream is closed.
+    pub fn from_stream<S: KvsStream + 'static>(log: &Logger, stream: S) -> KvsClient {
+        KvsClient {
+            log: log.new(o!()),
+            conn: Some(Connection::new(Box::new(stream))),
+            target: None,
+            auth: None,
+            options: ClientOptions::default(),
         }
     }
 
-    pub async fn remove(&mut self, key: String) -> Result<()> {
-        self.write_cmd(Command::Remove { key }).await?;
-        let result = self.read_result().await?;
+    /// Hands the connection over to a protocol other than request and
+    /// result, e.g. replication.
+    pub(crate) fn into_stream(self) -> Option<Box<dyn KvsStream>> {
+        self.conn.map(|conn| conn.stream)
+    }
+
+    pub(crate) fn with_target(
+        log: Logger,
+        target: Target,
+        auth: Option<ClientAuth>,
+        options: ClientOptions,
+    ) -> KvsClient {
+        KvsClient {
+            log,
+            conn: None,
+            target: Some(target),
+            auth,
+            options,
+        }
+    }
+
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let result = self.call(Command::Get { key }).await?;
+        parse_get_response(result)
+    }
+
+    /// Not retried once sent: if the connection drops before the result
+    /// arrives the key may or may not be removed, which is reported as
+    /// [`KvError::Interrupted`].
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Remove { key }).await?;
+        parse_void_response(result)
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Set { key, val }).await?;
         parse_void_response(result)
     }
 
+    /// Fails with [`KvError::Ut8Conversion`] on a value that is not UTF-8,
+    /// see [`KvsClient::get_bytes`].
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let val = self.get_bytes(key.clone().into_bytes()).await?;
+        text_value(key, val)
+    }
+
+    /// Not retried once sent, see [`KvsClient::remove_bytes`].
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
     pub async fn set(&mut self, key: String, val: String) -> Result<()> {
-        self.write_cmd(Command::Set { key, val }).await?;
-        let result = self.read_result().await?;
+        self.set_bytes(key.into_bytes(), val.into_bytes()).await
+    }
+
+    /// Authenticates the connection, servers with a credentials file refuse
+    /// every command but `PING` before this succeeds. Reconnects
+    /// authenticate with the same credentials.
+    pub async fn authenticate(&mut self, auth: &ClientAuth) -> Result<()> {
+        let result = self.call(auth_command(auth)).await?;
+        parse_void_response(result)?;
+        self.auth = Some(auth.clone());
+        Ok(())
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        let result = self.call(Command::Ping).await?;
+        parse_ping_response(result)
+    }
+
+    /// Server and engine description: version, uptime, engine name, data
+    /// directory, key count and disk usage.
+    pub async fn info(&mut self) -> Result<Vec<(String, String)>> {
+        let result = self.call(Command::Info).await?;
+        parse_fields_response(result)
+    }
+
+    /// Server counters followed by engine specific statistics.
+    pub async fn stats(&mut self) -> Result<Vec<(String, String)>> {
+        let result = self.call(Command::Stats).await?;
+        parse_fields_response(result)
+    }
+
+    pub async fn compact(&mut self) -> Result<()> {
+        let result = self.call(Command::Compact).await?;
+        parse_void_response(result)
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        let result = self.call(Command::Flush).await?;
+        parse_void_response(result)
+    }
+
+    /// Has the server back its engine data up to `dest`, a missing or empty
+    /// directory on the server's file system, see
+    /// [`KvsAdmin::backup`](crate::kvs::KvsAdmin::backup). Returns the
+    /// engine, key, file and byte counts of the backup.
+    pub async fn backup(&mut self, dest: impl AsRef<Path>) -> Result<Vec<(String, String)>> {
+        let dest = dest.as_ref().display().to_string();
+        let result = self.call(Command::Backup { dest }).await?;
+        parse_fields_response(result)
+    }
+
+    /// Adds a server to the cluster, sent to its leader. The server must run
+    /// in cluster mode, usually started with
+    /// [`ClusterConfig::join`](crate::kvs::ClusterConfig::join).
+    pub async fn add_member(&mut self, member: impl Into<KvsAddr>) -> Result<()> {
+        let member = member.into().to_string();
+        let result = self.call(Command::AddMember { member }).await?;
+        parse_void_response(result)
+    }
+
+    /// Removes a server from the cluster, sent to its leader.
+    pub async fn remove_member(&mut self, member: impl Into<KvsAddr>) -> Result<()> {
+        let member = member.into().to_string();
+        let result = self.call(Command::RemoveMember { member }).await?;
+        parse_void_response(result)
+    }
+
+    pub(crate) async fn raft(&mut self, msg: RaftRequest) -> Result<RaftResponse> {
+        let result = self.call(Command::Raft { msg }).await?;
+        parse_raft_response(result)
+    }
+
+    /// Next timestamp of the server's timestamp oracle.
+    pub async fn timestamp(&mut self) -> Result<u64> {
+        let result = self.call(Command::Timestamp).await?;
+        parse_timestamp_response(result)
+    }
+
+    pub(crate) async fn txn(&mut self, req: TxnRequest) -> Result<TxnResponse> {
+        let result = self.call(Command::Txn { req }).await?;
+        parse_txn_response(result)
+    }
+
+    /// Makes `target` hold the pairs this server holds, copying only the key
+    /// ranges whose Merkle tree hashes differ. Writes either server takes
+    /// meanwhile may be missed or undone, so it is meant for replicas that
+    /// drifted, run while writes are quiet. Needs admin access on both.
+    pub async fn repair(&mut self, target: &mut KvsClient) -> Result<RepairReport> {
+        repair::repair(self, target).await
+    }
+
+    pub(crate) async fn digest(
+        &mut self,
+        ranges: Vec<KeyRange>,
+        split: u32,
+    ) -> Result<Vec<RangeDigest>> {
+        let result = self.call(Command::Digest { ranges, split }).await?;
+        parse_digests_response(result)
+    }
+
+    pub(crate) async fn range(&mut self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let result = self.call(Command::Range { range }).await?;
+        parse_pairs_response(result)
+    }
+
+    pub(crate) async fn repair_write(
+        &mut self,
+        set: Vec<(Vec<u8>, Vec<u8>)>,
+        remove: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let result = self.call(Command::Repair { set, remove }).await?;
         parse_void_response(result)
     }
 
-    async fn write_cmd(&mut self, cmd: Command) -> Result<()> {
-        trace!(self.log, "command: {}", &cmd);
-        write_async(&mut self.stream, &cmd).await
+    /// Changes the state of a hash slot on the server, see
+    /// [`KvsServer::with_slots`](crate::kvs::KvsServer::with_slots).
+    pub async fn set_slot(&mut self, slot: u16, state: SlotState) -> Result<()> {
+        let result = self.call(Command::SetSlot { slot, state }).await?;
+        parse_void_response(result)
+    }
+
+    /// Has the server stream the keys of a slot in
+    /// [`SlotState::Migrating`] to its target, returns how many it moved.
+    pub async fn migrate_slot(&mut self, slot: u16) -> Result<u64> {
+        let result = self.call(Command::MigrateSlot { slot }).await?;
+        parse_count_response(result)
+    }
+
+    pub(crate) async fn import(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let result = self.call(Command::Import { pairs }).await?;
+        parse_void_response(result)
+    }
+
+    /// Moves the hash slots from this server to `target` one by one,
+    /// returns how many keys were moved. Both servers keep serving the
+    /// slots' keys meanwhile, redirecting clients to each other. The
+    /// addresses the clients were built with are the ones other clients
+    /// are redirected to.
+    pub async fn move_slots(
+        &mut self,
+        target: &mut KvsClient,
+        slots: RangeInclusive<u16>,
+    ) -> Result<u64> {
+        let from = self.redirect_addr()?;
+        let to = target.redirect_addr()?;
+        let mut moved = 0;
+        for slot in slots {
+            let importing = SlotState::Importing { from: from.clone() };
+            target.set_slot(slot, importing).await?;
+            let migrating = SlotState::Migrating { to: to.clone() };
+            self.set_slot(slot, migrating).await?;
+            moved += self.migrate_slot(slot).await?;
+            target.set_slot(slot, SlotState::Owned).await?;
+            self.set_slot(slot, SlotState::Node { addr: to.clone() })
+                .await?;
+        }
+        Ok(moved)
+    }
+
+    fn redirect_addr(&self) -> Result<String> {
+        match &self.target {
+            Some(target) => Ok(target.addr.to_string()),
+            None => Err(KvError::Slots {
+                msg: "a client built from a stream has no address to redirect to".to_string(),
+            }),
+        }
+    }
+
+    /// Sends the command and reads its result, reconnecting and retrying
+    /// on transport errors as far as the retry policy and the command allow.
+    pub(crate) async fn call(&mut self, cmd: Command) -> Result<CommandResult> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.ensure_connected().await {
+                Ok(()) => match self.send(&cmd).await {
+                    Ok(result) => return Ok(result),
+                    Err(e) => {
+                        self.conn = None;
+                        if is_transport(&e) && !cmd.is_idempotent() {
+                            return Err(interrupted(&cmd, e));
+                        }
+                        e
+                    }
+                },
+                Err(e) => e,
+            };
+
+            let can_reconnect = self.target.is_some();
+            if !should_retry(&err, can_reconnect, attempt, &self.options.retry) {
+                return Err(err);
+            }
+
+            let backoff = self.options.retry.backoff(attempt);
+            debug!(self.log, "retrying {} in {:?}: {}", cmd.name(), backoff, err);
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send(&mut self, cmd: &Command) -> Result<CommandResult> {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return Err(not_connected()),
+        };
+        let request = conn.request(&self.log, cmd);
+        with_timeout(self.options.request_timeout, cmd.name(), request).await
+    }
+
+    /// Whether the connection is open and ready for the next request,
+    /// without waiting on the network.
+    pub(crate) fn is_healthy(&mut self) -> bool {
+        match self.conn.as_mut() {
+            Some(conn) => !conn.is_closed(),
+            None => false,
+        }
     }
 
-    async fn read_result(&mut self) -> Result<CommandResult> {
+    /// Opens the first connection, retrying transport errors.
+    pub(crate) async fn establish(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.ensure_connected().await {
+                Err(e) if should_retry(&e, true, attempt, &self.options.retry) => {
+                    let backoff = self.options.retry.backoff(attempt);
+                    debug!(self.log, "retrying connect in {:?}: {}", backoff, e);
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Drops the connection if it is closed or idle for too long, and opens
+    /// a new one if needed.
+    async fn ensure_connected(&mut self) -> Result<()> {
+        if let Some(conn) = self.conn.as_mut() {
+            let idle = match self.options.idle_timeout {
+                Some(limit) => conn.last_used.elapsed() > limit,
+                None => false,
+            };
+            if idle || conn.is_closed() {
+                debug!(self.log, "dropping connection, idle: {}", idle);
+                self.conn = None;
+            }
+        }
+
+        if self.conn.is_none() {
+            let target = self.target.as_ref().ok_or_else(not_connected)?;
+            let open = open_connection(&self.log, target, self.auth.as_ref());
+            let conn = with_timeout(self.options.connect_timeout, "connect", open).await?;
+            self.conn = Some(conn);
+        }
+        Ok(())
+    }
+}
+
+impl Connection {
+    fn new(stream: Box<dyn KvsStream>) -> Connection {
+        Connection {
+            stream,
+            last_used: Instant::now(),
+            in_flight: false,
+        }
+    }
+
+    async fn request(&mut self, log: &Logger, cmd: &Command) -> Result<CommandResult> {
+        trace!(log, "command: {}", cmd);
+        self.in_flight = true;
+        write_async(&mut self.stream, cmd).await?;
         let result: CommandResult = read_async(&mut self.stream).await?;
-        trace!(self.log, "response: {}", &result);
+        self.in_flight = false;
+        trace!(log, "response: {}", &result);
+        self.last_used = Instant::now();
         Ok(result)
     }
+
+    /// Whether the server closed the connection. Between requests nothing
+    /// is expected on the stream, so a read that completes right away means
+    /// either EOF or a broken protocol.
+    fn is_closed(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        self.in_flight || self.stream.read(&mut buf).now_or_never().is_some()
+    }
+}
+
+/// Takes the client fields rather than `&KvsClient`: the client holds a
+/// stream that is not `Sync`, holding a shared reference to it across an
+/// await would make request futures `!Send`.
+async fn open_connection(
+    log: &Logger,
+    target: &Target,
+    auth: Option<&ClientAuth>,
+) -> Result<Connection> {
+    let stream = open_stream(&target.addr, target.tls.as_ref()).await?;
+    let mut conn = Connection::new(stream);
+    if let Some(auth) = auth {
+        let result = conn.request(log, &auth_command(auth)).await?;
+        parse_void_response(result)?;
+    }
+    Ok(conn)
+}
+
+async fn open_stream(addr: &KvsAddr, tls: Option<&ClientTls>) -> Result<Box<dyn KvsStream>> {
+    match (addr, tls) {
+        (KvsAddr::Tcp(addr), None) => Ok(Box::new(TcpStream::connect(addr).await?)),
+        (KvsAddr::Unix(path), None) => Ok(Box::new(UnixStream::connect(path).await?)),
+        (KvsAddr::Tcp(addr), Some(tls)) => {
+            let stream = TcpStream::connect(addr).await?;
+            tls.connect(&addr.ip().to_string(), stream).await
+        }
+        (KvsAddr::Unix(path), Some(tls)) => {
+            let stream = UnixStream::connect(path).await?;
+            tls.connect("localhost", stream).await
+        }
+    }
 }
 
-fn parse_void_response(result: CommandResult) -> Result<()> {
-    match result {
-        CommandResult::Ok => Ok(()),
-        CommandResult::Err(err) => Err(KvError::Server { msg: err }),
-        CommandResult::OkVal(val) => Err(KvError::UnexpectedResult { val }),
+async fn with_timeout<T>(
+    limit: Option<Duration>,
+    op: &str,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match limit {
+        Some(limit) => match timeout(limit, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout { op: op.to_string() }),
+        },
+        None => fut.await,
     }
 }
@@ -0,0 +1,197 @@
+use crate::kvs::net::{Command, CommandResult};
+use crate::kvs::server::txn::{TxnRequest, TxnResponse};
+use crate::kvs::{KvsClient, KvsClientBuilder, Result};
+use slog::{debug, o, Logger};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// A client sharing a fixed number of connections between concurrent tasks.
+///
+/// Clones share the pool. Each request borrows a connection, waiting when
+/// all of them are in use. Connections found closed or left in an unknown
+/// state by a failed request are dropped and replaced by a new one on the
+/// next request.
+#[derive(Clone)]
+pub struct PooledKvsClient {
+    pool: Arc<Pool>,
+}
+
+struct Pool {
+    builder: KvsClientBuilder,
+    /// Requests take the connection given back last, health checks the
+    /// one given back first.
+    idle: Mutex<VecDeque<KvsClient>>,
+    permits: Semaphore,
+    log: Logger,
+}
+
+/// A connection borrowed from the pool, given back on drop if healthy.
+struct Lease<'a> {
+    client: Option<KvsClient>,
+    pool: &'a Pool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledKvsClient {
+    pub(crate) async fn connect(builder: KvsClientBuilder, size: usize) -> Result<PooledKvsClient> {
+        let mut idle = VecDeque::with_capacity(size);
+        for _ in 0..size {
+            idle.push_back(builder.clone().connect().await?);
+        }
+
+        let health_check = builder.health_check();
+        let pool = Arc::new(Pool {
+            log: builder.logger().new(o!("pool" => size)),
+            builder,
+            idle: Mutex::new(idle),
+            permits: Semaphore::new(size),
+        });
+
+        if let Some(interval) = health_check {
+            tokio::spawn(health_check_loop(Arc::downgrade(&pool), interval));
+        }
+
+        Ok(PooledKvsClient { pool })
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.lease().await?.get_bytes(key).await
+    }
+
+    pub async fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.lease().await?.set_bytes(key, val).await
+    }
+
+    /// Not retried once sent, see [`KvsClient::remove_bytes`].
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.lease().await?.remove_bytes(key).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.lease().await?.get(key).await
+    }
+
+    pub async fn set(&self, key: String, val: String) -> Result<()> {
+        self.lease().await?.set(key, val).await
+    }
+
+    /// Not retried once sent, see [`KvsClient::remove_bytes`].
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.lease().await?.remove(key).await
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.lease().await?.ping().await
+    }
+
+    pub async fn timestamp(&self) -> Result<u64> {
+        self.lease().await?.timestamp().await
+    }
+
+    pub(crate) async fn txn(&self, req: TxnRequest) -> Result<TxnResponse> {
+        self.lease().await?.txn(req).await
+    }
+
+    pub(crate) async fn call(&self, cmd: Command) -> Result<CommandResult> {
+        self.lease().await?.call(cmd).await
+    }
+
+    /// Connections currently not lent to a request.
+    pub fn idle_connections(&self) -> usize {
+        self.pool.idle.lock().unwrap().len()
+    }
+
+    async fn lease(&self) -> Result<Lease<'_>> {
+        let pool = self.pool.as_ref();
+        let permit = pool.permits.acquire().await.expect("pool semaphore closed");
+
+        let client = match pool.take_healthy() {
+            Some(client) => client,
+            None => {
+                debug!(pool.log, "opening connection");
+                pool.builder.clone().connect().await?
+            }
+        };
+
+        Ok(Lease {
+            client: Some(client),
+            pool,
+            _permit: permit,
+        })
+    }
+}
+
+impl Pool {
+    fn take_healthy(&self) -> Option<KvsClient> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(mut client) = idle.pop_back() {
+            if client.is_healthy() {
+                return Some(client);
+            }
+            debug!(self.log, "dropping broken connection");
+        }
+        None
+    }
+
+    fn give_back(&self, mut client: KvsClient) {
+        if client.is_healthy() {
+            self.idle.lock().unwrap().push_back(client);
+        } else {
+            debug!(self.log, "dropping broken connection");
+        }
+    }
+}
+
+impl Deref for Lease<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Lease<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.give_back(client);
+        }
+    }
+}
+
+/// Pings every idle connection, from the one given back first, one at a
+/// time so requests are not starved, until the pool is dropped. Failed connections are dropped, requests open
+/// new ones when needed.
+async fn health_check_loop(pool: Weak<Pool>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        let idle = pool.idle.lock().unwrap().len();
+        for _ in 0..idle {
+            let _permit = match pool.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let client = pool.idle.lock().unwrap().pop_front();
+            if let Some(mut client) = client {
+                match client.ping().await {
+                    Ok(()) => pool.give_back(client),
+                    Err(e) => debug!(pool.log, "health check failed: {}", e),
+                }
+            }
+        }
+    }
+}
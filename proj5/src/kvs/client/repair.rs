@@ -0,0 +1,87 @@
+use crate::kvs::server::merkle::{KeyRange, RangeDigest};
+use crate::kvs::{KvsClient, Result};
+use std::collections::BTreeMap;
+
+/// Children asked for when a differing node is split.
+const FANOUT: u32 = 16;
+/// Differing nodes with at most as many pairs on both servers are copied
+/// instead of split further.
+const LEAF_KEYS: u64 = 32;
+
+/// What [`KvsClient::repair`] compared and copied.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RepairReport {
+    /// Tree nodes whose hashes were compared.
+    pub ranges_compared: u64,
+    /// Differing leaf ranges copied to the target.
+    pub ranges_repaired: u64,
+    /// Pairs read from both servers to copy the differing leaf ranges.
+    pub pairs_read: u64,
+    pub keys_set: u64,
+    pub keys_removed: u64,
+}
+
+/// Walks the trees of both servers from the root, down the nodes whose
+/// hashes differ, and copies the differing leaf ranges. A node is split by
+/// the server holding more of its pairs, so every step shrinks the nodes
+/// left to compare.
+pub(super) async fn repair(source: &mut KvsClient, target: &mut KvsClient) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    let root = vec![KeyRange::all()];
+    let mut pending = vec![(
+        source.digest(root.clone(), 1).await?.remove(0),
+        target.digest(root, 1).await?.remove(0),
+    )];
+
+    while let Some((theirs, ours)) = pending.pop() {
+        report.ranges_compared += 1;
+        if theirs.hash == ours.hash {
+            continue;
+        }
+        if theirs.keys <= LEAF_KEYS && ours.keys <= LEAF_KEYS {
+            copy_range(source, target, theirs.range, &mut report).await?;
+            continue;
+        }
+
+        let range = vec![theirs.range];
+        if theirs.keys >= ours.keys {
+            let children = source.digest(range, FANOUT).await?;
+            let matching = target.digest(ranges(&children), 1).await?;
+            pending.extend(children.into_iter().zip(matching));
+        } else {
+            let children = target.digest(range, FANOUT).await?;
+            let matching = source.digest(ranges(&children), 1).await?;
+            pending.extend(matching.into_iter().zip(children));
+        }
+    }
+    Ok(report)
+}
+
+async fn copy_range(
+    source: &mut KvsClient,
+    target: &mut KvsClient,
+    range: KeyRange,
+    report: &mut RepairReport,
+) -> Result<()> {
+    let theirs = source.range(range.clone()).await?;
+    let ours: BTreeMap<_, _> = target.range(range).await?.into_iter().collect();
+    report.ranges_repaired += 1;
+    report.pairs_read += (theirs.len() + ours.len()) as u64;
+
+    let remove: Vec<Vec<u8>> = ours
+        .keys()
+        .filter(|key| theirs.binary_search_by(|(k, _)| k.cmp(key)).is_err())
+        .cloned()
+        .collect();
+    let set: Vec<(Vec<u8>, Vec<u8>)> = theirs
+        .into_iter()
+        .filter(|(key, val)| ours.get(key) != Some(val))
+        .collect();
+    report.keys_set += set.len() as u64;
+    report.keys_removed += remove.len() as u64;
+    target.repair_write(set, remove).await
+}
+
+fn ranges(digests: &[RangeDigest]) -> Vec<KeyRange> {
+    digests.iter().map(|digest| digest.range.clone()).collect()
+}
@@ -0,0 +1,175 @@
+//! Mapping of command results and transport errors, shared by the async
+//! and the blocking client.
+
+use crate::kvs::client::{ClientAuth, RetryPolicy};
+use crate::kvs::net::{Command, CommandResult};
+use crate::kvs::server::merkle::RangeDigest;
+use crate::kvs::server::raft::RaftResponse;
+use crate::kvs::server::txn::TxnResponse;
+use crate::kvs::{KvError, Result};
+use std::io;
+
+pub(super) fn parse_get_response(result: CommandResult) -> Result<Option<Vec<u8>>> {
+    match result {
+        CommandResult::Ok => Ok(Option::None),
+        CommandResult::OkVal(val) => Ok(Option::Some(val.into_bytes())),
+        CommandResult::OkBytes(val) => Ok(Option::Some(val)),
+        result => Err(result_error(result)),
+    }
+}
+
+/// The value read for a `String` key as a `String`.
+pub(super) fn text_value(key: String, val: Option<Vec<u8>>) -> Result<Option<String>> {
+    match val.map(String::from_utf8) {
+        Some(Ok(val)) => Ok(Some(val)),
+        Some(Err(source)) => Err(KvError::Ut8Conversion { key, source }),
+        None => Ok(None),
+    }
+}
+
+pub(super) fn parse_ping_response(result: CommandResult) -> Result<()> {
+    match result {
+        CommandResult::OkVal(_) => Ok(()),
+        result => parse_void_response(result),
+    }
+}
+
+pub(super) fn parse_void_response(result: CommandResult) -> Result<()> {
+    match result {
+        CommandResult::Ok => Ok(()),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_fields_response(result: CommandResult) -> Result<Vec<(String, String)>> {
+    match result {
+        CommandResult::OkFields(fields) => Ok(fields),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_pairs_response(result: CommandResult) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    match result {
+        CommandResult::OkPairs(pairs) => Ok(pairs),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_raft_response(result: CommandResult) -> Result<RaftResponse> {
+    match result {
+        CommandResult::Raft(resp) => Ok(resp),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_txn_response(result: CommandResult) -> Result<TxnResponse> {
+    match result {
+        CommandResult::Txn(resp) => Ok(resp),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_timestamp_response(result: CommandResult) -> Result<u64> {
+    match result {
+        CommandResult::Timestamp(ts) => Ok(ts),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_digests_response(result: CommandResult) -> Result<Vec<RangeDigest>> {
+    match result {
+        CommandResult::Digests(digests) => Ok(digests),
+        result => Err(result_error(result)),
+    }
+}
+
+pub(super) fn parse_count_response(result: CommandResult) -> Result<u64> {
+    match result {
+        CommandResult::Count(count) => Ok(count),
+        result => Err(result_error(result)),
+    }
+}
+
+/// Error for a result other than the one the command succeeds with.
+pub(crate) fn result_error(result: CommandResult) -> KvError {
+    match result {
+        CommandResult::Err(msg) => KvError::Server { msg },
+        CommandResult::Busy(msg) => KvError::Busy { msg },
+        CommandResult::Denied(msg) => KvError::PermissionDenied { msg },
+        CommandResult::ReadOnly(primary) => KvError::ReadOnly { primary },
+        CommandResult::NotLeader(leader) => KvError::NotLeader { leader },
+        CommandResult::Moved(slot, addr) => KvError::Moved { slot, addr },
+        CommandResult::Ask(slot, addr) => KvError::Ask { slot, addr },
+        CommandResult::Ok => KvError::UnexpectedResult {
+            val: "Ok".to_string(),
+        },
+        CommandResult::OkVal(val) => KvError::UnexpectedResult { val },
+        CommandResult::OkBytes(val) => KvError::UnexpectedResult {
+            val: format!("{:?}", val),
+        },
+        CommandResult::OkFields(fields) => KvError::UnexpectedResult {
+            val: format!("{:?}", fields),
+        },
+        CommandResult::OkPairs(pairs) => KvError::UnexpectedResult {
+            val: format!("{:?}", pairs),
+        },
+        CommandResult::Raft(resp) => KvError::UnexpectedResult {
+            val: format!("{:?}", resp),
+        },
+        CommandResult::Txn(resp) => KvError::UnexpectedResult {
+            val: format!("{:?}", resp),
+        },
+        CommandResult::Timestamp(ts) => KvError::UnexpectedResult {
+            val: ts.to_string(),
+        },
+        CommandResult::Digests(digests) => KvError::UnexpectedResult {
+            val: format!("{:?}", digests),
+        },
+        CommandResult::Count(count) => KvError::UnexpectedResult {
+            val: count.to_string(),
+        },
+    }
+}
+
+/// Whether a failed attempt is tried again: only transport errors, only
+/// when the client knows where to reconnect, and only within the policy.
+pub(super) fn should_retry(
+    err: &KvError,
+    can_reconnect: bool,
+    attempt: u32,
+    retry: &RetryPolicy,
+) -> bool {
+    is_transport(err) && can_reconnect && attempt < retry.max_retries
+}
+
+/// Reports a transport error of a request that was sent but must not be
+/// retried, since it may already have been applied.
+pub(super) fn interrupted(cmd: &Command, e: KvError) -> KvError {
+    KvError::Interrupted {
+        op: cmd.name().to_string(),
+        msg: e.to_string(),
+    }
+}
+
+/// Errors after which the connection is in an unknown state, as opposed to
+/// errors reported by the server.
+pub(super) fn is_transport(e: &KvError) -> bool {
+    matches!(
+        e,
+        KvError::Io(_) | KvError::Timeout { .. } | KvError::Tls { .. }
+    )
+}
+
+pub(super) fn not_connected() -> KvError {
+    KvError::Io(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "connection closed",
+    ))
+}
+
+pub(super) fn auth_command(auth: &ClientAuth) -> Command {
+    match auth.clone() {
+        ClientAuth::Password { user, password } => Command::Auth { user, password },
+        ClientAuth::Token(token) => Command::AuthToken { token },
+    }
+}
@@ -0,0 +1,122 @@
+use crate::kvs::KvsAddr;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+/// Consistent-hash ring placing every node at `vnodes` points.
+///
+/// A key belongs to the first point at or after its hash, wrapping around.
+/// Adding or removing a node only moves the keys between its points and
+/// their predecessors, about `1 / nodes` of them, and the many points per
+/// node keep the shares even.
+#[derive(Debug, Clone)]
+pub(crate) struct HashRing {
+    vnodes: usize,
+    points: BTreeMap<u64, KvsAddr>,
+}
+
+impl HashRing {
+    pub(crate) fn new(vnodes: usize) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            points: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, node: &KvsAddr) {
+        for i in 0..self.vnodes {
+            self.points
+                .insert(hash(format!("{}#{}", node, i).as_bytes()), node.clone());
+        }
+    }
+
+    pub(crate) fn remove(&mut self, node: &KvsAddr) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    pub(crate) fn node(&self, key: &[u8]) -> Option<&KvsAddr> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node)
+    }
+}
+
+/// Stable across processes and platforms, unlike `DefaultHasher`, so every
+/// client routes a key to the same server.
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = Sha256::digest(bytes);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const KEYS: usize = 10_000;
+
+    fn node(port: u16) -> KvsAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn ring(ports: &[u16]) -> HashRing {
+        let mut ring = HashRing::new(160);
+        for port in ports {
+            ring.add(&node(*port));
+        }
+        ring
+    }
+
+    fn owners(ring: &HashRing) -> Vec<KvsAddr> {
+        (0..KEYS)
+            .map(|i| ring.node(format!("key{}", i).as_bytes()).unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn empty_ring() {
+        assert!(HashRing::new(160).node(b"key").is_none());
+    }
+
+    #[test]
+    fn balanced() {
+        let ring = ring(&[4000, 4001, 4002, 4003]);
+        let mut counts = HashMap::new();
+        for owner in owners(&ring) {
+            *counts.entry(owner).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        for count in counts.values() {
+            assert!(*count > KEYS / 4 * 3 / 4, "unbalanced: {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn adding_node_moves_keys_only_to_it() {
+        let before = owners(&ring(&[4000, 4001, 4002]));
+        let after = owners(&ring(&[4000, 4001, 4002, 4003]));
+
+        let moved: Vec<_> = (0..KEYS).filter(|&i| before[i] != after[i]).collect();
+        assert!(moved.iter().all(|&i| after[i] == node(4003)));
+        assert!(moved.len() < KEYS / 3, "moved {} keys", moved.len());
+    }
+
+    #[test]
+    fn removing_node_moves_only_its_keys() {
+        let before = owners(&ring(&[4000, 4001, 4002, 4003]));
+        let mut ring = ring(&[4000, 4001, 4002, 4003]);
+        ring.remove(&node(4001));
+        let after = owners(&ring);
+
+        for i in 0..KEYS {
+            if before[i] != node(4001) {
+                assert_eq!(before[i], after[i]);
+            } else {
+                assert_ne!(after[i], node(4001));
+            }
+        }
+    }
+}
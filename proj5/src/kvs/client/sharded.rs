@@ -0,0 +1,306 @@
+use crate::kvs::client::response::{parse_get_response, parse_void_response, text_value};
+use crate::kvs::client::ring::HashRing;
+use crate::kvs::net::{Command, CommandResult};
+use crate::kvs::server::slots::key_slot;
+use crate::kvs::{KvError, KvsAddr, KvsClientBuilder, PooledKvsClient, Result};
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const DEFAULT_VNODES: usize = 160;
+const DEFAULT_CONNECTIONS: usize = 4;
+
+/// Redirects followed by one request before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// A client spreading keys over several servers with consistent hashing.
+///
+/// Each key is sent to one server, picked by a hash ring with many virtual
+/// nodes per server. Multi-key operations are split by server and sent
+/// concurrently. Servers can be added and removed, which moves only the
+/// keys of about one server's share to another one. The keys themselves are
+/// not copied between servers: after a change, keys that moved read as
+/// missing until they are written again.
+///
+/// Servers serving hash slots, see
+/// [`KvsServer::with_slots`](crate::kvs::KvsServer::with_slots), move keys
+/// themselves. Requests they redirect are sent again where the redirect
+/// points, and the slots found moved are routed to their new server from
+/// then on, connecting to it if it is not one of the nodes.
+///
+/// Transactions over all servers need one of them, or another server, to
+/// be the timestamp oracle, set with [`ShardedKvsClientBuilder::oracle`].
+///
+/// ```no_run
+/// # async fn run() -> proj5::kvs::Result<()> {
+/// use proj5::kvs::{KvsAddr, ShardedKvsClient};
+///
+/// let nodes: Vec<KvsAddr> = vec!["127.0.0.1:4000".parse()?, "127.0.0.1:4001".parse()?];
+/// let client = ShardedKvsClient::builder(nodes).connect().await?;
+/// client.set("key".to_owned(), "value".to_owned()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    nodes: HashMap<KvsAddr, PooledKvsClient>,
+    client: Option<KvsClientBuilder>,
+    connections: usize,
+    oracle: Option<PooledKvsClient>,
+    /// Slots a MOVED redirect pointed elsewhere than the ring.
+    moved: Mutex<HashMap<u16, KvsAddr>>,
+    /// Pools of servers only known from redirects.
+    redirected: Mutex<HashMap<KvsAddr, PooledKvsClient>>,
+}
+
+pub struct ShardedKvsClientBuilder {
+    nodes: Vec<KvsAddr>,
+    vnodes: usize,
+    connections: usize,
+    client: Option<KvsClientBuilder>,
+    oracle: Option<KvsAddr>,
+}
+
+impl ShardedKvsClient {
+    pub fn builder<A: Into<KvsAddr>>(
+        nodes: impl IntoIterator<Item = A>,
+    ) -> ShardedKvsClientBuilder {
+        ShardedKvsClientBuilder {
+            nodes: nodes.into_iter().map(Into::into).collect(),
+            vnodes: DEFAULT_VNODES,
+            connections: DEFAULT_CONNECTIONS,
+            client: None,
+            oracle: None,
+        }
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let result = self.call(Command::Get { key }).await?;
+        parse_get_response(result)
+    }
+
+    pub async fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Set { key, val }).await?;
+        parse_void_response(result)
+    }
+
+    /// Not retried once sent, see
+    /// [`KvsClient::remove_bytes`](crate::kvs::KvsClient::remove_bytes).
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let result = self.call(Command::Remove { key }).await?;
+        parse_void_response(result)
+    }
+
+    /// Fails with [`KvError::Ut8Conversion`] on a value that is not UTF-8.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let val = self.get_bytes(key.clone().into_bytes()).await?;
+        text_value(key, val)
+    }
+
+    pub async fn set(&self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes()).await
+    }
+
+    /// Not retried once sent, see
+    /// [`KvsClient::remove_bytes`](crate::kvs::KvsClient::remove_bytes).
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Reads the keys from all servers at once, values are in the order of
+    /// the keys. Fails with the first error once all requests are done.
+    pub async fn get_many_bytes(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        let requests = keys.into_iter().map(|key| self.get_bytes(key));
+        join_all(requests).await.into_iter().collect()
+    }
+
+    /// Writes the pairs to all servers at once. Every pair is attempted,
+    /// the first error is returned.
+    pub async fn set_many_bytes(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let requests = pairs.into_iter().map(|(key, val)| self.set_bytes(key, val));
+        join_all(requests).await.into_iter().collect()
+    }
+
+    /// Fails with [`KvError::Ut8Conversion`] on a value that is not UTF-8,
+    /// see [`ShardedKvsClient::get_many_bytes`].
+    pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let vals = self
+            .get_many_bytes(keys.iter().map(|key| key.clone().into_bytes()).collect())
+            .await?;
+        keys.into_iter()
+            .zip(vals)
+            .map(|(key, val)| text_value(key, val))
+            .collect()
+    }
+
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, val)| (key.into_bytes(), val.into_bytes()))
+            .collect();
+        self.set_many_bytes(pairs).await
+    }
+
+    /// Server the ring routes the key to, before any redirect.
+    pub fn node_for(&self, key: impl AsRef<[u8]>) -> Option<&KvsAddr> {
+        self.ring.node(key.as_ref())
+    }
+
+    pub fn nodes(&self) -> Vec<&KvsAddr> {
+        let mut nodes: Vec<_> = self.nodes.keys().collect();
+        nodes.sort_by_key(|addr| addr.to_string());
+        nodes
+    }
+
+    /// Connects to the server and adds it to the ring. Adding a server
+    /// twice has no effect.
+    pub async fn add_node(&mut self, addr: impl Into<KvsAddr>) -> Result<()> {
+        let addr = addr.into();
+        if self.nodes.contains_key(&addr) {
+            return Ok(());
+        }
+        let client = node_builder(self.client.as_ref(), &addr)
+            .connect_pool(self.connections)
+            .await?;
+        self.ring.add(&addr);
+        self.nodes.insert(addr, client);
+        Ok(())
+    }
+
+    /// Removes the server from the ring and closes its connections once
+    /// pending requests are done. Returns whether it was part of the ring.
+    pub fn remove_node(&mut self, addr: &KvsAddr) -> bool {
+        self.ring.remove(addr);
+        self.moved.get_mut().unwrap().retain(|_, node| node != addr);
+        self.nodes.remove(addr).is_some()
+    }
+
+    /// Sends the keyed command to the server of its key, following the
+    /// redirects of servers serving hash slots.
+    async fn call(&self, cmd: Command) -> Result<CommandResult> {
+        let key = cmd.key().unwrap_or_default();
+        let slot = key_slot(key);
+        let moved = self.moved.lock().unwrap().get(&slot).cloned();
+        let mut addr = match moved {
+            Some(addr) => addr,
+            None => self.ring.node(key).cloned().ok_or(KvError::NoNodes)?,
+        };
+
+        let mut asking = false;
+        for _ in 0..=MAX_REDIRECTS {
+            let sent = if asking {
+                Command::Asking {
+                    request: Box::new(cmd.clone()),
+                }
+            } else {
+                cmd.clone()
+            };
+            match self.connection(&addr).await?.call(sent).await? {
+                CommandResult::Moved(slot, to) => {
+                    addr = to.parse()?;
+                    self.moved.lock().unwrap().insert(slot, addr.clone());
+                    asking = false;
+                }
+                CommandResult::Ask(_, to) => {
+                    addr = to.parse()?;
+                    asking = true;
+                }
+                result => return Ok(result),
+            }
+        }
+        Err(KvError::Slots {
+            msg: format!("too many redirects for slot {}", slot),
+        })
+    }
+
+    async fn connection(&self, addr: &KvsAddr) -> Result<PooledKvsClient> {
+        if let Some(client) = self.nodes.get(addr) {
+            return Ok(client.clone());
+        }
+        if let Some(client) = self.redirected.lock().unwrap().get(addr) {
+            return Ok(client.clone());
+        }
+        let client = node_builder(self.client.as_ref(), addr)
+            .connect_pool(self.connections)
+            .await?;
+        let mut redirected = self.redirected.lock().unwrap();
+        Ok(redirected.entry(addr.clone()).or_insert(client).clone())
+    }
+
+    pub(crate) fn client_for(&self, key: &[u8]) -> Result<&PooledKvsClient> {
+        self.ring
+            .node(key)
+            .and_then(|addr| self.nodes.get(addr))
+            .ok_or(KvError::NoNodes)
+    }
+
+    pub(crate) fn oracle(&self) -> Result<&PooledKvsClient> {
+        self.oracle.as_ref().ok_or_else(|| KvError::Txn {
+            msg: "no timestamp oracle set".to_string(),
+        })
+    }
+}
+
+impl ShardedKvsClientBuilder {
+    /// Points per server on the ring, more spread keys more evenly.
+    pub fn vnodes(mut self, vnodes: usize) -> Self {
+        self.vnodes = vnodes;
+        self
+    }
+
+    /// Size of the connection pool kept to each server.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Timeouts, retries, TLS and credentials used for every server. The
+    /// builder's own address is replaced by each server's.
+    pub fn client(mut self, client: KvsClientBuilder) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Server handing out the timestamps of transactions, see
+    /// [`KvsServer::with_tso`](crate::kvs::KvsServer::with_tso). It may be
+    /// one of the nodes.
+    pub fn oracle(mut self, addr: impl Into<KvsAddr>) -> Self {
+        self.oracle = Some(addr.into());
+        self
+    }
+
+    /// Connects to all servers, failing if any of them is unreachable.
+    pub async fn connect(self) -> Result<ShardedKvsClient> {
+        let mut client = ShardedKvsClient {
+            ring: HashRing::new(self.vnodes),
+            nodes: HashMap::new(),
+            client: self.client,
+            connections: self.connections,
+            oracle: None,
+            moved: Mutex::new(HashMap::new()),
+            redirected: Mutex::new(HashMap::new()),
+        };
+        for addr in self.nodes {
+            client.add_node(addr).await?;
+        }
+        if let Some(addr) = self.oracle {
+            let oracle = match client.nodes.get(&addr) {
+                Some(node) => node.clone(),
+                None => {
+                    node_builder(client.client.as_ref(), &addr)
+                        .connect_pool(client.connections)
+                        .await?
+                }
+            };
+            client.oracle = Some(oracle);
+        }
+        Ok(client)
+    }
+}
+
+fn node_builder(client: Option<&KvsClientBuilder>, addr: &KvsAddr) -> KvsClientBuilder {
+    match client {
+        Some(client) => client.clone().addr(addr.clone()),
+        None => KvsClientBuilder::new(addr.clone()),
+    }
+}
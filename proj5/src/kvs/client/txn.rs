@@ -0,0 +1,395 @@
+use crate::kvs::client::response::text_value;
+use crate::kvs::server::txn::{LockInfo, Mutation, TxnRequest, TxnResponse, TxnStatus};
+use crate::kvs::{KvError, KvsAddr, KvsClient, Result, ShardedKvsClient};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How long the locks of a transaction protect it from being rolled back
+/// by readers, unless changed with [`Transaction::set_lock_ttl`].
+const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(3);
+
+/// Times a read waits for a live lock before giving up.
+const LOCKED_RETRIES: u32 = 20;
+const LOCKED_BACKOFF: Duration = Duration::from_millis(20);
+
+/// A transaction with snapshot isolation, started with
+/// [`KvsClient::begin`] or [`ShardedKvsClient::begin`].
+///
+/// Reads see the data committed before the transaction started, and its own
+/// writes. Writes are buffered and sent by [`Transaction::commit`], which
+/// fails with [`KvError::TxnConflict`] if another transaction wrote one of
+/// the keys since this one started, or is writing it. Nothing is written
+/// then, and the transaction may be retried. Dropping a transaction without
+/// committing it discards its writes. Keys and values are arbitrary bytes,
+/// the `String` methods are shorthands for text.
+///
+/// ```no_run
+/// # async fn run(client: &mut proj5::kvs::KvsClient) -> proj5::kvs::Result<()> {
+/// let mut txn = client.begin().await?;
+/// if txn.get("alice".to_owned()).await?.is_some() {
+///     txn.remove("alice".to_owned());
+///     txn.set("bob".to_owned(), "moved from alice".to_owned());
+/// }
+/// txn.commit().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<'a> {
+    target: Target<'a>,
+    start_ts: u64,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    lock_ttl: Duration,
+}
+
+/// Where the timestamps and the keys of a transaction go.
+enum Target<'a> {
+    Client(&'a mut KvsClient),
+    Sharded(&'a ShardedKvsClient),
+}
+
+impl<'a> Transaction<'a> {
+    async fn begin(mut target: Target<'a>) -> Result<Transaction<'a>> {
+        let start_ts = target.timestamp().await?;
+        Ok(Transaction {
+            target,
+            start_ts,
+            writes: BTreeMap::new(),
+            lock_ttl: DEFAULT_LOCK_TTL,
+        })
+    }
+
+    /// The timestamp the transaction reads at.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    /// How long after the start of the transaction its locks may be rolled
+    /// back by readers, should the client crash while committing.
+    pub fn set_lock_ttl(&mut self, ttl: Duration) {
+        self.lock_ttl = ttl;
+    }
+
+    /// Reads the key as of the transaction start, or the value written by
+    /// the transaction. Locks of crashed transactions are cleaned up, live
+    /// ones waited for, up to [`KvError::TxnLocked`].
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(val) = self.writes.get(&key) {
+            return Ok(val.clone());
+        }
+
+        let mut attempt = 0;
+        loop {
+            let req = TxnRequest::Get {
+                key: key.clone(),
+                start_ts: self.start_ts,
+            };
+            match self.target.call(&key, req).await? {
+                TxnResponse::Value { val } => return Ok(val),
+                TxnResponse::Locked { lock } => {
+                    if !self.resolve(&lock).await? {
+                        if attempt >= LOCKED_RETRIES {
+                            return Err(KvError::TxnLocked {
+                                key: String::from_utf8_lossy(&key).into_owned(),
+                            });
+                        }
+                        attempt += 1;
+                        sleep(LOCKED_BACKOFF * attempt).await;
+                    }
+                }
+                resp => return Err(unexpected(resp)),
+            }
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.writes.insert(key, Some(val));
+    }
+
+    /// Removing a missing key is not an error.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Fails with [`KvError::Ut8Conversion`] on a value that is not UTF-8,
+    /// see [`Transaction::get_bytes`].
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let val = self.get_bytes(key.clone().into_bytes()).await?;
+        text_value(key, val)
+    }
+
+    pub fn set(&mut self, key: String, val: String) {
+        self.set_bytes(key.into_bytes(), val.into_bytes());
+    }
+
+    /// Removing a missing key is not an error.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Writes the buffered changes atomically. An error other than a
+    /// conflict or an abort after the commit started leaves the outcome to
+    /// the readers: they commit the transaction if its first key was
+    /// committed, and roll it back otherwise.
+    pub async fn commit(mut self) -> Result<()> {
+        let groups = self.groups();
+        let primary = match groups.first() {
+            Some(group) => group[0].key.clone(),
+            None => return Ok(()),
+        };
+
+        self.prewrite(&groups, &primary).await?;
+
+        let commit_ts = match self.target.timestamp().await {
+            Ok(ts) => ts,
+            Err(e) => {
+                self.rollback(&groups).await;
+                return Err(e);
+            }
+        };
+
+        for (i, group) in groups.iter().enumerate() {
+            let req = TxnRequest::Commit {
+                keys: group.iter().map(|m| m.key.clone()).collect(),
+                start_ts: self.start_ts,
+                commit_ts,
+            };
+            let res = self.target.call(&group[0].key, req).await;
+            // Past the primary the transaction is committed, readers commit
+            // the keys left locked.
+            if i == 0 {
+                match res? {
+                    TxnResponse::Ok => {}
+                    TxnResponse::Aborted { msg } => return Err(KvError::TxnAborted { msg }),
+                    resp => return Err(unexpected(resp)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Buffered writes grouped by server, the primary key alone first.
+    fn groups(&mut self) -> Vec<Vec<Mutation>> {
+        let mut mutations = std::mem::take(&mut self.writes)
+            .into_iter()
+            .map(|(key, val)| Mutation { key, val });
+        let primary = match mutations.next() {
+            Some(primary) => primary,
+            None => return Vec::new(),
+        };
+
+        let mut by_node: BTreeMap<Option<String>, Vec<Mutation>> = BTreeMap::new();
+        for mutation in mutations {
+            let node = self
+                .target
+                .node_for(&mutation.key)
+                .map(|addr| addr.to_string());
+            by_node.entry(node).or_default().push(mutation);
+        }
+        let mut groups = vec![vec![primary]];
+        groups.extend(by_node.into_values());
+        groups
+    }
+
+    /// Locks every key, the primary first. On failure the keys locked so far
+    /// are rolled back.
+    async fn prewrite(&mut self, groups: &[Vec<Mutation>], primary: &[u8]) -> Result<()> {
+        for (i, group) in groups.iter().enumerate() {
+            if let Err(e) = self.prewrite_group(group, primary).await {
+                self.rollback(&groups[..=i]).await;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn prewrite_group(&mut self, group: &[Mutation], primary: &[u8]) -> Result<()> {
+        loop {
+            let req = TxnRequest::Prewrite {
+                mutations: group.to_vec(),
+                primary: primary.to_vec(),
+                start_ts: self.start_ts,
+                ttl_ms: self.lock_ttl.as_millis() as u64,
+            };
+            match self.target.call(&group[0].key, req).await? {
+                TxnResponse::Ok => return Ok(()),
+                // A lock left by a crashed client is cleaned up and the
+                // prewrite tried again, a live one is a conflict.
+                TxnResponse::Locked { lock } => {
+                    if !self.resolve(&lock).await? {
+                        return Err(conflict(&lock.key));
+                    }
+                }
+                TxnResponse::Conflict { key, .. } => return Err(conflict(&key)),
+                TxnResponse::Aborted { msg } => return Err(KvError::TxnAborted { msg }),
+                resp => return Err(unexpected(resp)),
+            }
+        }
+    }
+
+    /// Drops the locks taken by the groups. Errors are ignored: locks left
+    /// behind expire and are rolled back by readers.
+    async fn rollback(&mut self, groups: &[Vec<Mutation>]) {
+        for group in groups {
+            let req = TxnRequest::Rollback {
+                keys: group.iter().map(|m| m.key.clone()).collect(),
+                start_ts: self.start_ts,
+            };
+            let _ = self.target.call(&group[0].key, req).await;
+        }
+    }
+
+    /// Commits or rolls back the lock the way its transaction's primary
+    /// went, rolling the primary back if its time to live passed. Returns
+    /// whether the lock is gone, `false` if its transaction is still alive.
+    async fn resolve(&mut self, lock: &LockInfo) -> Result<bool> {
+        let current_ts = self.target.timestamp().await?;
+        let req = TxnRequest::CheckStatus {
+            primary: lock.primary.clone(),
+            start_ts: lock.start_ts,
+            current_ts,
+        };
+        let status = match self.target.call(&lock.primary, req).await? {
+            TxnResponse::Status { status } => status,
+            resp => return Err(unexpected(resp)),
+        };
+
+        let keys = vec![lock.key.clone()];
+        let req = match status {
+            TxnStatus::Committed { commit_ts } => TxnRequest::Commit {
+                keys,
+                start_ts: lock.start_ts,
+                commit_ts,
+            },
+            TxnStatus::RolledBack => TxnRequest::Rollback {
+                keys,
+                start_ts: lock.start_ts,
+            },
+            TxnStatus::Locked => return Ok(false),
+        };
+        match self.target.call(&lock.key, req).await? {
+            TxnResponse::Ok => Ok(true),
+            TxnResponse::Aborted { msg } => Err(KvError::Txn { msg }),
+            resp => Err(unexpected(resp)),
+        }
+    }
+}
+
+impl Target<'_> {
+    async fn timestamp(&mut self) -> Result<u64> {
+        match self {
+            Target::Client(client) => client.timestamp().await,
+            Target::Sharded(client) => client.oracle()?.timestamp().await,
+        }
+    }
+
+    async fn call(&mut self, key: &[u8], req: TxnRequest) -> Result<TxnResponse> {
+        match self {
+            Target::Client(client) => client.txn(req).await,
+            Target::Sharded(client) => client.client_for(key)?.txn(req).await,
+        }
+    }
+
+    /// Server of the key, `None` if there is only one.
+    fn node_for(&self, key: &[u8]) -> Option<&KvsAddr> {
+        match self {
+            Target::Client(_) => None,
+            Target::Sharded(client) => client.node_for(key),
+        }
+    }
+}
+
+impl KvsClient {
+    /// Starts a transaction on the server, which must be the timestamp
+    /// oracle, see [`KvsServer::with_tso`](crate::kvs::KvsServer::with_tso).
+    pub async fn begin(&mut self) -> Result<Transaction<'_>> {
+        Transaction::begin(Target::Client(self)).await
+    }
+}
+
+impl ShardedKvsClient {
+    /// Starts a transaction over all servers, with the timestamps of the
+    /// oracle set on the builder.
+    pub async fn begin(&self) -> Result<Transaction<'_>> {
+        Transaction::begin(Target::Sharded(self)).await
+    }
+}
+
+fn conflict(key: &[u8]) -> KvError {
+    KvError::TxnConflict {
+        key: String::from_utf8_lossy(key).into_owned(),
+    }
+}
+
+fn unexpected(resp: TxnResponse) -> KvError {
+    KvError::UnexpectedResult {
+        val: format!("{:?}", resp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvs::testing::{TestEngine, TestServer};
+
+    async fn start() -> Result<TestServer> {
+        TestServer::builder(TestEngine::Kvs).tso().start().await
+    }
+
+    fn pairs(keys: &[&str]) -> Vec<Vec<Mutation>> {
+        let mutations = keys.iter().map(|key| Mutation {
+            key: key.as_bytes().to_vec(),
+            val: Some(format!("{}-new", key).into_bytes()),
+        });
+        vec![mutations.collect()]
+    }
+
+    #[tokio::test]
+    async fn crashed_before_commit_is_rolled_back() -> Result<()> {
+        let server = start().await?;
+        let mut client = server.client().await?;
+        let mut reader = server.client().await?;
+
+        let mut txn = client.begin().await?;
+        txn.set_lock_ttl(Duration::from_millis(100));
+        txn.prewrite(&pairs(&["a", "b"]), b"a").await?;
+        drop(txn);
+
+        // The reader waits for the lock to expire, then rolls it back.
+        let mut read = reader.begin().await?;
+        assert_eq!(read.get("b".to_owned()).await?, None);
+        assert_eq!(read.get("a".to_owned()).await?, None);
+
+        let mut txn = client.begin().await?;
+        txn.set("a".to_owned(), "1".to_owned());
+        txn.commit().await?;
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn crashed_after_primary_commit_is_committed() -> Result<()> {
+        let server = start().await?;
+        let mut client = server.client().await?;
+        let mut reader = server.client().await?;
+
+        let mut txn = client.begin().await?;
+        let start_ts = txn.start_ts();
+        txn.prewrite(&pairs(&["a", "b"]), b"a").await?;
+        let commit_ts = txn.target.timestamp().await?;
+        let req = TxnRequest::Commit {
+            keys: vec![b"a".to_vec()],
+            start_ts,
+            commit_ts,
+        };
+        assert_eq!(txn.target.call(b"a", req).await?, TxnResponse::Ok);
+        drop(txn);
+
+        // The lock on b is committed without waiting for it to expire.
+        let mut read = reader.begin().await?;
+        assert_eq!(read.get("b".to_owned()).await?, Some("b-new".to_owned()));
+        assert_eq!(read.get("a".to_owned()).await?, Some("a-new".to_owned()));
+
+        server.shutdown().await
+    }
+}
@@ -0,0 +1,119 @@
+//! Text forms of keys and values, which are arbitrary bytes, for the
+//! command line and exports.
+
+use crate::kvs::err::{KvError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// The bytes as UTF-8, bytes that are not valid UTF-8 have no text form.
+    Text,
+    /// Two lowercase hex digits per byte.
+    Hex,
+    /// Standard base64, padded.
+    Base64,
+}
+
+impl Encoding {
+    pub fn encode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Encoding::Text => match std::str::from_utf8(bytes) {
+                Ok(text) => Ok(text.to_string()),
+                Err(_) => Err(KvError::InvalidArgument {
+                    arg: "text".to_string(),
+                    val: format!(
+                        "{:?} is not valid UTF-8, use hex or base64",
+                        String::from_utf8_lossy(bytes)
+                    ),
+                }),
+            },
+            Encoding::Hex => Ok(to_hex(bytes)),
+            Encoding::Base64 => Ok(base64::encode(bytes)),
+        }
+    }
+
+    pub fn decode(self, text: &str) -> Result<Vec<u8>> {
+        let bytes = match self {
+            Encoding::Text => return Ok(text.as_bytes().to_vec()),
+            Encoding::Hex => from_hex(text),
+            Encoding::Base64 => base64::decode(text).ok(),
+        };
+        bytes.ok_or_else(|| KvError::InvalidArgument {
+            arg: self.name().to_string(),
+            val: text.to_string(),
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Text => "text",
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+        }
+    }
+}
+
+/// The [`Encoding::Hex`] form of bytes, which cannot fail.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of their [`Encoding::Hex`] form, `None` if `text` is not one.
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Encoding> {
+        match s {
+            "text" => Ok(Encoding::Text),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            _ => Err(KvError::InvalidArgument {
+                arg: "encoding".to_string(),
+                val: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::encoding::Encoding;
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = [0u8, 0x9f, b'k', 0xff];
+        assert_eq!(Encoding::Hex.encode(&bytes).unwrap(), "009f6bff");
+        assert_eq!(Encoding::Base64.encode(&bytes).unwrap(), "AJ9r/w==");
+        assert_eq!(Encoding::Text.encode(&bytes).is_err(), true);
+        for encoding in &[Encoding::Hex, Encoding::Base64] {
+            let text = encoding.encode(&bytes).unwrap();
+            assert_eq!(encoding.decode(&text).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Text.decode("key").unwrap(), b"key");
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert_eq!(Encoding::Hex.decode("abc").is_err(), true);
+        assert_eq!(Encoding::Hex.decode("zz").is_err(), true);
+        assert_eq!(Encoding::Hex.decode("é0").is_err(), true);
+        assert_eq!(Encoding::Base64.decode("a").is_err(), true);
+    }
+}
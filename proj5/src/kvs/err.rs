@@ -4,6 +4,7 @@ use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 use thiserror::Error;
 use tokio::sync::oneshot::error::RecvError;
+use tokio::task::JoinError;
 
 #[derive(Error, Debug)]
 pub enum KvError {
@@ -28,6 +29,35 @@ pub enum KvError {
         source: bson::ser::Error,
     },
 
+    #[error("decode entry failed at pos: {pos}, {msg}")]
+    DecodeEntry { pos: u32, msg: String },
+
+    #[error("{path}: unknown entry codec {codec}")]
+    UnknownCodec { path: String, codec: u8 },
+
+    #[error("corrupt frame at pos: {pos}, {msg}")]
+    CorruptFrame { pos: u32, msg: String },
+
+    #[error("store {path} is locked by pid {}", .pid.map_or("unknown".to_string(), |pid| pid.to_string()))]
+    StoreLocked { path: String, pid: Option<u32> },
+
+    #[error("store is open read-only")]
+    StoreReadOnly,
+
+    #[error("{path} holds {found} engine data, not {requested}")]
+    EngineMismatch {
+        path: String,
+        requested: String,
+        found: String,
+    },
+
+    #[error("{path}: format version {version} is newer than the supported {supported}")]
+    UnsupportedFormat {
+        path: String,
+        version: u32,
+        supported: u32,
+    },
+
     #[error("Key not found")]
     KeyNotFound,
 
@@ -46,9 +76,81 @@ pub enum KvError {
     #[error(transparent)]
     BsonDeserialize(bson::de::Error),
 
+    #[error("invalid value for {arg}: {val}")]
+    InvalidArgument { arg: String, val: String },
+
+    #[error("invalid address: {addr}")]
+    ParseAddr { addr: String },
+
     #[error("server error: {msg}")]
     Server { msg: String },
 
+    #[error("unsupported operation: {op}")]
+    Unsupported { op: String },
+
+    #[error("credentials error: {path}: {msg}")]
+    Credentials { path: String, msg: String },
+
+    #[error("tls error: {msg}")]
+    Tls { msg: String },
+
+    #[error("permission denied: {msg}")]
+    PermissionDenied { msg: String },
+
+    #[error("server busy: {msg}")]
+    Busy { msg: String },
+
+    #[error("frame of {size} bytes over the limit of {max}")]
+    FrameTooLarge { size: u32, max: u32 },
+
+    #[error("{op} timed out")]
+    Timeout { op: String },
+
+    #[error("{op} interrupted, it may or may not have been applied: {msg}")]
+    Interrupted { op: String, msg: String },
+
+    #[error("read-only replica, writes go to the primary: {primary}")]
+    ReadOnly { primary: String },
+
+    #[error("replication error: {msg}")]
+    Replication { msg: String },
+
+    #[error("not the cluster leader, leader: {}", .leader.as_deref().unwrap_or("unknown"))]
+    NotLeader { leader: Option<String> },
+
+    #[error("cluster error: {msg}")]
+    Cluster { msg: String },
+
+    #[error("transaction conflict on key: {key}")]
+    TxnConflict { key: String },
+
+    #[error("key locked by another transaction: {key}")]
+    TxnLocked { key: String },
+
+    #[error("transaction aborted: {msg}")]
+    TxnAborted { msg: String },
+
+    #[error("transaction error: {msg}")]
+    Txn { msg: String },
+
+    #[error("no server to route the key to")]
+    NoNodes,
+
+    /// The key's slot is served by the server at `addr`.
+    #[error("slot {slot} moved to {addr}")]
+    Moved { slot: u16, addr: String },
+
+    /// The key's slot is being moved to the server at `addr`, which serves
+    /// the key if asked right away.
+    #[error("slot {slot} is migrating, ask {addr}")]
+    Ask { slot: u16, addr: String },
+
+    #[error("slots error: {msg}")]
+    Slots { msg: String },
+
+    #[error("backup error: {path}: {msg}")]
+    Backup { path: String, msg: String },
+
     #[error("server unexpected result: {val}")]
     UnexpectedResult { val: String },
 
@@ -57,6 +159,9 @@ pub enum KvError {
 
     #[error(transparent)]
     OneshotRecv(RecvError),
+
+    #[error(transparent)]
+    Join(JoinError),
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -0,0 +1,266 @@
+//! Minimal metric primitives rendered in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Upper bounds of latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 16] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, val: u64) {
+        self.0.fetch_add(val, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, val: i64) {
+        self.0.store(val, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Histogram over fixed latency buckets.
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Metrics of one kind keyed by a label value, e.g. a counter per command.
+pub struct Family<T> {
+    label: &'static str,
+    metrics: RwLock<BTreeMap<String, Arc<T>>>,
+}
+
+impl<T: Default> Family<T> {
+    pub fn new(label: &'static str) -> Family<T> {
+        Family {
+            label,
+            metrics: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn get(&self, label_val: &str) -> Arc<T> {
+        if let Some(metric) = self.metrics.read().unwrap().get(label_val) {
+            return metric.clone();
+        }
+        self.metrics
+            .write()
+            .unwrap()
+            .entry(label_val.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, Arc<T>)> {
+        self.metrics
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+/// Renders metrics in the Prometheus text exposition format.
+///
+/// All series of one metric name have to be written one after another.
+#[derive(Default)]
+pub struct MetricsWriter {
+    buf: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> MetricsWriter {
+        MetricsWriter::default()
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], counter.get() as f64);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, val: f64) {
+        self.header(name, help, "gauge");
+        self.sample(name, &[], val);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        self.histogram_samples(name, &[], histogram);
+    }
+
+    pub fn counter_family(&mut self, name: &str, help: &str, family: &Family<Counter>) {
+        self.header(name, help, "counter");
+        for (label_val, counter) in family.snapshot() {
+            self.sample(name, &[(family.label, &label_val)], counter.get() as f64);
+        }
+    }
+
+    pub fn histogram_family(&mut self, name: &str, help: &str, family: &Family<Histogram>) {
+        self.header(name, help, "histogram");
+        for (label_val, histogram) in family.snapshot() {
+            self.histogram_samples(name, &[(family.label, &label_val)], &histogram);
+        }
+    }
+
+    /// Gauge with one series per label value.
+    pub fn gauge_series<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        series: impl IntoIterator<Item = (&'a str, f64)>,
+    ) {
+        self.header(name, help, "gauge");
+        for (label_val, val) in series {
+            self.sample(name, &[(label, label_val)], val);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.buf
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.buf, "# HELP {} {}", name, help);
+        let _ = writeln!(self.buf, "# TYPE {} {}", name, kind);
+    }
+
+    fn histogram_samples(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = bound.to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket_name, &bucket_labels, cumulative as f64);
+        }
+
+        let count = histogram.count() as f64;
+        let mut inf_labels = labels.to_vec();
+        inf_labels.push(("le", "+Inf"));
+        self.sample(&bucket_name, &inf_labels, count);
+
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        self.sample(&format!("{}_sum", name), labels, sum);
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], val: f64) {
+        self.buf.push_str(name);
+        if !labels.is_empty() {
+            self.buf.push('{');
+            for (i, (key, label_val)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.buf.push(',');
+                }
+                let escaped = label_val
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.buf, "{}=\"{}\"", key, escaped);
+            }
+            self.buf.push('}');
+        }
+        let _ = writeln!(self.buf, " {}", val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::metrics::{Counter, Family, Histogram, MetricsWriter};
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_counter_family() {
+        let family: Family<Counter> = Family::new("command");
+        family.get("get").inc();
+        family.get("get").inc();
+        family.get("set").inc();
+
+        let mut writer = MetricsWriter::new();
+        writer.counter_family("kvs_requests_total", "Requests.", &family);
+        let text = writer.finish();
+
+        assert!(text.contains("# TYPE kvs_requests_total counter\n"));
+        assert!(text.contains("kvs_requests_total{command=\"get\"} 2\n"));
+        assert!(text.contains("kvs_requests_total{command=\"set\"} 1\n"));
+    }
+
+    #[test]
+    fn test_write_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut writer = MetricsWriter::new();
+        writer.histogram("kvs_latency_seconds", "Latency.", &histogram);
+        let text = writer.finish();
+
+        assert!(text.contains("kvs_latency_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(text.contains("kvs_latency_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("kvs_latency_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("kvs_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("kvs_latency_seconds_count 3\n"));
+    }
+}
@@ -1,18 +1,47 @@
+mod addr;
 mod client;
+pub mod encoding;
 mod err;
+pub mod metrics;
 mod net;
 mod server;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod thread_pool;
+mod tls;
 
+pub use addr::KvsAddr;
+pub use encoding::Encoding;
 pub use err::KvError;
 pub use err::Result;
 
 pub use server::engine::sled_eng::SledKvsEngine;
+pub use server::engine::store::codec;
+pub use server::engine::store::codec::Codec;
+pub use server::engine::store::compress::Compression;
+pub use server::engine::store::inspect;
 pub use server::engine::store::io::LogEntry;
+pub use server::engine::store::io::{SEGMENT_FORMAT_VERSION, SEGMENT_HEADER_SIZE, SEGMENT_MAGIC};
 pub use server::engine::store::kv_store;
-pub use server::engine::store::kv_store::KvStore;
+pub use server::engine::store::kv_store::{KvStore, StoreOptions};
+pub use server::engine::store::FileId;
+pub use server::engine::admin::{EngineStats, KvsAdmin};
+pub use server::engine::backup;
+pub use server::engine::backup::BackupMeta;
+pub use server::engine::meta;
+pub use server::engine::meta::EngineMeta;
+pub use server::engine::transfer;
 pub use server::engine::KvsEngine;
 
-pub use server::kv_server::KvsServer;
+pub use server::auth::{hash_secret, Access, AclRule, Credentials, User};
+pub use server::kv_server::{KvsServer, KvsServerHandle};
+pub use server::limits::{RateLimit, ServerLimits};
+pub use server::raft::ClusterConfig;
+pub use server::slots::{key_slot, SlotConfig, SlotState, SLOTS};
 
-pub use client::KvsClient;
+pub use client::{
+    BlockingKvsClient, ClientAuth, KvsClient, KvsClientBuilder, PooledKvsClient, RepairReport,
+    RetryPolicy, ShardedKvsClient, ShardedKvsClientBuilder, Transaction,
+};
+pub use net::KvsStream;
+pub use tls::{ClientTls, ServerTls};
@@ -1,25 +1,163 @@
-use crate::kvs::{KvError, Result};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::kvs::server::merkle::{KeyRange, RangeDigest};
+use crate::kvs::server::raft::{RaftRequest, RaftResponse};
+use crate::kvs::server::slots::SlotState;
+use crate::kvs::server::txn::{TxnRequest, TxnResponse};
+use crate::kvs::{KvError, LogEntry, Result};
+use byteorder::{BigEndian, ReadBytesExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::io::{Read, Write};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// Largest frame read from a peer, whose size is checked before its body
+/// is allocated.
+pub(crate) const MAX_FRAME_SIZE: u32 = 256 * 1024 * 1024;
+
+/// Byte stream the protocol runs over, e.g. a TCP or a Unix socket.
+pub trait KvsStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> KvsStream for T {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "cmd")]
 pub(crate) enum Command {
-    Get { key: String },
-    Set { key: String, val: String },
-    Remove { key: String },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        val: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Ping,
+    Info,
+    Stats,
+    Compact,
+    Flush,
+    /// Backs the engine data up to `dest`, a directory on the server.
+    Backup { dest: String },
+    Auth { user: String, password: String },
+    AuthToken { token: String },
+    /// Turns the connection into a stream of [`ReplicationFrame`]s, starting
+    /// after `seq` of the primary's history `id`.
+    Replicate { id: String, seq: u64 },
+    /// A message between the members of a cluster.
+    Raft { msg: RaftRequest },
+    AddMember { member: String },
+    RemoveMember { member: String },
+    /// A step of a transaction on the keys this server holds.
+    Txn { req: TxnRequest },
+    /// Asks the timestamp oracle for the next timestamp.
+    Timestamp,
+    /// Merkle tree nodes of the ranges, each split into at most `split`
+    /// children.
+    Digest { ranges: Vec<KeyRange>, split: u32 },
+    /// The pairs of the range.
+    Range { range: KeyRange },
+    /// Writes sent by anti-entropy repair, accepted by replicas as well.
+    /// Removes of missing keys are ignored.
+    Repair {
+        #[serde(with = "byte_pairs")]
+        set: Vec<(Vec<u8>, Vec<u8>)>,
+        #[serde(with = "byte_list")]
+        remove: Vec<Vec<u8>>,
+    },
+    /// The command, following an ASK redirect to a server importing the
+    /// key's slot.
+    Asking { request: Box<Command> },
+    SetSlot { slot: u16, state: SlotState },
+    /// Streams the keys of a migrating slot to its target.
+    MigrateSlot { slot: u16 },
+    /// Keys of a slot migrating to this server.
+    Import {
+        #[serde(with = "byte_pairs")]
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+impl Command {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Remove { .. } => "remove",
+            Command::Ping => "ping",
+            Command::Info => "info",
+            Command::Stats => "stats",
+            Command::Compact => "compact",
+            Command::Flush => "flush",
+            Command::Backup { .. } => "backup",
+            Command::Auth { .. } | Command::AuthToken { .. } => "auth",
+            Command::Replicate { .. } => "replicate",
+            Command::Raft { .. } => "raft",
+            Command::AddMember { .. } => "add_member",
+            Command::RemoveMember { .. } => "remove_member",
+            Command::Txn { .. } => "txn",
+            Command::Timestamp => "timestamp",
+            Command::Digest { .. } => "digest",
+            Command::Range { .. } => "range",
+            Command::Repair { .. } => "repair",
+            Command::Asking { request } => request.name(),
+            Command::SetSlot { .. } => "set_slot",
+            Command::MigrateSlot { .. } => "migrate_slot",
+            Command::Import { .. } => "import",
+        }
+    }
+
+    /// Whether sending the command again after a lost result is harmless.
+    /// Commands are not retried unless listed here: one still running on
+    /// the server, e.g. a slot migration, must not be started twice.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Get { .. } | Command::Set { .. } => true,
+            Command::Ping | Command::Info | Command::Stats => true,
+            Command::Txn { req } => matches!(req, TxnRequest::Get { .. }),
+            Command::Asking { request } => request.is_idempotent(),
+            _ => false,
+        }
+    }
+
+    /// Whether the command may write, which replicas refuse.
+    pub(crate) fn is_write(&self) -> bool {
+        match self {
+            Command::Set { .. } | Command::Remove { .. } => true,
+            Command::MigrateSlot { .. } | Command::Import { .. } => true,
+            Command::Txn { req } => req.is_write(),
+            Command::Asking { request } => request.is_write(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Get { key } | Command::Set { key, .. } | Command::Remove { key } => {
+                Some(key.as_slice())
+            }
+            Command::Asking { request } => request.key(),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Command {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Command::Get { key } => write!(f, "Get({})", key),
-            Command::Set { key, val } => write!(f, "Set({}, {})", key, val),
-            Command::Remove { key } => write!(f, "Remove({})", key),
+            Command::Get { key } => write!(f, "Get({})", String::from_utf8_lossy(key)),
+            Command::Set { key, val } => write!(
+                f,
+                "Set({}, {})",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(val)
+            ),
+            Command::Remove { key } => write!(f, "Remove({})", String::from_utf8_lossy(key)),
+            _ => write!(f, "{}", self.name()),
         }
     }
 }
@@ -29,7 +167,52 @@ impl Display for Command {
 pub(crate) enum CommandResult {
     Ok,
     OkVal(String),
+    /// A value that is not UTF-8, others are sent as `OkVal`.
+    OkBytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    OkFields(Vec<(String, String)>),
+    OkPairs(#[serde(with = "byte_pairs")] Vec<(Vec<u8>, Vec<u8>)>),
     Err(String),
+    Busy(String),
+    Denied(String),
+    /// Writes sent to a replica, with the address of its primary.
+    ReadOnly(String),
+    /// Commands sent to a cluster member other than the leader, with the
+    /// leader's address if known.
+    NotLeader(Option<String>),
+    Raft(RaftResponse),
+    Txn(TxnResponse),
+    Timestamp(u64),
+    Digests(Vec<RangeDigest>),
+    /// The key's slot is served by another server, with its address.
+    Moved(u16, String),
+    /// The key's slot is migrating and the key is gone, the command is to
+    /// be sent once to the target as [`Command::Asking`].
+    Ask(u16, String),
+    Count(u64),
+}
+
+/// What a primary sends after accepting [`Command::Replicate`].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "t")]
+pub(crate) enum ReplicationFrame {
+    /// The replica's position is unknown to the primary: it drops its data
+    /// when the first pair arrives and loads the pairs up to `SnapshotEnd`,
+    /// which reflect at least the entries up to `seq`. Heartbeats may come
+    /// in between the pairs. Entries after `seq` follow.
+    Snapshot { id: String, seq: u64 },
+    Pair {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        val: Vec<u8>,
+    },
+    SnapshotEnd,
+    /// Entries right after the replica's position follow.
+    Resume,
+    Entry { seq: u64, entry: LogEntry },
+    /// Sent while there is nothing to replicate, with the primary's last
+    /// sequence, so both sides notice dead connections.
+    Heartbeat { seq: u64 },
 }
 
 impl Display for CommandResult {
@@ -37,30 +220,58 @@ impl Display for CommandResult {
         match self {
             CommandResult::Ok => write!(f, "Ok"),
             CommandResult::OkVal(val) => write!(f, "OkVal({})", val),
+            CommandResult::OkBytes(val) => write!(f, "OkBytes({})", String::from_utf8_lossy(val)),
+            CommandResult::OkFields(fields) => write!(f, "OkFields({:?})", fields),
+            CommandResult::OkPairs(pairs) => write!(f, "OkPairs({})", pairs.len()),
             CommandResult::Err(err) => write!(f, "Err({})", err),
+            CommandResult::Busy(msg) => write!(f, "Busy({})", msg),
+            CommandResult::Denied(msg) => write!(f, "Denied({})", msg),
+            CommandResult::ReadOnly(primary) => write!(f, "ReadOnly({})", primary),
+            CommandResult::NotLeader(leader) => write!(f, "NotLeader({:?})", leader),
+            CommandResult::Raft(resp) => write!(f, "Raft({:?})", resp),
+            CommandResult::Txn(resp) => write!(f, "Txn({:?})", resp),
+            CommandResult::Timestamp(ts) => write!(f, "Timestamp({})", ts),
+            CommandResult::Digests(digests) => write!(f, "Digests({})", digests.len()),
+            CommandResult::Moved(slot, addr) => write!(f, "Moved({}, {})", slot, addr),
+            CommandResult::Ask(slot, addr) => write!(f, "Ask({}, {})", slot, addr),
+            CommandResult::Count(count) => write!(f, "Count({})", count),
         }
     }
 }
 
 pub(crate) fn read<R: Read, V: DeserializeOwned>(reader: &mut R) -> Result<V> {
-    let size = reader.read_u32::<BigEndian>()?;
+    let size = check_frame_size(reader.read_u32::<BigEndian>()?, MAX_FRAME_SIZE)?;
     let mut buf = vec![0; size as usize];
     reader.read_exact(&mut buf)?;
     bson::from_slice(&buf).map_err(|e| KvError::BsonDeserialize(e))
 }
 
 pub(crate) async fn read_async<R: AsyncReadExt + Unpin, V: DeserializeOwned>(reader: &mut R) -> Result<V> {
-    let size = reader.read_u32().await?;
+    read_async_max(reader, MAX_FRAME_SIZE).await
+}
+
+/// Reads a frame of at most `max` bytes, failing on a larger one before
+/// reading its body.
+pub(crate) async fn read_async_max<R: AsyncReadExt + Unpin, V: DeserializeOwned>(
+    reader: &mut R,
+    max: u32,
+) -> Result<V> {
+    let size = check_frame_size(reader.read_u32().await?, max)?;
     let mut buf = vec![0; size as usize];
     reader.read_exact(&mut buf).await?;
     bson::from_slice(&buf).map_err(|e| KvError::BsonDeserialize(e))
 }
 
+fn check_frame_size(size: u32, max: u32) -> Result<u32> {
+    match size <= max {
+        true => Ok(size),
+        false => Err(KvError::FrameTooLarge { size, max }),
+    }
+}
+
 pub(crate) fn write<W: Write, V: Serialize>(writer: &mut W, val: &V) -> Result<()> {
-    let buf = bson::to_vec(val)?;
-    let size = buf.len();
-    writer.write_u32::<BigEndian>(size as u32);
-    writer.write(&buf)?;
+    writer.write_all(&frame(val)?)?;
+    writer.flush()?;
     Ok(())
 }
 
@@ -68,23 +279,77 @@ pub(crate) async fn write_async<W: AsyncWriteExt + Unpin, V: Serialize>(
     writer: &mut W,
     val: &V,
 ) -> Result<()> {
-    let buf = bson::to_vec(val)?;
-    let size = buf.len();
-    writer.write_u32(size as u32).await?;
-    writer.write(&buf).await?;
+    writer.write_all(&frame(val)?).await?;
+    writer.flush().await?;
     Ok(())
 }
 
+/// Size prefix and body in one buffer, written at once so Nagle's algorithm
+/// does not hold the body back until the size is acknowledged.
+fn frame<V: Serialize>(val: &V) -> Result<Vec<u8>> {
+    let buf = bson::to_vec(val)?;
+    let mut frame = Vec::with_capacity(buf.len() + 4);
+    frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&buf);
+    Ok(frame)
+}
+
+/// Pairs of keys and values serialized as BSON binaries, for
+/// `#[serde(with)]`.
+pub(crate) mod byte_pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(pairs.iter().map(|(key, val)| (Bytes::new(key), Bytes::new(val))))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, D::Error> {
+        let pairs = Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, val)| (key.into_vec(), val.into_vec()))
+            .collect())
+    }
+}
+
+/// Keys serialized as BSON binaries, for `#[serde(with)]`.
+pub(crate) mod byte_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub(crate) fn serialize<S: Serializer>(
+        keys: &[Vec<u8>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(keys.iter().map(|key| Bytes::new(key)))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        let keys = Vec::<ByteBuf>::deserialize(deserializer)?;
+        Ok(keys.into_iter().map(ByteBuf::into_vec).collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::kvs::net::{read, write, Command};
+    use crate::kvs::net::{read, write, Command, CommandResult, MAX_FRAME_SIZE};
+    use crate::kvs::server::txn::TxnRequest;
+    use crate::kvs::KvError;
     use std::io::Cursor;
 
     #[test]
     fn test_read_write() {
         let cmd = Command::Set {
-            key: "key".to_string(),
-            val: "val".to_string(),
+            key: b"key".to_vec(),
+            val: vec![0, 0xff, b'v'],
         };
 
         let mut buf = Vec::new();
@@ -94,4 +359,87 @@ mod tests {
 
         assert_eq!(read_cmd, cmd);
     }
+
+    #[test]
+    fn test_read_frame_too_large() {
+        let mut buf = (MAX_FRAME_SIZE + 1).to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0; 16]);
+
+        let res: crate::kvs::Result<Command> = read(&mut Cursor::new(&buf));
+
+        assert!(matches!(res, Err(KvError::FrameTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_read_write_fields() {
+        let result = CommandResult::OkFields(vec![
+            ("engine".to_string(), "kvs".to_string()),
+            ("keys".to_string(), "10".to_string()),
+        ]);
+
+        let mut buf = Vec::new();
+
+        write(&mut buf, &result).unwrap();
+        let read_result: CommandResult = read(&mut Cursor::new(&buf)).unwrap();
+
+        assert_eq!(read_result, result);
+    }
+
+    #[test]
+    fn test_read_write_pairs() {
+        let cmd = Command::Repair {
+            set: vec![(b"key".to_vec(), vec![0xc3, 0x28])],
+            remove: vec![vec![0x80]],
+        };
+
+        let mut buf = Vec::new();
+
+        write(&mut buf, &cmd).unwrap();
+        let read_cmd: Command = read(&mut Cursor::new(&buf)).unwrap();
+
+        assert_eq!(read_cmd, cmd);
+    }
+
+    #[test]
+    fn test_read_string_key() {
+        // Sent by clients that had string keys.
+        let doc = bson::doc! { "cmd": "Get", "key": "key" };
+
+        let mut buf = Vec::new();
+
+        write(&mut buf, &doc).unwrap();
+        let read_cmd: Command = read(&mut Cursor::new(&buf)).unwrap();
+
+        assert_eq!(read_cmd, Command::Get { key: b"key".to_vec() });
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        let get = Command::Get {
+            key: b"key".to_vec(),
+        };
+        assert!(get.is_idempotent());
+        let asking = Command::Asking {
+            request: Box::new(get),
+        };
+        assert!(asking.is_idempotent());
+        let txn_get = TxnRequest::Get {
+            key: b"key".to_vec(),
+            start_ts: 1,
+        };
+        assert!(Command::Txn { req: txn_get }.is_idempotent());
+
+        let rollback = TxnRequest::Rollback {
+            keys: vec![b"key".to_vec()],
+            start_ts: 1,
+        };
+        assert!(!Command::Txn { req: rollback }.is_idempotent());
+        assert!(!Command::MigrateSlot { slot: 7 }.is_idempotent());
+        assert!(!Command::Import { pairs: Vec::new() }.is_idempotent());
+        assert!(!Command::Compact.is_idempotent());
+        assert!(!Command::Asking {
+            request: Box::new(Command::Import { pairs: Vec::new() }),
+        }
+        .is_idempotent());
+    }
 }
@@ -0,0 +1,40 @@
+//! Replacing small state files so that a crash leaves either the old or the
+//! new content, never a mix or an empty file.
+
+use crate::kvs::Result;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Writes `content` to a temporary file next to `path`, syncs it, renames it
+/// over `path` and syncs the directory so the rename itself survives a crash.
+pub(crate) fn replace(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replace() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("state.json");
+        replace(&path, b"old")?;
+        replace(&path, b"new")?;
+        assert_eq!(fs::read(&path)?, b"new");
+        assert!(!path.with_extension("tmp").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use crate::kvs::encoding::{from_hex, to_hex};
+use crate::kvs::{KvError, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use subtle::ConstantTimeEq;
+
+const SHA256_PREFIX: &str = "sha256:";
+
+/// Server side credentials, loaded from a JSON file:
+///
+/// ```json
+/// {
+///   "users": [
+///     {
+///       "name": "alice",
+///       "password": "sha256:<hex digest>",
+///       "admin": true,
+///       "acl": [{ "prefix": "", "access": "rw" }]
+///     },
+///     {
+///       "name": "reporting",
+///       "token": "sha256:<hex digest>",
+///       "acl": [{ "prefix": "metrics/", "access": "r" }]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Secrets are stored as sha256 digests, see [`hash_secret`]. A user may have
+/// a password, a token or both. Keys are readable or writable when any of
+/// the user's rules has a matching prefix, admin commands need `admin`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Credentials {
+    users: Vec<User>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    acl: Vec<AclRule>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AclRule {
+    prefix: String,
+    access: Access,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+    #[serde(rename = "rw")]
+    ReadWrite,
+}
+
+impl Access {
+    fn grants(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Credentials> {
+        let content = std::fs::read_to_string(path)?;
+        Credentials::parse(&content, &path.display().to_string())
+    }
+
+    pub fn from_json(json: &str) -> Result<Credentials> {
+        Credentials::parse(json, "<inline>")
+    }
+
+    fn parse(json: &str, path: &str) -> Result<Credentials> {
+        let error = |msg: String| KvError::Credentials {
+            path: path.to_string(),
+            msg,
+        };
+
+        let credentials: Credentials =
+            serde_json::from_str(json).map_err(|e| error(e.to_string()))?;
+
+        for user in &credentials.users {
+            for secret in user.password.iter().chain(user.token.iter()) {
+                if parse_digest(secret).is_none() {
+                    return Err(error(format!(
+                        "user {}: secret is not a sha256 digest",
+                        user.name
+                    )));
+                }
+            }
+        }
+        Ok(credentials)
+    }
+
+    pub fn authenticate_password(&self, name: &str, password: &str) -> Option<&User> {
+        let digest = digest(password);
+        self.users
+            .iter()
+            .find(|user| user.name == name)
+            .filter(|user| matches(user.password.as_deref(), &digest))
+    }
+
+    pub fn authenticate_token(&self, token: &str) -> Option<&User> {
+        let digest = digest(token);
+        self.users
+            .iter()
+            .find(|user| matches(user.token.as_deref(), &digest))
+    }
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn can(&self, access: Access, key: &[u8]) -> bool {
+        self.acl
+            .iter()
+            .any(|rule| key.starts_with(rule.prefix.as_bytes()) && rule.access.grants(access))
+    }
+}
+
+/// Formats a secret the way the credentials file stores it.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{}{}", SHA256_PREFIX, to_hex(&digest(secret)))
+}
+
+fn digest(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+fn matches(stored: Option<&str>, digest: &[u8]) -> bool {
+    match stored.and_then(parse_digest) {
+        Some(stored) => bool::from(stored.ct_eq(digest)),
+        None => false,
+    }
+}
+
+fn parse_digest(stored: &str) -> Option<Vec<u8>> {
+    let hex = stored.strip_prefix(SHA256_PREFIX)?;
+    if hex.len() != 64 {
+        return None;
+    }
+    from_hex(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> Credentials {
+        let json = format!(
+            r#"{{"users": [
+                {{"name": "alice", "password": "{}", "admin": true,
+                  "acl": [{{"prefix": "", "access": "rw"}}]}},
+                {{"name": "bob", "token": "{}",
+                  "acl": [{{"prefix": "app/", "access": "r"}},
+                          {{"prefix": "app/bob/", "access": "w"}}]}}
+            ]}}"#,
+            hash_secret("secret"),
+            hash_secret("bob-token")
+        );
+        Credentials::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn authenticate() {
+        let credentials = credentials();
+
+        let alice = credentials.authenticate_password("alice", "secret");
+        assert_eq!(alice.map(User::name), Some("alice"));
+        assert!(credentials
+            .authenticate_password("alice", "wrong")
+            .is_none());
+        assert!(credentials
+            .authenticate_password("bob", "bob-token")
+            .is_none());
+        assert!(credentials
+            .authenticate_password("carol", "secret")
+            .is_none());
+
+        let bob = credentials.authenticate_token("bob-token");
+        assert_eq!(bob.map(User::name), Some("bob"));
+        assert!(credentials.authenticate_token("secret").is_none());
+    }
+
+    #[test]
+    fn acl() {
+        let credentials = credentials();
+        let bob = credentials.authenticate_token("bob-token").unwrap();
+
+        assert!(bob.can(Access::Read, b"app/config"));
+        assert!(!bob.can(Access::Write, b"app/config"));
+        assert!(bob.can(Access::Write, b"app/bob/x"));
+        assert!(!bob.can(Access::Read, b"other"));
+        assert!(!bob.is_admin());
+    }
+
+    #[test]
+    fn reject_plain_secret() {
+        let json = r#"{"users": [{"name": "alice", "password": "secret"}]}"#;
+        assert!(Credentials::from_json(json).is_err());
+    }
+}
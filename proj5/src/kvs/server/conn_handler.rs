@@ -1,59 +1,638 @@
-use crate::kvs::net::{read, read_async, write, write_async, Command, CommandResult};
+use crate::kvs::net::{read_async_max, write_async, Command, CommandResult, MAX_FRAME_SIZE};
+use crate::kvs::server::auth::{Access, Credentials, User};
+use crate::kvs::server::limits::Limiter;
+use crate::kvs::server::merkle::{self, KeyRange};
+use crate::kvs::server::raft::RaftNode;
+use crate::kvs::server::replication::{self, Replication};
+use crate::kvs::server::server_metrics::{ConnectionGuard, ServerMetrics};
+use crate::kvs::server::slots::{Route, SlotState, SlotTable};
+use crate::kvs::server::txn::{TxnRequest, TxnStore, TxnWriter, TXN_PREFIX};
 use crate::kvs::KvsEngine;
-use crate::kvs::Result;
-use crossbeam::channel::internal::SelectHandle;
-use slog::{info, Logger};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::select;
+use crate::kvs::{KvError, KvsStream, LogEntry, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use slog::{info, warn, Logger};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+const PONG: &str = "PONG";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const BUSY_RATE: &str = "rate limit exceeded";
+const BUSY_REQUESTS: &str = "too many pending requests";
+
+const AUTH_REQUIRED: &str = "authentication required";
+const AUTH_FAILED: &str = "invalid credentials";
+const AUTH_DISABLED: &str = "authentication is not enabled";
+
+const REPLICATION_DISABLED: &str = "replication is not enabled on this server";
+const CLUSTER_DISABLED: &str = "the server is not a cluster member";
+const TSO_DISABLED: &str = "the server is not a timestamp oracle";
+const RESERVED_KEY: &str = "keys starting with __txn/ are reserved for transactions";
+const SLOTS_DISABLED: &str = "the server does not serve hash slots";
+
+/// Keys copied to the target of a migrating slot at a time.
+const MIGRATION_BATCH: usize = 100;
+
+/// Failed authentication attempts after which the connection is closed.
+const MAX_AUTH_FAILURES: u32 = 3;
+
+/// Largest frame read from a client that has yet to authenticate, far above
+/// the size of an auth command.
+pub(crate) const MAX_UNAUTHENTICATED_FRAME_SIZE: u32 = 64 * 1024;
 
 pub struct ConnectionHandler<E: KvsEngine> {
     engine: E,
+    limiter: Arc<Limiter>,
+    metrics: Arc<ServerMetrics>,
+    credentials: Option<Arc<Credentials>>,
+    replication: Replication,
+    cluster: Option<Arc<RaftNode<E>>>,
+    txn: Arc<TxnStore<E>>,
+    slots: Option<Arc<SlotTable>>,
     log: Logger,
 }
 
 impl<E: KvsEngine> ConnectionHandler<E> {
-    pub fn new(engine: E, log: Logger) -> ConnectionHandler<E> {
-        ConnectionHandler { engine, log }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        engine: E,
+        limiter: Arc<Limiter>,
+        metrics: Arc<ServerMetrics>,
+        credentials: Option<Arc<Credentials>>,
+        replication: Replication,
+        cluster: Option<Arc<RaftNode<E>>>,
+        txn: Arc<TxnStore<E>>,
+        slots: Option<Arc<SlotTable>>,
+        log: Logger,
+    ) -> ConnectionHandler<E> {
+        ConnectionHandler {
+            engine,
+            limiter,
+            metrics,
+            credentials,
+            replication,
+            cluster,
+            txn,
+            slots,
+            log,
+        }
+    }
+
+    pub(crate) fn limiter(&self) -> &Arc<Limiter> {
+        &self.limiter
+    }
+
+    pub(crate) fn metrics(&self) -> &Arc<ServerMetrics> {
+        &self.metrics
     }
 
-    pub async fn handle(&self, mut stream: TcpStream) -> Result<()> {
-        let addr = stream.peer_addr()?;
-        info!(self.log, "connection from: {}", addr.ip());
+    pub async fn handle<S: KvsStream>(
+        &self,
+        mut stream: S,
+        peer: Option<SocketAddr>,
+    ) -> Result<()> {
+        match peer {
+            Some(addr) => info!(self.log, "connection from: {}", addr.ip()),
+            None => info!(self.log, "connection from: unix socket"),
+        }
+
+        let _guard = ConnectionGuard::new(self.metrics.clone());
+
+        let mut user: Option<User> = None;
+        let mut auth_failures = 0;
+
+        loop {
+            let max_frame_size = match (&self.credentials, &user) {
+                (Some(_), None) => MAX_UNAUTHENTICATED_FRAME_SIZE,
+                _ => MAX_FRAME_SIZE,
+            };
+            let cmd: Command = match read_async_max(&mut stream, max_frame_size).await {
+                Ok(cmd) => cmd,
+                Err(KvError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let (cmd, asking) = match cmd {
+                Command::Asking { request } => (*request, true),
+                cmd => (cmd, false),
+            };
+
+            if let Some(addr) = peer {
+                if !self.limiter.check_rate(addr.ip()) {
+                    warn!(self.log, "{}: {}", BUSY_RATE, addr.ip());
+                    self.metrics.busy.inc();
+                    write_busy(&mut stream, BUSY_RATE).await?;
+                    continue;
+                }
+            }
+
+            let permit = match self.limiter.try_request() {
+                Some(permit) => permit,
+                None => {
+                    warn!(self.log, "{}", BUSY_REQUESTS);
+                    self.metrics.busy.inc();
+                    write_busy(&mut stream, BUSY_REQUESTS).await?;
+                    continue;
+                }
+            };
 
-        let cmd: Command = read_async(&mut stream).await?;
+            let name = cmd.name();
+            let started = Instant::now();
 
-        match cmd {
-            Command::Set { key, val } => self.handle_set(key, val, &mut stream).await,
-            Command::Get { key } => self.handle_get(key, &mut stream).await,
-            Command::Remove { key } => self.handle_remove(key, &mut stream).await,
+            if let Err(msg) = self.authorize(user.as_ref(), &cmd) {
+                warn!(
+                    self.log,
+                    "permission denied: {} {} {} from {}",
+                    user.as_ref().map(User::name).unwrap_or("anonymous"),
+                    name,
+                    String::from_utf8_lossy(cmd.key().unwrap_or_default()),
+                    peer_name(peer)
+                );
+                write_async(&mut stream, &CommandResult::Denied(msg.to_string())).await?;
+                continue;
+            }
+
+            if let (true, Some(primary)) = (cmd.is_write(), self.replication.primary_addr()) {
+                write_async(&mut stream, &CommandResult::ReadOnly(primary.to_string())).await?;
+                continue;
+            }
+
+            if matches!(cmd.key(), Some(key) if key.starts_with(TXN_PREFIX.as_bytes())) {
+                write_async(&mut stream, &CommandResult::Err(RESERVED_KEY.to_string())).await?;
+                continue;
+            }
+
+            let _migrating = match (&self.slots, cmd.key()) {
+                (Some(slots), Some(key)) => match slots.route(&self.engine, key, asking).await {
+                    Ok(Route::Serve(guard)) => guard,
+                    Ok(Route::Redirect(result)) => {
+                        write_async(&mut stream, &result).await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        write_async(&mut stream, &error_result(e)).await?;
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+
+            match cmd {
+                Command::Replicate { id, seq } => {
+                    return match &self.replication {
+                        Replication::Primary(log) => {
+                            // The stream lasts as long as the replica, it
+                            // must not hold a request slot all along.
+                            drop(permit);
+                            info!(self.log, "replica connected from {}", peer_name(peer));
+                            let res = log.serve(&self.engine, &mut stream, id, seq, &self.log).await;
+                            info!(self.log, "replica disconnected from {}", peer_name(peer));
+                            res
+                        }
+                        _ => {
+                            let result = CommandResult::Err(REPLICATION_DISABLED.to_string());
+                            write_async(&mut stream, &result).await
+                        }
+                    };
+                }
+                Command::Raft { msg } => {
+                    let result = match &self.cluster {
+                        Some(node) => match node.clone().handle(msg).await {
+                            Ok(resp) => CommandResult::Raft(resp),
+                            Err(e) => CommandResult::Err(e.to_string()),
+                        },
+                        None => CommandResult::Err(CLUSTER_DISABLED.to_string()),
+                    };
+                    write_async(&mut stream, &result).await?
+                }
+                Command::AddMember { member } => {
+                    let result = match (&self.cluster, member.parse()) {
+                        (Some(node), Ok(member)) => node.add_member(member).await,
+                        (Some(_), Err(e)) => Err(e),
+                        (None, _) => Err(cluster_disabled()),
+                    };
+                    write_void(&mut stream, result).await?
+                }
+                Command::RemoveMember { member } => {
+                    let result = match (&self.cluster, member.parse()) {
+                        (Some(node), Ok(member)) => node.remove_member(member).await,
+                        (Some(_), Err(e)) => Err(e),
+                        (None, _) => Err(cluster_disabled()),
+                    };
+                    write_void(&mut stream, result).await?
+                }
+                Command::Txn { req } => self.handle_txn(req, &mut stream).await?,
+                Command::Timestamp => {
+                    let result = match self.txn.oracle() {
+                        Some(oracle) => match oracle.next() {
+                            Ok(ts) => CommandResult::Timestamp(ts),
+                            Err(e) => CommandResult::Err(e.to_string()),
+                        },
+                        None => CommandResult::Err(TSO_DISABLED.to_string()),
+                    };
+                    write_async(&mut stream, &result).await?
+                }
+                Command::Digest { ranges, split } => {
+                    self.handle_digest(ranges, split, &mut stream).await?
+                }
+                Command::Range { range } => self.handle_range(range, &mut stream).await?,
+                Command::Repair { set, remove } => {
+                    let result = self.repair(set, remove).await;
+                    write_void(&mut stream, result).await?
+                }
+                Command::SetSlot { slot, state } => {
+                    let result = self.set_slot(slot, state).await;
+                    write_void(&mut stream, result).await?
+                }
+                Command::MigrateSlot { slot } => {
+                    let result = match self.migrate_slot(slot).await {
+                        Ok(moved) => CommandResult::Count(moved),
+                        Err(e) => error_result(e),
+                    };
+                    write_async(&mut stream, &result).await?
+                }
+                Command::Import { pairs } => {
+                    let result = self.repair(pairs, Vec::new()).await;
+                    write_void(&mut stream, result).await?
+                }
+                Command::Asking { .. } => {
+                    let result = CommandResult::Err("nested ASKING".to_string());
+                    write_async(&mut stream, &result).await?
+                }
+                Command::Set { key, val } => self.handle_set(key, val, &mut stream).await?,
+                Command::Get { key } => self.handle_get(key, &mut stream).await?,
+                Command::Remove { key } => self.handle_remove(key, &mut stream).await?,
+                Command::Ping => {
+                    write_async(&mut stream, &CommandResult::OkVal(PONG.to_string())).await?
+                }
+                Command::Info => self.handle_info(&mut stream).await?,
+                Command::Stats => self.handle_stats(&mut stream).await?,
+                Command::Compact => self.handle_compact(&mut stream).await?,
+                Command::Flush => self.handle_flush(&mut stream).await?,
+                Command::Backup { dest } => self.handle_backup(dest, &mut stream).await?,
+                Command::Auth { .. } | Command::AuthToken { .. } => match self.authenticate(&cmd) {
+                    Ok(authenticated) => {
+                        info!(
+                            self.log,
+                            "authenticated: {} from {}",
+                            authenticated.name(),
+                            peer_name(peer)
+                        );
+                        user = Some(authenticated);
+                        write_async(&mut stream, &CommandResult::Ok).await?;
+                    }
+                    Err(msg) => {
+                        auth_failures += 1;
+                        warn!(self.log, "authentication failed from {}", peer_name(peer));
+                        write_async(&mut stream, &CommandResult::Denied(msg.to_string())).await?;
+                        if auth_failures >= MAX_AUTH_FAILURES {
+                            warn!(
+                                self.log,
+                                "closing connection from {} after {} failed attempts",
+                                peer_name(peer),
+                                auth_failures
+                            );
+                            return Ok(());
+                        }
+                    }
+                },
+            }
+
+            self.metrics.requests.get(name).inc();
+            self.metrics.latency.get(name).observe(started.elapsed());
         }
     }
 
-    async fn handle_set(&self, key: String, val: String, stream: &mut TcpStream) -> Result<()> {
-        let result = self.engine.set(key, val).await;
-        match result {
-            Ok(_) => write_async(stream, &CommandResult::Ok).await,
-            Err(e) => write_async(stream, &CommandResult::Err(e.to_string())).await,
+    /// Checks the command against the user's ACL, every command is allowed
+    /// when the server runs without credentials.
+    fn authorize(
+        &self,
+        user: Option<&User>,
+        cmd: &Command,
+    ) -> std::result::Result<(), &'static str> {
+        if self.credentials.is_none() {
+            return Ok(());
         }
+
+        let user = match cmd {
+            Command::Ping | Command::Auth { .. } | Command::AuthToken { .. } => return Ok(()),
+            _ => user.ok_or(AUTH_REQUIRED)?,
+        };
+
+        let allowed = match cmd {
+            Command::Get { key } => user.can(Access::Read, key),
+            Command::Set { key, .. } | Command::Remove { key } => user.can(Access::Write, key),
+            Command::Txn { req } => {
+                let access = if req.is_write() {
+                    Access::Write
+                } else {
+                    Access::Read
+                };
+                req.keys().into_iter().all(|key| user.can(access, key))
+            }
+            Command::Timestamp => true,
+            _ => user.is_admin(),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err("access to the key or command is not granted")
+        }
+    }
+
+    fn authenticate(&self, cmd: &Command) -> std::result::Result<User, &'static str> {
+        let credentials = self.credentials.as_ref().ok_or(AUTH_DISABLED)?;
+        let user = match cmd {
+            Command::Auth { user, password } => credentials.authenticate_password(user, password),
+            Command::AuthToken { token } => credentials.authenticate_token(token),
+            _ => None,
+        };
+        user.cloned().ok_or(AUTH_FAILED)
     }
 
-    async fn handle_get(&self, key: String, stream: &mut TcpStream) -> Result<()> {
-        let res = self.engine.get(key).await;
+    async fn handle_set<S: KvsStream>(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        stream: &mut S,
+    ) -> Result<()> {
+        let result = self.write(LogEntry::Set { key, val }).await;
+        write_void(stream, result).await
+    }
+
+    async fn handle_get<S: KvsStream>(&self, key: Vec<u8>, stream: &mut S) -> Result<()> {
+        if let Some(node) = &self.cluster {
+            if let Err(e) = node.read_index().await {
+                return write_async(stream, &error_result(e)).await;
+            }
+        }
+        let res = self.engine.get_bytes(key).await;
         match res {
-            Ok(val) => match val {
-                Some(v) => write_async(stream, &CommandResult::OkVal(v)).await,
+            Ok(val) => match val.map(String::from_utf8) {
+                Some(Ok(v)) => write_async(stream, &CommandResult::OkVal(v)).await,
+                Some(Err(e)) => write_async(stream, &CommandResult::OkBytes(e.into_bytes())).await,
                 None => write_async(stream, &CommandResult::Ok).await,
             },
             Err(e) => write_async(stream, &CommandResult::Err(e.to_string())).await,
         }
     }
 
-    async fn handle_remove(&self, key: String, stream: &mut TcpStream) -> Result<()> {
-        let result = self.engine.remove(key).await;
-        match result {
-            Ok(_) => write_async(stream, &CommandResult::Ok).await,
-            Err(e) => write_async(stream, &CommandResult::Err(e.to_string())).await,
+    async fn handle_remove<S: KvsStream>(&self, key: Vec<u8>, stream: &mut S) -> Result<()> {
+        let result = self.write(LogEntry::Remove { key }).await;
+        write_void(stream, result).await
+    }
+
+    async fn handle_txn<S: KvsStream>(&self, req: TxnRequest, stream: &mut S) -> Result<()> {
+        if let Some(node) = &self.cluster {
+            if let Err(e) = node.read_index().await {
+                return write_async(stream, &error_result(e)).await;
+            }
+        }
+        let result = match self.txn.handle(req, self).await {
+            Ok(resp) => CommandResult::Txn(resp),
+            Err(e) => error_result(e),
+        };
+        write_async(stream, &result).await
+    }
+
+    async fn handle_digest<S: KvsStream>(
+        &self,
+        ranges: Vec<KeyRange>,
+        split: u32,
+        stream: &mut S,
+    ) -> Result<()> {
+        let result = match merkle::digests(&self.engine, ranges, split as usize).await {
+            Ok(digests) => CommandResult::Digests(digests),
+            Err(e) => CommandResult::Err(e.to_string()),
+        };
+        write_async(stream, &result).await
+    }
+
+    async fn handle_range<S: KvsStream>(&self, range: KeyRange, stream: &mut S) -> Result<()> {
+        let result = match self.engine.scan_range(range.start, range.end).await {
+            Ok(pairs) => CommandResult::OkPairs(pairs),
+            Err(e) => CommandResult::Err(e.to_string()),
+        };
+        write_async(stream, &result).await
+    }
+
+    /// Applies the writes of a repair like any other, except on a replica,
+    /// which applies them to its engine.
+    async fn repair(&self, set: Vec<(Vec<u8>, Vec<u8>)>, remove: Vec<Vec<u8>>) -> Result<()> {
+        for (key, val) in set {
+            self.write(LogEntry::Set { key, val }).await?;
+        }
+        for key in remove {
+            match self.write(LogEntry::Remove { key }).await {
+                Ok(()) | Err(KvError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the slot's state. A slot no longer imported drops the keys
+    /// imported so far, they are still served by the source. They are
+    /// removed like any other write so replicas drop them too.
+    async fn set_slot(&self, slot: u16, state: SlotState) -> Result<()> {
+        let slots = self.slots.as_ref().ok_or_else(slots_disabled)?;
+        let aborted = !matches!(state, SlotState::Owned | SlotState::Importing { .. });
+        let previous = slots.set(slot, state)?;
+        if aborted && matches!(previous, SlotState::Importing { .. }) {
+            let mut removed = 0;
+            let mut after = None;
+            loop {
+                let pairs = self
+                    .engine
+                    .scan_slot(slot, after.take(), MIGRATION_BATCH)
+                    .await?;
+                after = match pairs.last() {
+                    Some((key, _)) => Some(key.clone()),
+                    None => break,
+                };
+                for (key, _) in pairs {
+                    match self.write(LogEntry::Remove { key }).await {
+                        Ok(()) | Err(KvError::KeyNotFound) => removed += 1,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            info!(
+                self.log,
+                "import of slot {} aborted, {} keys dropped", slot, removed
+            );
         }
+        Ok(())
     }
+
+    /// Copies the keys of a migrating slot to its target a batch at a
+    /// time, deleting each batch once the target has it.
+    async fn migrate_slot(&self, slot: u16) -> Result<u64> {
+        let slots = self.slots.as_ref().ok_or_else(slots_disabled)?;
+        let mut target = slots.migration_target(slot)?.connect().await?;
+        let mut moved = 0;
+        let mut after = None;
+        loop {
+            let _guard = slots.migration_guard().await;
+            let pairs = self
+                .engine
+                .scan_slot(slot, after.take(), MIGRATION_BATCH)
+                .await?;
+            after = match pairs.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+            let keys: Vec<Vec<u8>> = pairs.iter().map(|(key, _)| key.clone()).collect();
+            target.import(pairs).await?;
+            for key in keys {
+                match self.write(LogEntry::Remove { key }).await {
+                    Ok(()) | Err(KvError::KeyNotFound) => moved += 1,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        info!(self.log, "slot {}: {} keys migrated", slot, moved);
+        Ok(moved)
+    }
+
+    /// Applies a write, numbering it for the replicas on a primary, or
+    /// through the cluster log.
+    async fn write(&self, entry: LogEntry) -> Result<()> {
+        if let Some(node) = &self.cluster {
+            return node.write(entry).await;
+        }
+        match &self.replication {
+            Replication::Primary(log) => log.write(&self.engine, entry).await,
+            _ => replication::apply(&self.engine, entry).await,
+        }
+    }
+
+    async fn handle_info<S: KvsStream>(&self, stream: &mut S) -> Result<()> {
+        let mut fields = vec![
+            ("version".to_string(), VERSION.to_string()),
+            (
+                "uptime_secs".to_string(),
+                self.metrics.started.elapsed().as_secs().to_string(),
+            ),
+        ];
+
+        if let Some(admin) = self.engine.admin() {
+            let stats = match admin.stats().await {
+                Ok(stats) => stats,
+                Err(e) => return write_async(stream, &CommandResult::Err(e.to_string())).await,
+            };
+            let data_dir = admin
+                .data_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default();
+            fields.push(("engine".to_string(), admin.engine_name().to_string()));
+            fields.push(("data_dir".to_string(), data_dir));
+            fields.push(("keys".to_string(), stats.keys.to_string()));
+            fields.push(("disk_bytes".to_string(), stats.disk_bytes.to_string()));
+        }
+        match &self.cluster {
+            Some(node) => fields.extend(node.fields()),
+            None => fields.extend(self.replication.fields()),
+        }
+
+        write_async(stream, &CommandResult::OkFields(fields)).await
+    }
+
+    async fn handle_stats<S: KvsStream>(&self, stream: &mut S) -> Result<()> {
+        let mut fields = self.metrics.fields();
+
+        if let Some(admin) = self.engine.admin() {
+            match admin.stats().await {
+                Ok(stats) => fields.extend(stats.details),
+                Err(e) => return write_async(stream, &CommandResult::Err(e.to_string())).await,
+            }
+        }
+
+        write_async(stream, &CommandResult::OkFields(fields)).await
+    }
+
+    async fn handle_compact<S: KvsStream>(&self, stream: &mut S) -> Result<()> {
+        let result = match self.engine.admin() {
+            Some(admin) => admin.compact().await,
+            None => Err(no_admin("compact")),
+        };
+        write_void(stream, result).await
+    }
+
+    async fn handle_flush<S: KvsStream>(&self, stream: &mut S) -> Result<()> {
+        let result = match self.engine.admin() {
+            Some(admin) => admin.flush().await,
+            None => Err(no_admin("flush")),
+        };
+        write_void(stream, result).await
+    }
+
+    async fn handle_backup<S: KvsStream>(&self, dest: String, stream: &mut S) -> Result<()> {
+        let result = match self.engine.admin() {
+            Some(admin) => admin.backup(PathBuf::from(dest)).await,
+            None => Err(no_admin("backup")),
+        };
+        let result = match result {
+            Ok(meta) => {
+                info!(self.log, "backup done: {} keys", meta.keys);
+                CommandResult::OkFields(meta.fields())
+            }
+            Err(e) => error_result(e),
+        };
+        write_async(stream, &result).await
+    }
+}
+
+impl<E: KvsEngine> TxnWriter for ConnectionHandler<E> {
+    fn write_entry(&self, entry: LogEntry) -> BoxFuture<'_, Result<()>> {
+        self.write(entry).boxed()
+    }
+}
+
+fn peer_name(peer: Option<SocketAddr>) -> String {
+    match peer {
+        Some(addr) => addr.ip().to_string(),
+        None => "unix socket".to_string(),
+    }
+}
+
+fn no_admin(op: &str) -> KvError {
+    KvError::Unsupported {
+        op: format!("{} is not supported by the engine", op),
+    }
+}
+
+fn slots_disabled() -> KvError {
+    KvError::Slots {
+        msg: SLOTS_DISABLED.to_string(),
+    }
+}
+
+fn cluster_disabled() -> KvError {
+    KvError::Cluster {
+        msg: CLUSTER_DISABLED.to_string(),
+    }
+}
+
+/// Result of a failed command, cluster members redirect to the leader.
+fn error_result(e: KvError) -> CommandResult {
+    match e {
+        KvError::NotLeader { leader } => CommandResult::NotLeader(leader),
+        e => CommandResult::Err(e.to_string()),
+    }
+}
+
+async fn write_void<S: KvsStream>(stream: &mut S, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(_) => write_async(stream, &CommandResult::Ok).await,
+        Err(e) => write_async(stream, &error_result(e)).await,
+    }
+}
+
+async fn write_busy<S: KvsStream>(stream: &mut S, msg: &str) -> Result<()> {
+    write_async(stream, &CommandResult::Busy(msg.to_string())).await
 }
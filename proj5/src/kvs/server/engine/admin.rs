@@ -0,0 +1,34 @@
+use crate::kvs::err::Result;
+use crate::kvs::server::engine::backup::BackupMeta;
+use futures::future::BoxFuture;
+use std::path::{Path, PathBuf};
+
+/// Operator facing extension of an engine, exposed through
+/// [`KvsEngine::admin`](crate::kvs::KvsEngine::admin).
+pub trait KvsAdmin: Send + Sync {
+    /// Short engine name, e.g. `kvs` or `sled`.
+    fn engine_name(&self) -> &'static str;
+
+    /// Directory holding the engine data, if known.
+    fn data_dir(&self) -> Option<&Path>;
+
+    fn stats(&self) -> BoxFuture<Result<EngineStats>>;
+
+    /// Reclaims the space taken by overwritten and removed entries.
+    fn compact(&self) -> BoxFuture<Result<()>>;
+
+    /// Makes every acknowledged write durable on disk.
+    fn flush(&self) -> BoxFuture<Result<()>>;
+
+    /// Copies a consistent view of the data to `dest`, which must be missing
+    /// or empty, while writes go on. See [`backup`](crate::kvs::backup).
+    fn backup(&self, dest: PathBuf) -> BoxFuture<Result<BackupMeta>>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EngineStats {
+    pub keys: u64,
+    pub disk_bytes: u64,
+    /// Engine specific fields, in display order.
+    pub details: Vec<(String, String)>,
+}
@@ -0,0 +1,173 @@
+//! Backups written by [`KvsAdmin::backup`](crate::kvs::KvsAdmin::backup).
+//!
+//! A backup is a directory holding a copy of the engine's data directory
+//! and a [`BACKUP_META`] file listing the copied files. Restoring copies
+//! them into an empty data directory, after checking every listed file is
+//! there with its size.
+
+use crate::kvs::err::{KvError, Result};
+use crate::kvs::server::atomic_file;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Name of the metadata file of a backup.
+pub const BACKUP_META: &str = "backup.json";
+
+/// Version of the backup layout, restore refuses any other.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupMeta {
+    pub format_version: u32,
+    /// Name of the engine, see [`KvsAdmin::engine_name`](crate::kvs::KvsAdmin::engine_name).
+    pub engine: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub keys: u64,
+    /// Files of the backup other than the metadata, with paths relative to
+    /// the backup directory.
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub path: String,
+    pub bytes: u64,
+}
+
+impl BackupMeta {
+    /// Fields sent to clients for a backup done by the server.
+    pub(crate) fn fields(&self) -> Vec<(String, String)> {
+        let bytes: u64 = self.files.iter().map(|file| file.bytes).sum();
+        vec![
+            ("engine".to_string(), self.engine.clone()),
+            ("keys".to_string(), self.keys.to_string()),
+            ("files".to_string(), self.files.len().to_string()),
+            ("bytes".to_string(), bytes.to_string()),
+        ]
+    }
+}
+
+/// Creates `dest`, which must be missing or empty and outside the engine's
+/// data directory.
+pub(crate) fn prepare_dest(dest: &Path, data_dir: Option<&Path>) -> Result<()> {
+    let created = !dest.exists();
+    fs::create_dir_all(dest)?;
+    if let Some(data_dir) = data_dir {
+        if dest.canonicalize()?.starts_with(data_dir.canonicalize()?) {
+            if created {
+                let _ = fs::remove_dir(dest);
+            }
+            return Err(backup_error(dest, "the directory is in the data directory"));
+        }
+    }
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(backup_error(dest, "the directory is not empty"));
+    }
+    Ok(())
+}
+
+/// Lists the files an engine wrote to `dest` in its metadata file.
+pub(crate) fn finish(dest: &Path, engine: &str, keys: u64) -> Result<BackupMeta> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let meta = BackupMeta {
+        format_version: BACKUP_FORMAT_VERSION,
+        engine: engine.to_string(),
+        created_at,
+        keys,
+        files: list_files(dest)?,
+    };
+    let content = serde_json::to_vec_pretty(&meta).map_err(|e| backup_error(dest, e))?;
+    atomic_file::replace(&dest.join(BACKUP_META), &content)?;
+    Ok(meta)
+}
+
+/// Reads the metadata of the backup and checks every file it lists is
+/// there with its size.
+pub fn validate(backup: &Path) -> Result<BackupMeta> {
+    let content = match fs::read_to_string(backup.join(BACKUP_META)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(backup_error(backup, "no backup metadata"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let meta: BackupMeta = serde_json::from_str(&content).map_err(|e| backup_error(backup, e))?;
+    if meta.format_version != BACKUP_FORMAT_VERSION {
+        let msg = format!("unsupported format version {}", meta.format_version);
+        return Err(backup_error(backup, msg));
+    }
+    for file in &meta.files {
+        // Restore joins the paths to the data directory, they may not leave it.
+        let relative = Path::new(&file.path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !relative {
+            return Err(backup_error(backup, format!("invalid path {}", file.path)));
+        }
+        let bytes = match fs::metadata(backup.join(&file.path)) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(backup_error(backup, format!("{} is missing", file.path)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if bytes != file.bytes {
+            let msg = format!("{} has {} bytes, not {}", file.path, bytes, file.bytes);
+            return Err(backup_error(backup, msg));
+        }
+    }
+    Ok(meta)
+}
+
+/// Copies a valid backup to the data directory of its engine, which must
+/// be missing or empty. No server may have the directory open.
+pub fn restore(backup: &Path, data_dir: &Path) -> Result<BackupMeta> {
+    let meta = validate(backup)?;
+    if data_dir.exists() && fs::read_dir(data_dir)?.next().is_some() {
+        return Err(backup_error(data_dir, "the data directory is not empty"));
+    }
+    for file in &meta.files {
+        let dest = data_dir.join(&file.path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(backup.join(&file.path), &dest)?;
+    }
+    Ok(meta)
+}
+
+fn list_files(dir: &Path) -> Result<Vec<BackupFile>> {
+    let mut files = Vec::new();
+    let dir_error = |e| KvError::Dir {
+        path: dir.display().to_string(),
+        source: e,
+    };
+    for entry in WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry.map_err(dir_error)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let bytes = entry.metadata().map_err(dir_error)?.len();
+        let path = entry.path().strip_prefix(dir).unwrap();
+        files.push(BackupFile {
+            path: path.to_string_lossy().into_owned(),
+            bytes,
+        });
+    }
+    Ok(files)
+}
+
+fn backup_error(dir: &Path, msg: impl ToString) -> KvError {
+    KvError::Backup {
+        path: dir.display().to_string(),
+        msg: msg.to_string(),
+    }
+}
@@ -0,0 +1,150 @@
+//! The [`ENGINE_META`] file of a server directory, naming the engine that
+//! owns it and the on-disk format its data is written in.
+//!
+//! It is written when a server first opens the directory. Directories
+//! written before it existed get one from their `kvs_data` or `sled_data`
+//! directory instead.
+
+use crate::kvs::err::{KvError, Result};
+use crate::kvs::server::atomic_file;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the metadata file of a server directory.
+pub const ENGINE_META: &str = "engine.json";
+
+/// Version of the on-disk format written, newer ones are refused.
+pub const ENGINE_FORMAT_VERSION: u32 = 1;
+
+/// Names of the engines a directory may hold.
+pub const ENGINES: [&str; 2] = ["kvs", "sled"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EngineMeta {
+    pub format_version: u32,
+    /// Name of the engine, one of [`ENGINES`].
+    pub engine: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Options the engine was first opened with. Later opens may change
+    /// them, they tell how the oldest data was written.
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+}
+
+impl EngineMeta {
+    pub fn new(engine: &str) -> EngineMeta {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        EngineMeta {
+            format_version: ENGINE_FORMAT_VERSION,
+            engine: engine.to_string(),
+            created_at,
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// The directory inside the server directory the engine writes to.
+    pub fn data_dir(&self, dir: &Path) -> PathBuf {
+        data_dir(dir, &self.engine)
+    }
+}
+
+/// Reads the metadata of the server directory, `None` if it has none.
+pub fn load(dir: &Path) -> Result<Option<EngineMeta>> {
+    let path = dir.join(ENGINE_META);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let meta: EngineMeta =
+        serde_json::from_str(&content).map_err(|e| KvError::InvalidArgument {
+            arg: path.display().to_string(),
+            val: e.to_string(),
+        })?;
+    if meta.format_version > ENGINE_FORMAT_VERSION {
+        return Err(KvError::UnsupportedFormat {
+            path: path.display().to_string(),
+            version: meta.format_version,
+            supported: ENGINE_FORMAT_VERSION,
+        });
+    }
+    Ok(Some(meta))
+}
+
+/// Writes the metadata of the server directory, replacing any.
+pub fn save(dir: &Path, meta: &EngineMeta) -> Result<()> {
+    let content = serde_json::to_vec_pretty(meta).map_err(std::io::Error::from)?;
+    atomic_file::replace(&dir.join(ENGINE_META), &content)
+}
+
+/// The metadata of the server directory, written first if it has none.
+///
+/// `engine` is the engine asked for, `None` to use the one of the
+/// directory, or kvs for a new one. Fails with [`KvError::EngineMismatch`]
+/// when the directory belongs to another engine.
+pub fn open(dir: &Path, engine: Option<&str>) -> Result<EngineMeta> {
+    open_with(dir, engine, |_| BTreeMap::new())
+}
+
+/// Like [`open`], a new directory records the `options` of its engine.
+pub fn open_with<F>(dir: &Path, engine: Option<&str>, options: F) -> Result<EngineMeta>
+where
+    F: FnOnce(&str) -> BTreeMap<String, String>,
+{
+    if let Some(engine) = engine {
+        if !ENGINES.contains(&engine) {
+            return Err(KvError::InvalidArgument {
+                arg: "engine".to_string(),
+                val: engine.to_string(),
+            });
+        }
+    }
+
+    let meta = match load(dir)? {
+        Some(meta) => meta,
+        None => {
+            let found: Option<&str> = detect(dir)?;
+            let mut meta = EngineMeta::new(found.or(engine).unwrap_or("kvs"));
+            meta.options = options(&meta.engine);
+            fs::create_dir_all(dir)?;
+            save(dir, &meta)?;
+            meta
+        }
+    };
+
+    match engine {
+        Some(engine) if engine != meta.engine => Err(KvError::EngineMismatch {
+            path: dir.display().to_string(),
+            requested: engine.to_string(),
+            found: meta.engine,
+        }),
+        _ => Ok(meta),
+    }
+}
+
+/// The engine of a directory written before the metadata file existed.
+fn detect(dir: &Path) -> Result<Option<&'static str>> {
+    let mut found = ENGINES
+        .iter()
+        .copied()
+        .filter(|engine| data_dir(dir, engine).is_dir());
+    match (found.next(), found.next()) {
+        (Some(_), Some(_)) => Err(KvError::InvalidArgument {
+            arg: "dir".to_string(),
+            val: format!("{} holds data of several engines", dir.display()),
+        }),
+        (found, _) => Ok(found),
+    }
+}
+
+fn data_dir(dir: &Path, engine: &str) -> PathBuf {
+    dir.join(format!("{}_data", engine))
+}
@@ -1,14 +1,106 @@
+pub mod admin;
+pub mod backup;
+pub mod meta;
 pub mod sled_eng;
 pub mod store;
+pub mod transfer;
 
-use crate::kvs::err::Result;
+use crate::kvs::err::{KvError, Result};
+use crate::kvs::metrics::MetricsWriter;
+use admin::KvsAdmin;
 use futures::future::BoxFuture;
+use futures::FutureExt;
 use std::future::Future;
 
+/// Keys and values are arbitrary bytes. The `String` methods are shorthands
+/// for text, reads fail with [`KvError::Ut8Conversion`] on values that are
+/// not UTF-8.
 pub trait KvsEngine: Clone + Send + Sync + 'static {
-    fn get(&self, key: String) -> BoxFuture<Result<Option<String>>>;
+    fn get_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<Option<Vec<u8>>>>;
 
-    fn set(&self, key: String, value: String) -> BoxFuture<Result<()>>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<Result<()>>;
 
-    fn remove(&self, key: String) -> BoxFuture<Result<()>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<()>>;
+
+    /// Every pair whose key starts with `prefix`, ordered by key. The pairs
+    /// are collected in memory.
+    fn scan_bytes(&self, prefix: Vec<u8>) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>>;
+
+    /// Every pair whose key is in `[start, end)`, ordered by key, `end` is
+    /// `None` for a range without upper bound. The pairs are collected in
+    /// memory.
+    fn scan_range(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>>;
+
+    /// Up to `limit` pairs whose key sorts after `after`, or from the first
+    /// key if it is `None`, ordered by key. Resuming after the last key
+    /// returned walks the whole engine a batch at a time.
+    fn scan_after(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>>;
+
+    /// Up to `limit` pairs whose key is in the hash slot, see
+    /// [`key_slot`](crate::kvs::key_slot), and sorts after `after`, ordered
+    /// by key.
+    fn scan_slot(
+        &self,
+        slot: u16,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>>;
+
+    /// Writes engine specific metrics, engines without any write nothing.
+    fn write_metrics(&self, _out: &mut MetricsWriter) {}
+
+    /// Admin operations of the engine, `None` if it supports none.
+    fn admin(&self) -> Option<&dyn KvsAdmin> {
+        None
+    }
+
+    fn get(&self, key: String) -> BoxFuture<Result<Option<String>>> {
+        let get = self.get_bytes(key.clone().into_bytes());
+        async move {
+            match get.await? {
+                Some(val) => text(key, val).map(Some),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn set(&self, key: String, value: String) -> BoxFuture<Result<()>> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn remove(&self, key: String) -> BoxFuture<Result<()>> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Every pair whose key starts with `prefix`, ordered by key, failing on
+    /// the first key or value that is not UTF-8.
+    fn scan(&self, prefix: String) -> BoxFuture<Result<Vec<(String, String)>>> {
+        let scan = self.scan_bytes(prefix.into_bytes());
+        async move {
+            let mut pairs = Vec::new();
+            for (key, val) in scan.await? {
+                let key = text(String::from_utf8_lossy(&key).into_owned(), key)?;
+                match String::from_utf8(val) {
+                    Ok(val) => pairs.push((key, val)),
+                    Err(source) => return Err(KvError::Ut8Conversion { key, source }),
+                }
+            }
+            Ok(pairs)
+        }
+        .boxed()
+    }
+}
+
+/// The bytes as a `String`, errors name `key`.
+fn text(key: String, bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|source| KvError::Ut8Conversion { key, source })
 }
@@ -1,24 +1,48 @@
-use crate::kvs::err::KvError::{KeyNotFound, Sled, SledAccess, Ut8Conversion};
+use crate::kvs::err::KvError::{KeyNotFound, Sled, SledAccess};
+use crate::kvs::metrics::MetricsWriter;
+use crate::kvs::server::engine::admin::{EngineStats, KvsAdmin};
+use crate::kvs::server::engine::backup::{self, BackupMeta};
+use crate::kvs::server::slots::key_slot;
 use crate::kvs::thread_pool::ThreadPool;
 use crate::kvs::Result;
 use crate::kvs::{KvError, KvsEngine};
 use futures::future::BoxFuture;
 use futures::{FutureExt, TryFutureExt};
-use sled::Db;
+use sled::{Batch, Db, IVec};
 use std::future::Future;
-use std::ops::Deref;
+use std::ops::{Bound, Deref};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::sync::oneshot::channel;
 
+/// Pairs inserted at once by [`import_pairs`].
+const IMPORT_BATCH: u64 = 10_000;
+
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     db: Db,
     pool: P,
+    data_dir: Option<Arc<PathBuf>>,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
     pub fn new(db: Db, threads: u32) -> Result<SledKvsEngine<P>> {
         let pool = P::new(threads)?;
-        Ok(SledKvsEngine { db, pool })
+        Ok(SledKvsEngine {
+            db,
+            pool,
+            data_dir: None,
+        })
+    }
+
+    /// Opens the sled database at `path`, unlike `new` the engine knows
+    /// its data directory.
+    pub fn open(path: impl Into<PathBuf>, threads: u32) -> Result<SledKvsEngine<P>> {
+        let path = path.into();
+        let db = sled::open(path.as_path()).map_err(Sled)?;
+        let mut engine = SledKvsEngine::new(db, threads)?;
+        engine.data_dir = Some(Arc::new(path));
+        Ok(engine)
     }
 }
 
@@ -29,45 +53,37 @@ impl<P: ThreadPool> Drop for SledKvsEngine<P> {
 }
 
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn get(&self, key: String) -> BoxFuture<Result<Option<String>>> {
-        let (sender, receiver) = channel::<Result<Option<String>>>();
+    fn get_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<Option<Vec<u8>>>> {
+        let (sender, receiver) = channel::<Result<Option<Vec<u8>>>>();
 
         let db = self.db.clone();
 
         self.pool.spawn(move || {
-            let buf_opt = match db.get(key.as_bytes()) {
-                Ok(res) => res,
-                Err(e) => {
-                    sender.send(Err(SledAccess { key, source: e })).unwrap();
-                    return;
-                }
-            };
-
-            let res = match buf_opt {
-                Some(buf) => match String::from_utf8(buf.to_vec()) {
-                    Ok(val) => Some(val),
-                    Err(e) => {
-                        sender.send(Err(Ut8Conversion { key, source: e })).unwrap();
-                        return;
-                    }
-                },
-                None => None,
-            };
-            sender.send(Ok(res)).unwrap();
+            let res = db
+                .get(&key)
+                .map(|val| val.map(|val| val.to_vec()))
+                .map_err(|e| SledAccess {
+                    key: key_name(&key),
+                    source: e,
+                });
+            sender.send(res).unwrap();
         });
         receiver.map(|res| res.unwrap()).boxed()
     }
 
-    fn set(&self, key: String, value: String) -> BoxFuture<Result<()>> {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<Result<()>> {
         let (sender, receiver) = channel::<Result<()>>();
 
         let db = self.db.clone();
 
         self.pool.spawn(move || {
             let res = db
-                .insert(key.as_bytes(), value.as_bytes())
+                .insert(key.as_slice(), value)
                 .map(|_| ())
-                .map_err(|err| SledAccess { key, source: err })
+                .map_err(|err| SledAccess {
+                    key: key_name(&key),
+                    source: err,
+                })
                 .and_then(|_| flush(&db));
             sender.send(res).unwrap();
         });
@@ -75,18 +91,21 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         receiver.map(|res| res.unwrap()).boxed()
     }
 
-    fn remove(&self, key: String) -> BoxFuture<Result<()>> {
+    fn remove_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<()>> {
         let (sender, receiver) = channel::<Result<()>>();
 
         let db = self.db.clone();
 
         self.pool.spawn(move || {
-            let res = match db.remove(key.as_bytes()) {
+            let res = match db.remove(&key) {
                 Ok(val) => match val {
                     Some(_) => Ok(()),
                     None => Err(KeyNotFound),
                 },
-                Err(e) => Err(SledAccess { key, source: e }),
+                Err(e) => Err(SledAccess {
+                    key: key_name(&key),
+                    source: e,
+                }),
             }
             .and_then(|_| flush(&db));
 
@@ -95,8 +114,253 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
 
         receiver.map(|res| res.unwrap()).boxed()
     }
+
+    fn scan_bytes(&self, prefix: Vec<u8>) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            let res = collect_pairs(db.scan_prefix(&prefix), &prefix);
+            sender.send(res).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_range(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            let res = match &end {
+                Some(end) if end <= &start => Ok(Vec::new()),
+                Some(end) => collect_pairs(db.range(start.as_slice()..end.as_slice()), &start),
+                None => collect_pairs(db.range(start.as_slice()..), &start),
+            };
+            sender.send(res).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_after(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            let start = after.clone().map_or(Bound::Unbounded, Bound::Excluded);
+            let iter = db.range((start, Bound::Unbounded)).take(limit);
+            let res = collect_pairs(iter, after.as_deref().unwrap_or_default());
+            sender.send(res).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_slot(
+        &self,
+        slot: u16,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            let start = after.clone().map_or(Bound::Unbounded, Bound::Excluded);
+            let iter = db
+                .range((start, Bound::Unbounded))
+                .filter(|pair| match pair {
+                    Ok((key, _)) => key_slot(key) == slot,
+                    Err(_) => true,
+                })
+                .take(limit);
+            let res = collect_pairs(iter, after.as_deref().unwrap_or_default());
+            sender.send(res).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn write_metrics(&self, out: &mut MetricsWriter) {
+        if let Ok(size) = self.db.size_on_disk() {
+            out.gauge(
+                "kvs_sled_disk_bytes",
+                "Bytes used by the sled database on disk.",
+                size as f64,
+            );
+        }
+        out.gauge(
+            "kvs_thread_pool_queued_jobs",
+            "Jobs waiting for a thread pool worker.",
+            self.pool.queued_jobs() as f64,
+        );
+    }
+
+    fn admin(&self) -> Option<&dyn KvsAdmin> {
+        Some(self)
+    }
+}
+
+impl<P: ThreadPool> KvsAdmin for SledKvsEngine<P> {
+    fn engine_name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_deref().map(PathBuf::as_path)
+    }
+
+    fn stats(&self) -> BoxFuture<Result<EngineStats>> {
+        let (sender, receiver) = channel::<Result<EngineStats>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            let result = db.size_on_disk().map_err(Sled).map(|disk_bytes| EngineStats {
+                keys: db.len() as u64,
+                disk_bytes,
+                details: vec![
+                    ("trees".to_string(), db.tree_names().len().to_string()),
+                    ("checksum".to_string(), checksum(&db)),
+                    ("recovered".to_string(), db.was_recovered().to_string()),
+                ],
+            });
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn compact(&self) -> BoxFuture<Result<()>> {
+        let res = Err(KvError::Unsupported {
+            op: "compaction is managed by sled itself".to_string(),
+        });
+        futures::future::ready(res).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<Result<()>> {
+        let (sender, receiver) = channel::<Result<()>>();
+
+        let db = self.db.clone();
+
+        self.pool.spawn(move || {
+            sender.send(flush(&db)).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn backup(&self, dest: PathBuf) -> BoxFuture<Result<BackupMeta>> {
+        let (sender, receiver) = channel::<Result<BackupMeta>>();
+
+        let db = self.db.clone();
+        let data_dir = self.data_dir.clone();
+
+        self.pool.spawn(move || {
+            let res = do_backup(&db, data_dir.as_deref().map(PathBuf::as_path), &dest);
+            sender.send(res).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+}
+
+/// Passes every pair whose key starts with `prefix` to `visit`, ordered by
+/// key, without collecting them. Returns how many there were.
+pub(crate) fn export_pairs(
+    path: &Path,
+    prefix: &[u8],
+    mut visit: impl FnMut(Vec<u8>, Vec<u8>) -> Result<()>,
+) -> Result<u64> {
+    let db = sled::open(path).map_err(Sled)?;
+    let mut count = 0;
+    for pair in db.scan_prefix(prefix) {
+        let (key, val) = decode_pair(pair, prefix)?;
+        visit(key, val)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Inserts the pairs into the database at `path` a batch at a time,
+/// flushing once they are all in. Returns how many there were.
+pub(crate) fn import_pairs(
+    path: &Path,
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+) -> Result<u64> {
+    let db = sled::open(path).map_err(Sled)?;
+    let mut batch = Batch::default();
+    let mut count = 0;
+    for pair in pairs {
+        let (key, val) = pair?;
+        batch.insert(key, val);
+        count += 1;
+        if count % IMPORT_BATCH == 0 {
+            db.apply_batch(std::mem::take(&mut batch)).map_err(Sled)?;
+        }
+    }
+    db.apply_batch(batch).map_err(Sled)?;
+    flush(&db)?;
+    Ok(count)
+}
+
+/// Imports an export of the database into a new one in `dest`. Each tree is
+/// exported by iterating it, writes made meanwhile may or may not be in.
+fn do_backup(db: &Db, data_dir: Option<&Path>, dest: &Path) -> Result<BackupMeta> {
+    backup::prepare_dest(dest, data_dir)?;
+
+    let keys = {
+        let copy = sled::open(dest).map_err(Sled)?;
+        copy.import(db.export());
+        flush(&copy)?;
+        copy.len() as u64
+    };
+
+    backup::finish(dest, "sled", keys)
+}
+
+fn checksum(db: &Db) -> String {
+    match db.checksum() {
+        Ok(sum) => format!("{:08x}", sum),
+        Err(e) => e.to_string(),
+    }
 }
 
 fn flush(db: &Db) -> Result<()> {
     db.flush().map(|_| ()).map_err(|err| KvError::Sled(err))
 }
+
+/// Copies the pairs of an iteration, errors before a key is known name
+/// `start`, the key the iteration started at.
+fn collect_pairs(
+    iter: impl Iterator<Item = sled::Result<(IVec, IVec)>>,
+    start: &[u8],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    iter.map(|pair| decode_pair(pair, start)).collect()
+}
+
+fn decode_pair(pair: sled::Result<(IVec, IVec)>, start: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, val) = pair.map_err(|e| SledAccess {
+        key: key_name(start),
+        source: e,
+    })?;
+    Ok((key.to_vec(), val.to_vec()))
+}
+
+/// The key as shown in errors, bytes that are not UTF-8 replaced.
+fn key_name(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
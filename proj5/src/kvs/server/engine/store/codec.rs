@@ -0,0 +1,284 @@
+//! Encodings of the [`LogEntry`] of a frame.
+//!
+//! Segments record the codec their entries are encoded with in their
+//! header, see [`SEGMENT_FORMAT_VERSION`](crate::kvs::SEGMENT_FORMAT_VERSION).
+//! New segments are written with [`Codec::DEFAULT`], BSON is still read from
+//! segments written before.
+
+use crate::kvs::err::KvError::{DecodeEntry, DeserializeEntry, SerializeEntry};
+use crate::kvs::err::{KvError, Result};
+use crate::kvs::server::engine::store::io::LogEntry;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+const TAG_SET: u8 = 0;
+const TAG_REMOVE: u8 = 1;
+
+/// Encodes and decodes the entry of a frame, without its size.
+pub trait EntryCodec: Send + Sync {
+    fn encode(&self, entry: &LogEntry, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// Decodes the entry of the frame at `pos`, which errors name.
+    fn decode(&self, buf: &[u8], pos: u32) -> Result<LogEntry>;
+}
+
+/// The BSON document of the entry, with its fields named and tagged by
+/// `cmd`, as the first segments were written.
+pub struct BsonCodec;
+
+impl EntryCodec for BsonCodec {
+    fn encode(&self, entry: &LogEntry, buf: &mut Vec<u8>) -> Result<()> {
+        let doc = bson::to_vec(entry).map_err(|e| SerializeEntry {
+            entry: entry.clone(),
+            source: e,
+        })?;
+        buf.extend_from_slice(&doc);
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8], pos: u32) -> Result<LogEntry> {
+        bson::from_slice(buf).map_err(|e| DeserializeEntry { pos, source: e })
+    }
+}
+
+/// A tag byte, 0 for a set and 1 for a remove, then the key and the value
+/// of a set, each as its length in a LEB128 varint followed by its bytes.
+pub struct BinaryCodec;
+
+impl EntryCodec for BinaryCodec {
+    fn encode(&self, entry: &LogEntry, buf: &mut Vec<u8>) -> Result<()> {
+        match entry {
+            LogEntry::Set { key, val } => {
+                buf.push(TAG_SET);
+                put_bytes(buf, key);
+                put_bytes(buf, val);
+            }
+            LogEntry::Remove { key } => {
+                buf.push(TAG_REMOVE);
+                put_bytes(buf, key);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8], pos: u32) -> Result<LogEntry> {
+        let mut input = Input { buf, pos };
+        let entry = match input.byte()? {
+            TAG_SET => LogEntry::Set {
+                key: input.bytes()?.to_vec(),
+                val: input.bytes()?.to_vec(),
+            },
+            TAG_REMOVE => LogEntry::Remove {
+                key: input.bytes()?.to_vec(),
+            },
+            tag => return Err(input.error(format!("unknown entry tag {}", tag))),
+        };
+        if !input.buf.is_empty() {
+            return Err(input.error(format!("{} bytes after the entry", input.buf.len())));
+        }
+        Ok(entry)
+    }
+}
+
+/// Appends the bytes after their length as a LEB128 varint, as the key and
+/// value of a binary entry and the entries of a block are.
+pub(super) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let mut len = bytes.len() as u64;
+    while len >= 0x80 {
+        buf.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(bytes);
+}
+
+/// Ranges of the byte strings `buf` is made of, each written by
+/// [`put_bytes`]. `pos` is the position of the frame, which errors name.
+pub(super) fn split_bytes(buf: &[u8], pos: u32) -> Result<Vec<Range<usize>>> {
+    let mut input = Input { buf, pos };
+    let mut ranges = Vec::new();
+    while !input.buf.is_empty() {
+        let len = input.bytes()?.len();
+        let end = buf.len() - input.buf.len();
+        ranges.push(end - len..end);
+    }
+    Ok(ranges)
+}
+
+/// The bytes of an entry left to decode.
+struct Input<'a> {
+    buf: &'a [u8],
+    pos: u32,
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let (byte, rest) = self
+            .buf
+            .split_first()
+            .ok_or_else(|| self.error("truncated entry"))?;
+        self.buf = rest;
+        Ok(*byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(self.error("varint longer than 64 bits"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.buf.len())
+            .ok_or_else(|| self.error(format!("length {} past the end of the entry", len)))?;
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn error(&self, msg: impl ToString) -> KvError {
+        DecodeEntry {
+            pos: self.pos,
+            msg: msg.to_string(),
+        }
+    }
+}
+
+/// The codec of a segment, stored as its id in the segment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Bson,
+    Binary,
+}
+
+impl Codec {
+    /// The codec new segments are written with.
+    pub const DEFAULT: Codec = Codec::Binary;
+
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Bson => 0,
+            Codec::Binary => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::Bson),
+            1 => Some(Codec::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Bson => "bson",
+            Codec::Binary => "binary",
+        }
+    }
+
+    pub fn encode(self, entry: &LogEntry, buf: &mut Vec<u8>) -> Result<()> {
+        self.entry_codec().encode(entry, buf)
+    }
+
+    pub fn decode(self, buf: &[u8], pos: u32) -> Result<LogEntry> {
+        self.entry_codec().decode(buf, pos)
+    }
+
+    fn entry_codec(self) -> &'static dyn EntryCodec {
+        match self {
+            Codec::Bson => &BsonCodec,
+            Codec::Binary => &BinaryCodec,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Codec> {
+        match s {
+            "bson" => Ok(Codec::Bson),
+            "binary" => Ok(Codec::Binary),
+            _ => Err(KvError::InvalidArgument {
+                arg: "codec".to_string(),
+                val: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::server::engine::store::codec::Codec;
+    use crate::kvs::server::engine::store::io::LogEntry;
+
+    fn entries() -> Vec<LogEntry> {
+        vec![
+            LogEntry::Set {
+                key: b"key1".to_vec(),
+                val: b"value1".to_vec(),
+            },
+            LogEntry::Set {
+                key: Vec::new(),
+                val: vec![b'v'; 300],
+            },
+            LogEntry::Set {
+                key: vec![0, 0xff, 0x80],
+                val: vec![0xc3, 0x28],
+            },
+            LogEntry::Remove {
+                key: b"key1".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for codec in &[Codec::Bson, Codec::Binary] {
+            for entry in entries() {
+                let mut buf = Vec::new();
+                codec.encode(&entry, &mut buf).unwrap();
+                assert_eq!(codec.decode(&buf, 0).unwrap(), entry);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bson_strings() {
+        // Entries written before keys and values were bytes.
+        let doc = bson::doc! { "cmd": "Set", "key": "key1", "val": "value1" };
+        let buf = bson::to_vec(&doc).unwrap();
+        assert_eq!(Codec::Bson.decode(&buf, 0).unwrap(), entries()[0]);
+    }
+
+    #[test]
+    fn test_binary_layout() {
+        let mut buf = Vec::new();
+        Codec::Binary.encode(&entries()[1], &mut buf).unwrap();
+        // 300 as a varint, low 7 bits first.
+        assert_eq!(buf[..4], [0, 0, 0xac, 0x02]);
+        assert_eq!(buf.len(), 4 + 300);
+
+        assert_eq!(Codec::Binary.decode(&buf[..100], 0).is_err(), true);
+        buf.push(0);
+        assert_eq!(Codec::Binary.decode(&buf, 0).is_err(), true);
+        assert_eq!(Codec::Binary.decode(&[7], 0).is_err(), true);
+    }
+}
@@ -0,0 +1,115 @@
+//! Compression of the entries of a segment.
+//!
+//! From version 3 on, every frame records the compression of its entry in
+//! the byte after its size, see
+//! [`SEGMENT_FORMAT_VERSION`](crate::kvs::SEGMENT_FORMAT_VERSION), so a
+//! segment may mix compressed and plain frames and stays readable whatever
+//! the options of the store reading it.
+
+use crate::kvs::err::KvError::DecodeEntry;
+use crate::kvs::err::{KvError, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The compression of a frame, stored as its id in the frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Snappy, fast to write and read, for the repeated text of JSON
+    /// values.
+    Snappy,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Snappy),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Snappy => "snappy",
+        }
+    }
+
+    pub fn compress(self, input: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(input.to_vec()),
+            Compression::Snappy => Ok(snap::raw::Encoder::new()
+                .compress_vec(input)
+                .map_err(std::io::Error::from)?),
+        }
+    }
+
+    /// Decompresses the payload of the frame at `pos`, which errors name.
+    pub fn decompress(self, input: Vec<u8>, pos: u32) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(input),
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(&input)
+                .map_err(|e| DecodeEntry {
+                    pos,
+                    msg: format!("snappy: {}", e),
+                }),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(KvError::InvalidArgument {
+                arg: "compression".to_string(),
+                val: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::server::engine::store::compress::Compression;
+
+    #[test]
+    fn test_round_trip() {
+        let input = "{\"name\": \"value\"} ".repeat(100);
+        let compressed = Compression::Snappy.compress(input.as_bytes()).unwrap();
+        assert!(compressed.len() < input.len() / 4);
+        let output = Compression::Snappy.decompress(compressed, 0).unwrap();
+        assert_eq!(output, input.as_bytes());
+
+        assert_eq!(
+            Compression::Snappy.decompress(vec![0xff; 8], 0).is_err(),
+            true
+        );
+        assert_eq!(
+            Compression::from_id(Compression::Snappy.id()),
+            Some(Compression::Snappy)
+        );
+        assert_eq!(
+            "snappy".parse::<Compression>().unwrap(),
+            Compression::Snappy
+        );
+    }
+}
@@ -3,10 +3,11 @@ use walkdir::{DirEntry, WalkDir};
 
 use crate::kvs::err::KvError::{Dir, ParseFileId};
 use crate::kvs::err::Result;
+use crate::kvs::server::engine::store::lock::LOCK_FILE;
 use std::panic::panic_any;
 
 #[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub(super) enum FileId {
+pub enum FileId {
     Compact(u32),
     Append(u32),
     Temp(u32),
@@ -117,7 +118,7 @@ pub(super) fn extract_files(path: impl AsRef<Path>) -> Result<FileExtract> {
 
         let file_name = entry.file_name().to_str().unwrap();
 
-        if entry.file_type().is_dir() {
+        if entry.file_type().is_dir() || file_name == LOCK_FILE {
             continue;
         }
 
@@ -151,6 +152,18 @@ pub(super) fn extract_files(path: impl AsRef<Path>) -> Result<FileExtract> {
     })
 }
 
+/// Total size of the files directly inside `path`.
+pub(super) fn dir_size(path: impl AsRef<Path>) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path.as_ref())? {
+        let meta = entry?.metadata()?;
+        if meta.is_file() {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::kvs::server::engine::store::file::{extract_files, FileId};
@@ -0,0 +1,461 @@
+//! Offline inspection, repair and migration of a
+//! [`KvStore`](crate::kvs::KvStore) data directory, the `kvs_data`
+//! directory of a server.
+//!
+//! The directory lock keeps a store from being opened for writes while it
+//! is inspected, and from being opened at all while it is repaired. Each
+//! call fails with [`KvError::StoreLocked`](crate::kvs::KvError::StoreLocked)
+//! when it cannot take the lock.
+
+use crate::kvs::encoding::Encoding;
+use crate::kvs::err::KvError::CorruptFrame;
+use crate::kvs::err::Result;
+use crate::kvs::server::engine::store::codec::Codec;
+use crate::kvs::server::engine::store::file::{extract_files, FileId};
+use crate::kvs::server::engine::store::io::{
+    LogEntry, LogFrame, LogReader, LogWriter, SEGMENT_FORMAT_VERSION,
+};
+use crate::kvs::server::engine::store::lock::DirLock;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// A segment file of the data directory.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub file_id: FileId,
+    pub bytes: u64,
+}
+
+/// The first frame of a segment that cannot be read, nothing after it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    pub offset: u32,
+    pub msg: String,
+}
+
+/// Result of reading every frame of a segment.
+#[derive(Debug, Clone)]
+pub struct SegmentCheck {
+    pub file_id: FileId,
+    /// Format version of the segment, 0 for the layout without header.
+    pub format_version: u16,
+    pub codec: Codec,
+    pub frames: u64,
+    pub bytes: u64,
+    pub corruption: Option<Corruption>,
+}
+
+/// Live and dead entries of a segment, as the store would see them once
+/// opened. Entries of segments the store does not read are all dead.
+#[derive(Debug, Clone)]
+pub struct SegmentStats {
+    pub file_id: FileId,
+    pub live_keys: u64,
+    pub dead_keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub corruption: Option<Corruption>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirStats {
+    pub segments: Vec<SegmentStats>,
+    pub keys: u64,
+    pub key_sizes: SizeHistogram,
+    pub value_sizes: SizeHistogram,
+    /// Bytes the entries of every segment would take uncompressed.
+    pub raw_bytes: u64,
+    /// Bytes the entries of every segment take.
+    pub stored_bytes: u64,
+}
+
+impl DirStats {
+    /// Uncompressed bytes per stored byte, 1 without entries.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 1.0,
+            stored => self.raw_bytes as f64 / stored as f64,
+        }
+    }
+}
+
+/// Counts of sizes in power of two buckets.
+#[derive(Debug, Clone, Default)]
+pub struct SizeHistogram {
+    buckets: BTreeMap<u64, u64>,
+}
+
+impl SizeHistogram {
+    fn observe(&mut self, size: usize) {
+        *self
+            .buckets
+            .entry((size as u64).next_power_of_two())
+            .or_default() += 1;
+    }
+
+    /// The buckets holding any size, as the bucket's upper bound in bytes
+    /// and the count of sizes above the previous bound and up to it.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets.iter().map(|(bound, count)| (*bound, *count))
+    }
+}
+
+/// The segment files of the directory, oldest first.
+pub fn segments(dir: &Path) -> Result<Vec<Segment>> {
+    let _lock = DirLock::shared(dir)?;
+    list_segments(dir)
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<Segment>> {
+    let extract = extract_files(dir)?;
+    let mut segments = Vec::new();
+    let files = extract
+        .compact_files
+        .iter()
+        .chain(extract.append_files.iter())
+        .chain(extract.temp_files.iter());
+    for file_id in files {
+        let path = dir.join(String::from(file_id));
+        // The append file to create when there is none is listed too.
+        let bytes = match fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        segments.push(Segment {
+            file_id: *file_id,
+            bytes,
+        });
+    }
+    segments.sort_by_key(|segment| (segment.file_id.version(), segment.file_id));
+    Ok(segments)
+}
+
+/// Reads every frame of every segment.
+pub fn verify(dir: &Path) -> Result<Vec<SegmentCheck>> {
+    let _lock = DirLock::shared(dir)?;
+    verify_segments(dir)
+}
+
+fn verify_segments(dir: &Path) -> Result<Vec<SegmentCheck>> {
+    list_segments(dir)?
+        .iter()
+        .map(|segment| read_segment(dir, segment, |_, _| Ok(())))
+        .collect()
+}
+
+/// Writes every frame as a JSON line with its segment, offset and length,
+/// and a line with the error where a segment stops being readable. Keys
+/// and values that are not UTF-8 are written as `{"hex": ...}`.
+pub fn dump(dir: &Path, out: &mut dyn Write) -> Result<Vec<SegmentCheck>> {
+    let _lock = DirLock::shared(dir)?;
+    let mut checks = Vec::new();
+    for segment in list_segments(dir)? {
+        let file = String::from(segment.file_id);
+        let check = read_segment(dir, &segment, |frame, len| {
+            let line = DumpLine {
+                file: &file,
+                offset: frame.offset,
+                len,
+                entry: DumpEntry::from(&frame.entry),
+            };
+            write_json_line(out, &line)
+        })?;
+        if let Some(corruption) = &check.corruption {
+            let line = DumpError {
+                file: &file,
+                offset: corruption.offset,
+                error: &corruption.msg,
+            };
+            write_json_line(out, &line)?;
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+/// Truncates every segment at its first frame that cannot be read,
+/// dropping it and everything after it. Returns the truncated segments as
+/// they were before.
+pub fn repair(dir: &Path) -> Result<Vec<SegmentCheck>> {
+    let _lock = DirLock::exclusive(dir)?;
+    let mut repaired = Vec::new();
+    for check in verify_segments(dir)? {
+        let offset = match &check.corruption {
+            Some(corruption) => corruption.offset,
+            None => continue,
+        };
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.join(String::from(check.file_id)))?;
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
+        repaired.push(check);
+    }
+    Ok(repaired)
+}
+
+/// Rewrites every segment of a format older than the one written in it,
+/// with the default codec, through a temporary file renamed over it.
+/// Leftover temporary segments, which the store never reads, are left
+/// alone. Returns the rewritten segments as they were before. Fails on a
+/// segment that cannot be read whole, which must be repaired first.
+pub fn migrate(dir: &Path) -> Result<Vec<SegmentCheck>> {
+    let _lock = DirLock::exclusive(dir)?;
+    let mut migrated = Vec::new();
+    for segment in list_segments(dir)? {
+        let path = dir.join(String::from(segment.file_id));
+        let name = path.display().to_string();
+        if segment.file_id.is_temp()
+            || LogReader::new(File::open(&path)?, &name)?.version() == SEGMENT_FORMAT_VERSION
+        {
+            continue;
+        }
+
+        let temp = dir.join(String::from(FileId::Temp(segment.file_id.version())));
+        let mut writer = LogWriter::new(File::create(&temp)?, Codec::DEFAULT)?;
+        let check = read_segment(dir, &segment, |frame, _| writer.write(frame.entry));
+        let check = match check {
+            Ok(check) if check.corruption.is_none() => check,
+            Ok(check) => {
+                fs::remove_file(&temp)?;
+                let corruption = check.corruption.unwrap();
+                return Err(CorruptFrame {
+                    pos: corruption.offset,
+                    msg: format!("{}: {}, repair it first", name, corruption.msg),
+                });
+            }
+            Err(e) => {
+                fs::remove_file(&temp)?;
+                return Err(e);
+            }
+        };
+        writer.sync()?;
+        fs::rename(&temp, &path)?;
+        migrated.push(check);
+    }
+    Ok(migrated)
+}
+
+/// Replays the segments the store reads when opened, the last compacted
+/// and the last append one, to tell live entries from dead ones.
+pub fn stats(dir: &Path) -> Result<DirStats> {
+    let _lock = DirLock::shared(dir)?;
+    let segments = list_segments(dir)?;
+    let last_compact = segments.iter().rposition(|s| s.file_id.is_compacted());
+    let last_append = segments.iter().rposition(|s| s.file_id.is_append());
+
+    let mut stats: Vec<SegmentStats> = segments
+        .iter()
+        .map(|segment| SegmentStats {
+            file_id: segment.file_id,
+            live_keys: 0,
+            dead_keys: 0,
+            live_bytes: 0,
+            dead_bytes: 0,
+            corruption: None,
+        })
+        .collect();
+    // Segment, frame length and value size of the entry of every live key.
+    let mut live: HashMap<Vec<u8>, (usize, u32, usize)> = HashMap::new();
+    let (mut raw_bytes, mut stored_bytes) = (0, 0);
+
+    for (i, segment) in segments.iter().enumerate() {
+        let replayed = Some(i) == last_compact || Some(i) == last_append;
+        let check = read_segment(dir, segment, |frame, len| {
+            raw_bytes += frame.raw_len as u64;
+            stored_bytes += len as u64;
+            let (key, val_size) = match frame.entry {
+                LogEntry::Set { key, val } if replayed => (key, Some(val.len())),
+                LogEntry::Remove { key } if replayed => (key, None),
+                _ => {
+                    stats[i].dead_keys += 1;
+                    stats[i].dead_bytes += len as u64;
+                    return Ok(());
+                }
+            };
+            if let Some((old, old_len, _)) = live.remove(&key) {
+                stats[old].live_keys -= 1;
+                stats[old].live_bytes -= old_len as u64;
+                stats[old].dead_keys += 1;
+                stats[old].dead_bytes += old_len as u64;
+            }
+            match val_size {
+                Some(val_size) => {
+                    stats[i].live_keys += 1;
+                    stats[i].live_bytes += len as u64;
+                    live.insert(key, (i, len, val_size));
+                }
+                None => {
+                    stats[i].dead_keys += 1;
+                    stats[i].dead_bytes += len as u64;
+                }
+            }
+            Ok(())
+        })?;
+        stats[i].corruption = check.corruption;
+    }
+
+    let mut key_sizes = SizeHistogram::default();
+    let mut value_sizes = SizeHistogram::default();
+    for (key, (_, _, val_size)) in &live {
+        key_sizes.observe(key.len());
+        value_sizes.observe(*val_size);
+    }
+
+    Ok(DirStats {
+        segments: stats,
+        keys: live.len() as u64,
+        key_sizes,
+        value_sizes,
+        raw_bytes,
+        stored_bytes,
+    })
+}
+
+#[derive(Serialize)]
+struct DumpLine<'a> {
+    file: &'a str,
+    offset: u32,
+    len: u32,
+    #[serde(flatten)]
+    entry: DumpEntry<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "cmd")]
+enum DumpEntry<'a> {
+    Set { key: DumpBytes<'a>, val: DumpBytes<'a> },
+    Remove { key: DumpBytes<'a> },
+}
+
+impl<'a> From<&'a LogEntry> for DumpEntry<'a> {
+    fn from(entry: &'a LogEntry) -> DumpEntry<'a> {
+        match entry {
+            LogEntry::Set { key, val } => DumpEntry::Set {
+                key: DumpBytes(key),
+                val: DumpBytes(val),
+            },
+            LogEntry::Remove { key } => DumpEntry::Remove { key: DumpBytes(key) },
+        }
+    }
+}
+
+/// A string when the bytes are UTF-8, their hex digits under `hex` when not.
+struct DumpBytes<'a>(&'a [u8]);
+
+impl Serialize for DumpBytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match std::str::from_utf8(self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => {
+                let hex = Encoding::Hex.encode(self.0).unwrap_or_default();
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("hex", &hex)?;
+                map.end()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DumpError<'a> {
+    file: &'a str,
+    offset: u32,
+    error: &'a str,
+}
+
+fn write_json_line(out: &mut dyn Write, line: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut *out, line).map_err(std::io::Error::from)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Reads the frames of the segment in order, passing each with its length
+/// to `visit`, up to the end of the file or the first frame that cannot be
+/// read.
+fn read_segment(
+    dir: &Path,
+    segment: &Segment,
+    mut visit: impl FnMut(LogFrame, u32) -> Result<()>,
+) -> Result<SegmentCheck> {
+    let path = dir.join(String::from(segment.file_id));
+    let mut check = SegmentCheck {
+        file_id: segment.file_id,
+        format_version: 0,
+        codec: Codec::Bson,
+        frames: 0,
+        bytes: segment.bytes,
+        corruption: None,
+    };
+    let mut reader = match LogReader::new(File::open(&path)?, &path.display().to_string()) {
+        Ok(reader) => reader,
+        Err(CorruptFrame { pos, msg }) => {
+            check.corruption = Some(Corruption { offset: pos, msg });
+            return Ok(check);
+        }
+        Err(e) => return Err(e),
+    };
+    check.format_version = reader.version();
+    check.codec = reader.codec();
+
+    let mut pos = reader.pos();
+    while (pos as u64) < segment.bytes {
+        let frame = match read_frame(&mut reader, pos, segment.bytes) {
+            Ok(frame) => frame,
+            Err(e) => {
+                check.corruption = Some(Corruption {
+                    offset: pos,
+                    msg: e.to_string(),
+                });
+                break;
+            }
+        };
+        check.frames += 1;
+        let len = frame.len;
+        visit(frame, len)?;
+        // The other entries of a block, read with it.
+        loop {
+            let frame = match reader.next_in_block() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    check.corruption = Some(Corruption {
+                        offset: pos,
+                        msg: e.to_string(),
+                    });
+                    return Ok(check);
+                }
+            };
+            check.frames += 1;
+            let len = frame.len;
+            visit(frame, len)?;
+        }
+        pos = reader.pos();
+    }
+    Ok(check)
+}
+
+/// Reads the frame at `pos`, checking its size against the file's before
+/// reading its entry, the first of a block.
+fn read_frame(reader: &mut LogReader<File>, pos: u32, end: u64) -> Result<LogFrame> {
+    let header_end = pos as u64 + reader.header_size() as u64;
+    if header_end > end {
+        return Err(CorruptFrame {
+            pos,
+            msg: format!("truncated header, the file ends at {}", end),
+        });
+    }
+    let size = reader.frame_size(pos)?;
+    if header_end + size as u64 > end {
+        return Err(CorruptFrame {
+            pos,
+            msg: format!("entry of {} bytes past the end of the file at {}", size, end),
+        });
+    }
+    reader.read_at(pos, 0)
+}
@@ -1,43 +1,191 @@
-use crate::kvs::err::KvError::{DeserializeEntry, Io, SerializeEntry};
+use crate::kvs::err::KvError::{DecodeEntry, Io};
 use crate::kvs::err::Result;
+use crate::kvs::server::engine::store::codec::{put_bytes, split_bytes, Codec};
+use crate::kvs::server::engine::store::compress::Compression;
 use std::convert::TryInto;
+use std::ops::Range;
 use std::path::Path;
 
 use crate::kvs::err::KvError;
 use serde::{Deserialize, Serialize};
-use std::fs::ReadDir;
+use std::fs::{File, ReadDir};
 use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 
+/// Size of the size of a frame, the whole frame header up to version 2.
 const FRAME_HEADER_SIZE: usize = 4;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// First bytes of every segment written with a header.
+pub const SEGMENT_MAGIC: [u8; 4] = *b"KVSG";
+
+/// Size of the segment header: the magic number, the format version as a
+/// big-endian `u16`, the id of the [`Codec`] of the entries and a byte
+/// reserved as zero.
+pub const SEGMENT_HEADER_SIZE: usize = 8;
+
+/// Version of the segments written. Segments without a header are read as
+/// version 0, the headerless layout written before, whose frames are the
+/// same as those of version 1. Both encode their entries in BSON, version 2
+/// records the codec in the header, where version 1 has a zero. Version 3
+/// follows the size of every frame with a kind byte, the id of the
+/// [`Compression`] of its payload, with the high bit set when the payload
+/// is a block of entries, each as its length in a LEB128 varint followed by
+/// its bytes.
+pub const SEGMENT_FORMAT_VERSION: u16 = 3;
+
+/// First version whose frames have a kind byte.
+const KIND_VERSION: u16 = 3;
+
+/// Bit of the kind byte of a frame holding a block of entries.
+const BLOCK_FLAG: u8 = 0x80;
+
+/// Uncompressed size from which a block of entries is written.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// A write of the log. Keys and values are arbitrary bytes, serialized as
+/// BSON binaries, and read from the strings of entries written before.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "cmd")]
 pub enum LogEntry {
-    Set { key: String, val: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        val: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
 }
 
 #[derive(Debug)]
 pub(super) struct LogFrame {
     pub entry: LogEntry,
     pub offset: u32,
+    /// Position of the entry in the block of its frame, 0 for a frame of a
+    /// single entry.
+    pub index: u32,
+    /// Bytes of the segment taken by the entry, for the entries of a block
+    /// a share of the frame in proportion to their size.
+    pub len: u32,
+    /// Bytes the entry would take in an uncompressed frame of its own.
+    pub raw_len: u32,
 }
 
 pub(super) struct LogReader<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u32,
+    version: u16,
+    codec: Codec,
+    /// The block read last, kept for the reads of its other entries.
+    block: Option<Block>,
+}
+
+/// The decompressed payload of a block frame.
+struct Block {
+    offset: u32,
+    end: u32,
+    data: Vec<u8>,
+    entries: Vec<Range<usize>>,
+    lens: Vec<u32>,
+    /// Index of the entry [`LogReader::next_in_block`] returns.
+    next: usize,
+}
+
+impl Block {
+    fn new(offset: u32, end: u32, data: Vec<u8>) -> Result<Block> {
+        let entries = split_bytes(&data, offset)?;
+        let total = (end - offset) as u64;
+        let raw = entries.iter().map(|range| range.len() as u64).sum::<u64>();
+        let mut lens: Vec<u32> = entries
+            .iter()
+            .map(|range| (total * range.len() as u64 / raw.max(1)) as u32)
+            .collect();
+        let shared: u32 = lens.iter().sum();
+        if let Some(last) = lens.last_mut() {
+            *last += total as u32 - shared;
+        }
+        Ok(Block {
+            offset,
+            end,
+            data,
+            entries,
+            lens,
+            next: 0,
+        })
+    }
+
+    fn entry(&self, index: u32, codec: Codec, header_size: usize) -> Result<LogFrame> {
+        let range = self
+            .entries
+            .get(index as usize)
+            .ok_or_else(|| DecodeEntry {
+                pos: self.offset,
+                msg: format!("no entry {} in a block of {}", index, self.entries.len()),
+            })?;
+        Ok(LogFrame {
+            entry: codec.decode(&self.data[range.clone()], self.offset)?,
+            offset: self.offset,
+            index,
+            len: self.lens[index as usize],
+            raw_len: (header_size + range.len()) as u32,
+        })
+    }
 }
 
 impl<R: Read + Seek> LogReader<R> {
-    pub(super) fn new(reader: R) -> LogReader<R> {
-        LogReader {
-            reader: BufReader::new(reader),
-            pos: 0,
+    /// Reads the header of the segment, `name` names it in errors. Fails
+    /// with [`KvError::UnsupportedFormat`] on versions newer than the one
+    /// written.
+    pub(super) fn new(reader: R, name: &str) -> Result<LogReader<R>> {
+        let mut reader = BufReader::new(reader);
+        let (version, codec_id) = read_header(&mut reader)?;
+        if version > SEGMENT_FORMAT_VERSION {
+            return Err(KvError::UnsupportedFormat {
+                path: name.to_string(),
+                version: version as u32,
+                supported: SEGMENT_FORMAT_VERSION as u32,
+            });
         }
+        let codec = match version {
+            0 | 1 => Codec::Bson,
+            _ => Codec::from_id(codec_id).ok_or_else(|| KvError::UnknownCodec {
+                path: name.to_string(),
+                codec: codec_id,
+            })?,
+        };
+        let pos = match version {
+            0 => 0,
+            _ => SEGMENT_HEADER_SIZE as u32,
+        };
+        Ok(LogReader {
+            reader,
+            pos,
+            version,
+            codec,
+            block: None,
+        })
+    }
+
+    /// Format version of the segment, 0 for a segment without header.
+    pub(super) fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub(super) fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Size of the header of the frames of the segment.
+    pub(super) fn header_size(&self) -> usize {
+        frame_header_size(self.version)
     }
 
     pub(super) fn read_next(&mut self) -> Result<Option<LogFrame>> {
-        match self.read_pos(self.pos) {
+        if let Some(frame) = self.next_in_block()? {
+            return Ok(Some(frame));
+        }
+        match self.read_at(self.pos, 0) {
             Ok(f) => Ok(Some(f)),
             Err(e) => match e {
                 KvError::Io(io_err) => match io_err.kind() {
@@ -49,27 +197,90 @@ impl<R: Read + Seek> LogReader<R> {
         }
     }
 
-    pub(super) fn read_pos(&mut self, pos: u32) -> Result<LogFrame> {
+    /// The entry after the one read last in its block, while that block is
+    /// the frame read last.
+    pub(super) fn next_in_block(&mut self) -> Result<Option<LogFrame>> {
+        let (codec, header_size) = (self.codec, self.header_size());
+        let block = match self.block.as_mut() {
+            Some(block) if block.end == self.pos && block.next < block.entries.len() => block,
+            _ => return Ok(None),
+        };
+        let index = block.next as u32;
+        block.next += 1;
+        block.entry(index, codec, header_size).map(Some)
+    }
+
+    /// Reads the entry at `index` in the frame at `pos`, 0 for a frame of a
+    /// single entry.
+    pub(super) fn read_at(&mut self, pos: u32, index: u32) -> Result<LogFrame> {
+        let (codec, header_size) = (self.codec, self.header_size());
+        if let Some(block) = self.block.as_mut().filter(|block| block.offset == pos) {
+            block.next = index as usize + 1;
+            self.pos = block.end;
+            return block.entry(index, codec, header_size);
+        }
+
         self.seek_pos(pos)?;
 
-        let size = self.read_size()?;
+        let (size, kind) = self.read_frame_header()?;
 
         let mut vec: Vec<u8> = vec![0; size as usize];
         self.read_exact(vec.as_mut_slice())?;
 
-        self.pos = pos + FRAME_HEADER_SIZE as u32 + size;
+        self.pos = pos + header_size as u32 + size;
+
+        let compression = Compression::from_id(kind & !BLOCK_FLAG).ok_or_else(|| DecodeEntry {
+            pos,
+            msg: format!("unknown compression {}", kind & !BLOCK_FLAG),
+        })?;
+        let data = compression.decompress(vec, pos)?;
 
-        let entry_res = bson::from_slice(vec.as_slice());
-        match entry_res {
-            Ok(entry) => Ok(LogFrame { offset: pos, entry }),
-            Err(err) => Err(DeserializeEntry { pos, source: err }),
+        if kind & BLOCK_FLAG != 0 {
+            let mut block = Block::new(pos, self.pos, data)?;
+            block.next = index as usize + 1;
+            let frame = block.entry(index, codec, header_size);
+            self.block = Some(block);
+            return frame;
         }
+        if index != 0 {
+            return Err(DecodeEntry {
+                pos,
+                msg: format!("no entry {} in a frame of one", index),
+            });
+        }
+        let entry = codec.decode(data.as_slice(), pos)?;
+        Ok(LogFrame {
+            entry,
+            offset: pos,
+            index: 0,
+            len: header_size as u32 + size,
+            raw_len: (header_size + data.len()) as u32,
+        })
     }
 
     pub(super) fn pos(&self) -> u32 {
         self.pos
     }
 
+    /// Size of the payload of the frame at `pos`, read from its header
+    /// alone.
+    pub(super) fn frame_size(&mut self, pos: u32) -> Result<u32> {
+        self.seek_pos(pos)?;
+        self.read_size()
+    }
+
+    /// Size and kind byte of the frame the reader is at, the kind of a
+    /// frame of an uncompressed entry before version 3.
+    fn read_frame_header(&mut self) -> Result<(u32, u8)> {
+        let size = self.read_size()?;
+        if self.version < KIND_VERSION {
+            return Ok((size, 0));
+        }
+        let mut kind = [0u8; 1];
+        self.read_exact(&mut kind)?;
+        Ok((size, kind[0]))
+    }
+
     fn read_size(&mut self) -> Result<u32> {
         let mut buf = [0u8; FRAME_HEADER_SIZE];
         self.read_exact(&mut buf).map(|_| u32::from_be_bytes(buf))
@@ -88,32 +299,131 @@ impl<R: Read + Seek> LogReader<R> {
     }
 }
 
+/// Size of the header of the frames of a segment of `version`.
+fn frame_header_size(version: u16) -> usize {
+    match version < KIND_VERSION {
+        true => FRAME_HEADER_SIZE,
+        false => FRAME_HEADER_SIZE + 1,
+    }
+}
+
+/// Format version and codec id of the segment read by `reader`, seeked back
+/// to its start. A segment not starting with [`SEGMENT_MAGIC`] has no
+/// header, legacy frames start with their size, which never comes near.
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<(u16, u8)> {
+    let mut buf = [0u8; SEGMENT_HEADER_SIZE];
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(Io(e)),
+        }
+    }
+    reader.seek(SeekFrom::Start(0)).map_err(|e| Io(e))?;
+
+    if read < SEGMENT_MAGIC.len() || buf[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC {
+        return Ok((0, 0));
+    }
+    if read < SEGMENT_HEADER_SIZE {
+        return Err(KvError::CorruptFrame {
+            pos: 0,
+            msg: "truncated segment header".to_string(),
+        });
+    }
+    Ok((u16::from_be_bytes([buf[4], buf[5]]), buf[6]))
+}
+
 pub(super) struct LogWriter<W: Write> {
     writer: BufWriter<W>,
     pos: u32,
+    version: u16,
+    codec: Codec,
+    compression: Compression,
+    /// Entries encoded in fewer bytes are written uncompressed.
+    threshold: usize,
+    /// Entries encoded and not written yet, when written in blocks.
+    block: Option<Vec<u8>>,
+    /// Reused to encode every entry.
+    buf: Vec<u8>,
 }
 
 impl<W: Write> LogWriter<W> {
-    pub(super) fn new(writer: W) -> LogWriter<W> {
+    /// Starts a new segment, writing its header.
+    pub(super) fn new(writer: W, codec: Codec) -> Result<LogWriter<W>> {
+        let mut writer = LogWriter::append(writer, 0, SEGMENT_FORMAT_VERSION, codec);
+        let mut header = [0u8; SEGMENT_HEADER_SIZE];
+        header[..SEGMENT_MAGIC.len()].copy_from_slice(&SEGMENT_MAGIC);
+        header[4..6].copy_from_slice(&SEGMENT_FORMAT_VERSION.to_be_bytes());
+        header[6] = codec.id();
+        writer.writer.write_all(&header).map_err(|e| Io(e))?;
+        writer.writer.flush().map_err(|e| Io(e))?;
+        writer.pos = SEGMENT_HEADER_SIZE as u32;
+        Ok(writer)
+    }
+
+    /// Goes on with a segment of `pos` bytes written in the frames of
+    /// `version` with `codec`, `writer` must be at its end.
+    pub(super) fn append(writer: W, pos: u32, version: u16, codec: Codec) -> LogWriter<W> {
         LogWriter {
             writer: BufWriter::new(writer),
-            pos: 0,
+            pos,
+            version,
+            codec,
+            compression: Compression::None,
+            threshold: 0,
+            block: None,
+            buf: Vec::new(),
         }
     }
 
-    pub(super) fn write(&mut self, entry: LogEntry) -> Result<()> {
-        let entry_buf = bson::to_vec(&entry).map_err(|e| SerializeEntry { entry, source: e })?;
+    /// Compresses every entry encoded in `threshold` bytes or more, when
+    /// that makes it smaller. Segments older than version 3 have no room to
+    /// record it and stay uncompressed.
+    pub(super) fn compressed(mut self, compression: Compression, threshold: usize) -> Self {
+        if self.version >= KIND_VERSION {
+            self.compression = compression;
+            self.threshold = threshold;
+        }
+        self
+    }
 
-        let len = entry_buf.len() as u32;
+    /// Writes the entries in blocks, each compressed whole, of about
+    /// [`BLOCK_SIZE`] bytes. The last block is only written by
+    /// [`finish`](LogWriter::finish), which suits segments written at once
+    /// like compacted ones. Segments older than version 3 have no blocks.
+    pub(super) fn blocks(mut self, compression: Compression) -> Self {
+        if self.version >= KIND_VERSION {
+            self.compression = compression;
+            self.block = Some(Vec::new());
+        }
+        self
+    }
+
+    pub(super) fn write(&mut self, entry: LogEntry) -> Result<()> {
+        self.buf.clear();
+        self.codec.encode(&entry, &mut self.buf)?;
+
+        if let Some(block) = self.block.as_mut() {
+            put_bytes(block, &self.buf);
+            if block.len() >= BLOCK_SIZE {
+                self.write_block()?;
+            }
+            return Ok(());
+        }
 
-        self.write_size(len)?;
+        let compressed = match self.buf.len() >= self.threshold {
+            true => compress(self.compression, &self.buf)?,
+            false => None,
+        };
+        let kind = compressed.as_ref().map_or(0, |_| self.compression.id());
+        let payload = compressed.as_deref().unwrap_or(&self.buf);
 
-        self.writer.write(entry_buf.as_slice()).map_err(|e| Io(e))?;
+        self.pos += write_frame(&mut self.writer, self.version, kind, payload)?;
 
         self.writer.flush().map_err(|e| Io(e))?;
 
-        self.pos += FRAME_HEADER_SIZE as u32 + len;
-
         Ok(())
     }
 
@@ -121,19 +431,63 @@ impl<W: Write> LogWriter<W> {
         self.pos
     }
 
-    fn write_size(&mut self, size: u32) -> Result<()> {
-        let mut buf: [u8; FRAME_HEADER_SIZE] = size.to_be_bytes();
-        let res = self.writer.write(&mut buf);
-        match res {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Io(e)),
-        }
+    /// Writes the pending block, if any, and flushes buffered bytes.
+    pub(super) fn finish(&mut self) -> Result<()> {
+        self.write_block()?;
+        self.writer.flush().map_err(|e| Io(e))
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        let block = match self.block.as_mut() {
+            Some(block) if !block.is_empty() => block,
+            _ => return Ok(()),
+        };
+        let compressed = compress(self.compression, block)?;
+        let kind = BLOCK_FLAG | compressed.as_ref().map_or(0, |_| self.compression.id());
+        let payload = compressed.as_deref().unwrap_or(block.as_slice());
+        self.pos += write_frame(&mut self.writer, self.version, kind, payload)?;
+        block.clear();
+        Ok(())
+    }
+}
+
+impl LogWriter<File> {
+    /// Writes the pending block, flushes buffered bytes and waits until the
+    /// file reaches the disk.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.finish()?;
+        self.writer.get_ref().sync_all().map_err(|e| Io(e))
+    }
+}
+
+/// `buf` compressed, unless that does not make it smaller.
+fn compress(compression: Compression, buf: &[u8]) -> Result<Option<Vec<u8>>> {
+    if compression == Compression::None {
+        return Ok(None);
+    }
+    let compressed = compression.compress(buf)?;
+    Ok(Some(compressed).filter(|compressed| compressed.len() < buf.len()))
+}
+
+/// Writes a frame of `payload` in the layout of `version`, returning its
+/// length.
+fn write_frame<W: Write>(writer: &mut W, version: u16, kind: u8, payload: &[u8]) -> Result<u32> {
+    let size: [u8; FRAME_HEADER_SIZE] = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&size).map_err(|e| Io(e))?;
+    if version >= KIND_VERSION {
+        writer.write_all(&[kind]).map_err(|e| Io(e))?;
     }
+    writer.write_all(payload).map_err(|e| Io(e))?;
+    Ok((frame_header_size(version) + payload.len()) as u32)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::kvs::server::engine::store::io::{LogEntry, LogReader, LogWriter};
+    use crate::kvs::server::engine::store::codec::Codec;
+    use crate::kvs::server::engine::store::compress::Compression;
+    use crate::kvs::server::engine::store::io::{
+        LogEntry, LogReader, LogWriter, SEGMENT_FORMAT_VERSION, SEGMENT_HEADER_SIZE, SEGMENT_MAGIC,
+    };
     use std::io::{Cursor, Read, Write};
 
     struct WriteBuffer {
@@ -164,13 +518,14 @@ mod tests {
     #[test]
     fn test_reader() {
         let expected_entry = LogEntry::Set {
-            key: "key1".to_string(),
-            val: "val".to_string(),
+            key: b"key1".to_vec(),
+            val: b"val".to_vec(),
         };
         let buf = serialize_entry(&expected_entry);
         let entry_size = buf.len() as u32;
 
-        let mut reader = LogReader::new(Cursor::new(buf));
+        let mut reader = LogReader::new(Cursor::new(buf), "test").unwrap();
+        assert_eq!(reader.version(), 0);
 
         let res = reader.read_next();
 
@@ -178,8 +533,8 @@ mod tests {
         assert_eq!(frame.offset, 0);
 
         if let LogEntry::Set { key, val } = frame.entry {
-            assert_eq!(key, "key1");
-            assert_eq!(val, "val");
+            assert_eq!(key, b"key1");
+            assert_eq!(val, b"val");
         } else {
             unreachable!()
         }
@@ -188,28 +543,123 @@ mod tests {
     #[test]
     fn test_writer() {
         let entry = LogEntry::Set {
-            key: "key1".to_string(),
-            val: "val".to_string(),
+            key: b"key1".to_vec(),
+            val: b"val".to_vec(),
         };
 
         let mut write_buf = WriteBuffer::new();
         {
-            let mut writer = LogWriter::new(&mut write_buf);
+            let mut writer = LogWriter::new(&mut write_buf, Codec::Bson).unwrap();
             let res = writer.write(entry);
         }
 
         let buf = write_buf.buf();
+        assert_eq!(buf[..4], SEGMENT_MAGIC);
+        assert_eq!(buf[4..6], SEGMENT_FORMAT_VERSION.to_be_bytes());
+        assert_eq!(buf[6], Codec::Bson.id());
 
-        let result_entry = deserialize_entry(buf);
+        let result_entry = deserialize_entry(&buf[SEGMENT_HEADER_SIZE..]);
 
         if let LogEntry::Set { key, val } = result_entry {
-            assert_eq!(key, "key1");
-            assert_eq!(val, "val");
+            assert_eq!(key, b"key1");
+            assert_eq!(val, b"val");
         } else {
             unreachable!()
         }
     }
 
+    #[test]
+    fn test_reader_header() {
+        let mut write_buf = WriteBuffer::new();
+        {
+            let mut writer = LogWriter::new(&mut write_buf, Codec::DEFAULT).unwrap();
+            writer
+                .write(LogEntry::Remove {
+                    key: b"key1".to_vec(),
+                })
+                .unwrap();
+        }
+        let mut buf = write_buf.buf().to_vec();
+
+        let mut reader = LogReader::new(Cursor::new(buf.clone()), "test").unwrap();
+        assert_eq!(reader.version(), SEGMENT_FORMAT_VERSION);
+        assert_eq!(reader.codec(), Codec::DEFAULT);
+        let frame = reader.read_next().unwrap().unwrap();
+        assert_eq!(frame.offset, SEGMENT_HEADER_SIZE as u32);
+        assert_eq!(reader.read_next().unwrap().is_none(), true);
+
+        buf[4..6].copy_from_slice(&(SEGMENT_FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(LogReader::new(Cursor::new(buf), "test").is_err(), true);
+    }
+
+    #[test]
+    fn test_compressed_frames() {
+        let small = LogEntry::Set {
+            key: b"key1".to_vec(),
+            val: b"val".to_vec(),
+        };
+        let large = LogEntry::Set {
+            key: b"key2".to_vec(),
+            val: "{\"name\": \"value\"} ".repeat(50).into_bytes(),
+        };
+        let mut write_buf = WriteBuffer::new();
+        {
+            let mut writer = LogWriter::new(&mut write_buf, Codec::DEFAULT)
+                .unwrap()
+                .compressed(Compression::Snappy, 64);
+            writer.write(small.clone()).unwrap();
+            writer.write(large.clone()).unwrap();
+        }
+
+        let mut reader = LogReader::new(Cursor::new(write_buf.buf().to_vec()), "test").unwrap();
+        let frame = reader.read_next().unwrap().unwrap();
+        assert_eq!(frame.entry, small);
+        assert_eq!(frame.len, frame.raw_len);
+        let frame = reader.read_next().unwrap().unwrap();
+        assert_eq!(frame.entry, large);
+        assert_eq!(frame.len < frame.raw_len / 4, true);
+        assert_eq!(reader.read_next().unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let entries: Vec<LogEntry> = (0..3)
+            .map(|i| LogEntry::Set {
+                key: format!("key{}", i).into_bytes(),
+                val: "value ".repeat(20 * (i + 1)).into_bytes(),
+            })
+            .collect();
+        let mut write_buf = WriteBuffer::new();
+        {
+            let mut writer = LogWriter::new(&mut write_buf, Codec::DEFAULT)
+                .unwrap()
+                .blocks(Compression::Snappy);
+            for entry in &entries {
+                writer.write(entry.clone()).unwrap();
+            }
+            assert_eq!(writer.pos(), SEGMENT_HEADER_SIZE as u32);
+            writer.finish().unwrap();
+        }
+        let buf = write_buf.buf().to_vec();
+
+        let mut reader = LogReader::new(Cursor::new(buf.clone()), "test").unwrap();
+        let mut len = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            let frame = reader.read_next().unwrap().unwrap();
+            assert_eq!(frame.entry, *entry);
+            assert_eq!(frame.offset, SEGMENT_HEADER_SIZE as u32);
+            assert_eq!(frame.index, i as u32);
+            len += frame.len;
+        }
+        assert_eq!(reader.read_next().unwrap().is_none(), true);
+        assert_eq!(len as usize, buf.len() - SEGMENT_HEADER_SIZE);
+
+        let mut reader = LogReader::new(Cursor::new(buf), "test").unwrap();
+        let frame = reader.read_at(SEGMENT_HEADER_SIZE as u32, 1).unwrap();
+        assert_eq!(frame.entry, entries[1]);
+        assert_eq!(reader.read_at(SEGMENT_HEADER_SIZE as u32, 3).is_err(), true);
+    }
+
     fn serialize_entry(entry: &LogEntry) -> Vec<u8> {
         let mut entry_bytes = bson::to_vec(&entry).unwrap();
         let size = entry_bytes.len() as u32;
@@ -228,6 +678,10 @@ mod tests {
 
         let size = u32::from_be_bytes(size_buf);
 
+        let mut kind_buf = [0u8; 1];
+        cursor.read_exact(&mut kind_buf).unwrap();
+        assert_eq!(kind_buf[0], Compression::None.id());
+
         let mut entry_buf = vec![0u8; size as usize];
         cursor.read_exact(&mut entry_buf).unwrap();
 
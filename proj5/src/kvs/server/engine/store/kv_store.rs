@@ -5,10 +5,17 @@ use std::collections::{BTreeMap, HashMap};
 use super::file;
 use crate::kvs::err::KvError;
 use crate::kvs::err::KvError::Io;
-use crate::kvs::server::engine::store::file::{extract_files, FileExtract, FileId};
+use crate::kvs::metrics::{Counter, Histogram, MetricsWriter};
+use crate::kvs::server::engine::admin::{EngineStats, KvsAdmin};
+use crate::kvs::server::engine::backup::{self, BackupMeta};
+use crate::kvs::server::engine::store::codec::Codec;
+use crate::kvs::server::engine::store::compress::Compression;
+use crate::kvs::server::engine::store::file::{dir_size, extract_files, FileExtract, FileId};
 use crate::kvs::server::engine::store::io::{LogEntry, LogReader, LogWriter};
+use crate::kvs::server::engine::store::lock::DirLock;
 use crate::kvs::server::engine::KvsEngine;
-use crate::kvs::thread_pool::ThreadPool;
+use crate::kvs::server::slots::key_slot;
+use crate::kvs::thread_pool::{NaiveThreadPool, ThreadPool};
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
 use futures::future::BoxFuture;
@@ -16,24 +23,72 @@ use futures::FutureExt;
 use slog::Logger;
 use std::fs::{File, OpenOptions};
 use std::future::Future;
-use std::ops::Deref;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::{Bound, Deref};
 use std::panic::resume_unwind;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::oneshot::{channel, Sender};
 
 const DUPLICATE_COUNT_THRESHOLD: u32 = 1000;
 
+const DEFAULT_COMPRESS_THRESHOLD: usize = 1024;
+
+/// Compression of the segments a store writes. Segments are read whatever
+/// they were written with.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreOptions {
+    /// Compression of the appended entries and of the compacted blocks.
+    pub compression: Compression,
+    /// Appended entries encoded in fewer bytes are written uncompressed.
+    pub compress_threshold: usize,
+    /// Writes compacted segments in compressed blocks of entries rather
+    /// than entry by entry, no effect without a compression.
+    pub block_compression: bool,
+}
+
+impl StoreOptions {
+    /// The options as recorded in the metadata of a server directory.
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        let mut map = BTreeMap::new();
+        map.insert("compression".to_string(), self.compression.to_string());
+        map.insert(
+            "compress_threshold".to_string(),
+            self.compress_threshold.to_string(),
+        );
+        map.insert(
+            "block_compression".to_string(),
+            self.block_compression.to_string(),
+        );
+        map
+    }
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            compression: Compression::None,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            block_compression: false,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct KvStore<P: ThreadPool> {
     store: Arc<SharedKvStore>,
     pool: P,
+    /// Held by the handles alone, a pool thread done with a job must not
+    /// keep the directory locked after the store is dropped.
+    _lock: Arc<DirLock>,
 }
 
 struct SharedKvStore {
-    mem_table: SkipMap<String, TableEntry>,
+    root_path: PathBuf,
+    mem_table: SkipMap<Vec<u8>, TableEntry>,
     readers: ArrayQueue<KvStoreReader>,
     writer: SharedKvStoreWriter,
 }
@@ -47,26 +102,99 @@ struct KvStoreReader {
 
 struct KvStoreWriter {
     current_file: FileId,
-    writer: LogWriter<File>,
+    options: StoreOptions,
+    /// `None` for a read-only store.
+    writer: Option<LogWriter<File>>,
     duplicate_count: u32,
+    segments: Segments,
+    compactions: Counter,
+    compaction_duration: Histogram,
 }
 
 #[derive(Debug, Copy, Clone)]
 struct TableEntry {
     file_id: FileId,
     offset: u32,
+    /// Position of the entry in the block at `offset`.
+    index: u32,
+    len: u32,
+}
+
+/// Written and still referenced bytes of every segment file.
+#[derive(Default)]
+struct Segments(BTreeMap<FileId, SegmentStats>);
+
+#[derive(Default, Debug, Copy, Clone)]
+struct SegmentStats {
+    bytes: u64,
+    live_bytes: u64,
+}
+
+impl Segments {
+    fn written(&mut self, file_id: FileId, len: u32, live: bool) {
+        let stats = self.0.entry(file_id).or_default();
+        stats.bytes += len as u64;
+        if live {
+            stats.live_bytes += len as u64;
+        }
+    }
+
+    fn overwritten(&mut self, entry: &TableEntry) {
+        if let Some(stats) = self.0.get_mut(&entry.file_id) {
+            stats.live_bytes = stats.live_bytes.saturating_sub(entry.len as u64);
+        }
+    }
+}
+
+impl KvStoreWriter {
+    fn log(&mut self) -> Result<&mut LogWriter<File>> {
+        self.writer.as_mut().ok_or(KvError::StoreReadOnly)
+    }
 }
 
 impl<P: ThreadPool> KvStore<P> {
+    /// Opens the store for reads and writes. Fails with
+    /// [`KvError::StoreLocked`] while another store has the directory open.
     pub fn open(path: impl Into<PathBuf>, thread_size: u32) -> Result<KvStore<P>> {
+        KvStore::open_with(path, thread_size, StoreOptions::default())
+    }
+
+    /// Opens the store for reads and writes, writing segments as `options`
+    /// says.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        thread_size: u32,
+        options: StoreOptions,
+    ) -> Result<KvStore<P>> {
         let path = path.into();
+        let lock = DirLock::exclusive(path.as_path())?;
+        KvStore::open_locked(path, thread_size, lock, Some(options))
+    }
+
+    /// Opens the store for reads alone, alongside other read-only stores.
+    /// Writes and compactions fail with [`KvError::StoreReadOnly`].
+    pub fn open_read_only(path: impl Into<PathBuf>, thread_size: u32) -> Result<KvStore<P>> {
+        let path = path.into();
+        let lock = DirLock::shared(path.as_path())?;
+        KvStore::open_locked(path, thread_size, lock, None)
+    }
 
+    /// Opens the store for writes with `options`, for reads alone without.
+    fn open_locked(
+        path: PathBuf,
+        thread_size: u32,
+        lock: DirLock,
+        options: Option<StoreOptions>,
+    ) -> Result<KvStore<P>> {
         let file_extract = extract_files(path.as_path())?;
-        let writer = prepare_writer(&file_extract, path.as_path())?;
+        let mut writer = prepare_writer(&file_extract, path.as_path(), options)?;
         let mut readers = prepare_readers(&file_extract, path.as_path())?;
-        let table = prepare_table(&mut readers)?;
+        let mut segments = Segments::default();
+        let table = prepare_table(&mut readers, &mut segments)?;
         let pool = P::new(thread_size)?;
 
+        writer.segments = segments;
+
         let readers = ArrayQueue::new(thread_size as usize);
 
         for _ in 0..thread_size {
@@ -78,18 +206,20 @@ impl<P: ThreadPool> KvStore<P> {
 
         Ok(KvStore {
             store: Arc::new(SharedKvStore {
+                root_path: path,
                 mem_table: table,
                 readers,
                 writer: SharedKvStoreWriter(Mutex::new(writer)),
             }),
             pool,
+            _lock: Arc::new(lock),
         })
     }
 }
 
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
-    fn get(&self, key: String) -> BoxFuture<Result<Option<String>>> {
-        let (sender, receiver) = channel::<Result<Option<String>>>();
+    fn get_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<Option<Vec<u8>>>> {
+        let (sender, receiver) = channel::<Result<Option<Vec<u8>>>>();
 
         let store = self.store.clone();
 
@@ -103,7 +233,7 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         receiver.map(|res| res.unwrap()).boxed()
     }
 
-    fn set(&self, key: String, value: String) -> BoxFuture<Result<()>> {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<Result<()>> {
         let (sender, receiver) = channel::<Result<()>>();
 
         let store = self.store.clone();
@@ -118,7 +248,7 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         receiver.map(|res| res.unwrap()).boxed()
     }
 
-    fn remove(&self, key: String) -> BoxFuture<Result<()>> {
+    fn remove_bytes(&self, key: Vec<u8>) -> BoxFuture<Result<()>> {
         let (sender, receiver) = channel::<Result<()>>();
 
         let store = self.store.clone();
@@ -132,13 +262,231 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
 
         receiver.map(|res| res.unwrap()).boxed()
     }
+
+    fn scan_bytes(&self, prefix: Vec<u8>) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let reader = store.readers.pop().unwrap();
+            let result = do_scan(&store.mem_table, &reader, &prefix);
+            store.readers.push(reader);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_range(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let reader = store.readers.pop().unwrap();
+            let result = do_scan_range(&store.mem_table, &reader, &start, end.as_deref());
+            store.readers.push(reader);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_after(
+        &self,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let reader = store.readers.pop().unwrap();
+            let result = do_scan_after(&store.mem_table, &reader, after.as_deref(), limit);
+            store.readers.push(reader);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn scan_slot(
+        &self,
+        slot: u16,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> BoxFuture<Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let (sender, receiver) = channel::<Result<Vec<(Vec<u8>, Vec<u8>)>>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let reader = store.readers.pop().unwrap();
+            let result = do_scan_slot(&store.mem_table, &reader, slot, after.as_deref(), limit);
+            store.readers.push(reader);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn write_metrics(&self, out: &mut MetricsWriter) {
+        out.gauge(
+            "kvs_keydir_keys",
+            "Number of keys in the in-memory key directory.",
+            self.store.mem_table.len() as f64,
+        );
+
+        {
+            let writer = self.store.writer.0.lock().unwrap();
+            let segments: Vec<(String, SegmentStats)> = writer
+                .segments
+                .0
+                .iter()
+                .map(|(file_id, stats)| (file_id.into(), *stats))
+                .collect();
+
+            out.gauge_series(
+                "kvs_segment_live_bytes",
+                "Bytes of a segment still referenced by the key directory.",
+                "segment",
+                segments
+                    .iter()
+                    .map(|(name, stats)| (name.as_str(), stats.live_bytes as f64)),
+            );
+            out.gauge_series(
+                "kvs_segment_dead_bytes",
+                "Bytes of a segment reclaimable by compaction.",
+                "segment",
+                segments.iter().map(|(name, stats)| {
+                    (name.as_str(), (stats.bytes - stats.live_bytes) as f64)
+                }),
+            );
+            out.counter(
+                "kvs_compactions_total",
+                "Compactions since the store was opened.",
+                &writer.compactions,
+            );
+            out.histogram(
+                "kvs_compaction_duration_seconds",
+                "Duration of compactions.",
+                &writer.compaction_duration,
+            );
+        }
+
+        out.gauge(
+            "kvs_thread_pool_queued_jobs",
+            "Jobs waiting for a thread pool worker.",
+            self.pool.queued_jobs() as f64,
+        );
+    }
+
+    fn admin(&self) -> Option<&dyn KvsAdmin> {
+        Some(self)
+    }
+}
+
+impl<P: ThreadPool> KvsAdmin for KvStore<P> {
+    fn engine_name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn data_dir(&self) -> Option<&Path> {
+        Some(self.store.root_path.as_path())
+    }
+
+    fn stats(&self) -> BoxFuture<Result<EngineStats>> {
+        let (sender, receiver) = channel::<Result<EngineStats>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let result = dir_size(store.root_path.as_path()).map(|disk_bytes| {
+                let writer = store.writer.0.lock().unwrap();
+                let bytes: u64 = writer.segments.0.values().map(|s| s.bytes).sum();
+                let live_bytes: u64 = writer.segments.0.values().map(|s| s.live_bytes).sum();
+                let current_file: String = writer.current_file.into();
+                EngineStats {
+                    keys: store.mem_table.len() as u64,
+                    disk_bytes,
+                    details: vec![
+                        ("current_file".to_string(), current_file),
+                        ("segments".to_string(), writer.segments.0.len().to_string()),
+                        ("live_bytes".to_string(), live_bytes.to_string()),
+                        ("dead_bytes".to_string(), (bytes - live_bytes).to_string()),
+                        (
+                            "duplicate_count".to_string(),
+                            writer.duplicate_count.to_string(),
+                        ),
+                        (
+                            "compactions".to_string(),
+                            writer.compactions.get().to_string(),
+                        ),
+                    ],
+                }
+            });
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn compact(&self) -> BoxFuture<Result<()>> {
+        let (sender, receiver) = channel::<Result<()>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let reader = store.readers.pop().unwrap();
+            let result = {
+                let mut writer = store.writer.0.lock().unwrap();
+                compact(&store.mem_table, &reader, &mut writer)
+            };
+            store.readers.push(reader);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<Result<()>> {
+        let (sender, receiver) = channel::<Result<()>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            let mut writer = store.writer.0.lock().unwrap();
+            let result = writer.writer.as_mut().map_or(Ok(()), LogWriter::sync);
+            sender.send(result).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
+
+    fn backup(&self, dest: PathBuf) -> BoxFuture<Result<BackupMeta>> {
+        let (sender, receiver) = channel::<Result<BackupMeta>>();
+
+        let store = self.store.clone();
+
+        self.pool.spawn(move || {
+            sender.send(do_backup(&store, &dest)).unwrap();
+        });
+
+        receiver.map(|res| res.unwrap()).boxed()
+    }
 }
 
 fn do_get(
-    mem_table: &SkipMap<String, TableEntry>,
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
     reader: &KvStoreReader,
-    key: String,
-) -> Result<Option<String>> {
+    key: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
     let entry = match mem_table.get(&key) {
         Some(entry) => entry,
         None => return Ok(None),
@@ -146,31 +494,126 @@ fn do_get(
     read_entry(reader, *entry.value())
 }
 
+fn do_scan(
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
+    reader: &KvStoreReader,
+    prefix: &[u8],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    let range = (Bound::Included(prefix), Bound::Unbounded);
+    for pair in mem_table.range::<[u8], _>(range) {
+        if !pair.key().starts_with(prefix) {
+            break;
+        }
+        if let Some(val) = read_entry(reader, *pair.value())? {
+            pairs.push((pair.key().clone(), val));
+        }
+    }
+    Ok(pairs)
+}
+
+fn do_scan_range(
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
+    reader: &KvStoreReader,
+    start: &[u8],
+    end: Option<&[u8]>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    let range = (Bound::Included(start), Bound::Unbounded);
+    for pair in mem_table.range::<[u8], _>(range) {
+        if matches!(end, Some(end) if pair.key().as_slice() >= end) {
+            break;
+        }
+        if let Some(val) = read_entry(reader, *pair.value())? {
+            pairs.push((pair.key().clone(), val));
+        }
+    }
+    Ok(pairs)
+}
+
+fn do_scan_after(
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
+    reader: &KvStoreReader,
+    after: Option<&[u8]>,
+    limit: usize,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    let start = match after {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    for pair in mem_table.range::<[u8], _>((start, Bound::Unbounded)) {
+        if pairs.len() >= limit {
+            break;
+        }
+        if let Some(val) = read_entry(reader, *pair.value())? {
+            pairs.push((pair.key().clone(), val));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Keys are hashed while walking the key directory, only the values of
+/// the slot's keys are read.
+fn do_scan_slot(
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
+    reader: &KvStoreReader,
+    slot: u16,
+    after: Option<&[u8]>,
+    limit: usize,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = Vec::new();
+    let start = match after {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    for pair in mem_table.range::<[u8], _>((start, Bound::Unbounded)) {
+        if pairs.len() >= limit {
+            break;
+        }
+        if key_slot(pair.key()) != slot {
+            continue;
+        }
+        if let Some(val) = read_entry(reader, *pair.value())? {
+            pairs.push((pair.key().clone(), val));
+        }
+    }
+    Ok(pairs)
+}
+
 fn do_set(
-    mem_table: &SkipMap<String, TableEntry>,
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
     reader: &KvStoreReader,
     writer: &SharedKvStoreWriter,
-    key: String,
-    value: String,
+    key: Vec<u8>,
+    value: Vec<u8>,
 ) -> Result<()> {
     let mut writer = writer.0.lock().unwrap();
 
-    let offset = writer.writer.pos();
+    let log = writer.log()?;
+    let offset = log.pos();
 
-    writer.writer.write(LogEntry::Set {
+    log.write(LogEntry::Set {
         key: key.clone(),
         val: value,
     })?;
 
-    if mem_table.contains_key(&key) {
+    let len = log.pos() - offset;
+    let file_id = writer.current_file;
+    writer.segments.written(file_id, len, true);
+
+    if let Some(old) = mem_table.get(&key) {
         writer.duplicate_count += 1;
+        writer.segments.overwritten(old.value());
     }
 
     mem_table.insert(
         key,
         TableEntry {
-            file_id: writer.current_file,
+            file_id,
             offset,
+            index: 0,
+            len,
         },
     );
 
@@ -182,20 +625,27 @@ fn do_set(
 }
 
 fn do_remove(
-    mem_table: &SkipMap<String, TableEntry>,
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
     reader: &KvStoreReader,
     writer: &SharedKvStoreWriter,
-    key: String,
+    key: Vec<u8>,
 ) -> Result<()> {
     let mut writer = writer.0.lock().unwrap();
 
-    writer.writer.write(LogEntry::Remove { key: key.clone() })?;
+    let log = writer.log()?;
+    let offset = log.pos();
+
+    log.write(LogEntry::Remove { key: key.clone() })?;
+
+    let len = log.pos() - offset;
+    let file_id = writer.current_file;
+    writer.segments.written(file_id, len, false);
 
     writer.duplicate_count += 1;
 
     let res = mem_table
         .remove(&key)
-        .map(|e| ())
+        .map(|e| writer.segments.overwritten(e.value()))
         .ok_or(KvError::KeyNotFound);
 
     if writer.duplicate_count >= DUPLICATE_COUNT_THRESHOLD {
@@ -206,20 +656,22 @@ fn do_remove(
 }
 
 fn compact(
-    mem_table: &SkipMap<String, TableEntry>,
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
     reader: &KvStoreReader,
     writer: &mut KvStoreWriter,
 ) -> Result<()> {
+    writer.log()?;
+    let started = Instant::now();
+
     let file_id = FileId::Compact(writer.current_file.version());
-    write_compact_file(mem_table, reader, &file_id);
+    write_compact_file(mem_table, reader, &file_id, &writer.options)?;
 
     let mut file_reader = open_reader(&file_id, &reader.root_path)?;
 
-    fill_table_from(&mem_table, file_id, &mut file_reader)?;
+    let mut segments = Segments::default();
+    fill_table_from(&mem_table, &mut segments, file_id, &mut file_reader)?;
 
-    for kv in reader.readers.borrow().deref() {
-        remove_file(kv.0, &reader.root_path);
-    }
+    remove_stale_files(&file_id, &reader.root_path)?;
 
     let mut readers = reader.readers.borrow_mut();
 
@@ -228,21 +680,30 @@ fn compact(
 
     let append_file_id = FileId::Append(file_id.version() + 1);
 
-    let log_writer = open_writer(&append_file_id, &reader.root_path)?;
+    let log_writer = open_writer(&append_file_id, &reader.root_path, &writer.options)?;
 
-    writer.writer = log_writer;
+    writer.writer = Some(log_writer);
     writer.current_file = append_file_id;
     writer.duplicate_count = 0;
+    writer.segments = segments;
+    writer.compactions.inc();
+    writer.compaction_duration.observe(started.elapsed());
 
     Ok(())
 }
 
 fn write_compact_file(
-    mem_table: &SkipMap<String, TableEntry>,
+    mem_table: &SkipMap<Vec<u8>, TableEntry>,
     reader: &KvStoreReader,
     file_id: &FileId,
+    options: &StoreOptions,
 ) -> Result<()> {
-    let mut writer = open_writer(file_id, reader.root_path.as_path())?;
+    let writer = create_writer(file_id, reader.root_path.as_path())?;
+    let blocks = options.block_compression && options.compression != Compression::None;
+    let mut writer = match blocks {
+        true => writer.blocks(options.compression),
+        false => writer.compressed(options.compression, options.compress_threshold),
+    };
 
     for pair in mem_table.iter() {
         let val = match read_entry(reader, *pair.value())? {
@@ -253,13 +714,13 @@ fn write_compact_file(
             key: (*pair.key()).clone(),
             val,
         };
-        writer.write(entry);
+        writer.write(entry)?;
     }
 
-    Ok(())
+    writer.sync()
 }
 
-fn read_entry(reader: &KvStoreReader, entry: TableEntry) -> Result<Option<String>> {
+fn read_entry(reader: &KvStoreReader, entry: TableEntry) -> Result<Option<Vec<u8>>> {
     let mut readers = reader.readers.borrow_mut();
 
     if !readers.contains_key(&entry.file_id) {
@@ -270,25 +731,175 @@ fn read_entry(reader: &KvStoreReader, entry: TableEntry) -> Result<Option<String
     let mut reader = readers.get_mut(&entry.file_id).unwrap();
 
     reader
-        .read_pos(entry.offset)
+        .read_at(entry.offset, entry.index)
         .map(|frame| match frame.entry {
             LogEntry::Set { val, .. } => Some(val),
             _ => None,
         })
 }
 
+/// Passes every pair whose key starts with `prefix` to `visit`, ordered by
+/// key, reading the values one at a time. Returns how many there were.
+pub(crate) fn export_pairs(
+    path: &Path,
+    prefix: &[u8],
+    mut visit: impl FnMut(Vec<u8>, Vec<u8>) -> Result<()>,
+) -> Result<u64> {
+    let store = KvStore::<NaiveThreadPool>::open_read_only(path, 1)?;
+    let reader = store.store.readers.pop().unwrap();
+    let mut count = 0;
+    let range = (Bound::Included(prefix), Bound::Unbounded);
+    for pair in store.store.mem_table.range::<[u8], _>(range) {
+        if !pair.key().starts_with(prefix) {
+            break;
+        }
+        if let Some(val) = read_entry(&reader, *pair.value())? {
+            visit(pair.key().clone(), val)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes the pairs straight to the compacted segment of a directory
+/// without segments, instead of appending and indexing them one by one.
+/// The last pair of a key wins, the others are left out of the segment.
+/// Returns how many there were.
+pub(crate) fn import_pairs(
+    path: &Path,
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+) -> Result<u64> {
+    std::fs::create_dir_all(path)?;
+    let _lock = DirLock::exclusive(path)?;
+    if extract_files(path)?.last_version != 0 {
+        return Err(KvError::InvalidArgument {
+            arg: "dir".to_string(),
+            val: format!("{} already holds segments", path.display()),
+        });
+    }
+
+    let temp = FileId::Temp(1);
+    let deduped = FileId::Temp(2);
+    let res = write_import(&temp, path, pairs)
+        .and_then(|(count, last)| write_last_pairs(&temp, &deduped, path, &last).map(|()| count));
+    let removed = remove_file(&temp, path);
+    let count = match res {
+        Ok(count) => count,
+        Err(e) => {
+            let _ = remove_file(&deduped, path);
+            return Err(e);
+        }
+    };
+    removed?;
+    let compacted: String = FileId::Compact(1).into();
+    std::fs::rename(path.join(String::from(deduped)), path.join(compacted))?;
+    Ok(count)
+}
+
+/// Writes every pair, returns how many there were and the position of the
+/// last pair of each key.
+fn write_import(
+    file_id: &FileId,
+    path: &Path,
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+) -> Result<(u64, HashMap<Vec<u8>, u64>)> {
+    let mut writer = create_writer(file_id, path)?;
+    let mut last = HashMap::new();
+    let mut count = 0;
+    for pair in pairs {
+        let (key, val) = pair?;
+        last.insert(key.clone(), count);
+        writer.write(LogEntry::Set { key, val })?;
+        count += 1;
+    }
+    writer.sync()?;
+    Ok((count, last))
+}
+
+/// Copies the pairs of `from` found at their key's `last` position to `to`.
+fn write_last_pairs(
+    from: &FileId,
+    to: &FileId,
+    path: &Path,
+    last: &HashMap<Vec<u8>, u64>,
+) -> Result<()> {
+    let mut reader = open_reader(from, path)?;
+    let mut writer = create_writer(to, path)?;
+    let mut position = 0;
+    while let Some(frame) = reader.read_next()? {
+        if let LogEntry::Set { key, val } = frame.entry {
+            if last.get(&key) == Some(&position) {
+                writer.write(LogEntry::Set { key, val })?;
+            }
+        }
+        position += 1;
+    }
+    writer.sync()
+}
+
+/// Links the immutable segments and opens the append file while holding
+/// the writer, so no write or compaction happens meanwhile, then copies the
+/// append file up to its length at that time while writes go on.
+fn do_backup(store: &SharedKvStore, dest: &Path) -> Result<BackupMeta> {
+    backup::prepare_dest(dest, Some(store.root_path.as_path()))?;
+
+    let (append, keys) = {
+        let writer = store.writer.0.lock().unwrap();
+        // A read-only store has no file being appended to.
+        let current = writer.writer.as_ref().map(|_| writer.current_file);
+        let extract = extract_files(store.root_path.as_path())?;
+        let immutable = extract
+            .compact_files
+            .iter()
+            .chain(extract.append_files.iter())
+            .filter(|file_id| Some(**file_id) != current && file_exists(file_id, &store.root_path));
+        for file_id in immutable {
+            link_or_copy(file_id, &store.root_path, dest)?;
+        }
+        let append = match current {
+            Some(file_id) => {
+                let append_file: String = file_id.into();
+                let file = File::open(store.root_path.join(&append_file))?;
+                let len = file.metadata()?.len();
+                Some((append_file, file, len))
+            }
+            None => None,
+        };
+        (append, store.mem_table.len() as u64)
+    };
+
+    if let Some((append_file, file, len)) = append {
+        let mut copy = File::create(dest.join(&append_file))?;
+        std::io::copy(&mut file.take(len), &mut copy)?;
+        copy.sync_all()?;
+    }
+
+    backup::finish(dest, "kvs", keys)
+}
+
+fn link_or_copy(file_id: &FileId, root_path: &Path, dest: &Path) -> Result<()> {
+    let file_str: String = file_id.into();
+    let (from, to) = (root_path.join(&file_str), dest.join(&file_str));
+    if std::fs::hard_link(&from, &to).is_err() {
+        std::fs::copy(&from, &to)?;
+    }
+    Ok(())
+}
+
 fn prepare_table(
     readers: &mut BTreeMap<FileId, LogReader<File>>,
-) -> Result<SkipMap<String, TableEntry>> {
+    segments: &mut Segments,
+) -> Result<SkipMap<Vec<u8>, TableEntry>> {
     let table = SkipMap::new();
     for pair in readers {
-        fill_table_from(&table, *pair.0, pair.1)?;
+        fill_table_from(&table, segments, *pair.0, pair.1)?;
     }
     Ok(table)
 }
 
 fn fill_table_from(
-    table: &SkipMap<String, TableEntry>,
+    table: &SkipMap<Vec<u8>, TableEntry>,
+    segments: &mut Segments,
     file_id: FileId,
     reader: &mut LogReader<File>,
 ) -> Result<()> {
@@ -297,18 +908,28 @@ fn fill_table_from(
             Some(frame) => frame,
             None => return Ok(()),
         };
+        let len = frame.len;
         match frame.entry {
             LogEntry::Set { key, .. } => {
+                segments.written(file_id, len, true);
+                if let Some(old) = table.get(&key) {
+                    segments.overwritten(old.value());
+                }
                 table.insert(
                     key,
                     TableEntry {
                         file_id,
                         offset: frame.offset,
+                        index: frame.index,
+                        len,
                     },
                 );
             }
             LogEntry::Remove { key } => {
-                table.remove(&key);
+                segments.written(file_id, len, false);
+                if let Some(old) = table.remove(&key) {
+                    segments.overwritten(old.value());
+                }
             }
         };
     }
@@ -333,6 +954,10 @@ fn prepare_readers(
     }
 
     for file in files {
+        // The append file of a read-only store may not be created yet.
+        if !file_exists(file, path) {
+            continue;
+        }
         match open_reader(file, path) {
             Ok(reader) => {
                 readers.insert(file.clone(), reader);
@@ -343,41 +968,103 @@ fn prepare_readers(
     Ok(readers)
 }
 
-fn prepare_writer(extract: &FileExtract, path: &Path) -> Result<KvStoreWriter> {
+fn prepare_writer(
+    extract: &FileExtract,
+    path: &Path,
+    options: Option<StoreOptions>,
+) -> Result<KvStoreWriter> {
     let file_id = extract
         .append_files
         .get(extract.append_files.len() - 1)
         .unwrap();
 
-    open_writer(file_id, path).map(|w| KvStoreWriter {
+    let writer = match &options {
+        Some(options) => Some(open_writer(file_id, path, options)?),
+        None => None,
+    };
+
+    Ok(KvStoreWriter {
         current_file: *file_id,
-        writer: w,
+        options: options.unwrap_or_default(),
+        writer,
         duplicate_count: 0,
+        segments: Segments::default(),
+        compactions: Counter::default(),
+        compaction_duration: Histogram::default(),
     })
 }
 
+fn file_exists(file_id: &FileId, root_path: &Path) -> bool {
+    let file_str: String = file_id.into();
+    root_path.join(Path::new(&file_str)).exists()
+}
+
 fn open_reader(file_id: &FileId, root_path: &Path) -> Result<LogReader<File>> {
     let file_str: String = file_id.into();
     let file_path = root_path.join(Path::new(&file_str));
     match File::open(file_path.as_path()) {
-        Ok(f) => Ok(LogReader::new(f)),
+        Ok(f) => LogReader::new(f, &file_path.display().to_string()),
         Err(e) => Err(Io(e)),
     }
 }
 
-fn open_writer(file_id: &FileId, root_path: &Path) -> Result<LogWriter<File>> {
+/// Opens the segment to append to it, starting it if it is empty.
+fn open_writer(
+    file_id: &FileId,
+    root_path: &Path,
+    options: &StoreOptions,
+) -> Result<LogWriter<File>> {
     let file_str: String = file_id.into();
     let file_path = root_path.join(Path::new(&file_str));
 
-    let file_res = OpenOptions::new()
+    let mut file = OpenOptions::new()
+        .read(true)
         .write(true)
         .create(true)
-        .open(file_path.as_path());
+        .open(file_path.as_path())
+        .map_err(|e| Io(e))?;
+
+    let len = file.metadata()?.len();
+    let writer = match len {
+        0 => LogWriter::new(file, Codec::DEFAULT)?,
+        _ => {
+            // Refuses to append to a segment of a newer format, and goes on
+            // in the frames and codec of an older one.
+            let (version, codec) = {
+                let reader = LogReader::new(&mut file, &file_path.display().to_string())?;
+                (reader.version(), reader.codec())
+            };
+            file.seek(SeekFrom::End(0))?;
+            LogWriter::append(file, len as u32, version, codec)
+        }
+    };
+    Ok(writer.compressed(options.compression, options.compress_threshold))
+}
 
-    match file_res {
-        Ok(f) => Ok(LogWriter::new(f)),
-        Err(e) => Err(Io(e)),
+/// Starts the segment over, dropping whatever an interrupted write left.
+fn create_writer(file_id: &FileId, root_path: &Path) -> Result<LogWriter<File>> {
+    let file_str: String = file_id.into();
+    let file_path = root_path.join(Path::new(&file_str));
+
+    let file = File::create(file_path.as_path()).map_err(|e| Io(e))?;
+    LogWriter::new(file, Codec::DEFAULT)
+}
+
+/// Removes every file superseded by the `compacted` one, including files
+/// without live entries that no reader has opened.
+fn remove_stale_files(compacted: &FileId, root_path: &Path) -> Result<()> {
+    let extract = extract_files(root_path)?;
+    let stale = extract
+        .append_files
+        .iter()
+        .chain(extract.compact_files.iter())
+        .chain(extract.temp_files.iter())
+        .filter(|file_id| *file_id != compacted && file_id.version() <= compacted.version());
+
+    for file_id in stale {
+        remove_file(file_id, root_path)?;
     }
+    Ok(())
 }
 
 fn remove_file(file_id: &FileId, root_path: &Path) -> Result<()> {
@@ -0,0 +1,78 @@
+use crate::kvs::err::{KvError, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Name of the lock file of a data directory, never a segment.
+pub(super) const LOCK_FILE: &str = "LOCK";
+
+/// Advisory `flock` on the lock file of a data directory, released when
+/// dropped or when the process exits.
+///
+/// A store open for writes holds it exclusively and writes its pid to the
+/// file, so others failing to lock can name it. Stores open read-only and
+/// tools only reading segments share it.
+pub(super) struct DirLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl DirLock {
+    pub(super) fn exclusive(dir: &Path) -> Result<DirLock> {
+        let mut lock = DirLock::lock(dir, true)?;
+        lock.file.set_len(0)?;
+        write!(lock.file, "{}", std::process::id())?;
+        lock.file.sync_all()?;
+        Ok(lock)
+    }
+
+    pub(super) fn shared(dir: &Path) -> Result<DirLock> {
+        DirLock::lock(dir, false)
+    }
+
+    fn lock(dir: &Path, exclusive: bool) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        let op = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // Safety: the descriptor is owned by `file`, open for the call.
+        let res = unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+            return Err(KvError::StoreLocked {
+                path: dir.display().to_string(),
+                pid: holder_pid(&mut file),
+            });
+        }
+        Ok(DirLock { file, exclusive })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // The pid of a process gone would name the wrong holder later.
+        if self.exclusive {
+            let _ = self.file.set_len(0);
+        }
+    }
+}
+
+/// Pid written by the exclusive holder, none for shared holders.
+fn holder_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
@@ -1,3 +1,9 @@
+pub mod codec;
+pub mod compress;
 mod file;
+pub mod inspect;
 pub mod io;
 pub mod kv_store;
+mod lock;
+
+pub use file::FileId;
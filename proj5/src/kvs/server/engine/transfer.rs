@@ -0,0 +1,256 @@
+//! Logical export and import of the pairs of an engine data directory, to
+//! move data between engines and environments.
+//!
+//! Pairs are written as JSON Lines, one `{"key": ..., "value": ...}` object
+//! per line, or as CSV with a `key,value` header and fields quoted as in
+//! RFC 4180. Keys and values are written as text, or in hex or base64 for
+//! data that is not UTF-8, see [`Encoding`].
+//!
+//! Both directions stream: exports read the values one at a time, imports
+//! write the pairs as they are parsed, through a fast path of each engine
+//! instead of one `set` per pair. A kvs directory is locked as by a store
+//! open read-only for exports and for writes for imports, no server may
+//! have a sled directory open.
+
+use crate::kvs::encoding::Encoding;
+use crate::kvs::err::{KvError, Result};
+use crate::kvs::server::engine::sled_eng;
+use crate::kvs::server::engine::store::kv_store;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairFormat {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for PairFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<PairFormat> {
+        match s {
+            "jsonl" => Ok(PairFormat::JsonLines),
+            "csv" => Ok(PairFormat::Csv),
+            _ => Err(KvError::InvalidArgument {
+                arg: "format".to_string(),
+                val: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
+
+/// Writes the pairs of the `engine` data directory whose key starts with
+/// `prefix`, ordered by key. The prefix is given in `encoding` as well.
+/// Returns how many were written.
+pub fn export(
+    engine: &str,
+    dir: &Path,
+    prefix: &str,
+    format: PairFormat,
+    encoding: Encoding,
+    out: &mut dyn Write,
+) -> Result<u64> {
+    if !matches!(engine, "kvs" | "sled") {
+        return Err(unknown_engine(engine));
+    }
+    let prefix = encoding.decode(prefix)?;
+    if format == PairFormat::Csv {
+        out.write_all(b"key,value\n")?;
+    }
+    let visit = |key: Vec<u8>, value: Vec<u8>| {
+        let key = encoding.encode(&key)?;
+        let value = encoding.encode(&value)?;
+        write_pair(format, out, key, value)
+    };
+    let count = match engine {
+        "kvs" => kv_store::export_pairs(dir, &prefix, visit)?,
+        "sled" => sled_eng::export_pairs(dir, &prefix, visit)?,
+        _ => return Err(unknown_engine(engine)),
+    };
+    out.flush()?;
+    Ok(count)
+}
+
+/// Loads the pairs read from `input` into the `engine` data directory.
+/// A kvs directory must not hold any segment yet, a sled one may hold
+/// pairs, which imported ones replace. Returns how many were read.
+pub fn import(
+    engine: &str,
+    dir: &Path,
+    format: PairFormat,
+    encoding: Encoding,
+    input: &mut dyn BufRead,
+) -> Result<u64> {
+    let pairs = PairReader::new(format, encoding, input);
+    match engine {
+        "kvs" => kv_store::import_pairs(dir, pairs),
+        "sled" => sled_eng::import_pairs(dir, pairs),
+        _ => Err(unknown_engine(engine)),
+    }
+}
+
+fn write_pair(format: PairFormat, out: &mut dyn Write, key: String, value: String) -> Result<()> {
+    match format {
+        PairFormat::JsonLines => {
+            let pair = JsonPair { key, value };
+            serde_json::to_writer(&mut *out, &pair).map_err(std::io::Error::from)?;
+        }
+        PairFormat::Csv => {
+            write_csv_field(out, &key)?;
+            out.write_all(b",")?;
+            write_csv_field(out, &value)?;
+        }
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_csv_field(out: &mut dyn Write, field: &str) -> Result<()> {
+    if field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+    } else {
+        out.write_all(field.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Pairs parsed from an export, stopping after the first error.
+struct PairReader<'a> {
+    format: PairFormat,
+    encoding: Encoding,
+    input: &'a mut dyn BufRead,
+    line: u64,
+    failed: bool,
+}
+
+impl<'a> PairReader<'a> {
+    fn new(format: PairFormat, encoding: Encoding, input: &'a mut dyn BufRead) -> PairReader<'a> {
+        PairReader {
+            format,
+            encoding,
+            input,
+            line: 0,
+            failed: false,
+        }
+    }
+
+    /// The pair as stored, decoded from its fields.
+    fn decode(&self, key: &str, value: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        let key = self.encoding.decode(key).map_err(|e| self.error(e))?;
+        let value = self.encoding.decode(value).map_err(|e| self.error(e))?;
+        Ok((key, value))
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> Result<bool> {
+        self.line += 1;
+        Ok(self.input.read_line(buf)? > 0)
+    }
+
+    fn read_json(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut buf = String::new();
+        loop {
+            if !self.read_line(&mut buf)? {
+                return Ok(None);
+            }
+            if !buf.trim().is_empty() {
+                break;
+            }
+            buf.clear();
+        }
+        let pair: JsonPair = serde_json::from_str(&buf).map_err(|e| self.error(e))?;
+        self.decode(&pair.key, &pair.value).map(Some)
+    }
+
+    fn read_csv(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let record = match self.read_csv_record()? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            if self.line == 1 && record == ["key", "value"] {
+                continue;
+            }
+            let mut fields = record.into_iter();
+            return match (fields.next(), fields.next(), fields.next()) {
+                (Some(key), Some(value), None) => self.decode(&key, &value).map(Some),
+                _ => Err(self.error("expected 2 fields")),
+            };
+        }
+    }
+
+    /// Fields of the next record, which spans several lines when quoted
+    /// fields hold line breaks.
+    fn read_csv_record(&mut self) -> Result<Option<Vec<String>>> {
+        let mut buf = String::new();
+        if !self.read_line(&mut buf)? {
+            return Ok(None);
+        }
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            let mut chars = buf.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if quoted => quoted = false,
+                    '"' if field.is_empty() => quoted = true,
+                    ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                    '\n' | '\r' if !quoted => {}
+                    c => field.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+            buf.clear();
+            if !self.read_line(&mut buf)? {
+                return Err(self.error("unterminated quoted field"));
+            }
+        }
+        fields.push(field);
+        Ok(Some(fields))
+    }
+
+    fn error(&self, msg: impl ToString) -> KvError {
+        KvError::InvalidArgument {
+            arg: format!("line {}", self.line),
+            val: msg.to_string(),
+        }
+    }
+}
+
+impl Iterator for PairReader<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let pair = match self.format {
+            PairFormat::JsonLines => self.read_json(),
+            PairFormat::Csv => self.read_csv(),
+        };
+        self.failed = pair.is_err();
+        pair.transpose()
+    }
+}
+
+fn unknown_engine(engine: &str) -> KvError {
+    KvError::InvalidArgument {
+        arg: "engine".to_string(),
+        val: engine.to_string(),
+    }
+}
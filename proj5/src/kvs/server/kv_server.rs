@@ -1,37 +1,456 @@
-use crate::kvs::net::Command;
-use crate::kvs::net::{read, write, CommandResult};
-use crate::kvs::server::conn_handler::ConnectionHandler;
-use crate::kvs::thread_pool::ThreadPool;
-use crate::kvs::{KvsEngine, Result};
-use slog::{error, info, o, trace, Logger};
-use std::io::{Read, Write};
+use crate::kvs::metrics::MetricsWriter;
+use crate::kvs::net::{read_async_max, write_async, Command, CommandResult};
+use crate::kvs::server::auth::Credentials;
+use crate::kvs::server::conn_handler::{ConnectionHandler, MAX_UNAUTHENTICATED_FRAME_SIZE};
+use crate::kvs::server::limits::{Limiter, ServerLimits};
+use crate::kvs::server::metrics_http::serve_metrics;
+use crate::kvs::server::raft::{ClusterConfig, RaftNode};
+use crate::kvs::server::replication::{self, ReplicaStatus, Replication, ReplicationLog};
+use crate::kvs::server::server_metrics::{MeteredStream, ServerMetrics};
+use crate::kvs::server::slots::{SlotConfig, SlotTable};
+use crate::kvs::server::txn::{TimestampOracle, TxnStore};
+use crate::kvs::{KvError, KvsAddr, KvsClientBuilder, KvsEngine, KvsStream, Result, ServerTls};
+use slog::{error, info, o, warn, Logger};
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
-use std::ptr::write_bytes;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// How long a rejected connection may take to send its first command.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const BUSY_CONNECTIONS: &str = "too many connections";
 
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
     log: Logger,
+    limiter: Arc<Limiter>,
+    metrics: Arc<ServerMetrics>,
+    credentials: Option<Arc<Credentials>>,
+    tls: Option<ServerTls>,
+    replication: Replication,
+    primary: Option<Primary>,
+    cluster: Option<Arc<RaftNode<E>>>,
+    txn: Arc<TxnStore<E>>,
+    slots: Option<Arc<SlotTable>>,
+}
+
+/// Where a replica replicates from, and where it saves its position.
+struct Primary {
+    client: KvsClientBuilder,
+    state_path: PathBuf,
+    status: Arc<ReplicaStatus>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     pub fn new(engine: E, log: Logger) -> KvsServer<E> {
-        KvsServer { engine, log }
+        KvsServer::with_limits(engine, log, ServerLimits::default())
+    }
+
+    /// The limits are shared by every address the server is bound to.
+    pub fn with_limits(engine: E, log: Logger, limits: ServerLimits) -> KvsServer<E> {
+        KvsServer {
+            txn: Arc::new(TxnStore::new(engine.clone(), None)),
+            engine,
+            log,
+            limiter: Arc::new(Limiter::new(&limits)),
+            metrics: Arc::new(ServerMetrics::new()),
+            credentials: None,
+            tls: None,
+            replication: Replication::Standalone,
+            primary: None,
+            cluster: None,
+            slots: None,
+        }
+    }
+
+    /// Serves every bound address, TCP and Unix alike, over TLS only.
+    pub fn with_tls(mut self, tls: ServerTls) -> KvsServer<E> {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Requires clients to authenticate before any command but `PING`, and
+    /// checks keys and admin commands against the user's ACL.
+    pub fn with_credentials(mut self, credentials: Credentials) -> KvsServer<E> {
+        self.credentials = Some(Arc::new(credentials));
+        self
     }
 
-    pub async fn listen(&self, addr: SocketAddr) -> Result<()> {
+    /// Serves replicas, keeping the last `backlog` writes for replicas to
+    /// catch up from after a disconnect. Replicas further behind load a
+    /// snapshot. Writes are applied one at a time, in the order replicas
+    /// apply them.
+    pub fn with_replication(mut self, backlog: usize) -> KvsServer<E> {
+        self.replication = Replication::Primary(Arc::new(ReplicationLog::new(backlog)));
+        self.primary = None;
+        self
+    }
+
+    /// Makes the server a read-only replica of `primary`, started with
+    /// [`KvsServer::replicate`]. The position in the primary's history is
+    /// saved in `state_path`, a replica restarted on the same data resumes
+    /// from there. Clients writing to a replica get
+    /// [`KvError::ReadOnly`].
+    pub fn with_replica_of(
+        mut self,
+        primary: KvsClientBuilder,
+        state_path: impl Into<PathBuf>,
+    ) -> KvsServer<E> {
+        let status = Arc::new(ReplicaStatus::new(primary.target_addr()));
+        self.replication = Replication::Replica(status.clone());
+        self.primary = Some(Primary {
+            client: primary,
+            state_path: state_path.into(),
+            status,
+        });
+        self
+    }
+
+    /// Makes the server a member of a Raft cluster, started with
+    /// [`KvsServer::start_cluster`]. Writes are applied once a majority of
+    /// the members stored them, reads and writes are served by the leader.
+    /// The other members answer with [`KvError::NotLeader`].
+    pub fn with_cluster(mut self, cluster: ClusterConfig) -> Result<KvsServer<E>> {
+        let log = self.log.new(o!("member" => cluster.id().to_string()));
+        self.cluster = Some(RaftNode::new(cluster, self.engine.clone(), log)?);
+        Ok(self)
+    }
+
+    /// Makes the server the timestamp oracle of the transactions, e.g. of
+    /// every server a [`ShardedKvsClient`](crate::kvs::ShardedKvsClient)
+    /// routes to. Only one server may hand out the timestamps of a set of
+    /// servers. The oracle saves a timestamp ahead of the handed out ones in
+    /// `state_path`, and continues above it after a restart.
+    pub fn with_tso(mut self, state_path: impl Into<PathBuf>) -> Result<KvsServer<E>> {
+        let oracle = TimestampOracle::open(state_path)?;
+        self.txn = Arc::new(TxnStore::new(self.engine.clone(), Some(oracle)));
+        Ok(self)
+    }
+
+    /// Serves only the keys of the hash slots the server owns, clients
+    /// asking for others are redirected with [`KvError::Moved`] or
+    /// [`KvError::Ask`]. Slots are moved between servers with
+    /// [`KvsClient::move_slots`](crate::kvs::KvsClient::move_slots). The
+    /// slot states are saved in `state_path`; once it exists, they are used
+    /// instead of the config.
+    pub fn with_slots(
+        mut self,
+        config: SlotConfig,
+        state_path: impl Into<PathBuf>,
+    ) -> Result<KvsServer<E>> {
+        self.slots = Some(Arc::new(SlotTable::open(config, state_path)?));
+        Ok(self)
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn cluster_node(&self) -> Option<&Arc<RaftNode<E>>> {
+        self.cluster.as_ref()
+    }
+
+    pub async fn listen(&self, addr: impl Into<KvsAddr>) -> Result<()> {
+        self.bind(addr).await?.join().await
+    }
+
+    /// Binds the listener and serves connections on a background task.
+    ///
+    /// Port 0 picks an ephemeral port, the actual one is available through
+    /// [`KvsServerHandle::addr`]. Dropping the handle stops the accept loop.
+    /// A server may be bound to several addresses, one handle per address.
+    pub async fn bind(&self, addr: impl Into<KvsAddr>) -> Result<KvsServerHandle> {
+        let listener = Listener::bind(addr.into()).await?;
+        let local_addr = listener.local_addr()?;
+
+        info!(self.log, "listening on: {}", local_addr);
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let handler = Arc::new(ConnectionHandler::new(
+            self.engine.clone(),
+            self.limiter.clone(),
+            self.metrics.clone(),
+            self.credentials.clone(),
+            self.replication.clone(),
+            self.cluster.clone(),
+            self.txn.clone(),
+            self.slots.clone(),
+            self.log.new(o!()),
+        ));
+        let tls = self.tls.clone();
+        let log = self.log.new(o!());
+
+        let join = tokio::spawn(accept_loop(listener, handler, tls, log, shutdown_receiver));
+
+        Ok(KvsServerHandle {
+            addr: local_addr,
+            shutdown: shutdown_sender,
+            join,
+        })
+    }
+
+    /// Serves server and engine metrics in the Prometheus text format on
+    /// `GET /metrics`.
+    pub async fn bind_metrics(&self, addr: SocketAddr) -> Result<KvsServerHandle> {
         let listener = TcpListener::bind(addr).await?;
-        loop {
-            let (stream, _) = listener.accept().await?;
+        let local_addr = listener.local_addr()?;
+
+        info!(self.log, "metrics on: {}", local_addr);
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let engine = self.engine.clone();
+        let metrics = self.metrics.clone();
+        let render = move || {
+            let mut out = MetricsWriter::new();
+            metrics.write(&mut out);
+            engine.write_metrics(&mut out);
+            out.finish()
+        };
+
+        let log = self.log.new(o!());
+        let join = tokio::spawn(serve_metrics(listener, render, log, shutdown_receiver));
+
+        Ok(KvsServerHandle {
+            addr: KvsAddr::Tcp(local_addr),
+            shutdown: shutdown_sender,
+            join,
+        })
+    }
+
+    /// Starts replicating from the primary set with
+    /// [`KvsServer::with_replica_of`] on a background task, reconnecting
+    /// after failures until the handle is shut down.
+    pub async fn replicate(&self) -> Result<KvsServerHandle> {
+        let primary = self.primary.as_ref().ok_or_else(|| KvError::Replication {
+            msg: "the server is not a replica".to_string(),
+        })?;
+
+        info!(self.log, "replica of: {}", primary.client.target_addr());
+
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let join = tokio::spawn(replication::follow(
+            self.engine.clone(),
+            primary.client.clone(),
+            primary.state_path.clone(),
+            primary.status.clone(),
+            self.log.new(o!("replica_of" => primary.client.target_addr().to_string())),
+            shutdown_receiver,
+        ));
+
+        Ok(KvsServerHandle {
+            addr: primary.client.target_addr().clone(),
+            shutdown: shutdown_sender,
+            join,
+        })
+    }
 
-            let conn_engine = self.engine.clone();
-            let conn_log = self.log.new(o!());
+    /// Starts the elections and the replication of a server set up with
+    /// [`KvsServer::with_cluster`], until the handle is shut down.
+    pub async fn start_cluster(&self) -> Result<KvsServerHandle> {
+        let node = self.cluster.clone().ok_or_else(|| KvError::Cluster {
+            msg: "the server is not a cluster member".to_string(),
+        })?;
 
-            tokio::spawn(async move {
-                let handler = ConnectionHandler::new(conn_engine, conn_log);
-                handler.handle(stream).await;
-            });
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let addr = node.id().parse()?;
+        let join = tokio::spawn(node.run(shutdown_receiver));
+
+        Ok(KvsServerHandle {
+            addr,
+            shutdown: shutdown_sender,
+            join,
+        })
+    }
+}
+
+pub struct KvsServerHandle {
+    addr: KvsAddr,
+    shutdown: oneshot::Sender<()>,
+    join: JoinHandle<Result<()>>,
+}
+
+impl KvsServerHandle {
+    pub fn addr(&self) -> &KvsAddr {
+        &self.addr
+    }
+
+    /// Waits until the accept loop exits.
+    pub async fn join(self) -> Result<()> {
+        let KvsServerHandle { shutdown, join, .. } = self;
+        let res = join.await;
+        drop(shutdown);
+        res.map_err(KvError::Join)?
+    }
+
+    /// Stops accepting new connections and waits for the accept loop to exit.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(());
+        self.join.await.map_err(KvError::Join)?
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    async fn bind(addr: KvsAddr) -> Result<Listener> {
+        match addr {
+            KvsAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            KvsAddr::Unix(path) => {
+                remove_stale_socket(path.as_path())?;
+                let listener = UnixListener::bind(path.as_path())?;
+                Ok(Listener::Unix(listener, path))
+            }
+        }
+    }
+
+    /// Accepts the next connection, TCP and Unix streams are boxed so both
+    /// share one accept loop. Unix peers are unnamed.
+    async fn accept(&self) -> Result<(Box<dyn KvsStream>, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), Some(peer)))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<KvsAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(KvsAddr::Tcp(listener.local_addr()?)),
+            Listener::Unix(_, path) => Ok(KvsAddr::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path.as_path());
         }
     }
 }
+
+/// Removes a socket file left behind by a server that was not shut down
+/// cleanly, one nothing listens on anymore. Fails while a server still
+/// listens on it, anything else at the path is left for `bind` to fail on.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("address in use: {}", path.display()),
+        )
+        .into()),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(std::fs::remove_file(path)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn accept_loop<E: KvsEngine>(
+    listener: Listener,
+    handler: Arc<ConnectionHandler<E>>,
+    tls: Option<ServerTls>,
+    log: Logger,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    loop {
+        let (stream, peer) = select! {
+            res = listener.accept() => res?,
+            _ = &mut shutdown => {
+                info!(log, "shutdown");
+                return Ok(());
+            }
+        };
+
+        let stream = MeteredStream::new(stream, handler.metrics().clone());
+
+        let permit = match handler.limiter().try_connection() {
+            Some(permit) => permit,
+            None => {
+                warn!(log, "connection rejected: {}", BUSY_CONNECTIONS);
+                handler.metrics().busy.inc();
+                // A TLS client could only read the busy result after a
+                // handshake, which rejected connections are not worth.
+                match handler.limiter().try_reject() {
+                    Some(reject_permit) if tls.is_none() => {
+                        tokio::spawn(async move {
+                            reject(stream).await;
+                            drop(reject_permit);
+                        });
+                    }
+                    _ => drop(stream),
+                }
+                continue;
+            }
+        };
+
+        let conn_handler = handler.clone();
+        let conn_tls = tls.clone();
+        let conn_log = log.new(o!());
+
+        tokio::spawn(async move {
+            let stream = match handshake(stream, conn_tls.as_ref()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(conn_log, "tls handshake failed: {}", e);
+                    return;
+                }
+            };
+
+            if let Err(e) = conn_handler.handle(stream, peer).await {
+                error!(conn_log, "connection error: {}", e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Runs the TLS handshake on the connection task, so a slow client does not
+/// hold up the accept loop.
+async fn handshake<S: KvsStream + 'static>(
+    stream: S,
+    tls: Option<&ServerTls>,
+) -> Result<Box<dyn KvsStream>> {
+    match tls {
+        Some(tls) => match timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Tls {
+                msg: "handshake timed out".to_string(),
+            }),
+        },
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Answers the first command of a connection over the limit with a busy
+/// result. Reading the command first keeps the client from seeing a reset
+/// instead of the result.
+async fn reject<S: KvsStream>(mut stream: S) {
+    let cmd = read_async_max::<_, Command>(&mut stream, MAX_UNAUTHENTICATED_FRAME_SIZE);
+    let cmd = timeout(REJECT_TIMEOUT, cmd).await;
+    if let Ok(Ok(_)) = cmd {
+        let busy = CommandResult::Busy(BUSY_CONNECTIONS.to_string());
+        let _ = write_async(&mut stream, &busy).await;
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_PENDING_REQUESTS: usize = 1024;
+
+/// Connections over the limit answered busy at the same time, others are
+/// closed straight away.
+const MAX_PENDING_REJECTS: usize = 64;
+
+/// Idle buckets are dropped once the table grows past this size.
+const BUCKETS_PRUNE_THRESHOLD: usize = 4096;
+
+/// Load limits of a server, work above them is rejected with a busy result.
+#[derive(Debug, Clone)]
+pub struct ServerLimits {
+    /// Maximum number of connections served at the same time.
+    pub max_connections: usize,
+    /// Maximum number of requests waiting on the engine at the same time.
+    pub max_pending_requests: usize,
+    /// Per client IP request rate, unlimited if `None`.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_pending_requests: DEFAULT_MAX_PENDING_REQUESTS,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Token bucket refilled with `requests_per_sec` tokens up to `burst`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests_per_sec: u32,
+    pub burst: u32,
+}
+
+pub(crate) struct Limiter {
+    connections: Arc<Semaphore>,
+    rejects: Arc<Semaphore>,
+    requests: Semaphore,
+    rate: Option<RateLimiter>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: &ServerLimits) -> Limiter {
+        Limiter {
+            connections: Arc::new(Semaphore::new(limits.max_connections)),
+            rejects: Arc::new(Semaphore::new(MAX_PENDING_REJECTS)),
+            requests: Semaphore::new(limits.max_pending_requests),
+            rate: limits.rate_limit.map(RateLimiter::new),
+        }
+    }
+
+    pub(crate) fn try_connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// A permit to answer a connection over the limit busy.
+    pub(crate) fn try_reject(&self) -> Option<OwnedSemaphorePermit> {
+        self.rejects.clone().try_acquire_owned().ok()
+    }
+
+    pub(crate) fn try_request(&self) -> Option<SemaphorePermit<'_>> {
+        self.requests.try_acquire().ok()
+    }
+
+    pub(crate) fn check_rate(&self, ip: IpAddr) -> bool {
+        match &self.rate {
+            Some(rate) => rate.try_take(ip, Instant::now()),
+            None => true,
+        }
+    }
+}
+
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn try_take(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= BUCKETS_PRUNE_THRESHOLD && !buckets.contains_key(&ip) {
+            let limit = self.limit;
+            buckets.retain(|_, bucket| {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+
+        let burst = self.limit.burst as f64;
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        bucket.refill(&self.limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_sec as f64).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::server::limits::{
+        Limiter, RateLimit, RateLimiter, ServerLimits, MAX_PENDING_REJECTS,
+    };
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_sec: 10,
+            burst: 2,
+        });
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.try_take(ip1, now));
+        assert!(limiter.try_take(ip1, now));
+        assert!(!limiter.try_take(ip1, now));
+
+        assert!(limiter.try_take(ip2, now));
+
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.try_take(ip1, later));
+        assert!(!limiter.try_take(ip1, later));
+    }
+
+    #[test]
+    fn test_pending_rejects() {
+        let limiter = Limiter::new(&ServerLimits::default());
+        let permits: Vec<_> = (0..MAX_PENDING_REJECTS)
+            .map(|_| limiter.try_reject().unwrap())
+            .collect();
+        assert!(limiter.try_reject().is_none());
+        drop(permits);
+        assert!(limiter.try_reject().is_some());
+    }
+}
@@ -0,0 +1,134 @@
+//! Merkle trees over the ordered keyspace, compared by anti-entropy repair.
+//!
+//! The tree is computed on demand, one level per request. A node covers a
+//! key range, its leaves are the pairs of the range and its hash is the hash
+//! of their leaf hashes, so two servers holding the same pairs in a range
+//! agree on its hash however each of them splits it. A server splits a node
+//! at its own keys, into children holding about as many pairs each: repair
+//! asks the server holding more pairs of a differing node for the children,
+//! and the other one for the hashes of the same ranges.
+
+use crate::kvs::encoding::to_hex;
+use crate::kvs::{KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Keys in `[start, end)`, `end` is `None` for a range without upper bound.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct KeyRange {
+    #[serde(with = "serde_bytes")]
+    pub(crate) start: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub(crate) end: Option<Vec<u8>>,
+}
+
+/// A node of the tree: its range, the hash and number of its pairs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RangeDigest {
+    pub(crate) range: KeyRange,
+    pub(crate) hash: String,
+    pub(crate) keys: u64,
+}
+
+impl KeyRange {
+    /// The whole keyspace, the root of the tree.
+    pub(crate) fn all() -> KeyRange {
+        KeyRange {
+            start: Vec::new(),
+            end: None,
+        }
+    }
+}
+
+/// Digests of the ranges, each split into at most `split` children. A range
+/// is split only if it holds more than one pair, its children then hold at
+/// least one pair each.
+pub(crate) async fn digests<E: KvsEngine>(
+    engine: &E,
+    ranges: Vec<KeyRange>,
+    split: usize,
+) -> Result<Vec<RangeDigest>> {
+    let mut digests = Vec::new();
+    for range in ranges {
+        let pairs = engine
+            .scan_range(range.start.clone(), range.end.clone())
+            .await?;
+        let leaves: Vec<_> = pairs.iter().map(|(key, val)| leaf(key, val)).collect();
+        if split <= 1 || pairs.len() <= 1 {
+            digests.push(node(range, &leaves));
+            continue;
+        }
+
+        let chunk = pairs.len().div_ceil(split);
+        let mut start = range.start;
+        for first in (0..pairs.len()).step_by(chunk) {
+            let last = (first + chunk).min(pairs.len());
+            let end = match pairs.get(last) {
+                Some((key, _)) => Some(key.clone()),
+                None => range.end.clone(),
+            };
+            let child = KeyRange {
+                start,
+                end: end.clone(),
+            };
+            digests.push(node(child, &leaves[first..last]));
+            start = end.unwrap_or_default();
+        }
+    }
+    Ok(digests)
+}
+
+fn node(range: KeyRange, leaves: &[[u8; 32]]) -> RangeDigest {
+    let mut hasher = Sha256::new();
+    for leaf in leaves {
+        hasher.update(leaf);
+    }
+    RangeDigest {
+        range,
+        hash: to_hex(&hasher.finalize()),
+        keys: leaves.len() as u64,
+    }
+}
+
+/// Hash of a pair, lengths first so pairs splitting the same bytes
+/// differently do not collide.
+fn leaf(key: &[u8], val: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key);
+    hasher.update((val.len() as u64).to_be_bytes());
+    hasher.update(val);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvs::thread_pool::SharedQueueThreadPool;
+    use crate::kvs::SledKvsEngine;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn children_cover_the_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let engine = SledKvsEngine::<SharedQueueThreadPool>::open(dir.path(), 2)?;
+        for i in 0..10 {
+            engine.set(format!("key{}", i), i.to_string()).await?;
+        }
+
+        let root = digests(&engine, vec![KeyRange::all()], 1).await?;
+        let children = digests(&engine, vec![KeyRange::all()], 4).await?;
+        assert_eq!(children.len(), 4);
+        assert_eq!(children[0].range.start, b"");
+        assert_eq!(children[3].range.end, None);
+        for pair in children.windows(2) {
+            assert_eq!(pair[0].range.end.as_ref(), Some(&pair[1].range.start));
+        }
+        assert_eq!(children.iter().map(|d| d.keys).sum::<u64>(), root[0].keys);
+
+        // Hashes depend on the pairs only, not on how the range was split.
+        let ranges = children.iter().map(|d| d.range.clone()).collect();
+        assert_eq!(digests(&engine, ranges, 1).await?, children);
+        Ok(())
+    }
+}
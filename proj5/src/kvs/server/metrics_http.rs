@@ -0,0 +1,83 @@
+use crate::kvs::Result;
+use slog::{info, warn, Logger};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+const METRICS_PATH: &str = "/metrics";
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the output of `render` on `GET /metrics`, one request per
+/// connection.
+pub(crate) async fn serve_metrics<F>(
+    listener: TcpListener,
+    render: F,
+    log: Logger,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()>
+where
+    F: Fn() -> String + Clone + Send + Sync + 'static,
+{
+    loop {
+        let (stream, _) = select! {
+            res = listener.accept() => res?,
+            _ = &mut shutdown => {
+                info!(log, "metrics shutdown");
+                return Ok(());
+            }
+        };
+
+        let render = render.clone();
+        let log = log.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, render).await {
+                warn!(log, "metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond<F: Fn() -> String>(mut stream: TcpStream, render: F) -> Result<()> {
+    let head = match timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let response = match (method, path) {
+        ("GET", METRICS_PATH) => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -3,6 +3,16 @@ use crate::kvs::Result;
 use std::net::IpAddr;
 use std::str::FromStr;
 
+mod atomic_file;
+pub mod auth;
 mod conn_handler;
 pub mod engine;
 pub mod kv_server;
+pub mod limits;
+pub(crate) mod merkle;
+mod metrics_http;
+pub(crate) mod raft;
+mod replication;
+mod server_metrics;
+pub mod slots;
+pub(crate) mod txn;
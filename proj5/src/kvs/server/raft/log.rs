@@ -0,0 +1,343 @@
+//! Durable part of a Raft node: term, vote, snapshot position and the log
+//! entries after it.
+//!
+//! `state.json` holds the term, the vote and the snapshot position, and is
+//! replaced atomically. `log.jsonl` holds one entry per line and is
+//! appended to, truncating or compacting the log rewrites it.
+
+use crate::kvs::server::atomic_file;
+use crate::kvs::{KvError, LogEntry, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.json";
+const LOG_FILE: &str = "log.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) term: u64,
+    pub(crate) index: u64,
+    pub(crate) op: Op,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub(crate) enum Op {
+    /// Appended by a new leader, committing it commits the entries of the
+    /// previous terms.
+    Noop,
+    Write {
+        entry: LogEntry,
+    },
+    /// The cluster members from this entry on, in effect once appended.
+    Members {
+        members: Vec<String>,
+    },
+}
+
+/// Last entry covered by the engine data instead of the log.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct SnapshotMeta {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) members: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot: SnapshotMeta,
+}
+
+pub(crate) struct RaftLog {
+    dir: Option<PathBuf>,
+    hard: HardState,
+    /// Entries after the snapshot, the first one has index
+    /// `snapshot.index + 1`.
+    entries: Vec<Entry>,
+    file: Option<File>,
+}
+
+impl RaftLog {
+    /// Opens or creates the log in `dir`.
+    pub(crate) fn open(dir: &Path) -> Result<RaftLog> {
+        std::fs::create_dir_all(dir)?;
+
+        let hard = match std::fs::read_to_string(dir.join(STATE_FILE)) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| corrupt(dir, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(KvError::Io(e)),
+        };
+
+        let mut entries = Vec::new();
+        let path = dir.join(LOG_FILE);
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                // A crash while appending leaves a partial last line.
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+            }
+        }
+        entries.retain(|entry: &Entry| entry.index > hard.snapshot.index);
+
+        let mut log = RaftLog {
+            dir: Some(dir.to_path_buf()),
+            hard,
+            entries,
+            file: None,
+        };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn memory() -> RaftLog {
+        RaftLog {
+            dir: None,
+            hard: HardState::default(),
+            entries: Vec::new(),
+            file: None,
+        }
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.hard.term
+    }
+
+    pub(crate) fn voted_for(&self) -> Option<&str> {
+        self.hard.voted_for.as_deref()
+    }
+
+    pub(crate) fn set_term(&mut self, term: u64, voted_for: Option<String>) -> Result<()> {
+        self.hard.term = term;
+        self.hard.voted_for = voted_for;
+        self.save_state()
+    }
+
+    pub(crate) fn snapshot(&self) -> &SnapshotMeta {
+        &self.hard.snapshot
+    }
+
+    /// Whether nothing happened yet, neither entries nor snapshots.
+    pub(crate) fn is_new(&self) -> bool {
+        self.hard.snapshot.index == 0 && self.entries.is_empty()
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.hard.snapshot.index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        match self.entries.last() {
+            Some(entry) => entry.term,
+            None => self.hard.snapshot.term,
+        }
+    }
+
+    /// Term of the entry at `index`, `None` if it is compacted away or
+    /// missing.
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.hard.snapshot.index {
+            return Some(self.hard.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.hard.snapshot.index {
+            return None;
+        }
+        self.entries
+            .get((index - self.hard.snapshot.index - 1) as usize)
+    }
+
+    /// Up to `max` entries from `index` on.
+    pub(crate) fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = index.saturating_sub(self.hard.snapshot.index + 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Members in effect at `index`, set by the last membership entry up to
+    /// it.
+    pub(crate) fn members_at(&self, index: u64) -> &[String] {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.op {
+                Op::Members { members } => Some(members.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&self.hard.snapshot.members)
+    }
+
+    pub(crate) fn members(&self) -> &[String] {
+        self.members_at(self.last_index())
+    }
+
+    /// Index of the last membership entry, 0 if the members come from the
+    /// snapshot.
+    pub(crate) fn members_index(&self) -> u64 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.op, Op::Members { .. }))
+            .map(|entry| entry.index)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let mut buf = Vec::new();
+            for entry in &entries {
+                serde_json::to_writer(&mut buf, entry).map_err(encode_error)?;
+                buf.push(b'\n');
+            }
+            file.write_all(&buf)?;
+            file.sync_data()?;
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entries from `index` on.
+    pub(crate) fn truncate_from(&mut self, index: u64) -> Result<()> {
+        let keep = index.saturating_sub(self.hard.snapshot.index + 1) as usize;
+        self.entries.truncate(keep);
+        self.rewrite()
+    }
+
+    /// Drops the entries up to `snapshot.index`, which the engine data
+    /// covers. Later entries are kept if the log agrees with the snapshot.
+    pub(crate) fn compact(&mut self, snapshot: SnapshotMeta) -> Result<()> {
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let drop = (snapshot.index - self.hard.snapshot.index) as usize;
+            self.entries.drain(..drop.min(self.entries.len()));
+        } else {
+            self.entries.clear();
+        }
+        self.hard.snapshot = snapshot;
+        self.save_state()?;
+        self.rewrite()
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let content = serde_json::to_vec(&self.hard).map_err(encode_error)?;
+        atomic_file::replace(&dir.join(STATE_FILE), &content)
+    }
+
+    /// Writes the entries to a new log file and appends to it from then on.
+    fn rewrite(&mut self) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        let tmp = dir.join(format!("{}.tmp", LOG_FILE));
+        let mut buf = Vec::new();
+        for entry in &self.entries {
+            serde_json::to_writer(&mut buf, entry).map_err(encode_error)?;
+            buf.push(b'\n');
+        }
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, dir.join(LOG_FILE))?;
+
+        self.file = Some(OpenOptions::new().append(true).open(dir.join(LOG_FILE))?);
+        Ok(())
+    }
+}
+
+fn corrupt(dir: &Path, e: serde_json::Error) -> KvError {
+    KvError::Cluster {
+        msg: format!("{}: {}", dir.join(STATE_FILE).display(), e),
+    }
+}
+
+fn encode_error(e: serde_json::Error) -> KvError {
+    KvError::Cluster { msg: e.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn set(term: u64, index: u64) -> Entry {
+        Entry {
+            term,
+            index,
+            op: Op::Write {
+                entry: LogEntry::Set {
+                    key: format!("key{}", index).into_bytes(),
+                    val: index.to_string().into_bytes(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn reopen_after_truncate_and_compact() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut log = RaftLog::open(dir.path())?;
+        log.set_term(2, Some("a".to_string()))?;
+        log.append((1..=5).map(|i| set(1, i)).collect())?;
+        log.truncate_from(4)?;
+        log.append(vec![set(2, 4)])?;
+        log.compact(SnapshotMeta {
+            index: 2,
+            term: 1,
+            members: vec!["a".to_string()],
+        })?;
+        drop(log);
+
+        let log = RaftLog::open(dir.path())?;
+        assert_eq!(log.term(), 2);
+        assert_eq!(log.voted_for(), Some("a"));
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.term_at(2), Some(1));
+        assert_eq!(log.entry(2), None);
+        assert_eq!(log.entries_from(3, 10), vec![set(1, 3), set(2, 4)]);
+        assert_eq!(log.members(), ["a".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn members_follow_the_log() {
+        let mut log = RaftLog::memory();
+        let members = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        log.append(vec![
+            set(1, 1),
+            Entry {
+                term: 1,
+                index: 2,
+                op: Op::Members {
+                    members: members(&["a", "b"]),
+                },
+            },
+            set(1, 3),
+        ])
+        .unwrap();
+
+        assert!(log.members_at(1).is_empty());
+        assert_eq!(log.members_at(3), members(&["a", "b"]).as_slice());
+        assert_eq!(log.members_index(), 2);
+
+        log.truncate_from(2).unwrap();
+        assert!(log.members().is_empty());
+        assert_eq!(log.members_index(), 0);
+    }
+}
@@ -0,0 +1,63 @@
+//! Raft messages passed in memory between nodes of one process, for
+//! tests.
+
+use crate::kvs::server::raft::transport::{RaftPeer, RaftRequest, RaftResponse, RaftTransport};
+use crate::kvs::{KvError, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Nodes of one process calling each other directly, with links that can
+/// be cut to simulate partitions.
+#[derive(Default)]
+pub(crate) struct MemNetwork {
+    peers: Mutex<HashMap<String, Arc<dyn RaftPeer>>>,
+    isolated: Mutex<HashSet<String>>,
+}
+
+impl MemNetwork {
+    pub(crate) fn register(&self, id: &str, peer: Arc<dyn RaftPeer>) {
+        self.peers.lock().unwrap().insert(id.to_string(), peer);
+    }
+
+    /// Cuts every link to and from the node.
+    pub(crate) fn isolate(&self, id: &str) {
+        self.isolated.lock().unwrap().insert(id.to_string());
+    }
+
+    pub(crate) fn heal(&self) {
+        self.isolated.lock().unwrap().clear();
+    }
+
+    /// Transport for the node `id`.
+    pub(crate) fn transport(self: &Arc<Self>, id: &str) -> MemTransport {
+        MemTransport {
+            from: id.to_string(),
+            network: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct MemTransport {
+    from: String,
+    network: Arc<MemNetwork>,
+}
+
+impl RaftTransport for MemTransport {
+    fn call(&self, to: &str, req: RaftRequest) -> BoxFuture<'static, Result<RaftResponse>> {
+        let linked = {
+            let isolated = self.network.isolated.lock().unwrap();
+            !isolated.contains(&self.from) && !isolated.contains(to)
+        };
+        let peer = self.network.peers.lock().unwrap().get(to).cloned();
+        match (linked, peer) {
+            (true, Some(peer)) => peer.handle(req),
+            _ => {
+                let err = io::Error::new(io::ErrorKind::ConnectionRefused, "unreachable");
+                futures::future::ready(Err(KvError::Io(err))).boxed()
+            }
+        }
+    }
+}
@@ -0,0 +1,1212 @@
+//! Raft consensus for cluster mode.
+//!
+//! Every write goes through the leader's log and is applied to the engine
+//! of each member once a majority stored it. Reads are served by the
+//! leader after a round of heartbeats confirms it still leads. Other
+//! members answer with the leader's address.
+//!
+//! The engine is the state machine and its data is the snapshot: once the
+//! log grows past a threshold, the applied entries are dropped, and a
+//! member too far behind receives the leader's engine data instead. That
+//! data is read while entries keep being applied, so it reflects at least
+//! the entries up to the snapshot index; replaying the later ones on top
+//! converges, since a write replaces the value and removing a missing key
+//! is ignored. The data goes in chunks ordered by key, and the member
+//! replaces its own data one key range at a time. Compacting flushes the
+//! engine first, its data has to be on disk before the entries are dropped.
+//!
+//! Membership changes add or remove one member at a time and take effect
+//! as soon as they are appended, see section 4.1 of the Raft thesis. A
+//! member ignores candidates while it hears from a leader, so removed
+//! members do not disrupt the cluster.
+
+mod log;
+#[cfg(any(test, feature = "testing"))]
+mod mem_network;
+mod transport;
+
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use mem_network::MemNetwork;
+pub(crate) use transport::{RaftPeer, RaftRequest, RaftResponse};
+
+use crate::kvs::server::raft::log::{Entry, Op, RaftLog, SnapshotMeta};
+use crate::kvs::server::raft::transport::{RaftTransport, TcpTransport};
+use crate::kvs::server::replication;
+use crate::kvs::{KvError, KvsAddr, KvsClientBuilder, KvsEngine, LogEntry, Result, RetryPolicy};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use slog::{error, info, warn, Logger};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::{oneshot, watch, Notify};
+use tokio::time::{sleep, timeout};
+
+const DEFAULT_ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 10_000;
+
+/// Entries sent in one append request.
+const BATCH: usize = 256;
+
+/// Pairs sent in one snapshot request.
+const CHUNK: usize = 256;
+
+/// How a server joins a Raft cluster, see [`KvsServer::with_cluster`].
+///
+/// [`KvsServer::with_cluster`]: crate::kvs::KvsServer::with_cluster
+#[derive(Clone)]
+pub struct ClusterConfig {
+    pub(crate) id: String,
+    pub(crate) members: Vec<String>,
+    pub(crate) dir: PathBuf,
+    pub(crate) election_timeout: Duration,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) snapshot_threshold: u64,
+    pub(crate) peer_client: Option<KvsClientBuilder>,
+    pub(crate) transport: Option<Arc<dyn RaftTransport>>,
+}
+
+impl ClusterConfig {
+    /// A member reachable at `id`, with the initial members of the cluster,
+    /// `id` among them. The Raft state is kept in `dir`; once it exists,
+    /// the members recorded there are used instead.
+    pub fn new<A: Into<KvsAddr>>(
+        id: impl Into<KvsAddr>,
+        members: impl IntoIterator<Item = A>,
+        dir: impl Into<PathBuf>,
+    ) -> ClusterConfig {
+        ClusterConfig {
+            id: id.into().to_string(),
+            members: members.into_iter().map(|m| m.into().to_string()).collect(),
+            dir: dir.into(),
+            election_timeout: DEFAULT_ELECTION_TIMEOUT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            peer_client: None,
+            transport: None,
+        }
+    }
+
+    /// A server waiting to be added to an existing cluster, with
+    /// [`KvsClient::add_member`](crate::kvs::KvsClient::add_member) sent to
+    /// its leader.
+    pub fn join(id: impl Into<KvsAddr>, dir: impl Into<PathBuf>) -> ClusterConfig {
+        ClusterConfig::new(id, Vec::<KvsAddr>::new(), dir)
+    }
+
+    /// Shortest time without a leader before a member starts an election,
+    /// each member waits a random time up to twice as long.
+    pub fn election_timeout(mut self, timeout: Duration) -> Self {
+        self.election_timeout = timeout;
+        self
+    }
+
+    /// How often the leader contacts idle members, well below the election
+    /// timeout.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Applied entries kept in the log before it is compacted.
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.snapshot_threshold = entries.max(1);
+        self
+    }
+
+    /// TLS and credentials used to reach the other members. The builder's
+    /// own address is replaced by each member's.
+    pub fn peer_client(mut self, client: KvsClientBuilder) -> Self {
+        self.peer_client = Some(client);
+        self
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn transport(mut self, transport: Arc<dyn RaftTransport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub(crate) struct RaftNode<E: KvsEngine> {
+    id: String,
+    engine: E,
+    transport: Arc<dyn RaftTransport>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    snapshot_threshold: u64,
+    state: Mutex<State>,
+    /// Held while entries or snapshot data are written to the engine.
+    apply_lock: tokio::sync::Mutex<()>,
+    /// Wakes the applier after the commit index moved.
+    committed: Notify,
+    /// Last index of the log, wakes the replicators.
+    appended: watch::Sender<u64>,
+    applied: watch::Sender<u64>,
+    stopped: watch::Sender<bool>,
+    /// Keep the channels open while nobody listens.
+    _receivers: (
+        watch::Receiver<u64>,
+        watch::Receiver<u64>,
+        watch::Receiver<bool>,
+    ),
+    log: Logger,
+}
+
+struct State {
+    role: Role,
+    leader: Option<String>,
+    log: RaftLog,
+    commit: u64,
+    applied: u64,
+    election_deadline: Instant,
+    /// Last request from a leader.
+    heard: Option<Instant>,
+    votes: HashSet<String>,
+    /// Replication progress of the other members, on the leader.
+    progress: HashMap<String, Progress>,
+    /// Index of the leader's first entry in its term.
+    term_start: u64,
+    waiters: HashMap<u64, Waiter>,
+    install: Option<Install>,
+}
+
+struct Progress {
+    next: u64,
+    matched: u64,
+}
+
+/// A client waiting for its entry to be applied.
+struct Waiter {
+    term: u64,
+    sender: oneshot::Sender<Result<()>>,
+}
+
+/// Snapshot being received.
+struct Install {
+    term: u64,
+    index: u64,
+    received: u64,
+    /// Last key received, the data up to it matches the snapshot.
+    last_key: Option<Vec<u8>>,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    pub(crate) fn new(config: ClusterConfig, engine: E, log: Logger) -> Result<Arc<RaftNode<E>>> {
+        let mut raft_log = RaftLog::open(&config.dir)?;
+        if raft_log.is_new() && raft_log.snapshot().members.is_empty() {
+            raft_log.compact(SnapshotMeta {
+                index: 0,
+                term: 0,
+                members: config.members.clone(),
+            })?;
+        }
+
+        let transport = match config.transport {
+            Some(transport) => transport,
+            None => {
+                let client = match config.peer_client {
+                    Some(client) => client,
+                    None => KvsClientBuilder::new(config.id.parse()?),
+                };
+                let client = client
+                    .connect_timeout(config.election_timeout)
+                    .retry(RetryPolicy::none());
+                Arc::new(TcpTransport::new(client))
+            }
+        };
+
+        let snapshot = raft_log.snapshot().index;
+        let last = raft_log.last_index();
+        let (appended, appended_receiver) = watch::channel(last);
+        let (applied, applied_receiver) = watch::channel(snapshot);
+        let (stopped, stopped_receiver) = watch::channel(false);
+
+        let state = State {
+            role: Role::Follower,
+            leader: None,
+            log: raft_log,
+            commit: snapshot,
+            applied: snapshot,
+            election_deadline: Instant::now() + deadline(config.election_timeout),
+            heard: None,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            term_start: 0,
+            waiters: HashMap::new(),
+            install: None,
+        };
+
+        Ok(Arc::new(RaftNode {
+            id: config.id,
+            engine,
+            transport,
+            election_timeout: config.election_timeout,
+            heartbeat_interval: config.heartbeat_interval,
+            snapshot_threshold: config.snapshot_threshold,
+            state: Mutex::new(state),
+            apply_lock: tokio::sync::Mutex::new(()),
+            committed: Notify::new(),
+            appended,
+            applied,
+            stopped,
+            _receivers: (appended_receiver, applied_receiver, stopped_receiver),
+            log,
+        }))
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Runs elections and applies committed entries until shut down.
+    pub(crate) async fn run(self: Arc<Self>, shutdown: oneshot::Receiver<()>) -> Result<()> {
+        info!(self.log, "cluster member {}", self.id);
+        let ticker = tokio::spawn(self.clone().tick_loop());
+        let applier = tokio::spawn(self.clone().apply_loop());
+
+        let _ = shutdown.await;
+        let _ = self.stopped.send(true);
+
+        ticker.await.map_err(KvError::Join)?;
+        applier.await.map_err(KvError::Join)?;
+        info!(self.log, "cluster member stopped");
+        Ok(())
+    }
+
+    /// Appends a write and waits until it is applied.
+    pub(crate) async fn write(self: &Arc<Self>, entry: LogEntry) -> Result<()> {
+        self.propose(Op::Write { entry }).await
+    }
+
+    /// Waits until reads reflect every write acknowledged before the call.
+    pub(crate) async fn read_index(&self) -> Result<()> {
+        let (term, index, needed, requests) = {
+            let st = self.lock();
+            if st.role != Role::Leader {
+                return Err(not_leader(&st));
+            }
+            let members = st.log.members();
+            let voter = members.contains(&self.id) as usize;
+            let needed = (members.len() / 2 + 1).saturating_sub(voter);
+            let requests: Vec<_> = st
+                .progress
+                .iter()
+                .map(|(peer, progress)| {
+                    let req = RaftRequest::Append {
+                        term: st.log.term(),
+                        leader: self.id.clone(),
+                        prev_index: progress.matched,
+                        prev_term: st.log.term_at(progress.matched).unwrap_or(0),
+                        entries: Vec::new(),
+                        commit: st.commit,
+                    };
+                    (peer.clone(), req)
+                })
+                .collect();
+            (
+                st.log.term(),
+                st.commit.max(st.term_start),
+                needed,
+                requests,
+            )
+        };
+
+        // Any member answering in the same term knows no other leader.
+        let mut calls: FuturesUnordered<_> = requests
+            .into_iter()
+            .map(|(peer, req)| timeout(self.election_timeout, self.transport.call(&peer, req)))
+            .collect();
+        let mut confirmed = 0;
+        while confirmed < needed {
+            match calls.next().await {
+                Some(Ok(Ok(resp))) if resp.term() == term => confirmed += 1,
+                Some(Ok(Ok(resp))) if resp.term() > term => {
+                    let mut st = self.lock();
+                    self.step_down(&mut st, resp.term());
+                    return Err(not_leader(&st));
+                }
+                Some(_) => {}
+                None => {
+                    return Err(KvError::Cluster {
+                        msg: "no quorum to confirm the leader".to_string(),
+                    })
+                }
+            }
+        }
+
+        self.wait_applied(index).await
+    }
+
+    pub(crate) async fn add_member(self: &Arc<Self>, member: KvsAddr) -> Result<()> {
+        let member = member.to_string();
+        self.change_members(|members| {
+            if !members.contains(&member) {
+                members.push(member);
+            }
+        })
+        .await
+    }
+
+    pub(crate) async fn remove_member(self: &Arc<Self>, member: KvsAddr) -> Result<()> {
+        let member = member.to_string();
+        self.change_members(|members| members.retain(|m| *m != member))
+            .await
+    }
+
+    pub(crate) fn fields(&self) -> Vec<(String, String)> {
+        let st = self.lock();
+        let role = match st.role {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+        vec![
+            ("role".to_string(), role.to_string()),
+            ("cluster_id".to_string(), self.id.clone()),
+            ("cluster_term".to_string(), st.log.term().to_string()),
+            (
+                "cluster_leader".to_string(),
+                st.leader.clone().unwrap_or_default(),
+            ),
+            ("cluster_members".to_string(), st.log.members().join(",")),
+            ("commit_index".to_string(), st.commit.to_string()),
+            ("applied_index".to_string(), st.applied.to_string()),
+            ("last_index".to_string(), st.log.last_index().to_string()),
+            (
+                "snapshot_index".to_string(),
+                st.log.snapshot().index.to_string(),
+            ),
+        ]
+    }
+
+    pub(crate) async fn handle(self: Arc<Self>, req: RaftRequest) -> Result<RaftResponse> {
+        match req {
+            RaftRequest::Vote {
+                term,
+                candidate,
+                last_index,
+                last_term,
+            } => self.handle_vote(term, candidate, last_index, last_term),
+            RaftRequest::Append {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => self.handle_append(term, leader, prev_index, prev_term, entries, commit),
+            RaftRequest::Snapshot {
+                term,
+                leader,
+                index,
+                last_term,
+                members,
+                offset,
+                pairs,
+                done,
+            } => {
+                let meta = SnapshotMeta {
+                    index,
+                    term: last_term,
+                    members,
+                };
+                self.handle_snapshot(term, leader, meta, offset, pairs, done)
+                    .await
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    async fn propose(self: &Arc<Self>, op: Op) -> Result<()> {
+        let receiver = {
+            let mut st = self.lock();
+            self.append_waiting(&mut st, op)?
+        };
+        match timeout(self.election_timeout * 10, receiver).await {
+            Ok(res) => res.map_err(KvError::OneshotRecv)?,
+            Err(_) => Err(KvError::Timeout {
+                op: "commit".to_string(),
+            }),
+        }
+    }
+
+    async fn change_members<F: FnOnce(&mut Vec<String>)>(
+        self: &Arc<Self>,
+        change: F,
+    ) -> Result<()> {
+        let receiver = {
+            let mut st = self.lock();
+            if st.role != Role::Leader {
+                return Err(not_leader(&st));
+            }
+            // One change at a time, and only once the leader knows which
+            // entries are committed.
+            if st.log.members_index() > st.commit || st.commit < st.term_start {
+                return Err(KvError::Cluster {
+                    msg: "a membership change is in progress, try again".to_string(),
+                });
+            }
+            let mut members = st.log.members().to_vec();
+            change(&mut members);
+            if members == st.log.members() {
+                return Ok(());
+            }
+            if members.is_empty() {
+                return Err(KvError::Cluster {
+                    msg: "cannot remove the last member".to_string(),
+                });
+            }
+            info!(self.log, "changing members to {:?}", members);
+            self.append_waiting(&mut st, Op::Members { members })?
+        };
+        match timeout(self.election_timeout * 10, receiver).await {
+            Ok(res) => res.map_err(KvError::OneshotRecv)?,
+            Err(_) => Err(KvError::Timeout {
+                op: "membership change".to_string(),
+            }),
+        }
+    }
+
+    /// Appends the operation on the leader and returns where its result
+    /// arrives once applied.
+    fn append_waiting(
+        self: &Arc<Self>,
+        st: &mut State,
+        op: Op,
+    ) -> Result<oneshot::Receiver<Result<()>>> {
+        if st.role != Role::Leader {
+            return Err(not_leader(st));
+        }
+        let index = self.append(st, op)?;
+        let (sender, receiver) = oneshot::channel();
+        let term = st.log.term();
+        st.waiters.insert(index, Waiter { term, sender });
+        Ok(receiver)
+    }
+
+    /// Appends an entry of the current term to the leader's log.
+    fn append(self: &Arc<Self>, st: &mut State, op: Op) -> Result<u64> {
+        let index = st.log.last_index() + 1;
+        let members_changed = matches!(op, Op::Members { .. });
+        st.log.append(vec![Entry {
+            term: st.log.term(),
+            index,
+            op,
+        }])?;
+        if members_changed {
+            self.track_members(st);
+        }
+        let _ = self.appended.send(index);
+        self.advance_commit(st);
+        Ok(index)
+    }
+
+    async fn tick_loop(self: Arc<Self>) {
+        let mut stopped = self.stopped.subscribe();
+        loop {
+            select! {
+                _ = sleep(self.heartbeat_interval) => {}
+                _ = stopped.changed() => return,
+            }
+            let elect = {
+                let st = self.lock();
+                st.role != Role::Leader
+                    && Instant::now() >= st.election_deadline
+                    && st.log.members().contains(&self.id)
+            };
+            if elect {
+                if let Err(e) = self.start_election() {
+                    error!(self.log, "election failed: {}", e);
+                }
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>) -> Result<()> {
+        let mut st = self.lock();
+        let term = st.log.term() + 1;
+        st.log.set_term(term, Some(self.id.clone()))?;
+        st.role = Role::Candidate;
+        st.leader = None;
+        st.votes = HashSet::new();
+        st.votes.insert(self.id.clone());
+        st.election_deadline = Instant::now() + deadline(self.election_timeout);
+        info!(self.log, "starting election for term {}", term);
+
+        if has_quorum(&st.votes, st.log.members()) {
+            return self.become_leader(&mut st);
+        }
+
+        let req = RaftRequest::Vote {
+            term,
+            candidate: self.id.clone(),
+            last_index: st.log.last_index(),
+            last_term: st.log.last_term(),
+        };
+        for peer in st.log.members().iter().filter(|m| **m != self.id) {
+            let node = self.clone();
+            let peer = peer.clone();
+            let call = self.transport.call(&peer, req.clone());
+            tokio::spawn(async move {
+                if let Ok(Ok(resp)) = timeout(node.election_timeout, call).await {
+                    node.on_vote(peer, term, resp);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn on_vote(self: &Arc<Self>, peer: String, term: u64, resp: RaftResponse) {
+        let mut st = self.lock();
+        if resp.term() > st.log.term() {
+            self.step_down(&mut st, resp.term());
+            return;
+        }
+        let granted = matches!(resp, RaftResponse::Vote { granted: true, .. });
+        if st.role != Role::Candidate || st.log.term() != term || !granted {
+            return;
+        }
+        st.votes.insert(peer);
+        if has_quorum(&st.votes, st.log.members()) {
+            if let Err(e) = self.become_leader(&mut st) {
+                error!(self.log, "becoming leader failed: {}", e);
+            }
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, st: &mut State) -> Result<()> {
+        info!(self.log, "leader for term {}", st.log.term());
+        st.role = Role::Leader;
+        st.leader = Some(self.id.clone());
+        st.progress = HashMap::new();
+        st.term_start = st.log.last_index() + 1;
+        self.track_members(st);
+        self.append(st, Op::Noop)?;
+        Ok(())
+    }
+
+    /// Starts replicating to new members and stops for removed ones.
+    fn track_members(self: &Arc<Self>, st: &mut State) {
+        if st.role != Role::Leader {
+            return;
+        }
+        let members = st.log.members().to_vec();
+        st.progress.retain(|peer, _| members.contains(peer));
+        for peer in members.into_iter().filter(|m| *m != self.id) {
+            if st.progress.contains_key(&peer) {
+                continue;
+            }
+            st.progress.insert(
+                peer.clone(),
+                Progress {
+                    next: st.log.last_index() + 1,
+                    matched: 0,
+                },
+            );
+            tokio::spawn(self.clone().replicate(peer, st.log.term()));
+        }
+    }
+
+    fn step_down(&self, st: &mut State, term: u64) {
+        if term > st.log.term() {
+            if let Err(e) = st.log.set_term(term, None) {
+                error!(self.log, "saving term failed: {}", e);
+            }
+        }
+        if st.role != Role::Follower {
+            info!(self.log, "follower in term {}", st.log.term());
+        }
+        st.role = Role::Follower;
+        st.votes.clear();
+        st.progress.clear();
+    }
+
+    /// Commits the last entry of the current term a majority stored.
+    fn advance_commit(&self, st: &mut State) {
+        if st.role != Role::Leader {
+            return;
+        }
+        let members = st.log.members();
+        let mut matched: Vec<u64> = members
+            .iter()
+            .map(|member| match st.progress.get(member) {
+                _ if *member == self.id => st.log.last_index(),
+                Some(progress) => progress.matched,
+                None => 0,
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[members.len() / 2];
+        if index > st.commit && st.log.term_at(index) == Some(st.log.term()) {
+            st.commit = index;
+            self.committed.notify_one();
+        }
+
+        // A leader removed from the cluster leads until the change is
+        // committed, then leaves the election to the remaining members.
+        if !st.log.members().contains(&self.id) && st.log.members_index() <= st.commit {
+            info!(self.log, "removed from the cluster, stepping down");
+            st.role = Role::Follower;
+            st.leader = None;
+            st.progress.clear();
+        }
+    }
+
+    async fn replicate(self: Arc<Self>, peer: String, term: u64) {
+        let mut appended = self.appended.subscribe();
+        let mut stopped = self.stopped.subscribe();
+        loop {
+            if *stopped.borrow() {
+                return;
+            }
+            let req = {
+                let st = self.lock();
+                if st.role != Role::Leader || st.log.term() != term {
+                    return;
+                }
+                let progress = match st.progress.get(&peer) {
+                    Some(progress) => progress,
+                    None => return,
+                };
+                if progress.next <= st.log.snapshot().index {
+                    None
+                } else {
+                    let prev_index = progress.next - 1;
+                    Some(RaftRequest::Append {
+                        term,
+                        leader: self.id.clone(),
+                        prev_index,
+                        prev_term: st.log.term_at(prev_index).unwrap_or(0),
+                        entries: st.log.entries_from(progress.next, BATCH),
+                        commit: st.commit,
+                    })
+                }
+            };
+
+            let more = match req {
+                Some(req) => {
+                    let call = self.transport.call(&peer, req);
+                    match timeout(self.election_timeout, call).await {
+                        Ok(Ok(resp)) => self.on_append(&peer, term, resp),
+                        _ => false,
+                    }
+                }
+                None => match self.send_snapshot(&peer, term).await {
+                    Ok(more) => more,
+                    Err(e) => {
+                        warn!(self.log, "sending snapshot to {} failed: {}", peer, e);
+                        false
+                    }
+                },
+            };
+            if more {
+                continue;
+            }
+
+            select! {
+                _ = timeout(self.heartbeat_interval, appended.changed()) => {}
+                _ = stopped.changed() => return,
+            }
+        }
+    }
+
+    /// Updates the member's progress, returns whether more entries are
+    /// ready to be sent right away.
+    fn on_append(&self, peer: &str, term: u64, resp: RaftResponse) -> bool {
+        let mut st = self.lock();
+        if resp.term() > st.log.term() {
+            self.step_down(&mut st, resp.term());
+            return false;
+        }
+        if st.role != Role::Leader || st.log.term() != term {
+            return false;
+        }
+        let last_index = st.log.last_index();
+        let progress = match st.progress.get_mut(peer) {
+            Some(progress) => progress,
+            None => return false,
+        };
+        match resp {
+            RaftResponse::Append {
+                success: true,
+                index,
+                ..
+            } => {
+                progress.matched = progress.matched.max(index);
+                progress.next = progress.matched + 1;
+                let more = progress.next <= last_index;
+                self.advance_commit(&mut st);
+                more
+            }
+            RaftResponse::Append { index, .. } => {
+                progress.next = (progress.next - 1).min(index + 1).max(1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends the engine data in chunks, then continues with the entries
+    /// after the applied index it was read at.
+    async fn send_snapshot(&self, peer: &str, term: u64) -> Result<bool> {
+        let meta = {
+            let st = self.lock();
+            if st.role != Role::Leader || st.log.term() != term {
+                return Ok(false);
+            }
+            SnapshotMeta {
+                index: st.applied,
+                term: st.log.term_at(st.applied).unwrap_or(0),
+                members: st.log.members_at(st.applied).to_vec(),
+            }
+        };
+        info!(self.log, "sending snapshot at {} to {}", meta.index, peer);
+
+        // The next chunk is read ahead to tell whether this one is the last.
+        let mut chunk = self.engine.scan_after(None, CHUNK).await?;
+        let mut offset = 0;
+        loop {
+            let next = match chunk.last() {
+                Some((key, _)) => self.engine.scan_after(Some(key.clone()), CHUNK).await?,
+                None => Vec::new(),
+            };
+            let done = next.is_empty();
+            let count = chunk.len() as u64;
+            let req = RaftRequest::Snapshot {
+                term,
+                leader: self.id.clone(),
+                index: meta.index,
+                last_term: meta.term,
+                members: meta.members.clone(),
+                offset,
+                pairs: chunk,
+                done,
+            };
+            let resp = match timeout(self.election_timeout, self.transport.call(peer, req)).await {
+                Ok(resp) => resp?,
+                Err(_) => {
+                    return Err(KvError::Timeout {
+                        op: "snapshot".to_string(),
+                    })
+                }
+            };
+            if resp.term() > term {
+                let mut st = self.lock();
+                self.step_down(&mut st, resp.term());
+                return Ok(false);
+            }
+            if !matches!(resp, RaftResponse::Snapshot { success: true, .. }) {
+                return Ok(false);
+            }
+            if done {
+                break;
+            }
+            offset += count;
+            chunk = next;
+        }
+
+        let mut st = self.lock();
+        let last_index = st.log.last_index();
+        if let Some(progress) = st.progress.get_mut(peer) {
+            progress.matched = progress.matched.max(meta.index);
+            progress.next = progress.matched + 1;
+            let more = progress.next <= last_index;
+            self.advance_commit(&mut st);
+            return Ok(more);
+        }
+        Ok(false)
+    }
+
+    fn handle_vote(
+        &self,
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    ) -> Result<RaftResponse> {
+        let mut st = self.lock();
+        let leader_alive = match st.heard {
+            Some(heard) => st.role == Role::Follower && heard.elapsed() < self.election_timeout,
+            None => false,
+        };
+        if leader_alive && st.leader.is_some() {
+            return Ok(RaftResponse::Vote {
+                term: st.log.term(),
+                granted: false,
+            });
+        }
+
+        if term > st.log.term() {
+            self.step_down(&mut st, term);
+            st.leader = None;
+        }
+        let up_to_date = (last_term, last_index) >= (st.log.last_term(), st.log.last_index());
+        let free = match st.log.voted_for() {
+            Some(voted) => voted == candidate,
+            None => true,
+        };
+        let granted = term == st.log.term() && up_to_date && free;
+        if granted {
+            st.log.set_term(term, Some(candidate))?;
+            st.election_deadline = Instant::now() + deadline(self.election_timeout);
+        }
+        Ok(RaftResponse::Vote {
+            term: st.log.term(),
+            granted,
+        })
+    }
+
+    fn handle_append(
+        &self,
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<RaftResponse> {
+        let mut st = self.lock();
+        if term < st.log.term() {
+            return Ok(RaftResponse::Append {
+                term: st.log.term(),
+                success: false,
+                index: st.log.last_index(),
+            });
+        }
+        self.follow(&mut st, term, leader);
+        // The data of an incomplete snapshot matches no log position, the
+        // leader starts from the beginning of its log.
+        st.install = None;
+
+        let failure = |st: &State, index: u64| RaftResponse::Append {
+            term: st.log.term(),
+            success: false,
+            index,
+        };
+        let snapshot = st.log.snapshot().index;
+        if prev_index > st.log.last_index() {
+            return Ok(failure(&st, st.log.last_index()));
+        }
+        if prev_index >= snapshot {
+            if let Some(conflict) = st.log.term_at(prev_index).filter(|t| *t != prev_term) {
+                // Skip back over the whole conflicting term at once.
+                let mut index = prev_index;
+                while index > snapshot && st.log.term_at(index - 1) == Some(conflict) {
+                    index -= 1;
+                }
+                return Ok(failure(&st, index - 1));
+            }
+        }
+
+        let matched = (prev_index + entries.len() as u64).max(snapshot);
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.index <= snapshot {
+                continue;
+            }
+            match st.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    st.log.truncate_from(entry.index)?;
+                    st.log.append(entries[i..].to_vec())?;
+                }
+                None => st.log.append(entries[i..].to_vec())?,
+            }
+            break;
+        }
+
+        if commit > st.commit {
+            st.commit = commit.min(matched).max(st.commit);
+            self.committed.notify_one();
+        }
+        Ok(RaftResponse::Append {
+            term: st.log.term(),
+            success: true,
+            index: matched,
+        })
+    }
+
+    async fn handle_snapshot(
+        &self,
+        term: u64,
+        leader: String,
+        meta: SnapshotMeta,
+        offset: u64,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    ) -> Result<RaftResponse> {
+        let _apply = self.apply_lock.lock().await;
+        let last_key = {
+            let mut st = self.lock();
+            if term < st.log.term() {
+                return Ok(RaftResponse::Snapshot {
+                    term: st.log.term(),
+                    success: false,
+                });
+            }
+            self.follow(&mut st, term, leader);
+
+            if offset == 0 {
+                info!(self.log, "receiving snapshot at {}", meta.index);
+                st.install = Some(Install {
+                    term,
+                    index: meta.index,
+                    received: 0,
+                    last_key: None,
+                });
+                // Until the snapshot is complete the data matches no log
+                // position, a restart starts from an empty log.
+                st.log.compact(SnapshotMeta {
+                    index: 0,
+                    term: 0,
+                    members: meta.members.clone(),
+                })?;
+                st.commit = 0;
+                st.applied = 0;
+            }
+            match &st.install {
+                Some(install)
+                    if install.term == term
+                        && install.index == meta.index
+                        && install.received == offset =>
+                {
+                    install.last_key.clone()
+                }
+                _ => {
+                    return Ok(RaftResponse::Snapshot {
+                        term: st.log.term(),
+                        success: false,
+                    })
+                }
+            }
+        };
+
+        // The chunk covers the keys up to its last one, the last chunk every
+        // key after the previous one.
+        let until = match (done, pairs.last()) {
+            (true, _) => None,
+            (false, Some((key, _))) => Some(key.clone()),
+            (false, None) => last_key.clone(),
+        };
+        {
+            let keep: HashSet<&[u8]> = pairs.iter().map(|(key, _)| key.as_slice()).collect();
+            self.remove_stale(last_key, until.as_deref(), &keep).await?;
+        }
+        let count = pairs.len() as u64;
+        for (key, val) in pairs {
+            self.engine.set_bytes(key, val).await?;
+        }
+        if done {
+            self.flush_engine().await?;
+        }
+
+        let mut st = self.lock();
+        if let Some(install) = st.install.as_mut() {
+            install.received += count;
+            install.last_key = until;
+        }
+        if done {
+            info!(self.log, "snapshot at {} loaded", meta.index);
+            let index = meta.index;
+            st.log.compact(meta)?;
+            st.install = None;
+            st.commit = st.commit.max(index);
+            st.applied = index;
+            let _ = self.applied.send(index);
+            self.committed.notify_one();
+        }
+        Ok(RaftResponse::Snapshot {
+            term: st.log.term(),
+            success: true,
+        })
+    }
+
+    /// Removes the keys after `after` and up to `until`, or to the end if it
+    /// is `None`, that a snapshot chunk does not hold.
+    async fn remove_stale(
+        &self,
+        mut after: Option<Vec<u8>>,
+        until: Option<&[u8]>,
+        keep: &HashSet<&[u8]>,
+    ) -> Result<()> {
+        loop {
+            let pairs = self.engine.scan_after(after.take(), CHUNK).await?;
+            after = match pairs.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => return Ok(()),
+            };
+            for (key, _) in pairs {
+                if until.map_or(false, |until| key.as_slice() > until) {
+                    return Ok(());
+                }
+                if !keep.contains(key.as_slice()) {
+                    replication::apply_replicated(&self.engine, LogEntry::Remove { key }).await?;
+                }
+            }
+        }
+    }
+
+    /// Makes the applied writes durable, for engines that tell how.
+    async fn flush_engine(&self) -> Result<()> {
+        match self.engine.admin() {
+            Some(admin) => admin.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Accepts `leader` as the leader of `term`.
+    fn follow(&self, st: &mut State, term: u64, leader: String) {
+        if term > st.log.term() || st.role != Role::Follower {
+            self.step_down(st, term);
+        }
+        st.leader = Some(leader);
+        st.heard = Some(Instant::now());
+        st.election_deadline = Instant::now() + deadline(self.election_timeout);
+    }
+
+    async fn apply_loop(self: Arc<Self>) {
+        let mut stopped = self.stopped.subscribe();
+        loop {
+            let ready = {
+                let st = self.lock();
+                st.applied < st.commit && st.install.is_none()
+            };
+            if !ready {
+                select! {
+                    _ = self.committed.notified() => {}
+                    _ = stopped.changed() => return,
+                }
+                continue;
+            }
+
+            let _apply = self.apply_lock.lock().await;
+            let entry = {
+                let st = self.lock();
+                if st.applied >= st.commit || st.install.is_some() {
+                    continue;
+                }
+                st.log.entry(st.applied + 1).cloned()
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    error!(self.log, "committed entry missing from the log");
+                    sleep(self.heartbeat_interval).await;
+                    continue;
+                }
+            };
+
+            let res = match &entry.op {
+                Op::Write { entry } => replication::apply(&self.engine, entry.clone()).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = &res {
+                if !matches!(e, KvError::KeyNotFound) {
+                    error!(self.log, "applying entry {} failed: {}", entry.index, e);
+                }
+            }
+
+            let compact = {
+                let mut st = self.lock();
+                st.applied = entry.index;
+                if let Some(waiter) = st.waiters.remove(&entry.index) {
+                    // Another leader replaced the entry of the waiting client.
+                    let res = if waiter.term == entry.term {
+                        res
+                    } else {
+                        Err(not_leader(&st))
+                    };
+                    let _ = waiter.sender.send(res);
+                }
+                let _ = self.applied.send(entry.index);
+                st.applied - st.log.snapshot().index > self.snapshot_threshold
+            };
+            if compact {
+                self.compact().await;
+            }
+        }
+    }
+
+    /// Drops the applied entries, once the engine data they led to is on
+    /// disk. Called with the apply lock held, so nothing is applied meanwhile.
+    async fn compact(&self) {
+        if let Err(e) = self.flush_engine().await {
+            error!(self.log, "flushing before compaction failed: {}", e);
+            return;
+        }
+        let mut st = self.lock();
+        let meta = SnapshotMeta {
+            index: st.applied,
+            term: st.log.term_at(st.applied).unwrap_or(0),
+            members: st.log.members_at(st.applied).to_vec(),
+        };
+        match st.log.compact(meta) {
+            Ok(()) => info!(self.log, "log compacted up to {}", st.applied),
+            Err(e) => error!(self.log, "compacting the log failed: {}", e),
+        }
+    }
+
+    async fn wait_applied(&self, index: u64) -> Result<()> {
+        let mut applied = self.applied.subscribe();
+        let wait = async {
+            while *applied.borrow() < index {
+                if applied.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        timeout(self.election_timeout * 10, wait)
+            .await
+            .map_err(|_| KvError::Timeout {
+                op: "read".to_string(),
+            })
+    }
+}
+
+impl<E: KvsEngine> RaftPeer for RaftNode<E> {
+    fn handle(self: Arc<Self>, req: RaftRequest) -> BoxFuture<'static, Result<RaftResponse>> {
+        RaftNode::handle(self, req).boxed()
+    }
+}
+
+fn not_leader(st: &State) -> KvError {
+    KvError::NotLeader {
+        leader: st.leader.clone(),
+    }
+}
+
+fn has_quorum(votes: &HashSet<String>, members: &[String]) -> bool {
+    let granted = members.iter().filter(|m| votes.contains(*m)).count();
+    granted > members.len() / 2
+}
+
+/// Random election timeout between `min` and twice `min`.
+fn deadline(min: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    let jitter = hasher.finish() % (min.as_nanos() as u64).max(1);
+    min + Duration::from_nanos(jitter)
+}
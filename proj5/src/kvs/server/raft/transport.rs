@@ -0,0 +1,119 @@
+//! How Raft messages reach the other members, over the client protocol.
+
+use crate::kvs::net::byte_pairs;
+use crate::kvs::server::raft::log::Entry;
+use crate::kvs::{KvsClient, KvsClientBuilder, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "rpc")]
+pub(crate) enum RaftRequest {
+    Vote {
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    Append {
+        term: u64,
+        leader: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// One chunk of the leader's engine data, `offset` pairs were sent
+    /// before it. The data reflects at least the entries up to `index`.
+    Snapshot {
+        term: u64,
+        leader: String,
+        index: u64,
+        last_term: u64,
+        members: Vec<String>,
+        offset: u64,
+        #[serde(with = "byte_pairs")]
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "rpc")]
+pub(crate) enum RaftResponse {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// `index` is the last entry known to match the leader's log on
+    /// success, and the follower's last index otherwise.
+    Append {
+        term: u64,
+        success: bool,
+        index: u64,
+    },
+    Snapshot {
+        term: u64,
+        success: bool,
+    },
+}
+
+impl RaftResponse {
+    pub(crate) fn term(&self) -> u64 {
+        match self {
+            RaftResponse::Vote { term, .. }
+            | RaftResponse::Append { term, .. }
+            | RaftResponse::Snapshot { term, .. } => *term,
+        }
+    }
+}
+
+/// Sends a request to another member and waits for its response.
+pub(crate) trait RaftTransport: Send + Sync + 'static {
+    fn call(&self, to: &str, req: RaftRequest) -> BoxFuture<'static, Result<RaftResponse>>;
+}
+
+/// Receiving side of an in-process transport.
+pub(crate) trait RaftPeer: Send + Sync + 'static {
+    fn handle(self: Arc<Self>, req: RaftRequest) -> BoxFuture<'static, Result<RaftResponse>>;
+}
+
+/// Sends the requests as commands to the other servers, keeping idle
+/// connections for the next ones.
+pub(crate) struct TcpTransport {
+    client: KvsClientBuilder,
+    idle: Arc<Mutex<HashMap<String, Vec<KvsClient>>>>,
+}
+
+impl TcpTransport {
+    /// `client` carries the timeouts, TLS and credentials, its address is
+    /// replaced by each member's.
+    pub(crate) fn new(client: KvsClientBuilder) -> TcpTransport {
+        TcpTransport {
+            client,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl RaftTransport for TcpTransport {
+    fn call(&self, to: &str, req: RaftRequest) -> BoxFuture<'static, Result<RaftResponse>> {
+        let to = to.to_string();
+        let idle = self.idle.clone();
+        let builder = self.client.clone();
+        async move {
+            let client = idle.lock().unwrap().get_mut(&to).and_then(Vec::pop);
+            let mut client = match client {
+                Some(client) => client,
+                None => builder.addr(to.parse()?).connect().await?,
+            };
+            let resp = client.raft(req).await?;
+            idle.lock().unwrap().entry(to).or_default().push(client);
+            Ok(resp)
+        }
+        .boxed()
+    }
+}
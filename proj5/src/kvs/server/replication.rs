@@ -0,0 +1,582 @@
+//! Primary/replica replication.
+//!
+//! A primary applies writes one at a time and numbers them in a bounded
+//! in-memory backlog. A replica asks for the entries after the last
+//! sequence it applied; the primary streams them if its backlog still
+//! holds them, and a full snapshot of its engine otherwise, e.g. for a new
+//! replica or one that was disconnected for too long. Sequences are only
+//! meaningful within the history `id` of one primary process, a restarted
+//! primary sends snapshots to every replica.
+//!
+//! Replaying entries a replica already applied converges to the same data,
+//! so the replica saves its position lazily.
+
+use crate::kvs::client::result_error;
+use crate::kvs::net::{read_async, write_async, Command, CommandResult, ReplicationFrame};
+use crate::kvs::server::atomic_file;
+use crate::kvs::{
+    KvError, KvsAddr, KvsClientBuilder, KvsEngine, KvsStream, LogEntry, Result, RetryPolicy,
+};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::{oneshot, watch};
+use tokio::time::{sleep, timeout};
+
+/// How often an idle primary tells its replicas it is alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a replica waits for the next frame before reconnecting.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shortest wait before reconnecting to the primary.
+const MIN_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Entries a replica applies between saves of its position.
+const SAVE_EVERY: u64 = 100;
+
+/// Entries sent to a replica between checks of the backlog.
+const BATCH: usize = 128;
+
+/// Pairs of a snapshot read from the engine at a time.
+const SNAPSHOT_BATCH: usize = 1024;
+
+/// Replication role of a server.
+#[derive(Clone)]
+pub(crate) enum Replication {
+    Standalone,
+    Primary(Arc<ReplicationLog>),
+    Replica(Arc<ReplicaStatus>),
+}
+
+/// Recent writes of a primary, numbered from 1.
+pub(crate) struct ReplicationLog {
+    id: String,
+    capacity: usize,
+    backlog: Mutex<Backlog>,
+    /// Serializes writes, so the sequence order is the order the engine
+    /// applied them in.
+    write_lock: tokio::sync::Mutex<()>,
+    /// Wakes up the replica streams after a write.
+    written: watch::Sender<u64>,
+    /// Keeps the channel open while no replica is connected.
+    _written: watch::Receiver<u64>,
+    replicas: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Backlog {
+    entries: VecDeque<(u64, LogEntry)>,
+    last_seq: u64,
+}
+
+/// Progress of a replica, shown by `INFO`.
+pub(crate) struct ReplicaStatus {
+    primary: String,
+    seq: AtomicU64,
+    primary_seq: AtomicU64,
+    connected: AtomicBool,
+    snapshots: AtomicU64,
+}
+
+/// Last entry a replica applied, saved in its state file.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+struct Position {
+    id: String,
+    seq: u64,
+}
+
+impl Replication {
+    pub(crate) fn primary_addr(&self) -> Option<&str> {
+        match self {
+            Replication::Replica(status) => Some(status.primary.as_str()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn fields(&self) -> Vec<(String, String)> {
+        match self {
+            Replication::Standalone => vec![("role".to_string(), "standalone".to_string())],
+            Replication::Primary(log) => vec![
+                ("role".to_string(), "primary".to_string()),
+                ("replication_id".to_string(), log.id.clone()),
+                ("replication_seq".to_string(), log.last_seq().to_string()),
+                (
+                    "replicas".to_string(),
+                    log.replicas.load(Ordering::SeqCst).to_string(),
+                ),
+            ],
+            Replication::Replica(status) => {
+                let seq = status.seq.load(Ordering::SeqCst);
+                let primary_seq = status.primary_seq.load(Ordering::SeqCst);
+                vec![
+                    ("role".to_string(), "replica".to_string()),
+                    ("primary".to_string(), status.primary.clone()),
+                    (
+                        "primary_connected".to_string(),
+                        status.connected.load(Ordering::SeqCst).to_string(),
+                    ),
+                    ("replication_seq".to_string(), seq.to_string()),
+                    (
+                        "replication_lag".to_string(),
+                        primary_seq.saturating_sub(seq).to_string(),
+                    ),
+                    (
+                        "replication_snapshots".to_string(),
+                        status.snapshots.load(Ordering::SeqCst).to_string(),
+                    ),
+                ]
+            }
+        }
+    }
+}
+
+impl ReplicationLog {
+    pub(crate) fn new(capacity: usize) -> ReplicationLog {
+        let (written, receiver) = watch::channel(0);
+        ReplicationLog {
+            id: random_id(),
+            capacity: capacity.max(1),
+            backlog: Mutex::new(Backlog::default()),
+            write_lock: tokio::sync::Mutex::new(()),
+            written,
+            _written: receiver,
+            replicas: AtomicUsize::new(0),
+        }
+    }
+
+    /// Applies the entry and appends it to the backlog if it succeeded.
+    pub(crate) async fn write<E: KvsEngine>(&self, engine: &E, entry: LogEntry) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        apply(engine, entry.clone()).await?;
+
+        let seq = self.backlog.lock().unwrap().push(entry, self.capacity);
+        let _ = self.written.send(seq);
+        Ok(())
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.backlog.lock().unwrap().last_seq
+    }
+
+    /// Up to `max` entries after `seq`, `None` if the backlog no longer
+    /// holds them or `seq` is not part of this history.
+    fn entries_after(&self, seq: u64, max: usize) -> Option<Vec<(u64, LogEntry)>> {
+        let backlog = self.backlog.lock().unwrap();
+        let first = match backlog.entries.front() {
+            Some((first, _)) => *first,
+            None => backlog.last_seq + 1,
+        };
+        if seq > backlog.last_seq || seq + 1 < first {
+            return None;
+        }
+        let skip = (seq + 1 - first) as usize;
+        Some(
+            backlog
+                .entries
+                .iter()
+                .skip(skip)
+                .take(max)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Streams the history to a replica until the connection fails.
+    pub(crate) async fn serve<E: KvsEngine, S: KvsStream>(
+        &self,
+        engine: &E,
+        stream: &mut S,
+        id: String,
+        seq: u64,
+        log: &Logger,
+    ) -> Result<()> {
+        self.replicas.fetch_add(1, Ordering::SeqCst);
+        let res = self.stream_to(engine, stream, id, seq, log).await;
+        self.replicas.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    async fn stream_to<E: KvsEngine, S: KvsStream>(
+        &self,
+        engine: &E,
+        stream: &mut S,
+        id: String,
+        seq: u64,
+        log: &Logger,
+    ) -> Result<()> {
+        let mut changes = self.written.subscribe();
+        write_async(stream, &CommandResult::Ok).await?;
+
+        let mut pos = if id == self.id && self.entries_after(seq, 0).is_some() {
+            info!(log, "replica resumes after {}", seq);
+            write_async(stream, &ReplicationFrame::Resume).await?;
+            seq
+        } else {
+            info!(log, "sending snapshot to replica at {}:{}", id, seq);
+            self.send_snapshot(engine, stream).await?
+        };
+
+        loop {
+            let entries = match self.entries_after(pos, BATCH) {
+                Some(entries) => entries,
+                None => {
+                    warn!(log, "replica fell behind the backlog, sending snapshot");
+                    pos = self.send_snapshot(engine, stream).await?;
+                    continue;
+                }
+            };
+
+            if entries.is_empty() {
+                match timeout(HEARTBEAT_INTERVAL, changes.changed()).await {
+                    Ok(_) => {}
+                    Err(_) => {
+                        let heartbeat = ReplicationFrame::Heartbeat {
+                            seq: self.last_seq(),
+                        };
+                        write_async(stream, &heartbeat).await?;
+                    }
+                }
+                continue;
+            }
+
+            for (seq, entry) in entries {
+                write_async(stream, &ReplicationFrame::Entry { seq, entry }).await?;
+                pos = seq;
+            }
+        }
+    }
+
+    /// Sends every pair of the engine, a batch at a time, with heartbeats
+    /// between batches read slowly. Writes keep going meanwhile, the
+    /// entries after the returned sequence are replayed on top.
+    async fn send_snapshot<E: KvsEngine, S: KvsStream>(
+        &self,
+        engine: &E,
+        stream: &mut S,
+    ) -> Result<u64> {
+        let seq = self.last_seq();
+        let start = ReplicationFrame::Snapshot {
+            id: self.id.clone(),
+            seq,
+        };
+        write_async(stream, &start).await?;
+        let mut after = None;
+        loop {
+            let read_at = Instant::now();
+            let pairs = engine.scan_after(after.take(), SNAPSHOT_BATCH).await?;
+            if read_at.elapsed() >= HEARTBEAT_INTERVAL {
+                let heartbeat = ReplicationFrame::Heartbeat {
+                    seq: self.last_seq(),
+                };
+                write_async(stream, &heartbeat).await?;
+            }
+            after = match pairs.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+            for (key, val) in pairs {
+                write_async(stream, &ReplicationFrame::Pair { key, val }).await?;
+            }
+        }
+        write_async(stream, &ReplicationFrame::SnapshotEnd).await?;
+        Ok(seq)
+    }
+}
+
+impl Backlog {
+    /// Appends the entry, dropping the oldest one when full, and returns
+    /// its sequence.
+    fn push(&mut self, entry: LogEntry, capacity: usize) -> u64 {
+        self.last_seq += 1;
+        if self.entries.len() == capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((self.last_seq, entry));
+        self.last_seq
+    }
+}
+
+impl ReplicaStatus {
+    pub(crate) fn new(primary: &KvsAddr) -> ReplicaStatus {
+        ReplicaStatus {
+            primary: primary.to_string(),
+            seq: AtomicU64::new(0),
+            primary_seq: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+            snapshots: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Applies a replicated entry, removing a missing key is not an error
+/// since entries may be replayed.
+pub(crate) async fn apply_replicated<E: KvsEngine>(engine: &E, entry: LogEntry) -> Result<()> {
+    match apply(engine, entry).await {
+        Err(KvError::KeyNotFound) => Ok(()),
+        res => res,
+    }
+}
+
+pub(crate) async fn apply<E: KvsEngine>(engine: &E, entry: LogEntry) -> Result<()> {
+    match entry {
+        LogEntry::Set { key, val } => engine.set_bytes(key, val).await,
+        LogEntry::Remove { key } => engine.remove_bytes(key).await,
+    }
+}
+
+/// Follows the primary until shut down, reconnecting after failures.
+pub(crate) async fn follow<E: KvsEngine>(
+    engine: E,
+    primary: KvsClientBuilder,
+    state_path: PathBuf,
+    status: Arc<ReplicaStatus>,
+    log: Logger,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    let mut position = Position::load(&state_path)?;
+    status.seq.store(position.seq, Ordering::SeqCst);
+
+    let retry = RetryPolicy::default();
+    let mut attempt = 0;
+    loop {
+        let res = select! {
+            res = sync(&engine, &primary, &mut position, &state_path, &status, &log) => Some(res),
+            _ = &mut shutdown => None,
+        };
+        // Back off from scratch after a connection that worked.
+        if status.connected.swap(false, Ordering::SeqCst) {
+            attempt = 0;
+        }
+
+        match res {
+            Some(res) => {
+                let backoff = retry.backoff(attempt).max(MIN_BACKOFF);
+                if let Err(e) = res {
+                    warn!(
+                        log,
+                        "replication failed, reconnecting in {:?}: {}", backoff, e
+                    );
+                }
+                attempt += 1;
+                select! {
+                    _ = sleep(backoff) => {}
+                    _ = &mut shutdown => return position.save(&state_path),
+                }
+            }
+            None => {
+                info!(log, "replication stopped at {}", position.seq);
+                return position.save(&state_path);
+            }
+        }
+    }
+}
+
+/// One connection to the primary, returns once it fails.
+async fn sync<E: KvsEngine>(
+    engine: &E,
+    primary: &KvsClientBuilder,
+    position: &mut Position,
+    state_path: &Path,
+    status: &ReplicaStatus,
+    log: &Logger,
+) -> Result<()> {
+    let client = primary.clone().connect().await?;
+    let mut stream = client.into_stream().ok_or_else(|| KvError::Replication {
+        msg: "no connection to the primary".to_string(),
+    })?;
+
+    let cmd = Command::Replicate {
+        id: position.id.clone(),
+        seq: position.seq,
+    };
+    write_async(&mut stream, &cmd).await?;
+    match read_async(&mut stream).await? {
+        CommandResult::Ok => {}
+        result => return Err(result_error(result)),
+    }
+    status.connected.store(true, Ordering::SeqCst);
+    info!(
+        log,
+        "replicating from {} after {}", status.primary, position.seq
+    );
+
+    let mut unsaved = 0;
+    loop {
+        let frame = match timeout(READ_TIMEOUT, read_async(&mut stream)).await {
+            Ok(frame) => frame?,
+            Err(_) => {
+                return Err(KvError::Timeout {
+                    op: "replication".to_string(),
+                })
+            }
+        };
+
+        match frame {
+            ReplicationFrame::Snapshot { id, seq } => {
+                info!(log, "loading snapshot {}:{}", id, seq);
+                load_snapshot(engine, &mut stream, position, state_path).await?;
+                *position = Position { id, seq };
+                position.save(state_path)?;
+                status.seq.store(seq, Ordering::SeqCst);
+                status.snapshots.fetch_add(1, Ordering::SeqCst);
+            }
+            ReplicationFrame::Resume => {}
+            ReplicationFrame::Entry { seq, entry } => {
+                apply_replicated(engine, entry).await?;
+                position.seq = seq;
+                status.seq.store(seq, Ordering::SeqCst);
+                status.primary_seq.fetch_max(seq, Ordering::SeqCst);
+                unsaved += 1;
+                if unsaved >= SAVE_EVERY {
+                    position.save(state_path)?;
+                    unsaved = 0;
+                }
+            }
+            ReplicationFrame::Heartbeat { seq } => {
+                status.primary_seq.store(seq, Ordering::SeqCst);
+                if unsaved > 0 {
+                    position.save(state_path)?;
+                    unsaved = 0;
+                }
+            }
+            frame => {
+                return Err(KvError::Replication {
+                    msg: format!("unexpected frame: {:?}", frame),
+                })
+            }
+        }
+    }
+}
+
+/// Replaces the data with the pairs of the snapshot. The data is kept until
+/// the first of them arrives, a primary slow to start the snapshot leaves
+/// the replica serving its old data.
+async fn load_snapshot<E: KvsEngine, S: KvsStream>(
+    engine: &E,
+    stream: &mut S,
+    position: &mut Position,
+    state_path: &Path,
+) -> Result<()> {
+    let mut cleared = false;
+    loop {
+        let frame = match timeout(READ_TIMEOUT, read_async(stream)).await {
+            Ok(frame) => frame?,
+            Err(_) => {
+                return Err(KvError::Timeout {
+                    op: "snapshot".to_string(),
+                })
+            }
+        };
+        let data = matches!(
+            frame,
+            ReplicationFrame::Pair { .. } | ReplicationFrame::SnapshotEnd
+        );
+        if data && !cleared {
+            // Until the snapshot is complete the data matches no
+            // position, a restart must load a snapshot again.
+            *position = Position::default();
+            position.save(state_path)?;
+            clear(engine).await?;
+            cleared = true;
+        }
+        match frame {
+            ReplicationFrame::Pair { key, val } => engine.set_bytes(key, val).await?,
+            ReplicationFrame::Heartbeat { .. } => {}
+            ReplicationFrame::SnapshotEnd => return Ok(()),
+            frame => {
+                return Err(KvError::Replication {
+                    msg: format!("unexpected frame in snapshot: {:?}", frame),
+                })
+            }
+        }
+    }
+}
+
+async fn clear<E: KvsEngine>(engine: &E) -> Result<()> {
+    let mut after = None;
+    loop {
+        let pairs = engine.scan_after(after.take(), SNAPSHOT_BATCH).await?;
+        after = match pairs.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => return Ok(()),
+        };
+        for (key, _) in pairs {
+            apply_replicated(engine, LogEntry::Remove { key }).await?;
+        }
+    }
+}
+
+impl Position {
+    fn load(path: &Path) -> Result<Position> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| KvError::Replication {
+                msg: format!("{}: {}", path.display(), e),
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Position::default()),
+            Err(e) => Err(KvError::Io(e)),
+        }
+    }
+
+    /// Replaces the file atomically, a crash leaves the old or the new one.
+    fn save(&self, path: &Path) -> Result<()> {
+        let content =
+            serde_json::to_vec(self).map_err(|e| KvError::Replication { msg: e.to_string() })?;
+        atomic_file::replace(path, &content)
+    }
+}
+
+/// Identifies the history of one primary process.
+fn random_id() -> String {
+    let state = RandomState::new();
+    let mut id = String::new();
+    for i in 0..2u8 {
+        let mut hasher = state.build_hasher();
+        hasher.write_u8(i);
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(i: u32) -> LogEntry {
+        LogEntry::Set {
+            key: format!("key{}", i).into_bytes(),
+            val: i.to_string().into_bytes(),
+        }
+    }
+
+    fn push(log: &ReplicationLog, entry: LogEntry) {
+        log.backlog.lock().unwrap().push(entry, log.capacity);
+    }
+
+    #[test]
+    fn entries_after() {
+        let log = ReplicationLog::new(3);
+        assert_eq!(log.entries_after(0, 10), Some(vec![]));
+        assert_eq!(log.entries_after(1, 10), None);
+
+        for i in 1..=5 {
+            push(&log, set(i));
+        }
+        // The backlog holds 3..=5.
+        assert_eq!(log.entries_after(1, 10), None);
+        assert_eq!(
+            log.entries_after(2, 10),
+            Some(vec![(3, set(3)), (4, set(4)), (5, set(5))])
+        );
+        assert_eq!(log.entries_after(3, 1), Some(vec![(4, set(4))]));
+        assert_eq!(log.entries_after(5, 10), Some(vec![]));
+        assert_eq!(log.entries_after(6, 10), None);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::kvs::metrics::{Counter, Family, Gauge, Histogram, MetricsWriter};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub(crate) struct ServerMetrics {
+    pub(crate) started: Instant,
+    pub(crate) requests: Family<Counter>,
+    pub(crate) latency: Family<Histogram>,
+    pub(crate) busy: Counter,
+    pub(crate) active_connections: Gauge,
+    pub(crate) bytes_in: Counter,
+    pub(crate) bytes_out: Counter,
+}
+
+impl ServerMetrics {
+    pub(crate) fn new() -> ServerMetrics {
+        ServerMetrics {
+            started: Instant::now(),
+            requests: Family::new("command"),
+            latency: Family::new("command"),
+            busy: Counter::default(),
+            active_connections: Gauge::default(),
+            bytes_in: Counter::default(),
+            bytes_out: Counter::default(),
+        }
+    }
+
+    /// Counters reported by the `STATS` admin command.
+    pub(crate) fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = vec![
+            (
+                "active_connections".to_string(),
+                self.active_connections.get().to_string(),
+            ),
+            ("busy_rejections".to_string(), self.busy.get().to_string()),
+            (
+                "received_bytes".to_string(),
+                self.bytes_in.get().to_string(),
+            ),
+            ("sent_bytes".to_string(), self.bytes_out.get().to_string()),
+        ];
+        for (command, counter) in self.requests.snapshot() {
+            fields.push((format!("requests_{}", command), counter.get().to_string()));
+        }
+        fields
+    }
+
+    pub(crate) fn write(&self, out: &mut MetricsWriter) {
+        out.counter_family(
+            "kvs_requests_total",
+            "Requests handled, by command.",
+            &self.requests,
+        );
+        out.histogram_family(
+            "kvs_request_duration_seconds",
+            "Request handling latency, by command.",
+            &self.latency,
+        );
+        out.counter(
+            "kvs_busy_rejections_total",
+            "Connections and requests rejected by the server limits.",
+            &self.busy,
+        );
+        out.gauge(
+            "kvs_active_connections",
+            "Connections currently served.",
+            self.active_connections.get() as f64,
+        );
+        out.counter(
+            "kvs_received_bytes_total",
+            "Bytes read from clients.",
+            &self.bytes_in,
+        );
+        out.counter(
+            "kvs_sent_bytes_total",
+            "Bytes written to clients.",
+            &self.bytes_out,
+        );
+    }
+}
+
+/// Keeps the active connection gauge up while a connection is served.
+pub(crate) struct ConnectionGuard(Arc<ServerMetrics>);
+
+impl ConnectionGuard {
+    pub(crate) fn new(metrics: Arc<ServerMetrics>) -> ConnectionGuard {
+        metrics.active_connections.inc();
+        ConnectionGuard(metrics)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.dec();
+    }
+}
+
+/// Stream counting the bytes passing through it.
+pub(crate) struct MeteredStream<S> {
+    inner: S,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<S> MeteredStream<S> {
+    pub(crate) fn new(inner: S, metrics: Arc<ServerMetrics>) -> MeteredStream<S> {
+        MeteredStream { inner, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let read = buf.filled().len() - before;
+            self.metrics.bytes_in.add(read as u64);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.metrics.bytes_out.add(written as u64);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,304 @@
+//! Hash slots, the unit keys are moved between servers in.
+//!
+//! The keyspace is split into [`SLOTS`] slots by the CRC16 of the key, as
+//! in Redis Cluster. Each server knows the state of every slot: served
+//! here, served by another server, or moving. Moving a slot goes through
+//! two states. The target is told it is importing the slot, then the source
+//! that it is migrating it, and the source streams the slot's keys to the
+//! target in batches, deleting each batch once copied. Meanwhile the source
+//! serves the keys it still holds and sends clients asking for the others
+//! to the target with an ASK redirect, which the target honors only for
+//! that one request. Once the slot is empty both servers are told the
+//! target owns it, and clients asking the source are redirected for good
+//! with MOVED.
+//!
+//! Transactional keys are placed by their own slots, not by the slot of the
+//! key they belong to: slots holding transactions must not be moved.
+
+use crate::kvs::net::CommandResult;
+use crate::kvs::server::atomic_file;
+use crate::kvs::{KvError, KvsAddr, KvsClientBuilder, KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+/// Number of hash slots.
+pub const SLOTS: u16 = 16384;
+
+/// Slot of the key, the CRC16 (XMODEM) of its bytes modulo [`SLOTS`].
+pub fn key_slot(key: impl AsRef<[u8]>) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in key.as_ref() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc % SLOTS
+}
+
+/// State of a slot on one server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state")]
+pub enum SlotState {
+    /// Served by no known server.
+    Unassigned,
+    /// Served here.
+    Owned,
+    /// Served by the server at `addr`, clients are redirected with MOVED.
+    Node { addr: String },
+    /// Served here while the keys are moved to `to`, clients asking for a
+    /// key already moved are redirected with ASK.
+    Migrating { to: String },
+    /// Being moved here from `from`, which serves it until the move is
+    /// done. Only requests following an ASK redirect are served.
+    Importing { from: String },
+}
+
+/// The slots a server starts with, see
+/// [`KvsServer::with_slots`](crate::kvs::KvsServer::with_slots).
+#[derive(Clone)]
+pub struct SlotConfig {
+    states: Vec<SlotState>,
+    peer_client: Option<KvsClientBuilder>,
+}
+
+impl SlotConfig {
+    /// A server serving the slots in `owned`, every other slot unassigned.
+    pub fn new(owned: impl IntoIterator<Item = RangeInclusive<u16>>) -> SlotConfig {
+        let mut config = SlotConfig {
+            states: vec![SlotState::Unassigned; SLOTS as usize],
+            peer_client: None,
+        };
+        for range in owned {
+            config.set(range, SlotState::Owned);
+        }
+        config
+    }
+
+    /// Slots served by the server at `addr`, redirected there.
+    pub fn node(mut self, slots: RangeInclusive<u16>, addr: impl Into<KvsAddr>) -> Self {
+        let addr = addr.into().to_string();
+        self.set(slots, SlotState::Node { addr });
+        self
+    }
+
+    /// Timeouts, TLS and credentials used to stream migrating keys to other
+    /// servers. The builder's own address is replaced by the target's.
+    pub fn peer_client(mut self, client: KvsClientBuilder) -> Self {
+        self.peer_client = Some(client);
+        self
+    }
+
+    fn set(&mut self, slots: RangeInclusive<u16>, state: SlotState) {
+        for slot in slots.filter(|slot| *slot < SLOTS) {
+            self.states[slot as usize] = state.clone();
+        }
+    }
+}
+
+/// Runs of slots in the same state, as saved.
+#[derive(Serialize, Deserialize)]
+struct SlotRun {
+    start: u16,
+    end: u16,
+    #[serde(flatten)]
+    state: SlotState,
+}
+
+/// What to do with a request for a key.
+pub(crate) enum Route<'a> {
+    /// Serve it, holding the guard if any until it is done.
+    Serve(Option<MutexGuard<'a, ()>>),
+    Redirect(CommandResult),
+}
+
+/// The slot states of a server, saved after every change.
+pub(crate) struct SlotTable {
+    states: Mutex<Vec<SlotState>>,
+    state_path: PathBuf,
+    peer_client: Option<KvsClientBuilder>,
+    /// Held while serving a key of a migrating slot, and by the migration
+    /// while it copies and deletes a batch, so no key changes here after it
+    /// was copied.
+    migration: AsyncMutex<()>,
+}
+
+impl SlotTable {
+    /// Loads the states saved in `state_path`, or starts from the config
+    /// if there are none.
+    pub(crate) fn open(config: SlotConfig, state_path: impl Into<PathBuf>) -> Result<SlotTable> {
+        let state_path = state_path.into();
+        let states = match fs::read(&state_path) {
+            Ok(buf) => {
+                let runs: Vec<SlotRun> = serde_json::from_slice(&buf).map_err(slots_error)?;
+                let mut states = vec![SlotState::Unassigned; SLOTS as usize];
+                for run in runs {
+                    for slot in run.start..=run.end.min(SLOTS - 1) {
+                        states[slot as usize] = run.state.clone();
+                    }
+                }
+                states
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => config.states,
+            Err(e) => return Err(e.into()),
+        };
+        let table = SlotTable {
+            states: Mutex::new(states),
+            state_path,
+            peer_client: config.peer_client,
+            migration: AsyncMutex::new(()),
+        };
+        table.save(&table.states.lock().unwrap())?;
+        Ok(table)
+    }
+
+    pub(crate) fn state(&self, slot: u16) -> Result<SlotState> {
+        let states = self.states.lock().unwrap();
+        states
+            .get(slot as usize)
+            .cloned()
+            .ok_or_else(|| invalid_slot(slot))
+    }
+
+    /// Changes the slot's state, returns the previous one.
+    pub(crate) fn set(&self, slot: u16, state: SlotState) -> Result<SlotState> {
+        let mut states = self.states.lock().unwrap();
+        let current = states
+            .get_mut(slot as usize)
+            .ok_or_else(|| invalid_slot(slot))?;
+        let previous = std::mem::replace(current, state);
+        self.save(&states)?;
+        Ok(previous)
+    }
+
+    /// Where the request for `key` is served. `asking` is set for requests
+    /// following an ASK redirect.
+    pub(crate) async fn route<E: KvsEngine>(
+        &self,
+        engine: &E,
+        key: &[u8],
+        asking: bool,
+    ) -> Result<Route<'_>> {
+        let slot = key_slot(key);
+        match self.state(slot)? {
+            SlotState::Owned => Ok(Route::Serve(None)),
+            SlotState::Importing { .. } if asking => Ok(Route::Serve(None)),
+            SlotState::Importing { from } => Ok(Route::Redirect(CommandResult::Moved(slot, from))),
+            SlotState::Node { addr } => Ok(Route::Redirect(CommandResult::Moved(slot, addr))),
+            SlotState::Unassigned => Err(slots_error(format!("slot {} is not served", slot))),
+            SlotState::Migrating { .. } => {
+                let guard = self.migration.lock().await;
+                // The migration may have ended while waiting for the guard.
+                match self.state(slot)? {
+                    SlotState::Migrating { to } => {
+                        if engine.get_bytes(key.to_vec()).await?.is_some() {
+                            Ok(Route::Serve(Some(guard)))
+                        } else {
+                            Ok(Route::Redirect(CommandResult::Ask(slot, to)))
+                        }
+                    }
+                    SlotState::Owned => Ok(Route::Serve(None)),
+                    SlotState::Node { addr } => {
+                        Ok(Route::Redirect(CommandResult::Moved(slot, addr)))
+                    }
+                    state => Err(slots_error(format!("slot {} is {:?}", slot, state))),
+                }
+            }
+        }
+    }
+
+    /// Target of a migrating slot, and the builder to connect to it.
+    pub(crate) fn migration_target(&self, slot: u16) -> Result<KvsClientBuilder> {
+        let to = match self.state(slot)? {
+            SlotState::Migrating { to } => to.parse::<KvsAddr>()?,
+            state => return Err(slots_error(format!("slot {} is {:?}", slot, state))),
+        };
+        Ok(match &self.peer_client {
+            Some(client) => client.clone().addr(to),
+            None => KvsClientBuilder::new(to),
+        })
+    }
+
+    /// Guard held while a batch of a migration is copied and deleted.
+    pub(crate) async fn migration_guard(&self) -> MutexGuard<'_, ()> {
+        self.migration.lock().await
+    }
+
+    fn save(&self, states: &[SlotState]) -> Result<()> {
+        let mut runs: Vec<SlotRun> = Vec::new();
+        for (slot, state) in states.iter().enumerate() {
+            match runs.last_mut() {
+                Some(run) if run.state == *state => run.end = slot as u16,
+                _ => runs.push(SlotRun {
+                    start: slot as u16,
+                    end: slot as u16,
+                    state: state.clone(),
+                }),
+            }
+        }
+        let buf = serde_json::to_vec(&runs).map_err(slots_error)?;
+        atomic_file::replace(&self.state_path, &buf)
+    }
+}
+
+fn invalid_slot(slot: u16) -> KvError {
+    KvError::InvalidArgument {
+        arg: "slot".to_string(),
+        val: slot.to_string(),
+    }
+}
+
+fn slots_error(msg: impl ToString) -> KvError {
+    KvError::Slots {
+        msg: msg.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn key_slots_match_redis() {
+        assert_eq!(key_slot("123456789"), 0x31c3);
+        assert_eq!(key_slot(b"123456789".to_vec()), 0x31c3);
+        assert_eq!(key_slot(""), 0);
+        assert!((0..1000).all(|i| key_slot(&format!("key{}", i)) < SLOTS));
+    }
+
+    #[test]
+    fn states_survive_restart() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("slots.json");
+        let other: KvsAddr = "127.0.0.1:4001".parse()?;
+        let config = SlotConfig::new(vec![0..=8191]).node(8192..=16383, other.clone());
+
+        let table = SlotTable::open(config.clone(), &path)?;
+        let to = SlotState::Migrating {
+            to: other.to_string(),
+        };
+        assert_eq!(table.set(100, to.clone())?, SlotState::Owned);
+        drop(table);
+
+        let table = SlotTable::open(SlotConfig::new(vec![]), &path)?;
+        assert_eq!(table.state(100)?, to);
+        assert_eq!(table.state(101)?, SlotState::Owned);
+        assert_eq!(
+            table.state(9000)?,
+            SlotState::Node {
+                addr: other.to_string()
+            }
+        );
+        assert!(table.state(SLOTS).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! The three column families of a transactional key, stored as plain engine
+//! keys under a reserved prefix:
+//!
+//! - `__txn/l/KEY`: the lock of the transaction writing the key,
+//! - `__txn/w/KEY/COMMIT_TS`: one record per committed or rolled back
+//!   transaction,
+//! - `__txn/d/START_TS/KEY`: the value written by a transaction.
+//!
+//! Timestamps are zero padded so the write records of a key sort by commit
+//! timestamp, and a record of `KEY` is told apart from one of a longer key
+//! starting with `KEY/` by the fixed width suffix.
+
+use crate::kvs::{KvError, KvsEngine, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Keys starting with it are reserved for transactions.
+pub(crate) const TXN_PREFIX: &str = "__txn/";
+
+const TS_WIDTH: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WriteKind {
+    Put,
+    Delete,
+    /// Written at the start timestamp of a rolled back transaction, so its
+    /// prewrite fails if it arrives late.
+    Rollback,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Lock {
+    #[serde(with = "serde_bytes")]
+    pub(crate) primary: Vec<u8>,
+    pub(crate) start_ts: u64,
+    pub(crate) ttl_ms: u64,
+    pub(crate) kind: WriteKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct WriteRecord {
+    pub(crate) start_ts: u64,
+    pub(crate) kind: WriteKind,
+}
+
+pub(crate) fn lock_key(key: &[u8]) -> Vec<u8> {
+    [format!("{}l/", TXN_PREFIX).as_bytes(), key].concat()
+}
+
+pub(crate) fn write_key(key: &[u8], commit_ts: u64) -> Vec<u8> {
+    let mut write_key = write_prefix(key);
+    write_key.extend_from_slice(format!("{:0width$}", commit_ts, width = TS_WIDTH).as_bytes());
+    write_key
+}
+
+pub(crate) fn data_key(key: &[u8], start_ts: u64) -> Vec<u8> {
+    let prefix = format!("{}d/{:0width$}/", TXN_PREFIX, start_ts, width = TS_WIDTH);
+    [prefix.as_bytes(), key].concat()
+}
+
+fn write_prefix(key: &[u8]) -> Vec<u8> {
+    [format!("{}w/", TXN_PREFIX).as_bytes(), key, b"/"].concat()
+}
+
+pub(crate) async fn get_lock<E: KvsEngine>(engine: &E, key: &[u8]) -> Result<Option<Lock>> {
+    get_record(engine, lock_key(key)).await
+}
+
+/// Write records of the key with their commit timestamps, newest first.
+pub(crate) async fn write_records<E: KvsEngine>(
+    engine: &E,
+    key: &[u8],
+) -> Result<Vec<(u64, WriteRecord)>> {
+    let prefix = write_prefix(key);
+    let mut records = Vec::new();
+    for (record_key, val) in engine.scan_bytes(prefix.clone()).await? {
+        let suffix = &record_key[prefix.len()..];
+        let commit_ts = match std::str::from_utf8(suffix).map(str::parse::<u64>) {
+            Ok(Ok(ts)) if suffix.len() == TS_WIDTH => ts,
+            _ => continue,
+        };
+        records.push((commit_ts, decode(&record_key, &val)?));
+    }
+    records.reverse();
+    Ok(records)
+}
+
+/// Record of the transaction started at `start_ts`, if it committed or
+/// rolled back.
+pub(crate) async fn find_write<E: KvsEngine>(
+    engine: &E,
+    key: &[u8],
+    start_ts: u64,
+) -> Result<Option<(u64, WriteRecord)>> {
+    let records = write_records(engine, key).await?;
+    Ok(records
+        .into_iter()
+        .find(|(_, record)| record.start_ts == start_ts))
+}
+
+async fn get_record<E: KvsEngine, T: DeserializeOwned>(
+    engine: &E,
+    key: Vec<u8>,
+) -> Result<Option<T>> {
+    match engine.get_bytes(key.clone()).await? {
+        Some(val) => Ok(Some(decode(&key, &val)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(record).map_err(|e| KvError::Txn { msg: e.to_string() })
+}
+
+fn decode<T: DeserializeOwned>(key: &[u8], val: &[u8]) -> Result<T> {
+    serde_json::from_slice(val).map_err(|e| KvError::Txn {
+        msg: format!("corrupt record {}: {}", String::from_utf8_lossy(key), e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_keys_sort_by_commit_ts() {
+        let mut keys = vec![
+            write_key(b"a", 100),
+            write_key(b"a", 9),
+            write_key(b"a", 10),
+        ];
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                write_key(b"a", 9),
+                write_key(b"a", 10),
+                write_key(b"a", 100)
+            ]
+        );
+        assert!(write_key(b"a/b", 1).starts_with(&write_prefix(b"a")));
+        assert_eq!(data_key(&[0xff], 1), b"__txn/d/00000000000000000001/\xff");
+    }
+}
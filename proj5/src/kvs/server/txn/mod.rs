@@ -0,0 +1,423 @@
+//! Server side of Percolator transactions.
+//!
+//! A transaction reads at its start timestamp and buffers its writes on the
+//! client. To commit, the client prewrites every key: the value goes to the
+//! data column and a lock naming the primary key to the lock column. It
+//! then commits the primary, which writes a record at the commit timestamp
+//! and drops the lock, and the other keys after it. The transaction is
+//! committed once the primary is.
+//!
+//! A reader meeting the lock of a client that crashed asks the primary's
+//! server for the transaction's status. Once the lock's time to live has
+//! passed, a primary still locked is rolled back, and the other keys follow
+//! the primary either way.
+
+mod cf;
+mod tso;
+
+pub(crate) use cf::TXN_PREFIX;
+pub(crate) use tso::{physical_ms, TimestampOracle};
+
+use crate::kvs::net::byte_list;
+use crate::kvs::{KvsEngine, LogEntry, Result};
+use cf::{Lock, WriteKind, WriteRecord};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "txn")]
+pub(crate) enum TxnRequest {
+    /// Reads the key as of `start_ts`.
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        start_ts: u64,
+    },
+    /// Locks the keys for the transaction, none of them is written if any
+    /// of them is locked by another transaction or written after
+    /// `start_ts`.
+    Prewrite {
+        mutations: Vec<Mutation>,
+        #[serde(with = "serde_bytes")]
+        primary: Vec<u8>,
+        start_ts: u64,
+        ttl_ms: u64,
+    },
+    /// Commits the keys locked by the transaction, already committed keys
+    /// are skipped.
+    Commit {
+        #[serde(with = "byte_list")]
+        keys: Vec<Vec<u8>>,
+        start_ts: u64,
+        commit_ts: u64,
+    },
+    /// Drops the locks of the transaction and keeps it from committing.
+    Rollback {
+        #[serde(with = "byte_list")]
+        keys: Vec<Vec<u8>>,
+        start_ts: u64,
+    },
+    /// Status of the transaction, sent to the server of its primary key.
+    /// A primary lock expired at `current_ts` is rolled back.
+    CheckStatus {
+        #[serde(with = "serde_bytes")]
+        primary: Vec<u8>,
+        start_ts: u64,
+        current_ts: u64,
+    },
+}
+
+/// A buffered write, `val` is `None` for removes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Mutation {
+    #[serde(with = "serde_bytes")]
+    pub(crate) key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub(crate) val: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "res")]
+pub(crate) enum TxnResponse {
+    Ok,
+    Value {
+        #[serde(with = "serde_bytes")]
+        val: Option<Vec<u8>>,
+    },
+    /// The key is locked by the transaction started at `lock.start_ts`.
+    Locked {
+        lock: LockInfo,
+    },
+    /// The key was written by a transaction committed at `commit_ts`, after
+    /// the requesting transaction started.
+    Conflict {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        commit_ts: u64,
+    },
+    /// The transaction was rolled back, or committed when a rollback was
+    /// asked for.
+    Aborted {
+        msg: String,
+    },
+    Status {
+        status: TxnStatus,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct LockInfo {
+    #[serde(with = "serde_bytes")]
+    pub(crate) key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub(crate) primary: Vec<u8>,
+    pub(crate) start_ts: u64,
+    pub(crate) ttl_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status")]
+pub(crate) enum TxnStatus {
+    Committed {
+        commit_ts: u64,
+    },
+    RolledBack,
+    /// The primary is locked and its time to live has not passed.
+    Locked,
+}
+
+impl TxnRequest {
+    pub(crate) fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            TxnRequest::Get { key, .. } => vec![key.as_slice()],
+            TxnRequest::Prewrite { mutations, .. } => {
+                mutations.iter().map(|m| m.key.as_slice()).collect()
+            }
+            TxnRequest::Commit { keys, .. } | TxnRequest::Rollback { keys, .. } => {
+                keys.iter().map(Vec::as_slice).collect()
+            }
+            TxnRequest::CheckStatus { primary, .. } => vec![primary.as_slice()],
+        }
+    }
+
+    /// Whether the request may write, which replicas refuse.
+    pub(crate) fn is_write(&self) -> bool {
+        !matches!(self, TxnRequest::Get { .. })
+    }
+}
+
+/// Applies the engine writes of a transaction request the way plain writes
+/// are applied, e.g. through the cluster log.
+pub(crate) trait TxnWriter: Send + Sync {
+    fn write_entry(&self, entry: LogEntry) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Runs the transaction requests of one server, one at a time, and hands
+/// out timestamps if the server is the oracle.
+pub(crate) struct TxnStore<E: KvsEngine> {
+    engine: E,
+    oracle: Option<TimestampOracle>,
+    latch: Mutex<()>,
+}
+
+impl<E: KvsEngine> TxnStore<E> {
+    pub(crate) fn new(engine: E, oracle: Option<TimestampOracle>) -> TxnStore<E> {
+        TxnStore {
+            engine,
+            oracle,
+            latch: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn oracle(&self) -> Option<&TimestampOracle> {
+        self.oracle.as_ref()
+    }
+
+    pub(crate) async fn handle<W: TxnWriter>(
+        &self,
+        req: TxnRequest,
+        writer: &W,
+    ) -> Result<TxnResponse> {
+        let _latch = self.latch.lock().await;
+        match req {
+            TxnRequest::Get { key, start_ts } => self.get(key, start_ts).await,
+            TxnRequest::Prewrite {
+                mutations,
+                primary,
+                start_ts,
+                ttl_ms,
+            } => {
+                self.prewrite(mutations, primary, start_ts, ttl_ms, writer)
+                    .await
+            }
+            TxnRequest::Commit {
+                keys,
+                start_ts,
+                commit_ts,
+            } => self.commit(keys, start_ts, commit_ts, writer).await,
+            TxnRequest::Rollback { keys, start_ts } => {
+                for key in keys {
+                    if let Some(resp) = self.rollback(&key, start_ts, writer).await? {
+                        return Ok(resp);
+                    }
+                }
+                Ok(TxnResponse::Ok)
+            }
+            TxnRequest::CheckStatus {
+                primary,
+                start_ts,
+                current_ts,
+            } => {
+                self.check_status(primary, start_ts, current_ts, writer)
+                    .await
+            }
+        }
+    }
+
+    async fn get(&self, key: Vec<u8>, start_ts: u64) -> Result<TxnResponse> {
+        if let Some(lock) = cf::get_lock(&self.engine, &key).await? {
+            if lock.start_ts <= start_ts {
+                return Ok(TxnResponse::Locked {
+                    lock: lock_info(key, lock),
+                });
+            }
+        }
+
+        let visible = cf::write_records(&self.engine, &key)
+            .await?
+            .into_iter()
+            .find(|(commit_ts, record)| {
+                *commit_ts <= start_ts && record.kind != WriteKind::Rollback
+            });
+        let val = match visible {
+            Some((_, record)) if record.kind == WriteKind::Put => {
+                self.engine
+                    .get_bytes(cf::data_key(&key, record.start_ts))
+                    .await?
+            }
+            _ => None,
+        };
+        Ok(TxnResponse::Value { val })
+    }
+
+    async fn prewrite<W: TxnWriter>(
+        &self,
+        mutations: Vec<Mutation>,
+        primary: Vec<u8>,
+        start_ts: u64,
+        ttl_ms: u64,
+        writer: &W,
+    ) -> Result<TxnResponse> {
+        let mut pending = Vec::with_capacity(mutations.len());
+        for mutation in mutations {
+            let records = cf::write_records(&self.engine, &mutation.key).await?;
+            if let Some((commit_ts, record)) = records.first() {
+                if *commit_ts >= start_ts {
+                    if record.start_ts == start_ts {
+                        return Ok(aborted("the transaction was rolled back"));
+                    }
+                    return Ok(TxnResponse::Conflict {
+                        key: mutation.key,
+                        commit_ts: *commit_ts,
+                    });
+                }
+            }
+            match cf::get_lock(&self.engine, &mutation.key).await? {
+                // Sent again after a lost response.
+                Some(lock) if lock.start_ts == start_ts => continue,
+                Some(lock) => {
+                    return Ok(TxnResponse::Locked {
+                        lock: lock_info(mutation.key, lock),
+                    })
+                }
+                None => pending.push(mutation),
+            }
+        }
+
+        for Mutation { key, val } in pending {
+            let kind = match val {
+                Some(val) => {
+                    let entry = LogEntry::Set {
+                        key: cf::data_key(&key, start_ts),
+                        val,
+                    };
+                    writer.write_entry(entry).await?;
+                    WriteKind::Put
+                }
+                None => WriteKind::Delete,
+            };
+            let lock = Lock {
+                primary: primary.clone(),
+                start_ts,
+                ttl_ms,
+                kind,
+            };
+            let entry = LogEntry::Set {
+                key: cf::lock_key(&key),
+                val: cf::encode(&lock)?,
+            };
+            writer.write_entry(entry).await?;
+        }
+        Ok(TxnResponse::Ok)
+    }
+
+    async fn commit<W: TxnWriter>(
+        &self,
+        keys: Vec<Vec<u8>>,
+        start_ts: u64,
+        commit_ts: u64,
+        writer: &W,
+    ) -> Result<TxnResponse> {
+        for key in keys {
+            let lock = match cf::get_lock(&self.engine, &key).await? {
+                Some(lock) if lock.start_ts == start_ts => lock,
+                _ => match cf::find_write(&self.engine, &key, start_ts).await? {
+                    Some((_, record)) if record.kind != WriteKind::Rollback => continue,
+                    _ => return Ok(aborted("the transaction's lock is gone")),
+                },
+            };
+
+            let record = WriteRecord {
+                start_ts,
+                kind: lock.kind,
+            };
+            let entry = LogEntry::Set {
+                key: cf::write_key(&key, commit_ts),
+                val: cf::encode(&record)?,
+            };
+            writer.write_entry(entry).await?;
+            let entry = LogEntry::Remove {
+                key: cf::lock_key(&key),
+            };
+            writer.write_entry(entry).await?;
+        }
+        Ok(TxnResponse::Ok)
+    }
+
+    /// Rolls the key back, returns the response to stop at if the
+    /// transaction already committed it.
+    async fn rollback<W: TxnWriter>(
+        &self,
+        key: &[u8],
+        start_ts: u64,
+        writer: &W,
+    ) -> Result<Option<TxnResponse>> {
+        match cf::find_write(&self.engine, key, start_ts).await? {
+            Some((_, record)) if record.kind == WriteKind::Rollback => return Ok(None),
+            Some(_) => return Ok(Some(aborted("the transaction is committed"))),
+            None => {}
+        }
+
+        if let Some(lock) = cf::get_lock(&self.engine, key).await? {
+            if lock.start_ts == start_ts {
+                let entry = LogEntry::Remove {
+                    key: cf::lock_key(key),
+                };
+                writer.write_entry(entry).await?;
+                if lock.kind == WriteKind::Put {
+                    let entry = LogEntry::Remove {
+                        key: cf::data_key(key, start_ts),
+                    };
+                    writer.write_entry(entry).await?;
+                }
+            }
+        }
+
+        let record = WriteRecord {
+            start_ts,
+            kind: WriteKind::Rollback,
+        };
+        let entry = LogEntry::Set {
+            key: cf::write_key(key, start_ts),
+            val: cf::encode(&record)?,
+        };
+        writer.write_entry(entry).await?;
+        Ok(None)
+    }
+
+    async fn check_status<W: TxnWriter>(
+        &self,
+        primary: Vec<u8>,
+        start_ts: u64,
+        current_ts: u64,
+        writer: &W,
+    ) -> Result<TxnResponse> {
+        let status = match cf::get_lock(&self.engine, &primary).await? {
+            Some(lock) if lock.start_ts == start_ts => {
+                if physical_ms(start_ts) + lock.ttl_ms > physical_ms(current_ts) {
+                    TxnStatus::Locked
+                } else {
+                    self.rollback(&primary, start_ts, writer).await?;
+                    TxnStatus::RolledBack
+                }
+            }
+            _ => match cf::find_write(&self.engine, &primary, start_ts).await? {
+                Some((_, record)) if record.kind == WriteKind::Rollback => TxnStatus::RolledBack,
+                Some((commit_ts, _)) => TxnStatus::Committed { commit_ts },
+                // Never prewritten, or the prewrite is still on its way:
+                // the rollback record makes it fail.
+                None => {
+                    self.rollback(&primary, start_ts, writer).await?;
+                    TxnStatus::RolledBack
+                }
+            },
+        };
+        Ok(TxnResponse::Status { status })
+    }
+}
+
+fn lock_info(key: Vec<u8>, lock: Lock) -> LockInfo {
+    LockInfo {
+        key,
+        primary: lock.primary,
+        start_ts: lock.start_ts,
+        ttl_ms: lock.ttl_ms,
+    }
+}
+
+fn aborted(msg: &str) -> TxnResponse {
+    TxnResponse::Aborted {
+        msg: msg.to_string(),
+    }
+}
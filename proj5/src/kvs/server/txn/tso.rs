@@ -0,0 +1,114 @@
+//! Timestamp oracle: hands out strictly increasing timestamps made of the
+//! wall clock in milliseconds and a logical counter.
+//!
+//! A timestamp above every one handed out so far is saved before it is
+//! reached, in steps of [`SAVE_WINDOW_MS`], so an oracle restarted on the
+//! same file, even with the clock set back, never repeats a timestamp.
+
+use crate::kvs::server::atomic_file;
+use crate::kvs::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bits of a timestamp holding the logical counter.
+const LOGICAL_BITS: u32 = 18;
+
+/// How far ahead of the last timestamp the saved limit is moved.
+const SAVE_WINDOW_MS: u64 = 3000;
+
+/// Milliseconds since the epoch the timestamp was handed out at.
+pub(crate) fn physical_ms(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+pub(crate) struct TimestampOracle {
+    path: PathBuf,
+    state: Mutex<OracleState>,
+}
+
+struct OracleState {
+    last: u64,
+    /// Saved in the file, timestamps stay below it.
+    limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedLimit {
+    limit: u64,
+}
+
+impl TimestampOracle {
+    /// Loads the saved limit from `path`, timestamps handed out start above
+    /// it.
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<TimestampOracle> {
+        let path = path.into();
+        let saved = load(&path)?;
+        Ok(TimestampOracle {
+            path,
+            state: Mutex::new(OracleState {
+                last: saved.limit,
+                limit: saved.limit,
+            }),
+        })
+    }
+
+    pub(crate) fn next(&self) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let ts = (now_ms() << LOGICAL_BITS).max(state.last + 1);
+        if ts >= state.limit {
+            let limit = ts + (SAVE_WINDOW_MS << LOGICAL_BITS);
+            save(&self.path, &SavedLimit { limit })?;
+            state.limit = limit;
+        }
+        state.last = ts;
+        Ok(ts)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn load(path: &Path) -> Result<SavedLimit> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).map_err(|e| KvError::Txn {
+            msg: format!("{}: {}", path.display(), e),
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SavedLimit::default()),
+        Err(e) => Err(KvError::Io(e)),
+    }
+}
+
+/// Replaces the file atomically, a crash leaves the old or the new one.
+fn save(path: &Path, saved: &SavedLimit) -> Result<()> {
+    let content = serde_json::to_vec(saved).map_err(|e| KvError::Txn { msg: e.to_string() })?;
+    atomic_file::replace(path, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn restart_continues_above_handed_out() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("tso.json");
+
+        let oracle = TimestampOracle::open(&path)?;
+        let first = oracle.next()?;
+        let second = oracle.next()?;
+        assert!(second > first);
+        assert!(physical_ms(first) > 0);
+        drop(oracle);
+
+        let oracle = TimestampOracle::open(&path)?;
+        assert!(oracle.next()? > second + (SAVE_WINDOW_MS << LOGICAL_BITS) / 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,532 @@
+//! Helpers for running an in-process server from `#[tokio::test]`.
+//!
+//! The server binds an ephemeral loopback port, or a socket file, and keeps
+//! its data in a temporary directory, which is removed when the
+//! [`TestServer`] is dropped. Built with the `testing` feature only.
+
+use crate::kvs::server::raft::MemNetwork;
+use crate::kvs::thread_pool::RayonThreadPool;
+use crate::kvs::{
+    ClientTls, ClusterConfig, Credentials, KvError, KvStore, KvsAddr, KvsClient, KvsClientBuilder,
+    KvsEngine, KvsServer, KvsServerHandle, Result, ServerLimits, ServerTls, SledKvsEngine,
+    SlotConfig,
+};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use slog::{o, Discard, Logger};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::time::sleep;
+
+const THREADS: u32 = 2;
+
+/// How long cluster helpers wait for elections and replication.
+const CLUSTER_WAIT: Duration = Duration::from_secs(10);
+
+type Replicate = Box<dyn Fn() -> BoxFuture<'static, Result<KvsServerHandle>> + Send + Sync>;
+
+/// Tasks of a started server.
+struct Handles {
+    server: KvsServerHandle,
+    metrics: Option<KvsServerHandle>,
+    replicate: Option<Replicate>,
+    cluster: Option<KvsServerHandle>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestEngine {
+    Kvs,
+    Sled,
+}
+
+pub struct TestServer {
+    handle: KvsServerHandle,
+    metrics: Option<KvsServerHandle>,
+    replication: Option<KvsServerHandle>,
+    replicate: Option<Replicate>,
+    cluster: Option<KvsServerHandle>,
+    log: Logger,
+    dir: TempDir,
+}
+
+pub struct TestServerBuilder {
+    engine: TestEngine,
+    log: Logger,
+    limits: ServerLimits,
+    credentials: Option<Credentials>,
+    tls: Option<ServerTls>,
+    unix: bool,
+    metrics: bool,
+    backlog: Option<usize>,
+    replica_of: Option<KvsAddr>,
+    cluster: Option<ClusterConfig>,
+    network: Option<Arc<MemNetwork>>,
+    tso: bool,
+    slots: Option<SlotConfig>,
+}
+
+impl TestServer {
+    pub fn builder(engine: TestEngine) -> TestServerBuilder {
+        TestServerBuilder {
+            engine,
+            log: Logger::root(Discard, o!()),
+            limits: ServerLimits::default(),
+            credentials: None,
+            tls: None,
+            unix: false,
+            metrics: false,
+            backlog: None,
+            replica_of: None,
+            cluster: None,
+            network: None,
+            tso: false,
+            slots: None,
+        }
+    }
+
+    pub async fn start(engine: TestEngine) -> Result<TestServer> {
+        TestServer::builder(engine).start().await
+    }
+
+    /// Starts a server listening on a Unix socket inside the data directory.
+    pub async fn start_unix(engine: TestEngine) -> Result<TestServer> {
+        TestServer::builder(engine).unix().start().await
+    }
+
+    pub fn addr(&self) -> KvsAddr {
+        self.handle.addr().clone()
+    }
+
+    /// Address of the metrics endpoint, if enabled on the builder.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().and_then(|handle| handle.addr().tcp())
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub async fn client(&self) -> Result<KvsClient> {
+        KvsClient::connect(&self.log, self.addr()).await
+    }
+
+    /// Connects to a server started with [`TestServerBuilder::tls`].
+    pub async fn tls_client(&self, tls: &ClientTls) -> Result<KvsClient> {
+        KvsClient::connect_tls(&self.log, self.addr(), tls).await
+    }
+
+    /// Disconnects a replica from its primary, its position is saved.
+    pub async fn pause_replication(&mut self) -> Result<()> {
+        match self.replication.take() {
+            Some(replication) => replication.shutdown().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Reconnects a replica paused with [`TestServer::pause_replication`].
+    pub async fn resume_replication(&mut self) -> Result<()> {
+        if self.replication.is_none() {
+            let replicate = self
+                .replicate
+                .as_ref()
+                .ok_or_else(|| KvError::Replication {
+                    msg: "the server is not a replica".to_string(),
+                })?;
+            self.replication = Some(replicate().await?);
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.pause_replication().await?;
+        if let Some(cluster) = self.cluster {
+            cluster.shutdown().await?;
+        }
+        if let Some(metrics) = self.metrics {
+            metrics.shutdown().await?;
+        }
+        self.handle.shutdown().await
+    }
+}
+
+impl TestServerBuilder {
+    pub fn log(mut self, log: Logger) -> Self {
+        self.log = log;
+        self
+    }
+
+    pub fn limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn unix(mut self) -> Self {
+        self.unix = true;
+        self
+    }
+
+    /// Also serves metrics on an ephemeral loopback port.
+    pub fn metrics(mut self) -> Self {
+        self.metrics = true;
+        self
+    }
+
+    /// Makes the server a primary keeping `backlog` writes for replicas.
+    pub fn replication(mut self, backlog: usize) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Makes the server a replica of `primary`, replicating from the start.
+    pub fn replica_of(mut self, primary: KvsAddr) -> Self {
+        self.replica_of = Some(primary);
+        self
+    }
+
+    /// Makes the server a cluster member listening on the member address,
+    /// with its Raft state in the data directory.
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Makes the server the timestamp oracle of transactions.
+    pub fn tso(mut self) -> Self {
+        self.tso = true;
+        self
+    }
+
+    /// Serves the hash slots of the config, with the slot states in the
+    /// data directory.
+    pub fn slots(mut self, config: SlotConfig) -> Self {
+        self.slots = Some(config);
+        self
+    }
+
+    /// Sends the Raft messages of a cluster member through `network`.
+    pub(crate) fn network(mut self, network: Arc<MemNetwork>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub async fn start(mut self) -> Result<TestServer> {
+        let dir = TempDir::new()?;
+        let addr = match &mut self.cluster {
+            Some(config) => {
+                config.dir = dir.path().join("raft");
+                if let Some(network) = &self.network {
+                    let transport = Arc::new(network.transport(config.id()));
+                    *config = config.clone().transport(transport);
+                }
+                config.id().parse()?
+            }
+            None if self.unix => KvsAddr::Unix(dir.path().join("kvs.sock")),
+            None => KvsAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+        };
+
+        let handles = match self.engine {
+            TestEngine::Kvs => {
+                let path = dir.path().join("kvs_data");
+                std::fs::create_dir_all(path.as_path())?;
+                let kvs = KvStore::<RayonThreadPool>::open(path, THREADS)?;
+                self.bind(kvs, addr, dir.path()).await?
+            }
+            TestEngine::Sled => {
+                let path = dir.path().join("sled_data");
+                let sled = SledKvsEngine::<RayonThreadPool>::open(path, THREADS)?;
+                self.bind(sled, addr, dir.path()).await?
+            }
+        };
+
+        let mut server = TestServer {
+            handle: handles.server,
+            metrics: handles.metrics,
+            replication: None,
+            replicate: handles.replicate,
+            cluster: handles.cluster,
+            log: self.log,
+            dir,
+        };
+        if server.replicate.is_some() {
+            server.resume_replication().await?;
+        }
+        Ok(server)
+    }
+
+    async fn bind<E: KvsEngine>(
+        &self,
+        engine: E,
+        addr: KvsAddr,
+        dir: &Path,
+    ) -> Result<Handles> {
+        let log = self.log.new(o!());
+        let mut server = KvsServer::with_limits(engine, log, self.limits.clone());
+        if let Some(credentials) = &self.credentials {
+            server = server.with_credentials(credentials.clone());
+        }
+        if let Some(tls) = &self.tls {
+            server = server.with_tls(tls.clone());
+        }
+        if let Some(backlog) = self.backlog {
+            server = server.with_replication(backlog);
+        }
+        if let Some(primary) = &self.replica_of {
+            let client = KvsClientBuilder::new(primary.clone()).log(&self.log);
+            server = server.with_replica_of(client, dir.join("replica_state.json"));
+        }
+        if self.tso {
+            server = server.with_tso(dir.join("tso.json"))?;
+        }
+        if let Some(config) = &self.slots {
+            server = server.with_slots(config.clone(), dir.join("slots.json"))?;
+        }
+        if let Some(config) = &self.cluster {
+            server = server.with_cluster(config.clone())?;
+            if let (Some(network), Some(node)) = (&self.network, server.cluster_node()) {
+                network.register(config.id(), node.clone());
+            }
+        }
+        let server = Arc::new(server);
+        let handle = server.bind(addr).await?;
+        let metrics = if self.metrics {
+            Some(server.bind_metrics("127.0.0.1:0".parse().unwrap()).await?)
+        } else {
+            None
+        };
+        let cluster = match &self.cluster {
+            Some(_) => Some(server.start_cluster().await?),
+            None => None,
+        };
+        let replicate = self.replica_of.as_ref().map(|_| -> Replicate {
+            Box::new(move || {
+                let server = server.clone();
+                async move { server.replicate().await }.boxed()
+            })
+        });
+        Ok(Handles {
+            server: handle,
+            metrics,
+            replicate,
+            cluster,
+        })
+    }
+}
+
+/// Members of one cluster started in-process. Raft messages go through an
+/// in-memory network where members can be cut off, or over loopback TCP
+/// like the client requests.
+pub struct TestCluster {
+    builder: TestClusterBuilder,
+    network: Option<Arc<MemNetwork>>,
+    servers: Vec<Option<TestServer>>,
+}
+
+#[derive(Clone)]
+pub struct TestClusterBuilder {
+    engine: TestEngine,
+    size: usize,
+    tcp: bool,
+    snapshot_threshold: u64,
+}
+
+impl TestCluster {
+    pub fn builder(size: usize, engine: TestEngine) -> TestClusterBuilder {
+        TestClusterBuilder {
+            engine,
+            size,
+            tcp: false,
+            snapshot_threshold: 10_000,
+        }
+    }
+
+    pub async fn start(size: usize, engine: TestEngine) -> Result<TestCluster> {
+        TestCluster::builder(size, engine).start().await
+    }
+
+    /// Servers started so far, including stopped ones.
+    pub fn size(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn addr(&self, i: usize) -> KvsAddr {
+        self.server(i).addr()
+    }
+
+    pub fn server(&self, i: usize) -> &TestServer {
+        self.servers[i].as_ref().expect("server stopped")
+    }
+
+    pub async fn client(&self, i: usize) -> Result<KvsClient> {
+        self.server(i).client().await
+    }
+
+    pub async fn info(&self, i: usize) -> Result<HashMap<String, String>> {
+        let fields = self.client(i).await?.info().await?;
+        Ok(fields.into_iter().collect())
+    }
+
+    /// Waits until a running server outside `exclude` leads, and returns
+    /// the one with the highest term.
+    pub async fn wait_for_leader(&self, exclude: &[usize]) -> Result<usize> {
+        let deadline = Instant::now() + CLUSTER_WAIT;
+        loop {
+            let mut leader: Option<(u64, usize)> = None;
+            for i in (0..self.size()).filter(|i| !exclude.contains(i)) {
+                if self.servers[i].is_none() {
+                    continue;
+                }
+                let info = self.info(i).await?;
+                if info.get("role").map(String::as_str) == Some("leader") {
+                    let term = info["cluster_term"].parse().unwrap_or(0);
+                    if !matches!(leader, Some((max, _)) if max >= term) {
+                        leader = Some((term, i));
+                    }
+                }
+            }
+            if let Some((_, i)) = leader {
+                return Ok(i);
+            }
+            if Instant::now() > deadline {
+                return Err(KvError::Cluster {
+                    msg: "no leader elected".to_string(),
+                });
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Waits until the running servers outside `exclude` applied the same
+    /// entries as the leader `leader`.
+    pub async fn wait_for_sync(&self, leader: usize, exclude: &[usize]) -> Result<()> {
+        let deadline = Instant::now() + CLUSTER_WAIT;
+        loop {
+            let target = self.info(leader).await?["applied_index"].clone();
+            let mut synced = true;
+            for i in (0..self.size()).filter(|i| !exclude.contains(i)) {
+                if self.servers[i].is_some() && self.info(i).await?["applied_index"] != target {
+                    synced = false;
+                }
+            }
+            if synced {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                return Err(KvError::Cluster {
+                    msg: format!("members did not reach index {}", target),
+                });
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Cuts the server off the other members, clients still reach it.
+    pub fn isolate(&self, i: usize) {
+        let network = self.network.as_ref().expect("cluster over tcp");
+        network.isolate(&self.addr(i).to_string());
+    }
+
+    pub fn heal(&self) {
+        if let Some(network) = &self.network {
+            network.heal();
+        }
+    }
+
+    /// Starts a server waiting to be added with
+    /// [`KvsClient::add_member`], returns its index.
+    pub async fn add_server(&mut self) -> Result<usize> {
+        let addr = free_addr()?;
+        let config = self.builder.config(ClusterConfig::join(addr, "raft"));
+        let server = self.builder.start_server(config, &self.network).await?;
+        self.servers.push(Some(server));
+        Ok(self.servers.len() - 1)
+    }
+
+    pub async fn stop(&mut self, i: usize) -> Result<()> {
+        match self.servers[i].take() {
+            Some(server) => server.shutdown().await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn shutdown(mut self) -> Result<()> {
+        for i in 0..self.size() {
+            self.stop(i).await?;
+        }
+        Ok(())
+    }
+}
+
+impl TestClusterBuilder {
+    /// Exchanges the Raft messages over loopback TCP, members cannot be
+    /// isolated.
+    pub fn tcp(mut self) -> Self {
+        self.tcp = true;
+        self
+    }
+
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.snapshot_threshold = entries;
+        self
+    }
+
+    pub async fn start(self) -> Result<TestCluster> {
+        let network = if self.tcp {
+            None
+        } else {
+            Some(Arc::new(MemNetwork::default()))
+        };
+        let addrs = (0..self.size)
+            .map(|_| free_addr())
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut servers = Vec::with_capacity(self.size);
+        for addr in &addrs {
+            let config = self.config(ClusterConfig::new(addr.clone(), addrs.clone(), "raft"));
+            servers.push(Some(self.start_server(config, &network).await?));
+        }
+        Ok(TestCluster {
+            builder: self,
+            network,
+            servers,
+        })
+    }
+
+    fn config(&self, config: ClusterConfig) -> ClusterConfig {
+        config
+            .election_timeout(Duration::from_millis(300))
+            .heartbeat_interval(Duration::from_millis(50))
+            .snapshot_threshold(self.snapshot_threshold)
+    }
+
+    async fn start_server(
+        &self,
+        config: ClusterConfig,
+        network: &Option<Arc<MemNetwork>>,
+    ) -> Result<TestServer> {
+        let mut builder = TestServer::builder(self.engine).cluster(config);
+        if let Some(network) = network {
+            builder = builder.network(network.clone());
+        }
+        builder.start().await
+    }
+}
+
+/// A loopback address with a port nobody listens on right now.
+fn free_addr() -> Result<KvsAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(KvsAddr::Tcp(listener.local_addr()?))
+}
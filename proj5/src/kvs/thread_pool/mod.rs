@@ -16,4 +16,9 @@ pub trait ThreadPool: Clone + Send + Sync + 'static {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Number of spawned jobs not yet picked up by a worker.
+    fn queued_jobs(&self) -> usize {
+        0
+    }
 }
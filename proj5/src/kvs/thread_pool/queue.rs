@@ -40,6 +40,10 @@ impl ThreadPool for SharedQueueThreadPool {
     {
         self.sender.send(QueueMessage::Job(Box::new(job)));
     }
+
+    fn queued_jobs(&self) -> usize {
+        self.sender.len()
+    }
 }
 
 impl Drop for SharedQueueThreadPool {
@@ -2,11 +2,13 @@ use crate::kvs::thread_pool::ThreadPool;
 use crate::kvs::KvError;
 use crate::kvs::Result;
 use rayon::ThreadPoolBuilder;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct RayonThreadPool {
     pool: Arc<rayon::ThreadPool>,
+    queued: Arc<AtomicUsize>,
 }
 
 impl ThreadPool for RayonThreadPool {
@@ -22,6 +24,7 @@ impl ThreadPool for RayonThreadPool {
             })?;
         Ok(RayonThreadPool {
             pool: Arc::new(pool),
+            queued: Arc::new(AtomicUsize::new(0)),
         })
     }
 
@@ -29,6 +32,15 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job);
+        let queued = self.queued.clone();
+        queued.fetch_add(1, Ordering::Relaxed);
+        self.pool.spawn(move || {
+            queued.fetch_sub(1, Ordering::Relaxed);
+            job();
+        });
+    }
+
+    fn queued_jobs(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
     }
 }
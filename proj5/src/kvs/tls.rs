@@ -0,0 +1,179 @@
+//! TLS for the server and client streams. The BSON framing runs unchanged
+//! over the encrypted stream.
+
+use crate::kvs::{KvError, KvsStream, Result};
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerName, StreamOwned,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Server certificate and key, optionally with the CA client certificates
+/// must be signed by (mutual TLS).
+#[derive(Clone)]
+pub struct ServerTls {
+    acceptor: TlsAcceptor,
+}
+
+/// CA the server certificate must be signed by, optionally with a client
+/// certificate and key for servers requiring mutual TLS.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl ServerTls {
+    pub fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerTls> {
+        let client_ca = match client_ca {
+            Some(path) => Some(read_file(path)?),
+            None => None,
+        };
+        ServerTls::from_pem(&read_file(cert)?, &read_file(key)?, client_ca.as_deref())
+    }
+
+    pub fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> Result<ServerTls> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier = AllowAnyAuthenticatedClient::new(parse_roots(ca)?);
+                builder.with_client_cert_verifier(verifier.boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(parse_certs(cert)?, parse_key(key)?)
+            .map_err(tls_error)?;
+
+        Ok(ServerTls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub(crate) async fn accept<S: KvsStream + 'static>(
+        &self,
+        stream: S,
+    ) -> Result<Box<dyn KvsStream>> {
+        Ok(Box::new(self.acceptor.accept(stream).await?))
+    }
+}
+
+impl ClientTls {
+    pub fn load(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<ClientTls> {
+        let identity = match identity {
+            Some((cert, key)) => Some((read_file(cert)?, read_file(key)?)),
+            None => None,
+        };
+        let identity = identity
+            .as_ref()
+            .map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+        ClientTls::from_pem(&read_file(ca)?, identity)
+    }
+
+    pub fn from_pem(ca: &[u8], identity: Option<(&[u8], &[u8])>) -> Result<ClientTls> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(parse_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(parse_certs(cert)?, parse_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = Arc::new(config);
+        Ok(ClientTls {
+            connector: TlsConnector::from(config.clone()),
+            config,
+            server_name: None,
+        })
+    }
+
+    /// Name the server certificate is verified against. Defaults to the IP
+    /// address for TCP and to `localhost` for Unix sockets.
+    pub fn server_name(mut self, name: impl Into<String>) -> ClientTls {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    pub(crate) async fn connect<S: KvsStream + 'static>(
+        &self,
+        default_name: &str,
+        stream: S,
+    ) -> Result<Box<dyn KvsStream>> {
+        let name = self.name(default_name)?;
+        Ok(Box::new(self.connector.connect(name, stream).await?))
+    }
+
+    /// Runs the handshake on a blocking stream before returning it.
+    pub(crate) fn connect_blocking<S: Read + Write>(
+        &self,
+        default_name: &str,
+        mut stream: S,
+    ) -> Result<StreamOwned<ClientConnection, S>> {
+        let name = self.name(default_name)?;
+        let mut conn = ClientConnection::new(self.config.clone(), name).map_err(tls_error)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    fn name(&self, default_name: &str) -> Result<ServerName> {
+        let name = self.server_name.as_deref().unwrap_or(default_name);
+        ServerName::try_from(name).map_err(|_| KvError::Tls {
+            msg: format!("invalid server name: {}", name),
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| KvError::Tls {
+        msg: format!("{}: {}", path.display(), e),
+    })
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])?;
+    if certs.is_empty() {
+        return Err(KvError::Tls {
+            msg: "no certificate found".to_string(),
+        });
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn parse_roots(pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certs(pem)? {
+        roots
+            .add(&cert)
+            .map_err(|e| KvError::Tls { msg: e.to_string() })?;
+    }
+    Ok(roots)
+}
+
+fn parse_key(pem: &[u8]) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut &pem[..])? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(KvError::Tls {
+        msg: "no private key found".to_string(),
+    })
+}
+
+fn tls_error(e: tokio_rustls::rustls::Error) -> KvError {
+    KvError::Tls { msg: e.to_string() }
+}
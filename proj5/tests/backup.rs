@@ -0,0 +1,145 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::{backup, KvError, KvStore, KvsEngine, Result, SledKvsEngine};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const KEYS: u32 = 200;
+
+/// Backs a server up halfway through its writes, returns the backup.
+async fn backup_live_server(engine: TestEngine, dir: &Path) -> Result<()> {
+    let server = TestServer::start(engine).await?;
+    let mut client = server.client().await?;
+    for i in 0..KEYS {
+        client.set(format!("key{}", i), "old".to_owned()).await?;
+    }
+
+    let fields = client.backup(dir.join("backup")).await?;
+    assert!(fields.contains(&("keys".to_string(), KEYS.to_string())));
+
+    for i in 0..KEYS {
+        client.set(format!("key{}", i), "new".to_owned()).await?;
+    }
+    // A backup never writes over another.
+    assert!(matches!(
+        client.backup(dir.join("backup")).await,
+        Err(KvError::Server { .. })
+    ));
+    server.shutdown().await
+}
+
+async fn assert_restored<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..KEYS {
+        let val = engine.get(format!("key{}", i)).await?;
+        assert_eq!(val, Some("old".to_owned()));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn backup_and_restore_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_live_server(TestEngine::Kvs, temp_dir.path()).await?;
+
+    let data_dir = temp_dir.path().join("kvs_data");
+    let meta = backup::restore(&temp_dir.path().join("backup"), &data_dir)?;
+    assert_eq!(meta.engine, "kvs");
+    assert_eq!(meta.keys, KEYS as u64);
+
+    assert_restored(KvStore::<NaiveThreadPool>::open(data_dir, 1)?).await
+}
+
+#[tokio::test]
+async fn backup_and_restore_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_live_server(TestEngine::Sled, temp_dir.path()).await?;
+
+    let data_dir = temp_dir.path().join("sled_data");
+    let meta = backup::restore(&temp_dir.path().join("backup"), &data_dir)?;
+    assert_eq!(meta.engine, "sled");
+    assert_eq!(meta.keys, KEYS as u64);
+
+    assert_restored(SledKvsEngine::<NaiveThreadPool>::open(data_dir, 1)?).await
+}
+
+#[tokio::test]
+async fn restore_validates_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_live_server(TestEngine::Kvs, temp_dir.path()).await?;
+    let backup_dir = temp_dir.path().join("backup");
+
+    let meta = backup::validate(&backup_dir)?;
+    let data_dir = temp_dir.path().join("kvs_data");
+    fs::create_dir(&data_dir)?;
+    fs::write(data_dir.join("a_1"), b"")?;
+    assert!(matches!(
+        backup::restore(&backup_dir, &data_dir),
+        Err(KvError::Backup { .. })
+    ));
+
+    let file = backup_dir.join(&meta.files[0].path);
+    fs::write(&file, b"truncated")?;
+    assert!(matches!(
+        backup::validate(&backup_dir),
+        Err(KvError::Backup { .. })
+    ));
+    fs::remove_file(&file)?;
+    assert!(matches!(
+        backup::validate(&backup_dir),
+        Err(KvError::Backup { .. })
+    ));
+    fs::remove_file(backup_dir.join(backup::BACKUP_META))?;
+    assert!(matches!(
+        backup::validate(&backup_dir),
+        Err(KvError::Backup { .. })
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn restore_refuses_paths_leaving_the_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_live_server(TestEngine::Kvs, temp_dir.path()).await?;
+    let backup_dir = temp_dir.path().join("backup");
+
+    // The listed file is there with its size, only its path is wrong.
+    let mut meta = backup::validate(&backup_dir)?;
+    fs::copy(
+        backup_dir.join(&meta.files[0].path),
+        temp_dir.path().join("escaped"),
+    )?;
+    meta.files[0].path = "../escaped".to_owned();
+    let content = serde_json::to_vec(&meta).unwrap();
+    fs::write(backup_dir.join(backup::BACKUP_META), content)?;
+
+    let data_dir = temp_dir.path().join("kvs_data");
+    assert!(matches!(
+        backup::restore(&backup_dir, &data_dir),
+        Err(KvError::Backup { .. })
+    ));
+    assert!(!data_dir.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn backup_refuses_the_data_dir() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let mut client = server.client().await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+
+    let dest = server.path().join("kvs_data").join("backup");
+    assert!(matches!(
+        client.backup(dest.clone()).await,
+        Err(KvError::Server { .. })
+    ));
+    assert!(!dest.exists());
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    server.shutdown().await
+}
@@ -1,5 +1,6 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
+use predicates::prelude::*;
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -164,6 +165,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,12 +186,14 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("holds sled engine data"));
     }
 
     // kvs first, sled second
@@ -203,12 +207,14 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("holds kvs engine data"));
     }
 }
 
@@ -224,9 +230,26 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("PONG\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("engine: {}", engine)));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key1", "value1", "--addr", addr])
@@ -291,6 +314,30 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "--hex", "ff00", "80", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "--base64", "/wA=", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("gA==\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "--hex", "ff00", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("80\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -305,6 +352,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -324,6 +372,38 @@ fn cli_access_server(engine: &str, addr: &str) {
         .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
+
+    // Reopen with the engine recorded in the directory
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("engine: {}", engine)));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value3"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
 }
 
 #[test]
@@ -331,7 +411,92 @@ fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
 
+#[test]
+fn cli_access_server_unix_socket() {
+    let socket_dir = TempDir::new().unwrap();
+    let addr = format!("unix:{}", socket_dir.path().join("kvs.sock").display());
+    cli_access_server("kvs", &addr);
+}
+
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_tls() {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+
+    let temp_dir = TempDir::new().unwrap();
+
+    let mut ca_params = CertificateParams::default();
+    ca_params.distinguished_name.push(DnType::CommonName, "kvs ca");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = vec![SanType::IpAddress("127.0.0.1".parse().unwrap())];
+    let cert = Certificate::from_params(params).unwrap();
+
+    fs::write(temp_dir.path().join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    fs::write(
+        temp_dir.path().join("cert.pem"),
+        cert.serialize_pem_with_signer(&ca).unwrap(),
+    )
+    .unwrap();
+    fs::write(temp_dir.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr, "--tls-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--tls-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["admin", "ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// An unreachable server is reported as an error, not a panic.
+#[test]
+fn client_cli_unreachable_server() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4007", "--connect-timeout", "500"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Connection refused").and(contains("panicked").not()));
+}
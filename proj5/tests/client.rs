@@ -0,0 +1,476 @@
+use bson::{doc, Document};
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{
+    BlockingKvsClient, ClientAuth, KvError, KvsClient, PooledKvsClient, Result, RetryPolicy,
+};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::spawn_blocking;
+
+/// What the fake server does with the next request it reads.
+enum Step {
+    Reply(Document),
+    ReplyClose(Document),
+    Close,
+    Hang,
+}
+
+fn ok_val(val: &str) -> Step {
+    Step::Reply(doc! { "t": "OkVal", "__field0": val })
+}
+
+fn ok() -> Step {
+    Step::Reply(doc! { "t": "Ok" })
+}
+
+/// Serves the steps in order across all connections it accepts, and
+/// counts the connections.
+async fn fake_server(steps: Vec<Step>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        let mut steps = steps.into_iter();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let mut hang = false;
+            while read_frame(&mut stream).await.is_some() {
+                match steps.next() {
+                    Some(Step::Reply(doc)) => write_frame(&mut stream, &doc).await,
+                    Some(Step::ReplyClose(doc)) => {
+                        write_frame(&mut stream, &doc).await;
+                        break;
+                    }
+                    Some(Step::Hang) => {
+                        hang = true;
+                        break;
+                    }
+                    Some(Step::Close) | None => break,
+                }
+            }
+            if hang {
+                // Keep the connection open without answering, and go on
+                // serving new connections.
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    drop(stream);
+                });
+            }
+        }
+    });
+
+    (addr, connections)
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let size = stream.read_u32().await.ok()?;
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf).await.ok()?;
+    Some(buf)
+}
+
+async fn write_frame(stream: &mut TcpStream, doc: &Document) {
+    let buf = bson::to_vec(doc).unwrap();
+    let _ = stream.write_u32(buf.len() as u32).await;
+    let _ = stream.write_all(&buf).await;
+}
+
+#[tokio::test]
+async fn request_timeout() -> Result<()> {
+    let (addr, _) = fake_server(vec![Step::Hang]).await;
+    let mut client = KvsClient::builder(addr)
+        .request_timeout(Duration::from_millis(200))
+        .retry(RetryPolicy::none())
+        .connect()
+        .await?;
+
+    let started = Instant::now();
+    match client.get("key".to_owned()).await {
+        Err(KvError::Timeout { .. }) => {}
+        res => panic!("expected timeout, got: {:?}", res),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_timeout() -> Result<()> {
+    let (addr, _) = fake_server(vec![Step::Hang]).await;
+    let res = KvsClient::builder(addr)
+        .auth(ClientAuth::Token("token".to_owned()))
+        .connect_timeout(Duration::from_millis(200))
+        .retry(RetryPolicy::none())
+        .connect()
+        .await;
+
+    match res {
+        Err(KvError::Timeout { .. }) => Ok(()),
+        Err(e) => panic!("expected timeout, got: {:?}", e),
+        Ok(_) => panic!("expected timeout"),
+    }
+}
+
+#[tokio::test]
+async fn reconnect_after_server_closes_connection() -> Result<()> {
+    let (addr, connections) =
+        fake_server(vec![ok_val("value1"), Step::Close, ok_val("value2")]).await;
+    let mut client = KvsClient::builder(addr).connect().await?;
+
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_idempotent_requests() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Close, Step::Close, ok()]).await;
+    let mut client = KvsClient::builder(addr).connect().await?;
+
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn do_not_retry_remove() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Close, ok()]).await;
+    let mut client = KvsClient::builder(addr).connect().await?;
+
+    match client.remove("key".to_owned()).await {
+        Err(KvError::Interrupted { op, .. }) => assert_eq!(op, "remove"),
+        res => panic!("expected interrupted, got: {:?}", res),
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // The next request reconnects.
+    client.remove("key".to_owned()).await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn do_not_retry_slot_migration() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Close, ok()]).await;
+    let mut client = KvsClient::builder(addr).connect().await?;
+
+    match client.migrate_slot(7).await {
+        Err(KvError::Interrupted { op, .. }) => assert_eq!(op, "migrate_slot"),
+        res => panic!("expected interrupted, got: {:?}", res),
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn give_up_after_max_retries() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Close, Step::Close, Step::Close]).await;
+    let mut client = KvsClient::builder(addr)
+        .retry(RetryPolicy {
+            max_retries: 2,
+            ..RetryPolicy::default()
+        })
+        .connect()
+        .await?;
+
+    assert!(client.get("key".to_owned()).await.is_err());
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_idle_timeout() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let mut client = KvsClient::builder(server.addr())
+        .idle_timeout(Duration::from_millis(100))
+        .connect()
+        .await?;
+
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn authenticate_reconnects() -> Result<()> {
+    let (addr, connections) = fake_server(vec![
+        ok(),
+        ok_val("value1"),
+        Step::Close,
+        ok(),
+        ok_val("value2"),
+    ])
+    .await;
+    let mut client = KvsClient::builder(addr)
+        .auth(ClientAuth::Token("token".to_owned()))
+        .connect()
+        .await?;
+
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+
+#[test]
+fn pooled_client_is_shareable() {
+    assert_shareable::<PooledKvsClient>();
+}
+
+#[tokio::test]
+async fn pooled_client_concurrent_requests() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let pool = KvsClient::builder(server.addr()).connect_pool(4).await?;
+
+    let mut tasks = Vec::new();
+    for i in 0..32 {
+        let pool = pool.clone();
+        tasks.push(tokio::spawn(async move {
+            let key = format!("key{}", i);
+            pool.set(key.clone(), format!("value{}", i)).await?;
+            pool.get(key).await
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+    assert_eq!(pool.idle_connections(), 4);
+
+    let stats = server.client().await?.stats().await?;
+    let active = stats
+        .iter()
+        .find(|(name, _)| name == "active_connections")
+        .map(|(_, val)| val.as_str());
+    assert_eq!(active, Some("5"));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn pooled_client_replaces_broken_connections() -> Result<()> {
+    let (addr, connections) = fake_server(vec![
+        Step::ReplyClose(doc! { "t": "OkVal", "__field0": "value1" }),
+        ok_val("value2"),
+    ])
+    .await;
+    let pool = KvsClient::builder(addr).connect_pool(1).await?;
+
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value2".to_owned()));
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.idle_connections(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn pooled_client_drops_cancelled_requests() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Hang, ok_val("value")]).await;
+    let pool = KvsClient::builder(addr).connect_pool(1).await?;
+
+    let cancelled = tokio::time::timeout(Duration::from_millis(100), pool.get("key".to_owned()));
+    assert!(cancelled.await.is_err());
+
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value".to_owned()));
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+// Should ping every idle connection, replacing the one the server closed
+#[tokio::test]
+async fn pooled_client_health_checks_every_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // The first connection, the pool's oldest idle one, is killed.
+            if accepted.fetch_add(1, Ordering::SeqCst) == 0 {
+                continue;
+            }
+            tokio::spawn(async move {
+                while read_frame(&mut stream).await.is_some() {
+                    write_frame(&mut stream, &doc! { "t": "OkVal", "__field0": "PONG" }).await;
+                }
+            });
+        }
+    });
+
+    let pool = KvsClient::builder(addr)
+        .health_check_interval(Duration::from_millis(50))
+        .connect_pool(2)
+        .await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    assert_eq!(pool.idle_connections(), 2);
+    Ok(())
+}
+
+/// Runs a blocking client test off the runtime thread serving the server.
+async fn blocking<F: FnOnce() -> Result<()> + Send + 'static>(test: F) -> Result<()> {
+    spawn_blocking(test).await.unwrap()
+}
+
+#[tokio::test]
+async fn blocking_client_access_server() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let addr = server.addr();
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr).connect_blocking()?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.remove("key1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, None);
+        match client.remove("key1".to_owned()) {
+            Err(KvError::Server { .. }) => {}
+            res => panic!("expected server error, got: {:?}", res),
+        }
+        client.ping()
+    })
+    .await?;
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn blocking_client_access_unix_socket() -> Result<()> {
+    let server = TestServer::start_unix(TestEngine::Sled).await?;
+    let addr = server.addr();
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr).connect_blocking()?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    })
+    .await?;
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn blocking_request_timeout() -> Result<()> {
+    let (addr, _) = fake_server(vec![Step::Hang]).await;
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr)
+            .request_timeout(Duration::from_millis(200))
+            .retry(RetryPolicy::none())
+            .connect_blocking()?;
+
+        let started = Instant::now();
+        match client.get("key".to_owned()) {
+            Err(KvError::Timeout { .. }) => {}
+            res => panic!("expected timeout, got: {:?}", res),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn blocking_connect_timeout() -> Result<()> {
+    let (addr, _) = fake_server(vec![Step::Hang]).await;
+
+    blocking(move || {
+        let res = BlockingKvsClient::builder(addr)
+            .auth(ClientAuth::Token("token".to_owned()))
+            .connect_timeout(Duration::from_millis(200))
+            .retry(RetryPolicy::none())
+            .connect_blocking();
+
+        match res {
+            Err(KvError::Timeout { .. }) => Ok(()),
+            Err(e) => panic!("expected timeout, got: {:?}", e),
+            Ok(_) => panic!("expected timeout"),
+        }
+    })
+    .await
+}
+
+#[tokio::test]
+async fn blocking_retry_idempotent_requests() -> Result<()> {
+    let (addr, connections) =
+        fake_server(vec![ok_val("value1"), Step::Close, Step::Close, ok()]).await;
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr).connect_blocking()?;
+        assert_eq!(client.get("key".to_owned())?, Some("value1".to_owned()));
+        client.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn blocking_do_not_retry_remove() -> Result<()> {
+    let (addr, connections) = fake_server(vec![Step::Close, ok()]).await;
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr).connect_blocking()?;
+        match client.remove("key".to_owned()) {
+            Err(KvError::Interrupted { op, .. }) => assert_eq!(op, "remove"),
+            res => panic!("expected interrupted, got: {:?}", res),
+        }
+        client.remove("key".to_owned())?;
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn blocking_authenticate_reconnects() -> Result<()> {
+    let (addr, connections) = fake_server(vec![
+        ok(),
+        ok_val("value1"),
+        Step::ReplyClose(doc! { "t": "OkVal", "__field0": "value2" }),
+        ok(),
+        ok_val("value3"),
+    ])
+    .await;
+
+    blocking(move || {
+        let mut client = BlockingKvsClient::builder(addr)
+            .auth(ClientAuth::Token("token".to_owned()))
+            .connect_blocking()?;
+        for val in &["value1", "value2", "value3"] {
+            assert_eq!(client.get("key".to_owned())?, Some(val.to_string()));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,178 @@
+use proj5::kvs::testing::{TestCluster, TestEngine};
+use proj5::kvs::{KvError, Result};
+
+async fn write_keys(
+    cluster: &TestCluster,
+    leader: usize,
+    keys: std::ops::Range<u32>,
+) -> Result<()> {
+    let mut client = cluster.client(leader).await?;
+    for i in keys {
+        client.set(format!("key{}", i), i.to_string()).await?;
+    }
+    Ok(())
+}
+
+async fn keys(cluster: &TestCluster, i: usize) -> Result<String> {
+    Ok(cluster.info(i).await?["keys"].clone())
+}
+
+#[tokio::test]
+async fn leader_replicates_writes() -> Result<()> {
+    let cluster = TestCluster::start(3, TestEngine::Kvs).await?;
+    let leader = cluster.wait_for_leader(&[]).await?;
+
+    write_keys(&cluster, leader, 0..20).await?;
+    let mut client = cluster.client(leader).await?;
+    client.remove("key0".to_owned()).await?;
+    assert!(matches!(
+        client.remove("key0".to_owned()).await,
+        Err(KvError::Server { .. })
+    ));
+    assert_eq!(client.get("key0".to_owned()).await?, None);
+    assert_eq!(client.get("key7".to_owned()).await?, Some("7".to_owned()));
+
+    cluster.wait_for_sync(leader, &[]).await?;
+    for i in 0..3 {
+        assert_eq!(keys(&cluster, i).await?, "19");
+    }
+
+    // Followers point clients to the leader.
+    let follower = (leader + 1) % 3;
+    let mut client = cluster.client(follower).await?;
+    let expected = Some(cluster.addr(leader).to_string());
+    match client.set("key".to_owned(), "value".to_owned()).await {
+        Err(KvError::NotLeader { leader }) => assert_eq!(leader, expected),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match client.get("key1".to_owned()).await {
+        Err(KvError::NotLeader { leader }) => assert_eq!(leader, expected),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    cluster.shutdown().await
+}
+
+#[tokio::test]
+async fn new_leader_after_partition() -> Result<()> {
+    let cluster = TestCluster::start(3, TestEngine::Kvs).await?;
+    let old = cluster.wait_for_leader(&[]).await?;
+    write_keys(&cluster, old, 0..10).await?;
+
+    cluster.isolate(old);
+    let leader = cluster.wait_for_leader(&[old]).await?;
+    assert_ne!(leader, old);
+    write_keys(&cluster, leader, 10..20).await?;
+
+    // Without a majority the old leader commits nothing.
+    let mut client = cluster.client(old).await?;
+    assert!(client.set("lost".to_owned(), "1".to_owned()).await.is_err());
+
+    cluster.heal();
+    cluster.wait_for_sync(leader, &[]).await?;
+    assert_eq!(cluster.info(old).await?["role"], "follower");
+    for i in 0..3 {
+        assert_eq!(keys(&cluster, i).await?, "20");
+    }
+    let mut client = cluster.client(leader).await?;
+    assert_eq!(client.get("lost".to_owned()).await?, None);
+    assert_eq!(client.get("key15".to_owned()).await?, Some("15".to_owned()));
+
+    cluster.shutdown().await
+}
+
+#[tokio::test]
+async fn lagging_member_loads_snapshot() -> Result<()> {
+    let cluster = TestCluster::builder(3, TestEngine::Kvs)
+        .snapshot_threshold(10)
+        .start()
+        .await?;
+    let leader = cluster.wait_for_leader(&[]).await?;
+    let lagging = (leader + 1) % 3;
+
+    cluster.isolate(lagging);
+    write_keys(&cluster, leader, 0..50).await?;
+    let mut client = cluster.client(leader).await?;
+    client.remove("key3".to_owned()).await?;
+    let info = cluster.info(leader).await?;
+    assert_ne!(info["snapshot_index"], "0");
+
+    cluster.heal();
+    cluster.wait_for_sync(leader, &[]).await?;
+    let info = cluster.info(lagging).await?;
+    assert_ne!(info["snapshot_index"], "0");
+    assert_eq!(info["keys"], "49");
+
+    cluster.shutdown().await
+}
+
+#[tokio::test]
+async fn snapshot_in_chunks_drops_stale_keys() -> Result<()> {
+    let cluster = TestCluster::builder(3, TestEngine::Kvs)
+        .snapshot_threshold(10)
+        .start()
+        .await?;
+    let leader = cluster.wait_for_leader(&[]).await?;
+    let lagging = (leader + 1) % 3;
+    write_keys(&cluster, leader, 0..10).await?;
+    cluster.wait_for_sync(leader, &[]).await?;
+
+    cluster.isolate(lagging);
+    let mut client = cluster.client(leader).await?;
+    for i in 1..5 {
+        client.remove(format!("key{}", i)).await?;
+    }
+    // More pairs than one snapshot chunk holds.
+    write_keys(&cluster, leader, 10..600).await?;
+
+    cluster.heal();
+    cluster.wait_for_sync(leader, &[]).await?;
+    assert_eq!(keys(&cluster, lagging).await?, "596");
+
+    cluster.shutdown().await
+}
+
+#[tokio::test]
+async fn add_and_remove_members() -> Result<()> {
+    let mut cluster = TestCluster::start(3, TestEngine::Kvs).await?;
+    let leader = cluster.wait_for_leader(&[]).await?;
+    write_keys(&cluster, leader, 0..10).await?;
+
+    let new = cluster.add_server().await?;
+    let mut client = cluster.client(leader).await?;
+    client.add_member(cluster.addr(new)).await?;
+    cluster.wait_for_sync(leader, &[]).await?;
+    assert_eq!(keys(&cluster, new).await?, "10");
+    let members = cluster.info(new).await?["cluster_members"].clone();
+    assert_eq!(members.split(',').count(), 4);
+
+    // Removing the leader hands the cluster over to the others.
+    let removed = cluster.addr(leader).to_string();
+    client.remove_member(cluster.addr(leader)).await?;
+    let next = cluster.wait_for_leader(&[leader]).await?;
+    cluster.stop(leader).await?;
+    write_keys(&cluster, next, 10..20).await?;
+    cluster.wait_for_sync(next, &[leader]).await?;
+    assert_eq!(keys(&cluster, new).await?, "20");
+    let members = cluster.info(next).await?["cluster_members"].clone();
+    assert_eq!(members.split(',').count(), 3);
+    assert!(!members.split(',').any(|member| member == removed));
+
+    cluster.shutdown().await
+}
+
+#[tokio::test]
+async fn cluster_over_tcp() -> Result<()> {
+    let cluster = TestCluster::builder(3, TestEngine::Sled)
+        .tcp()
+        .start()
+        .await?;
+    let leader = cluster.wait_for_leader(&[]).await?;
+    write_keys(&cluster, leader, 0..20).await?;
+    cluster.wait_for_sync(leader, &[]).await?;
+
+    let mut client = cluster.client(leader).await?;
+    assert_eq!(client.get("key19".to_owned()).await?, Some("19".to_owned()));
+
+    cluster.shutdown().await
+}
@@ -0,0 +1,146 @@
+use proj5::kvs::inspect;
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::{
+    Codec, KvError, KvStore, KvsEngine, Result, SEGMENT_FORMAT_VERSION, SEGMENT_MAGIC,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Segments written by a store in every format, in BSON up to version 1
+/// and in the default codec after, uncompressed: a compacted `c_1` holding
+/// key1 to key3, and an `a_2` overwriting key2, removing key3 and adding
+/// key4.
+fn fixture(version: u16) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/segments")
+        .join(format!("v{}", version))
+}
+
+/// Copies the fixture, which stores write to, to a new directory.
+fn copy_fixture(version: u16) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(fixture(version))? {
+        let entry = entry?;
+        fs::copy(entry.path(), temp_dir.path().join(entry.file_name()))?;
+    }
+    Ok(temp_dir)
+}
+
+async fn assert_fixture_pairs(store: &KvStore<NaiveThreadPool>) -> Result<()> {
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).await?, Some("new value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned()).await?, None);
+    assert_eq!(store.get("key4".to_owned()).await?, Some("value4".to_owned()));
+    Ok(())
+}
+
+async fn read_fixture(version: u16) -> Result<()> {
+    let temp_dir = copy_fixture(version)?;
+    for check in inspect::verify(temp_dir.path())? {
+        assert_eq!(check.format_version, version);
+        assert_eq!(check.corruption, None);
+    }
+
+    let store = KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1)?;
+    assert_fixture_pairs(&store).await?;
+    store.set("key5".to_owned(), "value5".to_owned()).await?;
+    drop(store);
+
+    // Appended to in its own format
+    let checks = inspect::verify(temp_dir.path())?;
+    assert!(checks.iter().all(|check| check.format_version == version));
+    let store = KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1)?;
+    assert_fixture_pairs(&store).await?;
+    assert_eq!(store.get("key5".to_owned()).await?, Some("value5".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_segments_without_header() -> Result<()> {
+    read_fixture(0).await
+}
+
+#[tokio::test]
+async fn read_bson_segments_with_header() -> Result<()> {
+    read_fixture(1).await
+}
+
+#[tokio::test]
+async fn read_segments_with_codec() -> Result<()> {
+    read_fixture(2).await
+}
+
+#[tokio::test]
+async fn read_segments_with_frame_kinds() -> Result<()> {
+    read_fixture(SEGMENT_FORMAT_VERSION).await
+}
+
+// Should rewrite old segments as the current code writes them
+async fn migrate_fixture(version: u16) -> Result<()> {
+    let temp_dir = copy_fixture(version)?;
+
+    let migrated = inspect::migrate(temp_dir.path())?;
+    assert_eq!(migrated.len(), 2);
+    let codec = match version {
+        0 | 1 => Codec::Bson,
+        _ => Codec::Binary,
+    };
+    assert!(migrated
+        .iter()
+        .all(|check| check.format_version == version && check.codec == codec));
+    for name in &["c_1", "a_2"] {
+        assert_eq!(
+            fs::read(temp_dir.path().join(name))?,
+            fs::read(fixture(SEGMENT_FORMAT_VERSION).join(name))?
+        );
+    }
+    assert!(inspect::migrate(temp_dir.path())?.is_empty());
+
+    let store = KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1)?;
+    assert_fixture_pairs(&store).await
+}
+
+#[tokio::test]
+async fn migrate_segments_without_header() -> Result<()> {
+    migrate_fixture(0).await
+}
+
+#[tokio::test]
+async fn migrate_bson_segments() -> Result<()> {
+    migrate_fixture(1).await
+}
+
+#[tokio::test]
+async fn migrate_segments_with_codec() -> Result<()> {
+    migrate_fixture(2).await
+}
+
+// Should refuse segments of a newer format
+#[test]
+fn refuse_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut header = SEGMENT_MAGIC.to_vec();
+    header.extend_from_slice(&(SEGMENT_FORMAT_VERSION + 1).to_be_bytes());
+    header.extend_from_slice(&[0, 0]);
+    fs::write(temp_dir.path().join("a_1"), header)?;
+
+    assert!(matches!(
+        KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1),
+        Err(KvError::UnsupportedFormat { version, .. }) if version == SEGMENT_FORMAT_VERSION as u32 + 1
+    ));
+    assert!(matches!(
+        inspect::migrate(temp_dir.path()),
+        Err(KvError::UnsupportedFormat { .. })
+    ));
+
+    let mut header = SEGMENT_MAGIC.to_vec();
+    header.extend_from_slice(&SEGMENT_FORMAT_VERSION.to_be_bytes());
+    header.extend_from_slice(&[9, 0]);
+    fs::write(temp_dir.path().join("a_1"), header)?;
+    assert!(matches!(
+        KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1),
+        Err(KvError::UnknownCodec { codec: 9, .. })
+    ));
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use proj5::kvs::inspect;
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::{FileId, KvStore, KvsEngine, Result, SEGMENT_HEADER_SIZE};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+async fn fill(dir: &Path) -> Result<()> {
+    let store = KvStore::<NaiveThreadPool>::open(dir, 1)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned()).await?;
+    }
+    store.set("key0".to_owned(), "new value".to_owned()).await?;
+    store.remove("key1".to_owned()).await
+}
+
+fn append_garbage(dir: &Path) -> Result<u64> {
+    let segment = inspect::segments(dir)?.pop().unwrap();
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(dir.join(String::from(segment.file_id)))?;
+    file.write_all(&[0, 0, 1, 0, 42])?;
+    Ok(segment.bytes)
+}
+
+// Should read every frame and count live and dead entries
+#[tokio::test]
+async fn dump_and_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path()).await?;
+
+    let segments = inspect::segments(temp_dir.path())?;
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].file_id, FileId::Append(1));
+
+    let mut out = Vec::new();
+    let checks = inspect::dump(temp_dir.path(), &mut out)?;
+    assert_eq!(checks[0].frames, 12);
+    assert_eq!(checks[0].corruption, None);
+    let lines: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 12);
+    assert_eq!(lines[0]["file"], "a_1");
+    assert_eq!(lines[0]["offset"], SEGMENT_HEADER_SIZE);
+    assert_eq!(lines[0]["key"], "key0");
+    assert_eq!(lines[11]["cmd"], "Remove");
+
+    let stats = inspect::stats(temp_dir.path())?;
+    assert_eq!(stats.keys, 9);
+    assert_eq!(stats.segments[0].live_keys, 9);
+    // The overwritten and removed values, and the remove itself.
+    assert_eq!(stats.segments[0].dead_keys, 3);
+    assert_eq!(
+        stats.segments[0].live_bytes + stats.segments[0].dead_bytes + SEGMENT_HEADER_SIZE as u64,
+        segments[0].bytes
+    );
+    assert_eq!(stats.key_sizes.buckets().collect::<Vec<_>>(), vec![(4, 9)]);
+    assert_eq!(
+        stats.value_sizes.buckets().collect::<Vec<_>>(),
+        vec![(8, 8), (16, 1)]
+    );
+
+    Ok(())
+}
+
+// Should find a torn frame at the end of a segment and truncate it away
+#[tokio::test]
+async fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path()).await?;
+    let valid_bytes = append_garbage(temp_dir.path())?;
+
+    let checks = inspect::verify(temp_dir.path())?;
+    assert_eq!(checks[0].frames, 12);
+    assert_eq!(checks[0].corruption.as_ref().unwrap().offset as u64, valid_bytes);
+
+    let repaired = inspect::repair(temp_dir.path())?;
+    assert_eq!(repaired.len(), 1);
+    assert_eq!(repaired[0].bytes, valid_bytes + 5);
+
+    let checks = inspect::verify(temp_dir.path())?;
+    assert_eq!(checks[0].corruption, None);
+    assert_eq!(checks[0].bytes, valid_bytes);
+    assert!(inspect::repair(temp_dir.path())?.is_empty());
+
+    let store = KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).await?, Some("new value".to_owned()));
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    Ok(())
+}
@@ -2,7 +2,9 @@ use crate::future::{BoxFuture, FutureExt};
 use futures::future::join_all;
 use futures::{future, join, TryFutureExt};
 use proj5::kvs::thread_pool::RayonThreadPool;
-use proj5::kvs::{KvError, KvStore, KvsEngine, Result};
+use proj5::kvs::{
+    inspect, key_slot, Compression, KvError, KvStore, KvsAdmin, KvsEngine, Result, StoreOptions,
+};
 use std::future::Future;
 use std::pin::Pin;
 use tempfile::TempDir;
@@ -257,3 +259,233 @@ trait Wait {
 }
 
 impl<'a, T> Wait for BoxFuture<'a, T> {}
+
+// Should list the pairs under a prefix, ordered by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store.set("app/b".to_owned(), "2".to_owned()).wait()?;
+    store.set("app/a".to_owned(), "1".to_owned()).wait()?;
+    store.set("other".to_owned(), "3".to_owned()).wait()?;
+    store.set("app/c".to_owned(), "4".to_owned()).wait()?;
+    store.remove("app/c".to_owned()).wait()?;
+
+    let expected = vec![
+        ("app/a".to_owned(), "1".to_owned()),
+        ("app/b".to_owned(), "2".to_owned()),
+    ];
+    assert_eq!(store.scan("app/".to_owned()).wait()?, expected);
+    assert_eq!(store.scan(String::new()).wait()?.len(), 3);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.scan("app/".to_owned()).wait()?, expected);
+
+    Ok(())
+}
+
+// Should walk every pair a batch at a time, resuming after the last key
+#[test]
+fn scan_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    for i in 0..25 {
+        store.set(format!("key{:02}", i), i.to_string()).wait()?;
+    }
+    store.remove("key07".to_owned()).wait()?;
+
+    let mut keys = Vec::new();
+    let mut after = None;
+    loop {
+        let pairs = store.scan_after(after.take(), 10).wait()?;
+        assert!(pairs.len() <= 10);
+        after = match pairs.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => break,
+        };
+        keys.extend(pairs.into_iter().map(|(key, _)| key));
+    }
+    let expected: Vec<Vec<u8>> = (0..25)
+        .filter(|i| *i != 7)
+        .map(|i| format!("key{:02}", i).into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+
+    Ok(())
+}
+
+// Should store keys and values that are not UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let key = vec![0xff, 0, b'k'];
+    store.set_bytes(key.clone(), vec![0x80, 0]).wait()?;
+    store.set("text".to_owned(), "value".to_owned()).wait()?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(vec![0x80, 0]));
+    assert_eq!(
+        store.get_bytes(b"text".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+
+    // The text API fails on values that have no text form.
+    store.set_bytes(b"bin".to_vec(), vec![0xc3]).wait()?;
+    let res = store.get("bin".to_owned()).wait();
+    assert!(matches!(res, Err(KvError::Ut8Conversion { .. })));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(vec![0x80, 0]));
+    assert_eq!(
+        store.scan_bytes(vec![0xff]).wait()?,
+        vec![(key.clone(), vec![0x80, 0])]
+    );
+    store.remove_bytes(key.clone()).wait()?;
+    assert_eq!(store.get_bytes(key).wait()?, None);
+
+    Ok(())
+}
+
+// Should compress large entries and compacted blocks, and read them back
+// whatever the options of the store reading them
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = StoreOptions {
+        compression: Compression::Snappy,
+        compress_threshold: 64,
+        block_compression: true,
+    };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    let value = |i: u32| format!("{{\"id\": {}, \"tags\": [\"{}\"]}}", i, "tag".repeat(50));
+    for i in 0..200 {
+        store.set(format!("key{}", i), value(i)).wait()?;
+    }
+    store.set("small".to_owned(), "value".to_owned()).wait()?;
+    assert!(inspect::stats(temp_dir.path())?.compression_ratio() > 2.0);
+
+    store.compact().wait()?;
+    drop(store);
+    let checks = inspect::verify(temp_dir.path())?;
+    assert_eq!(checks[0].frames, 201);
+    assert_eq!(checks[0].corruption, None);
+    assert!(inspect::stats(temp_dir.path())?.compression_ratio() > 2.0);
+
+    // Open from disk again without compression
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i)).wait()?, Some(value(i)));
+    }
+    assert_eq!(
+        store.get("small".to_owned()).wait()?,
+        Some("value".to_owned())
+    );
+    store
+        .set("key0".to_owned(), "new value".to_owned())
+        .wait()?;
+    assert_eq!(store.scan("key1".to_owned()).wait()?.len(), 111);
+
+    Ok(())
+}
+
+// Should list only the pairs of a hash slot, a batch at a time
+#[test]
+fn scan_slot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    for i in 0..500 {
+        store.set(format!("key{}", i), i.to_string()).wait()?;
+    }
+    let slot = key_slot("key0");
+    let in_slot: Vec<String> = (0..500)
+        .map(|i| format!("key{}", i))
+        .filter(|key| key_slot(key) == slot)
+        .collect();
+
+    let pairs = store.scan_slot(slot, None, 1000).wait()?;
+    let keys: Vec<String> = pairs
+        .into_iter()
+        .map(|(key, _)| String::from_utf8(key).unwrap())
+        .collect();
+    assert_eq!(keys.len(), in_slot.len());
+    assert!(keys.iter().all(|key| in_slot.contains(key)));
+    assert_eq!(store.scan_slot(slot, None, 0).wait()?.len(), 0);
+
+    // Resuming after the last key returned walks the slot a key at a time.
+    let mut walked = Vec::new();
+    let mut after = None;
+    loop {
+        let pairs = store.scan_slot(slot, after.take(), 1).wait()?;
+        after = match pairs.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => break,
+        };
+        walked.extend(
+            pairs
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap()),
+        );
+    }
+    assert_eq!(walked, keys);
+
+    for key in &in_slot {
+        store.remove(key.clone()).wait()?;
+    }
+    assert_eq!(store.scan_slot(slot, None, 1000).wait()?.len(), 0);
+    assert_eq!(store.scan(String::new()).wait()?.len(), 500 - in_slot.len());
+
+    Ok(())
+}
+
+// Should refuse a second open of the directory while the first one holds it
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let res = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1);
+    let pid = std::process::id();
+    assert!(matches!(res, Err(KvError::StoreLocked { pid: Some(p), .. }) if p == pid));
+    let res = KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1);
+    assert!(matches!(res, Err(KvError::StoreLocked { .. })));
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should share the directory between read-only stores, which refuse writes
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    drop(store);
+
+    let first = KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?;
+    let second = KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?;
+    assert_eq!(first.get("key1".to_owned()).wait()?, Some("value1".to_owned()));
+    assert_eq!(second.get("key1".to_owned()).wait()?, Some("value1".to_owned()));
+
+    let res = first.set("key2".to_owned(), "value2".to_owned()).wait();
+    assert!(matches!(res, Err(KvError::StoreReadOnly)));
+    let res = first.remove("key1".to_owned()).wait();
+    assert!(matches!(res, Err(KvError::StoreReadOnly)));
+    assert!(matches!(
+        KvStore::<RayonThreadPool>::open(temp_dir.path(), 1),
+        Err(KvError::StoreLocked { pid: None, .. })
+    ));
+
+    Ok(())
+}
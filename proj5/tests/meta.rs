@@ -0,0 +1,87 @@
+use proj5::kvs::meta::{self, EngineMeta, ENGINE_FORMAT_VERSION, ENGINE_META};
+use proj5::kvs::{Compression, KvError, Result, StoreOptions};
+use std::fs;
+use tempfile::TempDir;
+
+// Should record the engine of a new directory and keep to it
+#[test]
+fn open_records_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    assert_eq!(meta::load(dir)?, None);
+
+    let created = meta::open(dir, Some("sled"))?;
+    assert_eq!(created.engine, "sled");
+    assert_eq!(created.format_version, ENGINE_FORMAT_VERSION);
+    assert_eq!(created.data_dir(dir), dir.join("sled_data"));
+    assert_eq!(meta::load(dir)?, Some(created.clone()));
+
+    assert_eq!(meta::open(dir, None)?, created);
+    assert_eq!(meta::open(dir, Some("sled"))?, created);
+    assert!(matches!(
+        meta::open(dir, Some("kvs")),
+        Err(KvError::EngineMismatch { requested, found, .. }) if requested == "kvs" && found == "sled"
+    ));
+    assert!(matches!(
+        meta::open(dir, Some("rocks")),
+        Err(KvError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+// Should default to kvs and take the engine of directories without metadata
+#[test]
+fn open_selects_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(meta::open(temp_dir.path(), None)?.engine, "kvs");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("sled_data"))?;
+    assert!(matches!(
+        meta::open(temp_dir.path(), Some("kvs")),
+        Err(KvError::EngineMismatch { .. })
+    ));
+    assert_eq!(meta::open(temp_dir.path(), None)?.engine, "sled");
+    Ok(())
+}
+
+// Should refuse a format newer than the one written
+#[test]
+fn refuses_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut newer = EngineMeta::new("kvs");
+    newer.format_version = ENGINE_FORMAT_VERSION + 1;
+    meta::save(temp_dir.path(), &newer)?;
+
+    assert!(matches!(
+        meta::open(temp_dir.path(), None),
+        Err(KvError::UnsupportedFormat { version, .. }) if version == ENGINE_FORMAT_VERSION + 1
+    ));
+    fs::write(temp_dir.path().join(ENGINE_META), b"{")?;
+    assert!(meta::load(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Should record the options of the engine a directory is created for
+#[test]
+fn open_records_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let options = StoreOptions {
+        compression: Compression::Snappy,
+        compress_threshold: 64,
+        block_compression: true,
+    };
+    let created = meta::open_with(dir, None, |engine| {
+        assert_eq!(engine, "kvs");
+        options.to_map()
+    })?;
+    assert_eq!(created.options["compression"], "snappy");
+    assert_eq!(created.options["compress_threshold"], "64");
+    assert_eq!(created.options["block_compression"], "true");
+
+    let reopened = meta::open_with(dir, None, |_| StoreOptions::default().to_map())?;
+    assert_eq!(reopened.options, created.options);
+    assert_eq!(meta::load(dir)?, Some(created));
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{KvError, KvsClient, Result};
+
+async fn fill(client: &mut KvsClient, keys: u32) -> Result<()> {
+    for i in 0..keys {
+        client.set(format!("key{:04}", i), i.to_string()).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn copies_only_differing_ranges() -> Result<()> {
+    let source = TestServer::start(TestEngine::Kvs).await?;
+    let target = TestServer::start(TestEngine::Sled).await?;
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    fill(&mut from, 1000).await?;
+    fill(&mut to, 1000).await?;
+
+    // Diverge the target: a stale value, a missing key and an extra one.
+    to.set("key0100".to_owned(), "stale".to_owned()).await?;
+    to.remove("key0500".to_owned()).await?;
+    to.set("key0900x".to_owned(), "extra".to_owned()).await?;
+
+    let report = from.repair(&mut to).await?;
+    assert_eq!(report.keys_set, 2);
+    assert_eq!(report.keys_removed, 1);
+    assert!(report.ranges_repaired <= 3, "{:?}", report);
+    assert!(report.pairs_read < 200, "{:?}", report);
+
+    assert_eq!(to.get("key0100".to_owned()).await?, Some("100".to_owned()));
+    assert_eq!(to.get("key0500".to_owned()).await?, Some("500".to_owned()));
+    assert_eq!(to.get("key0900x".to_owned()).await?, None);
+
+    // The trees now match at the root.
+    let report = from.repair(&mut to).await?;
+    assert_eq!(report.ranges_compared, 1);
+    assert_eq!(report.ranges_repaired, 0);
+
+    source.shutdown().await?;
+    target.shutdown().await
+}
+
+#[tokio::test]
+async fn empties_and_fills_whole_stores() -> Result<()> {
+    let source = TestServer::start(TestEngine::Sled).await?;
+    let target = TestServer::start(TestEngine::Kvs).await?;
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    fill(&mut to, 300).await?;
+
+    let report = from.repair(&mut to).await?;
+    assert_eq!(report.keys_removed, 300);
+    assert_eq!(to.get("key0000".to_owned()).await?, None);
+
+    fill(&mut from, 300).await?;
+    let report = from.repair(&mut to).await?;
+    assert_eq!(report.keys_set, 300);
+    assert_eq!(to.get("key0299".to_owned()).await?, Some("299".to_owned()));
+
+    source.shutdown().await?;
+    target.shutdown().await
+}
+
+#[tokio::test]
+async fn repairs_a_replica() -> Result<()> {
+    let primary = TestServer::builder(TestEngine::Kvs)
+        .replication(1000)
+        .start()
+        .await?;
+    let mut replica = TestServer::builder(TestEngine::Kvs)
+        .replica_of(primary.addr())
+        .start()
+        .await?;
+    replica.pause_replication().await?;
+
+    let mut from = primary.client().await?;
+    let mut to = replica.client().await?;
+    fill(&mut from, 100).await?;
+    assert!(matches!(
+        to.set("key".to_owned(), "val".to_owned()).await,
+        Err(KvError::ReadOnly { .. })
+    ));
+
+    let report = from.repair(&mut to).await?;
+    assert_eq!(report.keys_set, 100);
+    assert_eq!(to.get("key0042".to_owned()).await?, Some("42".to_owned()));
+
+    primary.shutdown().await?;
+    replica.shutdown().await
+}
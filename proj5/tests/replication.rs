@@ -0,0 +1,189 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{KvError, KvsClient, Result, ServerLimits};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+async fn info(client: &mut KvsClient, field: &str) -> Result<String> {
+    let fields = client.info().await?;
+    Ok(fields
+        .into_iter()
+        .find(|(name, _)| name == field)
+        .map(|(_, val)| val)
+        .unwrap_or_default())
+}
+
+/// Waits until the replica applied every write the primary made.
+async fn wait_synced(primary: &TestServer, replica: &TestServer) -> Result<()> {
+    let mut primary = primary.client().await?;
+    let mut replica = replica.client().await?;
+    let seq = info(&mut primary, "replication_seq").await?;
+    let deadline = Instant::now() + WAIT;
+    while info(&mut replica, "replication_seq").await? != seq {
+        assert!(Instant::now() < deadline, "replica did not reach {}", seq);
+        sleep(Duration::from_millis(10)).await;
+    }
+    Ok(())
+}
+
+async fn start_pair(engine: TestEngine, backlog: usize) -> Result<(TestServer, TestServer)> {
+    let primary = TestServer::builder(TestEngine::Kvs)
+        .replication(backlog)
+        .start()
+        .await?;
+    let replica = TestServer::builder(engine)
+        .replica_of(primary.addr())
+        .start()
+        .await?;
+    Ok((primary, replica))
+}
+
+#[tokio::test]
+async fn replica_applies_writes() -> Result<()> {
+    let (primary, replica) = start_pair(TestEngine::Kvs, 1000).await?;
+    let mut client = primary.client().await?;
+
+    for i in 0..50 {
+        client.set(format!("key{}", i), i.to_string()).await?;
+    }
+    client.remove("key0".to_owned()).await?;
+    client.set("key1".to_owned(), "changed".to_owned()).await?;
+    wait_synced(&primary, &replica).await?;
+
+    let mut reader = replica.client().await?;
+    assert_eq!(reader.get("key0".to_owned()).await?, None);
+    assert_eq!(
+        reader.get("key1".to_owned()).await?,
+        Some("changed".to_owned())
+    );
+    assert_eq!(reader.get("key49".to_owned()).await?, Some("49".to_owned()));
+
+    assert_eq!(info(&mut client, "role").await?, "primary");
+    assert_eq!(info(&mut client, "replicas").await?, "1");
+    assert_eq!(info(&mut reader, "role").await?, "replica");
+    assert_eq!(info(&mut reader, "primary_connected").await?, "true");
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
+
+#[tokio::test]
+async fn replica_rejects_writes() -> Result<()> {
+    let (primary, replica) = start_pair(TestEngine::Kvs, 1000).await?;
+    let mut client = replica.client().await?;
+
+    match client.set("key".to_owned(), "value".to_owned()).await {
+        Err(KvError::ReadOnly { primary: addr }) => assert_eq!(addr, primary.addr().to_string()),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(matches!(
+        client.remove("key".to_owned()).await,
+        Err(KvError::ReadOnly { .. })
+    ));
+    assert_eq!(client.get("key".to_owned()).await?, None);
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
+
+#[tokio::test]
+async fn replica_resumes_from_backlog() -> Result<()> {
+    let (primary, mut replica) = start_pair(TestEngine::Kvs, 1000).await?;
+    let mut client = primary.client().await?;
+
+    client.set("before".to_owned(), "1".to_owned()).await?;
+    wait_synced(&primary, &replica).await?;
+
+    replica.pause_replication().await?;
+    client.set("during".to_owned(), "2".to_owned()).await?;
+    client.remove("before".to_owned()).await?;
+    replica.resume_replication().await?;
+    wait_synced(&primary, &replica).await?;
+
+    let mut reader = replica.client().await?;
+    assert_eq!(reader.get("before".to_owned()).await?, None);
+    assert_eq!(reader.get("during".to_owned()).await?, Some("2".to_owned()));
+    // Only the initial sync loaded a snapshot.
+    assert_eq!(info(&mut reader, "replication_snapshots").await?, "1");
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
+
+#[tokio::test]
+async fn replica_behind_backlog_loads_snapshot() -> Result<()> {
+    let (primary, mut replica) = start_pair(TestEngine::Kvs, 4).await?;
+    let mut client = primary.client().await?;
+
+    client.set("removed".to_owned(), "1".to_owned()).await?;
+    wait_synced(&primary, &replica).await?;
+
+    replica.pause_replication().await?;
+    client.remove("removed".to_owned()).await?;
+    for i in 0..20 {
+        client.set(format!("key{}", i), i.to_string()).await?;
+    }
+    replica.resume_replication().await?;
+    wait_synced(&primary, &replica).await?;
+
+    let mut reader = replica.client().await?;
+    assert_eq!(reader.get("removed".to_owned()).await?, None);
+    for i in 0..20 {
+        assert_eq!(reader.get(format!("key{}", i)).await?, Some(i.to_string()));
+    }
+    assert_eq!(info(&mut reader, "replication_snapshots").await?, "2");
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
+
+#[tokio::test]
+async fn sled_replica() -> Result<()> {
+    let primary = TestServer::builder(TestEngine::Kvs)
+        .replication(1000)
+        .start()
+        .await?;
+    let mut client = primary.client().await?;
+    // Written before the replica exists, sent in its first snapshot.
+    client.set("old".to_owned(), "1".to_owned()).await?;
+
+    let replica = TestServer::builder(TestEngine::Sled)
+        .replica_of(primary.addr())
+        .start()
+        .await?;
+    client.set("new".to_owned(), "2".to_owned()).await?;
+    wait_synced(&primary, &replica).await?;
+
+    let mut reader = replica.client().await?;
+    assert_eq!(reader.get("old".to_owned()).await?, Some("1".to_owned()));
+    assert_eq!(reader.get("new".to_owned()).await?, Some("2".to_owned()));
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
+
+#[tokio::test]
+async fn replicas_do_not_hold_request_slots() -> Result<()> {
+    let limits = ServerLimits {
+        max_pending_requests: 1,
+        ..ServerLimits::default()
+    };
+    let primary = TestServer::builder(TestEngine::Kvs)
+        .replication(1000)
+        .limits(limits)
+        .start()
+        .await?;
+    let replica = TestServer::builder(TestEngine::Kvs)
+        .replica_of(primary.addr())
+        .start()
+        .await?;
+
+    let mut client = primary.client().await?;
+    client.set("key".to_owned(), "1".to_owned()).await?;
+    wait_synced(&primary, &replica).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("1".to_owned()));
+
+    replica.shutdown().await?;
+    primary.shutdown().await
+}
@@ -0,0 +1,450 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::{
+    hash_secret, ClientAuth, Credentials, KvError, KvStore, KvsAddr, KvsClient, KvsServer,
+    RateLimit, Result, ServerLimits,
+};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn access_server(engine: TestEngine) -> Result<()> {
+    let server = TestServer::start(engine).await?;
+    let mut client = server.client().await?;
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    client.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value2".to_owned()));
+
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+    assert!(client.remove("key2".to_owned()).await.is_err());
+
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    let key = vec![0xff, 0, b'k'];
+    client.set_bytes(key.clone(), vec![0x80]).await?;
+    assert_eq!(client.get_bytes(key.clone()).await?, Some(vec![0x80]));
+    client.set_bytes(b"key2".to_vec(), vec![0x80]).await?;
+    assert!(matches!(
+        client.get("key2".to_owned()).await,
+        Err(KvError::Ut8Conversion { .. })
+    ));
+    client.remove_bytes(key.clone()).await?;
+    assert_eq!(client.get_bytes(key).await?, None);
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn access_server_kvs_engine() -> Result<()> {
+    access_server(TestEngine::Kvs).await
+}
+
+#[tokio::test]
+async fn access_server_sled_engine() -> Result<()> {
+    access_server(TestEngine::Sled).await
+}
+
+#[tokio::test]
+async fn bind_ephemeral_ports() -> Result<()> {
+    let server1 = TestServer::start(TestEngine::Kvs).await?;
+    let server2 = TestServer::start(TestEngine::Kvs).await?;
+
+    assert_ne!(server1.addr().tcp().unwrap().port(), 0);
+    assert_ne!(server1.addr(), server2.addr());
+
+    server1.shutdown().await?;
+    server2.shutdown().await
+}
+
+#[tokio::test]
+async fn shutdown_stops_accepting() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let addr = server.addr();
+
+    server.shutdown().await?;
+
+    let log = Logger::root(Discard, o!());
+    assert!(KvsClient::connect(&log, addr).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_server_unix_socket() -> Result<()> {
+    let server = TestServer::start_unix(TestEngine::Kvs).await?;
+    let path = match server.addr() {
+        KvsAddr::Unix(path) => path,
+        addr => panic!("unexpected addr: {}", addr),
+    };
+    assert!(path.exists());
+
+    let mut client = server.client().await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    server.shutdown().await?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// Should refuse the socket of a running server and reuse a stale one
+#[tokio::test]
+async fn unix_socket_in_use() -> Result<()> {
+    let server = TestServer::start_unix(TestEngine::Kvs).await?;
+    let path = match server.addr() {
+        KvsAddr::Unix(path) => path,
+        addr => panic!("unexpected addr: {}", addr),
+    };
+
+    let temp_dir = tempfile::TempDir::new()?;
+    let data_dir = temp_dir.path().join("kvs_data");
+    std::fs::create_dir_all(&data_dir)?;
+    let engine = KvStore::<NaiveThreadPool>::open(data_dir, 1)?;
+    let other = KvsServer::new(engine, Logger::root(Discard, o!()));
+    assert!(other.bind(KvsAddr::Unix(path.clone())).await.is_err());
+    let mut client = server.client().await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    server.shutdown().await?;
+
+    let stale = temp_dir.path().join("stale.sock");
+    drop(std::os::unix::net::UnixListener::bind(&stale)?);
+    assert!(stale.exists());
+    other.bind(KvsAddr::Unix(stale)).await?.shutdown().await
+}
+
+#[tokio::test]
+async fn reject_connections_over_limit() -> Result<()> {
+    let limits = ServerLimits {
+        max_connections: 1,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::builder(TestEngine::Kvs)
+        .limits(limits)
+        .start()
+        .await?;
+
+    let mut client1 = server.client().await?;
+    client1.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    let mut client2 = server.client().await?;
+    match client2.get("key1".to_owned()).await {
+        Err(KvError::Busy { .. }) => {}
+        res => panic!("expected busy, got: {:?}", res),
+    }
+
+    drop(client1);
+    drop(client2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client3 = server.client().await?;
+    assert_eq!(client3.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn reject_requests_over_rate_limit() -> Result<()> {
+    let limits = ServerLimits {
+        rate_limit: Some(RateLimit {
+            requests_per_sec: 1,
+            burst: 2,
+        }),
+        ..ServerLimits::default()
+    };
+    let server = TestServer::builder(TestEngine::Kvs)
+        .limits(limits)
+        .start()
+        .await?;
+
+    let mut client = server.client().await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    match client.get("key1".to_owned()).await {
+        Err(KvError::Busy { .. }) => {}
+        res => panic!("expected busy, got: {:?}", res),
+    }
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn reject_requests_over_pending_limit() -> Result<()> {
+    let limits = ServerLimits {
+        max_pending_requests: 0,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::builder(TestEngine::Sled)
+        .limits(limits)
+        .start()
+        .await?;
+
+    let mut client = server.client().await?;
+    match client.set("key1".to_owned(), "value1".to_owned()).await {
+        Err(KvError::Busy { .. }) => {}
+        res => panic!("expected busy, got: {:?}", res),
+    }
+
+    server.shutdown().await
+}
+
+async fn scrape(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn serve_metrics() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .metrics()
+        .start()
+        .await?;
+
+    let mut client = server.client().await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.set("key1".to_owned(), "value2".to_owned()).await?;
+    client.get("key1".to_owned()).await?;
+
+    let metrics_addr = server.metrics_addr().unwrap();
+    let response = scrape(metrics_addr, "/metrics").await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_requests_total{command=\"set\"} 2\n"));
+    assert!(response.contains("kvs_requests_total{command=\"get\"} 1\n"));
+    assert!(response.contains("kvs_request_duration_seconds_count{command=\"get\"} 1\n"));
+    assert!(response.contains("kvs_active_connections 1\n"));
+    assert!(response.contains("kvs_keydir_keys 1\n"));
+    assert!(response.contains("kvs_segment_dead_bytes{segment=\"a_1\"}"));
+    assert!(response.contains("kvs_compactions_total 0\n"));
+    assert!(!response.contains("kvs_received_bytes_total 0\n"));
+
+    let response = scrape(metrics_addr, "/other").await;
+    assert!(response.starts_with("HTTP/1.1 404"));
+
+    server.shutdown().await
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> &'a str {
+    fields
+        .iter()
+        .find(|(field_name, _)| field_name == name)
+        .map(|(_, val)| val.as_str())
+        .unwrap_or_else(|| panic!("missing field: {}", name))
+}
+
+#[tokio::test]
+async fn admin_commands_kvs_engine() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let mut client = server.client().await?;
+
+    client.ping().await?;
+
+    for i in 0..10 {
+        client.set("key1".to_owned(), format!("value{}", i)).await?;
+    }
+    client.set("key2".to_owned(), "value".to_owned()).await?;
+
+    let info = client.info().await?;
+    assert_eq!(field(&info, "version"), env!("CARGO_PKG_VERSION"));
+    assert_eq!(field(&info, "engine"), "kvs");
+    assert_eq!(field(&info, "keys"), "2");
+    assert!(field(&info, "data_dir").starts_with(server.path().to_str().unwrap()));
+    let disk_before: u64 = field(&info, "disk_bytes").parse().unwrap();
+
+    let stats = client.stats().await?;
+    assert_eq!(field(&stats, "requests_set"), "11");
+    assert_eq!(field(&stats, "compactions"), "0");
+    assert_ne!(field(&stats, "dead_bytes"), "0");
+
+    client.compact().await?;
+    client.flush().await?;
+
+    let stats = client.stats().await?;
+    assert_eq!(field(&stats, "compactions"), "1");
+    assert_eq!(field(&stats, "dead_bytes"), "0");
+
+    let info = client.info().await?;
+    let disk_after: u64 = field(&info, "disk_bytes").parse().unwrap();
+    assert!(disk_after < disk_before);
+
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value9".to_owned())
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn admin_commands_sled_engine() -> Result<()> {
+    let server = TestServer::start(TestEngine::Sled).await?;
+    let mut client = server.client().await?;
+
+    client.ping().await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    let info = client.info().await?;
+    assert_eq!(field(&info, "engine"), "sled");
+    assert_eq!(field(&info, "keys"), "1");
+
+    client.flush().await?;
+    match client.compact().await {
+        Err(KvError::Server { .. }) => {}
+        res => panic!("expected server error, got: {:?}", res),
+    }
+
+    server.shutdown().await
+}
+
+fn test_credentials() -> Credentials {
+    let json = format!(
+        r#"{{"users": [
+            {{"name": "admin", "password": "{}", "admin": true,
+              "acl": [{{"prefix": "", "access": "rw"}}]}},
+            {{"name": "app", "token": "{}",
+              "acl": [{{"prefix": "app/", "access": "rw"}},
+                      {{"prefix": "shared/", "access": "r"}}]}}
+        ]}}"#,
+        hash_secret("admin-password"),
+        hash_secret("app-token")
+    );
+    Credentials::from_json(&json).unwrap()
+}
+
+fn assert_denied<T: std::fmt::Debug>(res: Result<T>) {
+    match res {
+        Err(KvError::PermissionDenied { .. }) => {}
+        res => panic!("expected permission denied, got: {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn require_authentication() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .credentials(test_credentials())
+        .start()
+        .await?;
+    let mut client = server.client().await?;
+
+    client.ping().await?;
+    assert_denied(client.get("app/key".to_owned()).await);
+    assert_denied(client.set("app/key".to_owned(), "value".to_owned()).await);
+
+    let wrong = ClientAuth::Password {
+        user: "admin".to_owned(),
+        password: "wrong".to_owned(),
+    };
+    assert_denied(client.authenticate(&wrong).await);
+
+    let admin = ClientAuth::Password {
+        user: "admin".to_owned(),
+        password: "admin-password".to_owned(),
+    };
+    client.authenticate(&admin).await?;
+    client.set("app/key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("app/key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn close_connection_after_failed_authentication() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .credentials(test_credentials())
+        .start()
+        .await?;
+    // A client over a plain stream, so it does not reconnect.
+    let addr = server.addr().tcp().unwrap();
+    let log = Logger::root(Discard, o!());
+    let mut client = KvsClient::from_stream(&log, TcpStream::connect(addr).await?);
+
+    let wrong = ClientAuth::Token("wrong".to_owned());
+    for _ in 0..3 {
+        assert_denied(client.authenticate(&wrong).await);
+    }
+    assert!(client.ping().await.is_err());
+
+    server.shutdown().await
+}
+
+// Should close the connection of a client sending a large frame before
+// authenticating, and take it once authenticated
+#[tokio::test]
+async fn limit_frame_size_before_authentication() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .credentials(test_credentials())
+        .start()
+        .await?;
+    let addr = server.addr().tcp().unwrap();
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&(1u32 << 30).to_be_bytes()).await?;
+    let mut buf = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buf).await?, 0);
+
+    let mut client = server.client().await?;
+    client
+        .authenticate(&ClientAuth::Token("app-token".to_owned()))
+        .await?;
+    let value = "v".repeat(256 * 1024);
+    client.set("app/key".to_owned(), value.clone()).await?;
+    assert_eq!(client.get("app/key".to_owned()).await?, Some(value));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn enforce_prefix_acl() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .credentials(test_credentials())
+        .start()
+        .await?;
+
+    let mut admin = server.client().await?;
+    admin
+        .authenticate(&ClientAuth::Password {
+            user: "admin".to_owned(),
+            password: "admin-password".to_owned(),
+        })
+        .await?;
+    admin
+        .set("shared/key".to_owned(), "value".to_owned())
+        .await?;
+    admin
+        .set("other/key".to_owned(), "value".to_owned())
+        .await?;
+
+    let mut app = server.client().await?;
+    app.authenticate(&ClientAuth::Token("app-token".to_owned()))
+        .await?;
+
+    app.set("app/key".to_owned(), "value".to_owned()).await?;
+    app.remove("app/key".to_owned()).await?;
+    assert_eq!(
+        app.get("shared/key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    assert_denied(app.set("shared/key".to_owned(), "other".to_owned()).await);
+    assert_denied(app.remove("shared/key".to_owned()).await);
+    assert_denied(app.get("other/key".to_owned()).await);
+    assert_denied(app.stats().await);
+    assert_denied(app.compact().await);
+
+    admin.stats().await?;
+
+    server.shutdown().await
+}
@@ -0,0 +1,149 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{KvError, KvsAddr, Result, ShardedKvsClient};
+use std::collections::HashMap;
+
+const KEYS: usize = 200;
+
+async fn start_servers(n: usize) -> Result<Vec<TestServer>> {
+    let mut servers = Vec::new();
+    for _ in 0..n {
+        servers.push(TestServer::start(TestEngine::Kvs).await?);
+    }
+    Ok(servers)
+}
+
+fn keys() -> Vec<String> {
+    (0..KEYS).map(|i| format!("key{}", i)).collect()
+}
+
+async fn shutdown(servers: Vec<TestServer>) -> Result<()> {
+    for server in servers {
+        server.shutdown().await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn route_keys_to_their_node() -> Result<()> {
+    let servers = start_servers(3).await?;
+    let client = ShardedKvsClient::builder(servers.iter().map(TestServer::addr))
+        .connect()
+        .await?;
+
+    for key in keys() {
+        client.set(key.clone(), format!("{}-value", key)).await?;
+    }
+
+    // Every key is stored on exactly the server the ring picks.
+    let mut per_node = HashMap::new();
+    for server in &servers {
+        let mut direct = server.client().await?;
+        for key in keys() {
+            let stored = direct.get(key.clone()).await?.is_some();
+            let routed = client.node_for(&key) == Some(&server.addr());
+            assert_eq!(stored, routed, "key {} on {}", key, server.addr());
+            if stored {
+                *per_node.entry(server.addr()).or_insert(0) += 1;
+            }
+        }
+    }
+    assert_eq!(per_node.len(), 3);
+
+    client.remove("key0".to_owned()).await?;
+    assert_eq!(client.get("key0".to_owned()).await?, None);
+
+    shutdown(servers).await
+}
+
+#[tokio::test]
+async fn fan_out_multi_key_operations() -> Result<()> {
+    let servers = start_servers(3).await?;
+    let client = ShardedKvsClient::builder(servers.iter().map(TestServer::addr))
+        .connections(2)
+        .connect()
+        .await?;
+
+    let pairs = keys()
+        .into_iter()
+        .map(|key| (key.clone(), format!("{}-value", key)))
+        .collect();
+    client.set_many(pairs).await?;
+
+    let mut wanted = keys();
+    wanted.push("missing".to_owned());
+    let values = client.get_many(wanted).await?;
+    assert_eq!(values.len(), KEYS + 1);
+    for (i, val) in values[..KEYS].iter().enumerate() {
+        assert_eq!(val, &Some(format!("key{}-value", i)));
+    }
+    assert_eq!(values[KEYS], None);
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..KEYS as u8)
+        .map(|i| (vec![0xff, i], vec![i, 0x9f]))
+        .collect();
+    client.set_many_bytes(pairs.clone()).await?;
+    let keys = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values = client.get_many_bytes(keys).await?;
+    for ((_, val), got) in pairs.iter().zip(values) {
+        assert_eq!(got.as_ref(), Some(val));
+    }
+    client.set_bytes(b"key0".to_vec(), vec![0xff]).await?;
+    assert!(matches!(
+        client.get_many(keys()).await,
+        Err(KvError::Ut8Conversion { key, .. }) if key == "key0"
+    ));
+
+    shutdown(servers).await
+}
+
+#[tokio::test]
+async fn add_and_remove_nodes() -> Result<()> {
+    let mut servers = start_servers(4).await?;
+    let addrs: Vec<KvsAddr> = servers.iter().map(TestServer::addr).collect();
+    let mut client = ShardedKvsClient::builder(addrs[..3].to_vec())
+        .connect()
+        .await?;
+
+    let before: Vec<_> = keys().iter().map(|k| client.node_for(k).cloned()).collect();
+    for key in keys() {
+        client.set(key.clone(), key).await?;
+    }
+
+    // Only keys now routed to the new server move, and they read as
+    // missing there until written again.
+    client.add_node(addrs[3].clone()).await?;
+    assert_eq!(client.nodes().len(), 4);
+    let mut moved = 0;
+    for (key, before) in keys().into_iter().zip(before.iter()) {
+        let after = client.node_for(&key).cloned();
+        if &after == before {
+            assert_eq!(client.get(key.clone()).await?, Some(key));
+        } else {
+            assert_eq!(after.as_ref(), Some(&addrs[3]));
+            assert_eq!(client.get(key).await?, None);
+            moved += 1;
+        }
+    }
+    assert!(moved > 0 && moved < KEYS / 2, "moved {} keys", moved);
+
+    // Removing it routes the keys back to where they are stored.
+    assert!(client.remove_node(&addrs[3]));
+    assert!(!client.remove_node(&addrs[3]));
+    for key in keys() {
+        assert_eq!(client.get(key.clone()).await?, Some(key));
+    }
+
+    servers.pop().unwrap().shutdown().await?;
+    shutdown(servers).await
+}
+
+#[tokio::test]
+async fn no_nodes() -> Result<()> {
+    let client = ShardedKvsClient::builder(Vec::<KvsAddr>::new())
+        .connect()
+        .await?;
+    match client.get("key".to_owned()).await {
+        Err(KvError::NoNodes) => Ok(()),
+        res => panic!("expected no nodes, got: {:?}", res),
+    }
+}
@@ -0,0 +1,235 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{key_slot, KvError, KvsClient, Result, ShardedKvsClient, SlotConfig, SlotState};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const KEYS: u32 = 300;
+
+/// A server owning every slot, and an empty one redirecting to it.
+async fn start_pair() -> Result<(TestServer, TestServer)> {
+    let owner = TestServer::builder(TestEngine::Kvs)
+        .slots(SlotConfig::new(vec![0..=16383]))
+        .start()
+        .await?;
+    let empty = TestServer::builder(TestEngine::Sled)
+        .slots(SlotConfig::new(vec![]).node(0..=16383, owner.addr()))
+        .start()
+        .await?;
+    Ok((owner, empty))
+}
+
+async fn fill(client: &mut KvsClient) -> Result<()> {
+    for i in 0..KEYS {
+        client.set(format!("key{}", i), i.to_string()).await?;
+    }
+    Ok(())
+}
+
+/// Two keys of the same slot.
+fn same_slot_keys() -> (String, String) {
+    let first = "key0".to_owned();
+    let slot = key_slot(&first);
+    let second = (1..)
+        .map(|i| format!("key{}", i))
+        .find(|key| key_slot(key) == slot)
+        .unwrap();
+    (first, second)
+}
+
+#[tokio::test]
+async fn moved_slots_redirect_for_good() -> Result<()> {
+    let (source, target) = start_pair().await?;
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    fill(&mut from).await?;
+
+    let moved = (0..KEYS)
+        .filter(|i| key_slot(&format!("key{}", i)) < 4096)
+        .count();
+    assert_eq!(from.move_slots(&mut to, 0..=4095).await?, moved as u64);
+
+    for i in 0..KEYS {
+        let key = format!("key{}", i);
+        let slot = key_slot(&key);
+        let (owner, other, owner_addr) = if slot < 4096 {
+            (&mut to, &mut from, target.addr())
+        } else {
+            (&mut from, &mut to, source.addr())
+        };
+        assert_eq!(owner.get(key.clone()).await?, Some(i.to_string()));
+        match other.get(key).await {
+            Err(KvError::Moved { slot: s, addr }) => {
+                assert_eq!(s, slot);
+                assert_eq!(addr, owner_addr.to_string());
+            }
+            res => panic!("expected MOVED, got {:?}", res),
+        }
+    }
+
+    source.shutdown().await?;
+    target.shutdown().await
+}
+
+#[tokio::test]
+async fn migrating_slot_asks_for_moved_keys() -> Result<()> {
+    let (source, target) = start_pair().await?;
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    let (kept, missing) = same_slot_keys();
+    let slot = key_slot(&kept);
+    from.set(kept.clone(), "val".to_owned()).await?;
+
+    let importing = SlotState::Importing {
+        from: source.addr().to_string(),
+    };
+    to.set_slot(slot, importing).await?;
+    let migrating = SlotState::Migrating {
+        to: target.addr().to_string(),
+    };
+    from.set_slot(slot, migrating).await?;
+
+    // The source serves the keys it holds and sends the others on.
+    assert_eq!(from.get(kept.clone()).await?, Some("val".to_owned()));
+    assert!(matches!(
+        from.get(missing.clone()).await,
+        Err(KvError::Ask { slot: s, .. }) if s == slot
+    ));
+    assert!(matches!(
+        from.set(missing, "new".to_owned()).await,
+        Err(KvError::Ask { .. })
+    ));
+    // The target only serves requests following an ASK.
+    assert!(matches!(
+        to.get(kept.clone()).await,
+        Err(KvError::Moved { .. })
+    ));
+
+    assert_eq!(from.migrate_slot(slot).await?, 1);
+    assert!(matches!(
+        from.get(kept.clone()).await,
+        Err(KvError::Ask { .. })
+    ));
+
+    to.set_slot(slot, SlotState::Owned).await?;
+    let node = SlotState::Node {
+        addr: target.addr().to_string(),
+    };
+    from.set_slot(slot, node).await?;
+    assert_eq!(to.get(kept.clone()).await?, Some("val".to_owned()));
+    assert!(matches!(from.get(kept).await, Err(KvError::Moved { .. })));
+
+    source.shutdown().await?;
+    target.shutdown().await
+}
+
+async fn replication_seq(server: &TestServer) -> Result<String> {
+    let fields = server.client().await?.info().await?;
+    Ok(fields
+        .into_iter()
+        .find(|(name, _)| name == "replication_seq")
+        .map(|(_, val)| val)
+        .unwrap_or_default())
+}
+
+#[tokio::test]
+async fn aborted_import_drops_keys_on_replicas() -> Result<()> {
+    let source = TestServer::builder(TestEngine::Kvs)
+        .slots(SlotConfig::new(vec![0..=16383]))
+        .start()
+        .await?;
+    let target = TestServer::builder(TestEngine::Kvs)
+        .replication(1000)
+        .slots(SlotConfig::new(vec![]).node(0..=16383, source.addr()))
+        .start()
+        .await?;
+    let replica = TestServer::builder(TestEngine::Kvs)
+        .replica_of(target.addr())
+        .start()
+        .await?;
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    let (first, second) = same_slot_keys();
+    let slot = key_slot(&first);
+    from.set(first.clone(), "val".to_owned()).await?;
+    from.set(second.clone(), "val".to_owned()).await?;
+
+    let importing = SlotState::Importing {
+        from: source.addr().to_string(),
+    };
+    to.set_slot(slot, importing).await?;
+    let migrating = SlotState::Migrating {
+        to: target.addr().to_string(),
+    };
+    from.set_slot(slot, migrating).await?;
+    assert_eq!(from.migrate_slot(slot).await?, 2);
+
+    let node = SlotState::Node {
+        addr: source.addr().to_string(),
+    };
+    to.set_slot(slot, node).await?;
+    let seq = replication_seq(&target).await?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while replication_seq(&replica).await? != seq {
+        assert!(Instant::now() < deadline, "replica did not reach {}", seq);
+        sleep(Duration::from_millis(10)).await;
+    }
+    // Two imported keys, then their two removals.
+    assert_eq!(seq, "4");
+    let mut reader = replica.client().await?;
+    assert_eq!(reader.get(first).await?, None);
+    assert_eq!(reader.get(second).await?, None);
+
+    replica.shutdown().await?;
+    target.shutdown().await?;
+    source.shutdown().await
+}
+
+#[tokio::test]
+async fn sharded_client_follows_redirects_during_move() -> Result<()> {
+    let (source, target) = start_pair().await?;
+    let client = ShardedKvsClient::builder(vec![source.addr(), target.addr()])
+        .connect()
+        .await?;
+    for i in 0..KEYS {
+        client.set(format!("key{}", i), i.to_string()).await?;
+    }
+
+    let mut from = source.client().await?;
+    let mut to = target.client().await?;
+    let done = AtomicBool::new(false);
+    let mover = async {
+        let moved = from.move_slots(&mut to, 0..=2047).await;
+        done.store(true, Ordering::SeqCst);
+        moved
+    };
+    let writer = async {
+        let mut round = 0;
+        while round == 0 || !done.load(Ordering::SeqCst) {
+            round += 1;
+            for i in 0..KEYS {
+                let key = format!("key{}", i);
+                let val = format!("{}-{}", i, round);
+                client.set(key.clone(), val.clone()).await?;
+                assert_eq!(client.get(key).await?, Some(val));
+            }
+        }
+        Ok::<u32, KvError>(round)
+    };
+    let (moved, rounds) = tokio::join!(mover, writer);
+    moved?;
+    let rounds = rounds?;
+
+    let mut direct = target.client().await?;
+    for i in 0..KEYS {
+        let key = format!("key{}", i);
+        let val = Some(format!("{}-{}", i, rounds));
+        assert_eq!(client.get(key.clone()).await?, val);
+        if key_slot(&key) < 2048 {
+            assert_eq!(direct.get(key).await?, val);
+        }
+    }
+
+    source.shutdown().await?;
+    target.shutdown().await
+}
@@ -0,0 +1,170 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{BlockingKvsClient, ClientTls, Result, ServerTls};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    SanType,
+};
+use std::net::{IpAddr, Ipv4Addr};
+
+struct Pem {
+    cert: String,
+    key: String,
+}
+
+fn ca(name: &str) -> Certificate {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+fn leaf(ca: &Certificate, purpose: ExtendedKeyUsagePurpose) -> Pem {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "kvs");
+    params.subject_alt_names = vec![
+        SanType::DnsName("localhost".to_string()),
+        SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    ];
+    params.extended_key_usages = vec![purpose];
+    let cert = Certificate::from_params(params).unwrap();
+    Pem {
+        cert: cert.serialize_pem_with_signer(ca).unwrap(),
+        key: cert.serialize_private_key_pem(),
+    }
+}
+
+fn server_tls(ca: &Certificate, client_ca: Option<&Certificate>) -> ServerTls {
+    let server = leaf(ca, ExtendedKeyUsagePurpose::ServerAuth);
+    let client_ca = client_ca.map(|ca| ca.serialize_pem().unwrap());
+    ServerTls::from_pem(
+        server.cert.as_bytes(),
+        server.key.as_bytes(),
+        client_ca.as_ref().map(|ca| ca.as_bytes()),
+    )
+    .unwrap()
+}
+
+fn client_tls(ca: &Certificate, identity: Option<&Pem>) -> ClientTls {
+    let ca = ca.serialize_pem().unwrap();
+    let identity = identity.map(|pem| (pem.cert.as_bytes(), pem.key.as_bytes()));
+    ClientTls::from_pem(ca.as_bytes(), identity).unwrap()
+}
+
+#[tokio::test]
+async fn access_server_over_tls() -> Result<()> {
+    let ca = ca("kvs ca");
+    let server = TestServer::builder(TestEngine::Kvs)
+        .tls(server_tls(&ca, None))
+        .start()
+        .await?;
+
+    let mut client = server.tls_client(&client_tls(&ca, None)).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn access_unix_socket_over_tls() -> Result<()> {
+    let ca = ca("kvs ca");
+    let server = TestServer::builder(TestEngine::Sled)
+        .tls(server_tls(&ca, None))
+        .unix()
+        .start()
+        .await?;
+
+    let mut client = server.tls_client(&client_tls(&ca, None)).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    client.ping().await?;
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn reject_untrusted_server() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .tls(server_tls(&ca("kvs ca"), None))
+        .start()
+        .await?;
+
+    assert!(server
+        .tls_client(&client_tls(&ca("other ca"), None))
+        .await
+        .is_err());
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn reject_plaintext_client() -> Result<()> {
+    let server = TestServer::builder(TestEngine::Kvs)
+        .tls(server_tls(&ca("kvs ca"), None))
+        .start()
+        .await?;
+
+    let mut client = server.client().await?;
+    assert!(client.ping().await.is_err());
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn mutual_tls() -> Result<()> {
+    let ca = ca("kvs ca");
+    let client_ca = ca_with_clients();
+    let server = TestServer::builder(TestEngine::Kvs)
+        .tls(server_tls(&ca, Some(&client_ca.0)))
+        .start()
+        .await?;
+
+    let mut client = server
+        .tls_client(&client_tls(&ca, Some(&client_ca.1)))
+        .await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    // TLS 1.3 clients finish the handshake before the server checks the
+    // certificate, the first request fails instead.
+    let anonymous = server.tls_client(&client_tls(&ca, None)).await;
+    assert!(match anonymous {
+        Ok(mut client) => client.ping().await.is_err(),
+        Err(_) => true,
+    });
+
+    server.shutdown().await
+}
+
+fn ca_with_clients() -> (Certificate, Pem) {
+    let ca = ca("kvs client ca");
+    let client = leaf(&ca, ExtendedKeyUsagePurpose::ClientAuth);
+    (ca, client)
+}
+
+#[tokio::test]
+async fn blocking_client_over_tls() -> Result<()> {
+    let client_ca = ca("kvs client ca");
+    let ca = ca("kvs ca");
+    let server = TestServer::builder(TestEngine::Kvs)
+        .tls(server_tls(&ca, Some(&client_ca)))
+        .start()
+        .await?;
+
+    let identity = leaf(&client_ca, ExtendedKeyUsagePurpose::ClientAuth);
+    let tls = client_tls(&ca, Some(&identity));
+    let addr = server.addr();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut client = BlockingKvsClient::builder(addr)
+            .tls(tls)
+            .connect_blocking()?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    })
+    .await
+    .unwrap()?;
+
+    server.shutdown().await
+}
@@ -0,0 +1,201 @@
+use proj5::kvs::thread_pool::NaiveThreadPool;
+use proj5::kvs::transfer::{self, PairFormat};
+use proj5::kvs::{Encoding, KvError, KvStore, KvsAdmin, KvsEngine, Result, SledKvsEngine};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn pairs() -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    pairs.push(("odd,key".to_owned(), "a \"quoted\",\nmulti-line value".to_owned()));
+    pairs.push(("user/1".to_owned(), "".to_owned()));
+    pairs.push(("user/2".to_owned(), "\r\n".to_owned()));
+    pairs.sort();
+    pairs
+}
+
+async fn fill_kvs(dir: &Path) -> Result<()> {
+    let store = KvStore::<NaiveThreadPool>::open(dir, 1)?;
+    for (key, val) in pairs() {
+        store.set(key, val).await?;
+    }
+    Ok(())
+}
+
+fn export(engine: &str, dir: &Path, prefix: &str, format: PairFormat) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    transfer::export(engine, dir, prefix, format, Encoding::Text, &mut out)?;
+    Ok(out)
+}
+
+async fn migrate_and_back(format: PairFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_dir = temp_dir.path().join("kvs_data");
+    let sled_dir = temp_dir.path().join("sled_data");
+    let kvs_copy_dir = temp_dir.path().join("kvs_copy");
+    fill_kvs(&kvs_dir).await?;
+
+    let exported = export("kvs", &kvs_dir, "", format)?;
+    let count = transfer::import(
+        "sled",
+        &sled_dir,
+        format,
+        Encoding::Text,
+        &mut exported.as_slice(),
+    )?;
+    assert_eq!(count, pairs().len() as u64);
+
+    let sled = SledKvsEngine::<NaiveThreadPool>::open(&sled_dir, 1)?;
+    assert_eq!(sled.scan(String::new()).await?, pairs());
+    drop(sled);
+
+    let exported_again = export("sled", &sled_dir, "", format)?;
+    assert_eq!(exported, exported_again);
+    transfer::import(
+        "kvs",
+        &kvs_copy_dir,
+        format,
+        Encoding::Text,
+        &mut exported_again.as_slice(),
+    )?;
+
+    let store = KvStore::<NaiveThreadPool>::open(&kvs_copy_dir, 1)?;
+    assert_eq!(store.scan(String::new()).await?, pairs());
+    Ok(())
+}
+
+#[tokio::test]
+async fn migrate_json_lines() -> Result<()> {
+    migrate_and_back(PairFormat::JsonLines).await
+}
+
+#[tokio::test]
+async fn migrate_csv() -> Result<()> {
+    migrate_and_back(PairFormat::Csv).await
+}
+
+#[tokio::test]
+async fn export_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill_kvs(temp_dir.path()).await?;
+
+    let exported = export("kvs", temp_dir.path(), "user/", PairFormat::Csv)?;
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "key,value\nuser/1,\nuser/2,\"\r\n\"\n"
+    );
+
+    let exported = export("kvs", temp_dir.path(), "key050", PairFormat::JsonLines)?;
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "{\"key\":\"key050\",\"value\":\"value50\"}\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_rejects_bad_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_dir = temp_dir.path().join("kvs_data");
+
+    let input = "key,value\nkey1,value1\nkey2\n";
+    let res = transfer::import(
+        "kvs",
+        &kvs_dir,
+        PairFormat::Csv,
+        Encoding::Text,
+        &mut input.as_bytes(),
+    );
+    assert!(matches!(res, Err(KvError::InvalidArgument { arg, .. }) if arg == "line 3"));
+    // Nothing is left behind by a failed import.
+    let input = "{\"key\":\"key1\",\"value\":\"value1\"}\n";
+    transfer::import(
+        "kvs",
+        &kvs_dir,
+        PairFormat::JsonLines,
+        Encoding::Text,
+        &mut input.as_bytes(),
+    )?;
+
+    // A kvs directory is only imported into once.
+    let res = transfer::import(
+        "kvs",
+        &kvs_dir,
+        PairFormat::JsonLines,
+        Encoding::Text,
+        &mut input.as_bytes(),
+    );
+    assert!(matches!(res, Err(KvError::InvalidArgument { .. })));
+
+    let store = KvStore::<NaiveThreadPool>::open(&kvs_dir, 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn binary_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_dir = temp_dir.path().join("kvs_data");
+    let sled_dir = temp_dir.path().join("sled_data");
+    let store = KvStore::<NaiveThreadPool>::open(&kvs_dir, 1)?;
+    store.set_bytes(vec![0xff, 0], vec![0x80]).await?;
+    drop(store);
+
+    // Binary data has no text form.
+    let res = export("kvs", &kvs_dir, "", PairFormat::JsonLines);
+    assert!(matches!(res, Err(KvError::InvalidArgument { arg, .. }) if arg == "text"));
+
+    let mut exported = Vec::new();
+    transfer::export(
+        "kvs",
+        &kvs_dir,
+        "ff",
+        PairFormat::Csv,
+        Encoding::Hex,
+        &mut exported,
+    )?;
+    assert_eq!(
+        String::from_utf8(exported.clone()).unwrap(),
+        "key,value\nff00,80\n"
+    );
+    let mut input = exported.as_slice();
+    transfer::import(
+        "sled",
+        &sled_dir,
+        PairFormat::Csv,
+        Encoding::Hex,
+        &mut input,
+    )?;
+
+    let sled = SledKvsEngine::<NaiveThreadPool>::open(&sled_dir, 1)?;
+    assert_eq!(sled.get_bytes(vec![0xff, 0]).await?, Some(vec![0x80]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_keeps_the_last_pair_of_a_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let input = "key,value\nkey1,old\nkey2,value2\nkey1,new\n";
+    let count = transfer::import(
+        "kvs",
+        temp_dir.path(),
+        PairFormat::Csv,
+        Encoding::Text,
+        &mut input.as_bytes(),
+    )?;
+    assert_eq!(count, 3);
+
+    let store = KvStore::<NaiveThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("new".to_owned()));
+    // The compacted segment holds nothing to reclaim.
+    let stats = store.admin().unwrap().stats().await?;
+    assert_eq!(stats.keys, 2);
+    assert!(stats
+        .details
+        .contains(&("dead_bytes".to_string(), "0".to_string())));
+    Ok(())
+}
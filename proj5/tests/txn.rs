@@ -0,0 +1,248 @@
+use proj5::kvs::testing::{TestEngine, TestServer};
+use proj5::kvs::{KvError, KvsClient, Result, ShardedKvsClient};
+use std::sync::Arc;
+
+async fn start(engine: TestEngine) -> Result<TestServer> {
+    TestServer::builder(engine).tso().start().await
+}
+
+async fn commit_pairs(client: &mut KvsClient, pairs: &[(&str, &str)]) -> Result<()> {
+    let mut txn = client.begin().await?;
+    for (key, val) in pairs {
+        txn.set(key.to_string(), val.to_string());
+    }
+    txn.commit().await
+}
+
+#[tokio::test]
+async fn reads_snapshot_at_start() -> Result<()> {
+    let server = start(TestEngine::Kvs).await?;
+    let mut client = server.client().await?;
+    let mut writer = server.client().await?;
+    commit_pairs(&mut writer, &[("a", "1"), ("b", "1")]).await?;
+
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get("a".to_owned()).await?, Some("1".to_owned()));
+    commit_pairs(&mut writer, &[("a", "2"), ("b", "2")]).await?;
+    assert_eq!(txn.get("b".to_owned()).await?, Some("1".to_owned()));
+    assert_eq!(txn.get("a".to_owned()).await?, Some("1".to_owned()));
+    drop(txn);
+
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get("b".to_owned()).await?, Some("2".to_owned()));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn reads_own_writes() -> Result<()> {
+    let server = start(TestEngine::Sled).await?;
+    let mut client = server.client().await?;
+    commit_pairs(&mut client, &[("a", "1")]).await?;
+
+    let mut txn = client.begin().await?;
+    txn.set("b".to_owned(), "2".to_owned());
+    txn.remove("a".to_owned());
+    txn.remove("missing".to_owned());
+    assert_eq!(txn.get("a".to_owned()).await?, None);
+    assert_eq!(txn.get("b".to_owned()).await?, Some("2".to_owned()));
+    txn.commit().await?;
+
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get("a".to_owned()).await?, None);
+    assert_eq!(txn.get("b".to_owned()).await?, Some("2".to_owned()));
+    drop(txn);
+
+    // Transactional keys live apart from the plain ones.
+    assert_eq!(client.get("b".to_owned()).await?, None);
+    assert!(matches!(
+        client.set("__txn/l/b".to_owned(), "x".to_owned()).await,
+        Err(KvError::Server { .. })
+    ));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn binary_keys_and_values() -> Result<()> {
+    let server = start(TestEngine::Kvs).await?;
+    let mut client = server.client().await?;
+    let key = vec![0, 0xff, b'k'];
+    let val = vec![0x9f, 0, b'v'];
+
+    let mut txn = client.begin().await?;
+    txn.set_bytes(key.clone(), val.clone());
+    txn.commit().await?;
+
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get_bytes(key.clone()).await?, Some(val));
+    txn.set_bytes(b"text".to_vec(), vec![0xff]);
+    assert!(matches!(
+        txn.get("text".to_owned()).await,
+        Err(KvError::Ut8Conversion { .. })
+    ));
+    txn.remove_bytes(key.clone());
+    txn.commit().await?;
+
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get_bytes(key).await?, None);
+    drop(txn);
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn concurrent_writers_conflict() -> Result<()> {
+    let server = start(TestEngine::Kvs).await?;
+    let mut first = server.client().await?;
+    let mut second = server.client().await?;
+
+    let mut txn1 = first.begin().await?;
+    let mut txn2 = second.begin().await?;
+    txn1.set("key".to_owned(), "1".to_owned());
+    txn2.set("key".to_owned(), "2".to_owned());
+    txn2.set("other".to_owned(), "2".to_owned());
+    txn1.commit().await?;
+    match txn2.commit().await {
+        Err(KvError::TxnConflict { key }) => assert_eq!(key, "key"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Nothing of the failed transaction is visible or left locked.
+    let mut txn = first.begin().await?;
+    assert_eq!(txn.get("key".to_owned()).await?, Some("1".to_owned()));
+    assert_eq!(txn.get("other".to_owned()).await?, None);
+    txn.set("other".to_owned(), "3".to_owned());
+    txn.commit().await?;
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn retried_increments_are_serializable() -> Result<()> {
+    let server = Arc::new(start(TestEngine::Kvs).await?);
+
+    let mut tasks = Vec::new();
+    for _ in 0..4 {
+        let server = server.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut client = server.client().await?;
+            for _ in 0..10 {
+                loop {
+                    let mut txn = client.begin().await?;
+                    let count: u32 = match txn.get("counter".to_owned()).await {
+                        Ok(val) => val.map_or(0, |val| val.parse().unwrap()),
+                        Err(KvError::TxnLocked { .. }) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    txn.set("counter".to_owned(), (count + 1).to_string());
+                    match txn.commit().await {
+                        Ok(()) => break,
+                        Err(KvError::TxnConflict { .. }) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Ok::<_, KvError>(())
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    let mut client = server.client().await?;
+    let mut txn = client.begin().await?;
+    assert_eq!(txn.get("counter".to_owned()).await?, Some("40".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfers_across_shards_keep_total() -> Result<()> {
+    let oracle = start(TestEngine::Kvs).await?;
+    let other = TestServer::start(TestEngine::Sled).await?;
+    let third = TestServer::start(TestEngine::Kvs).await?;
+    let client = ShardedKvsClient::builder(vec![oracle.addr(), other.addr(), third.addr()])
+        .oracle(oracle.addr())
+        .connect()
+        .await?;
+    let client = Arc::new(client);
+
+    let accounts: Vec<String> = (0..8).map(|i| format!("account{}", i)).collect();
+    let mut txn = client.begin().await?;
+    for account in &accounts {
+        txn.set(account.clone(), "100".to_owned());
+    }
+    txn.commit().await?;
+
+    let mut tasks = Vec::new();
+    for i in 0..4 {
+        let client = client.clone();
+        let accounts = accounts.clone();
+        tasks.push(tokio::spawn(async move {
+            for j in 0..10 {
+                let from = &accounts[(i + j) % accounts.len()];
+                let to = &accounts[(i * 3 + j + 1) % accounts.len()];
+                if from == to {
+                    continue;
+                }
+                let mut txn = client.begin().await?;
+                let balance = |val: Option<String>| -> i64 { val.unwrap().parse().unwrap() };
+                let (from_balance, to_balance) =
+                    match (txn.get(from.clone()).await, txn.get(to.clone()).await) {
+                        (Ok(a), Ok(b)) => (balance(a), balance(b)),
+                        (Err(KvError::TxnLocked { .. }), _)
+                        | (_, Err(KvError::TxnLocked { .. })) => continue,
+                        (Err(e), _) | (_, Err(e)) => return Err(e),
+                    };
+                txn.set(from.clone(), (from_balance - 7).to_string());
+                txn.set(to.clone(), (to_balance + 7).to_string());
+                match txn.commit().await {
+                    Ok(()) | Err(KvError::TxnConflict { .. }) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok::<_, KvError>(())
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    let mut txn = client.begin().await?;
+    let mut total = 0;
+    for account in &accounts {
+        total += txn
+            .get(account.clone())
+            .await?
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+    }
+    assert_eq!(total, 800);
+
+    // Every server holds some of the accounts.
+    for server in [&oracle, &other, &third] {
+        let keys = server.client().await?.info().await?;
+        let keys = keys.iter().find(|(name, _)| name == "keys").unwrap();
+        assert_ne!(keys.1, "0");
+    }
+
+    drop(txn);
+    oracle.shutdown().await?;
+    other.shutdown().await?;
+    third.shutdown().await
+}
+
+#[tokio::test]
+async fn begin_needs_an_oracle() -> Result<()> {
+    let server = TestServer::start(TestEngine::Kvs).await?;
+    let mut client = server.client().await?;
+    assert!(matches!(client.begin().await, Err(KvError::Server { .. })));
+
+    let sharded = ShardedKvsClient::builder(vec![server.addr()])
+        .connect()
+        .await?;
+    assert!(matches!(sharded.begin().await, Err(KvError::Txn { .. })));
+
+    server.shutdown().await
+}